@@ -0,0 +1,70 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Benchmarks for the cost of logging calls as seen by the caller.
+//!
+//! The logger is backed by an in-memory SQLite database so that the background writes are as
+//! cheap as possible and the measurements are dominated by the work done in the logging path.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use db_logger::{sqlite, Handle, LoggerOptions};
+use log::Level;
+use tokio::runtime::Runtime;
+
+/// Installs the global logger backed by an in-memory database, recording entries up to `Debug`.
+///
+/// The returned runtime hosts the logger's background task and must outlive the handle.
+fn setup() -> (Runtime, Handle) {
+    let runtime = Runtime::new().unwrap();
+    let handle = runtime.block_on(async {
+        let db = sqlite::connect(sqlite::ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        let opts =
+            LoggerOptions { default_level: Level::Debug, ignore_env: true, ..Default::default() };
+        db_logger::init_with_options(db, opts).await.unwrap()
+    });
+    (runtime, handle)
+}
+
+/// Measures the cost of logging calls with different kinds of messages and levels.
+fn bench_log(c: &mut Criterion) {
+    let (runtime, handle) = setup();
+
+    let mut group = c.benchmark_group("log");
+    group.bench_function("static_message", |b| {
+        b.iter(|| log::info!("A message without arguments"));
+    });
+    group.bench_function("formatted_message", |b| {
+        b.iter(|| log::info!("A message with {} arguments: {}", black_box(2), black_box("foo")));
+    });
+    group.bench_function("debug_level", |b| {
+        b.iter(|| log::debug!("A message at the lowest recorded level"));
+    });
+    group.bench_function("disabled_level", |b| {
+        b.iter(|| log::trace!("A message below the recorded level"));
+    });
+    group.finish();
+
+    drop(handle);
+    drop(runtime);
+}
+
+criterion_group!(benches, bench_log);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Benchmarks for the throughput of the writes to a PostgreSQL database.
+//!
+//! The logger writes to unique tables in the test database configured via the `POSTGRES_TEST_*`
+//! environment variables.  The logger is a global resource so a single run can only measure one
+//! way of writing batches: set `DB_LOGGER_BENCH_COPY_THRESHOLD` to the `copy_threshold` to use,
+//! or leave it unset to write with `INSERT`.  `make bench-postgres` runs both.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use db_logger::{postgres, Connection, Handle, LogEntry, LoggerOptions};
+use log::Level;
+use std::env;
+use std::time::Instant;
+use time::OffsetDateTime;
+use tokio::runtime::Runtime;
+
+/// Number of log entries to write in every iteration.
+const ENTRIES_PER_ITERATION: usize = 1000;
+
+/// Returns the `copy_threshold` requested via `DB_LOGGER_BENCH_COPY_THRESHOLD`, if any.
+fn copy_threshold() -> Option<usize> {
+    env::var("DB_LOGGER_BENCH_COPY_THRESHOLD")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().expect("DB_LOGGER_BENCH_COPY_THRESHOLD must be a number"))
+}
+
+/// Installs the global logger backed by the test database, writing batches of at least
+/// `copy_threshold` entries with `COPY`.
+///
+/// The returned runtime hosts the logger's background task and must outlive the handle.  The
+/// returned connection must be dropped last, outside of the runtime, to clean up the database.
+fn setup(copy_threshold: Option<usize>) -> (Runtime, Handle, Connection) {
+    #[tokio::main]
+    async fn prepare(copy_threshold: Option<usize>) -> Connection {
+        let mut opts = postgres::ConnectionOptions::from_env("POSTGRES_TEST").unwrap();
+        opts.copy_threshold = copy_threshold;
+        postgres::setup_test(opts).await
+    }
+    let db = prepare(copy_threshold);
+
+    let runtime = Runtime::new().unwrap();
+    let handle = runtime.block_on(async {
+        // Only write full batches and explicit flushes so that timers do not skew the results.
+        let opts = LoggerOptions { ignore_env: true, flush_delay: None, ..Default::default() };
+        db_logger::init_with_options(db.clone(), opts).await.unwrap()
+    });
+    (runtime, handle, db)
+}
+
+/// Creates the `i`th log entry to write.
+fn new_entry(i: usize) -> LogEntry {
+    let mut entry = LogEntry::new(
+        OffsetDateTime::now_utc(),
+        "bench-host",
+        Level::Info,
+        format!("Benchmark entry number {} with some text to make it realistic", i),
+    );
+    entry.module = Some("db_logger::bench".into());
+    entry.filename = Some("benches/postgres.rs".into());
+    entry.line = Some(123);
+    entry
+}
+
+/// Measures the throughput of persisting log entries.
+fn bench_write(c: &mut Criterion) {
+    let copy_threshold = copy_threshold();
+    let (runtime, handle, db) = setup(copy_threshold);
+
+    let mut group = c.benchmark_group("postgres_write");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ENTRIES_PER_ITERATION as u64));
+    let name = match copy_threshold {
+        Some(threshold) => format!("copy_threshold_{}", threshold),
+        None => "insert".to_owned(),
+    };
+    group.bench_function(name, |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            for _ in 0..iters {
+                for i in 0..ENTRIES_PER_ITERATION {
+                    handle.record(new_entry(i));
+                }
+                handle.flush().unwrap();
+            }
+            start.elapsed()
+        });
+    });
+    group.finish();
+
+    drop(handle);
+    drop(runtime);
+    drop(db);
+}
+
+criterion_group!(benches, bench_write);
+criterion_main!(benches);
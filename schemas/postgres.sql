@@ -17,8 +17,8 @@ CREATE TABLE logs (
     timestamp TIMESTAMPTZ NOT NULL,
 
     -- The sequence number is a monotonically increasing number for each instance of the server that
-    -- wraps around.  Needed to disambiguate log messages when the timestamps do not have sufficient
-    -- granularity.
+    -- wraps around to zero after reaching the maximum value of a BIGINT.  Needed to disambiguate log
+    -- messages when the timestamps do not have sufficient granularity.
     -- TODO(jmmv): A SMALLSERIAL would be sufficient, but sqlx 0.5 doesn't support them.
     sequence BIGSERIAL NOT NULL,
 
@@ -26,13 +26,77 @@ CREATE TABLE logs (
 
     level SMALLINT NOT NULL,
 
+    -- Name of the level, such as ERROR or WARN, for the benefit of ad-hoc queries.  Not used by
+    -- this code: filter on the numeric level instead.
+    level_name TEXT,
+
     module TEXT,
     filename TEXT,
-    line SMALLINT,
+    line INTEGER,
 
     message TEXT NOT NULL,
 
+    -- Nanoseconds elapsed since the logger was initialized, measured with a monotonic clock so that
+    -- the distance between entries of the same process is accurate even if the system clock jumps.
+    uptime_nanos BIGINT,
+
+    -- Offset from UTC of the local time of the host that recorded the entry, in seconds, if known.
+    -- The timestamp is always absolute: this is only used to render it in the original local time.
+    utc_offset_secs INTEGER,
+
+    -- Identifiers of the distributed trace and span that were active when the entry was recorded,
+    -- if known, to correlate the entry with its trace.
+    trace_id TEXT,
+    span_id TEXT,
+
+    -- ULID of the entry, if the logger was configured to generate them.  Sorts in the order in
+    -- which the entries were recorded and is unique across hosts, so it serves to merge entries
+    -- from several databases without relying on per-host sequence numbers.
+    ulid TEXT COLLATE "C",
+
+    -- Name of the crate that emitted the entry, derived from the first component of the module, so
+    -- that selecting the entries of a crate is an exact match instead of a prefix scan.
+    crate_name TEXT,
+
+    -- Correlation identifier that the application attached to the entry, such as the identifier of
+    -- the request being served, to select all of its entries with an exact indexed match.
+    correlation_id TEXT,
+
+    -- Opaque binary payload that the application attached to the entry, such as a serialized
+    -- request, which is stored verbatim and not interpreted in any way.
+    payload BYTEA,
+
+    -- Structured fields of the entry, such as the key-values of the log record, serialized by the
+    -- field codec configured in the connection options, which defaults to a JSON object.
+    fields BYTEA,
+
+    -- Position of the entry among the parts into which its message was split, or zero if it was
+    -- not split, so that all parts of an entry share its ULID and can still be told apart.
+    part INTEGER NOT NULL DEFAULT 0,
+
+    -- Identifier assigned by the database in insertion order to reference specific entries and to
+    -- paginate through them.  Not part of the primary key so that entries with the same timestamp
+    -- are kept apart by their sequence number.
+    id BIGSERIAL NOT NULL,
+
     PRIMARY KEY (timestamp, sequence, hostname)
 );
 
+-- Serves queries sorted in either direction because the index can be scanned backwards.
 CREATE INDEX logs_by_timestamp ON logs (timestamp, sequence);
+
+-- Serves keyset pagination by identifier.
+CREATE UNIQUE INDEX logs_by_id ON logs (id);
+
+-- Serves keyset pagination by ULID.
+CREATE INDEX logs_by_ulid ON logs (ulid);
+
+-- Identifies the entries to skip when replaying them.  The sequence number is reassigned on every
+-- write, so it cannot be used for this, and entries without a ULID are never considered duplicates.
+CREATE UNIQUE INDEX logs_by_dedup_key ON logs (timestamp, hostname, ulid, part);
+
+-- Serves queries for the entries of a crate sorted by timestamp.
+CREATE INDEX logs_by_crate_name ON logs (crate_name, timestamp, sequence);
+
+-- Serves queries for the entries of a correlation identifier sorted by timestamp.
+CREATE INDEX logs_by_correlation_id ON logs (correlation_id, timestamp, sequence);
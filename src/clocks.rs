@@ -19,17 +19,32 @@
 use std::convert::TryFrom;
 #[cfg(test)]
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
 
 /// Generic definition of a clock.
 pub(crate) trait Clock {
     /// Returns the current UTC time.
     fn now_utc(&self) -> OffsetDateTime;
+
+    /// Returns the time elapsed since an arbitrary epoch fixed at the creation of the clock.
+    ///
+    /// Unlike `now_utc`, readings never go backwards, so their differences are suitable to
+    /// measure elapsed time.
+    fn monotonic(&self) -> Duration;
 }
 
 /// Clock implementation that uses the system clock.
-#[derive(Default)]
-pub(crate) struct SystemClock {}
+pub(crate) struct SystemClock {
+    /// Epoch of the monotonic readings.
+    epoch: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self { epoch: Instant::now() }
+    }
+}
 
 impl Clock for SystemClock {
     fn now_utc(&self) -> OffsetDateTime {
@@ -42,19 +57,27 @@ impl Clock for SystemClock {
 
         OffsetDateTime::from_unix_timestamp_nanos(nanos).expect("unable to create OffsetDateTime from nanos")
     }
+
+    fn monotonic(&self) -> Duration {
+        self.epoch.elapsed()
+    }
 }
 
 /// A clock that returns a monotonically increasing instant every time it is queried.
+///
+/// The wall clock and the monotonic readings advance independently: the former by one second and
+/// the latter by one millisecond per query.
 #[cfg(test)]
 pub(crate) struct MonotonicClock {
     now: AtomicU64,
+    elapsed_millis: AtomicU64,
 }
 
 #[cfg(test)]
 impl MonotonicClock {
     /// Creates a new clock whose "now" start time is `now`.
     pub(crate) fn new(now: u64) -> Self {
-        Self { now: AtomicU64::new(now) }
+        Self { now: AtomicU64::new(now), elapsed_millis: AtomicU64::new(0) }
     }
 }
 
@@ -64,6 +87,10 @@ impl Clock for MonotonicClock {
         let now = self.now.fetch_add(1, Ordering::SeqCst);
         OffsetDateTime::from_unix_timestamp(i64::try_from(now).expect("Mock timestamp too long")).expect("unable to create OffsetDateTime from nanos")
     }
+
+    fn monotonic(&self) -> Duration {
+        Duration::from_millis(self.elapsed_millis.fetch_add(1, Ordering::SeqCst))
+    }
 }
 
 #[cfg(test)]
@@ -87,6 +114,14 @@ mod tests {
         assert_eq!(0, now.nanosecond() % 1000);
     }
 
+    #[test]
+    fn test_systemclock_monotonic() {
+        let clock = SystemClock::default();
+        let elapsed1 = clock.monotonic();
+        let elapsed2 = clock.monotonic();
+        assert!(elapsed2 >= elapsed1);
+    }
+
     #[test]
     fn test_monotonicclock() {
         let clock = MonotonicClock::new(123);
@@ -94,4 +129,13 @@ mod tests {
         assert_eq!(OffsetDateTime::from_unix_timestamp(124).expect("unable to create OffsetDateTime from nanos"), clock.now_utc());
         assert_eq!(OffsetDateTime::from_unix_timestamp(125).expect("unable to create OffsetDateTime from nanos"), clock.now_utc());
     }
+
+    #[test]
+    fn test_monotonicclock_monotonic() {
+        let clock = MonotonicClock::new(123);
+        assert_eq!(Duration::from_millis(0), clock.monotonic());
+        assert_eq!(OffsetDateTime::from_unix_timestamp(123).expect("unable to create OffsetDateTime from nanos"), clock.now_utc());
+        assert_eq!(Duration::from_millis(1), clock.monotonic());
+        assert_eq!(Duration::from_millis(2), clock.monotonic());
+    }
 }
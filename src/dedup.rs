@@ -0,0 +1,218 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Collapsing of identical log entries emitted in quick succession.
+
+use crate::logger::LogEntry;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// Maximum number of distinct recent entries to track for deduplication purposes.
+const DEDUP_CAPACITY: usize = 16;
+
+/// Tracks an entry that was recorded recently and the repetitions it has seen since.
+struct Repeated {
+    /// Time at which the first occurrence of the entry was recorded.
+    first_seen: OffsetDateTime,
+
+    /// Last occurrence of the entry, which provides the contents of the summary.
+    last: LogEntry,
+
+    /// Number of occurrences that have been suppressed so far.
+    count: u64,
+}
+
+impl Repeated {
+    /// Returns true if `entry` is a repetition of the tracked entry.
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.last.level == entry.level
+            && self.last.module == entry.module
+            && self.last.message == entry.message
+            && self.last.correlation_id == entry.correlation_id
+    }
+
+    /// Returns the entry that summarizes the suppressed repetitions, if any.
+    fn into_summary(self) -> Option<LogEntry> {
+        if self.count == 0 {
+            return None;
+        }
+        let message = format!("{} (repeated {} times)", self.last.message, self.count);
+        Some(LogEntry { message, ..self.last })
+    }
+}
+
+/// Collapses identical entries, as determined by their level, module, message and correlation
+/// identifier, that are recorded within a time window.
+///
+/// The first occurrence of an entry is always recorded.  Subsequent occurrences within the window
+/// are counted instead, and a single summary entry with the message suffixed by `(repeated N
+/// times)` is recorded once the window closes or once the entry is evicted from the small set of
+/// recent entries that are tracked.  Windows are only checked when new entries arrive, so callers
+/// must call `drain` before flushing to not lose the pending summaries.
+pub(crate) struct Deduplicator {
+    /// Duration of the window during which repetitions are collapsed.
+    window: Duration,
+
+    /// Recently-seen entries sorted from least to most recently used.
+    recent: Mutex<VecDeque<Repeated>>,
+}
+
+impl Deduplicator {
+    /// Creates a new deduplicator that collapses repetitions within `window`.
+    pub(crate) fn new(window: Duration) -> Self {
+        Self { window, recent: Mutex::from(VecDeque::with_capacity(DEDUP_CAPACITY)) }
+    }
+
+    /// Processes a new `entry` and returns the entries that should be recorded as a result.
+    pub(crate) fn check(&self, entry: LogEntry) -> Vec<LogEntry> {
+        let mut recent = self.recent.lock().unwrap();
+        let mut output = vec![];
+
+        let mut i = 0;
+        while i < recent.len() {
+            if recent[i].first_seen + self.window <= entry.timestamp {
+                let expired = recent.remove(i).expect("Index must be valid");
+                output.extend(expired.into_summary());
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Some(i) = recent.iter().position(|r| r.matches(&entry)) {
+            let mut repeated = recent.remove(i).expect("Index must be valid");
+            repeated.count += 1;
+            repeated.last = entry;
+            recent.push_back(repeated);
+            return output;
+        }
+
+        if recent.len() == DEDUP_CAPACITY {
+            let evicted = recent.pop_front().expect("Capacity must be positive");
+            output.extend(evicted.into_summary());
+        }
+        recent.push_back(Repeated { first_seen: entry.timestamp, last: entry.clone(), count: 0 });
+        output.push(entry);
+        output
+    }
+
+    /// Returns the summaries for all entries with pending repetitions and forgets about them.
+    pub(crate) fn drain(&self) -> Vec<LogEntry> {
+        let mut recent = self.recent.lock().unwrap();
+        recent.drain(..).filter_map(Repeated::into_summary).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    /// Creates a log entry recorded at `secs` with the given `level`, `module` and `message`.
+    fn entry(secs: i64, level: Level, module: &str, message: &str) -> LogEntry {
+        LogEntry {
+            module: Some(module.to_owned().into()),
+            filename: Some("the-file".into()),
+            line: Some(secs as u32),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+                "the-host",
+                level,
+                message.to_owned(),
+            )
+        }
+    }
+
+    /// Returns the `(timestamp, message)` pairs of `entries` for easier comparisons.
+    fn summarize(entries: Vec<LogEntry>) -> Vec<(i64, String)> {
+        entries.into_iter().map(|e| (e.timestamp.unix_timestamp(), e.message)).collect()
+    }
+
+    #[test]
+    fn test_deduplicator_collapses_within_window() {
+        let dedup = Deduplicator::new(Duration::from_secs(10));
+
+        assert_eq!(
+            vec![(0, "Foo".to_owned())],
+            summarize(dedup.check(entry(0, Level::Info, "m", "Foo")))
+        );
+        for i in 1..5 {
+            assert!(dedup.check(entry(i, Level::Info, "m", "Foo")).is_empty());
+        }
+        assert_eq!(
+            vec![(4, "Foo (repeated 4 times)".to_owned()), (10, "Foo".to_owned())],
+            summarize(dedup.check(entry(10, Level::Info, "m", "Foo")))
+        );
+        assert!(dedup.drain().is_empty());
+    }
+
+    #[test]
+    fn test_deduplicator_distinguishes_level_module_and_message() {
+        let dedup = Deduplicator::new(Duration::from_secs(10));
+
+        assert_eq!(1, dedup.check(entry(0, Level::Info, "m", "Foo")).len());
+        assert_eq!(1, dedup.check(entry(1, Level::Warn, "m", "Foo")).len());
+        assert_eq!(1, dedup.check(entry(2, Level::Info, "n", "Foo")).len());
+        assert_eq!(1, dedup.check(entry(3, Level::Info, "m", "Bar")).len());
+        assert!(dedup.check(entry(4, Level::Info, "m", "Foo")).is_empty());
+        assert!(dedup.check(entry(5, Level::Info, "m", "Bar")).is_empty());
+        assert!(dedup.check(entry(6, Level::Info, "m", "Bar")).is_empty());
+
+        assert_eq!(
+            vec![
+                (4, "Foo (repeated 1 times)".to_owned()),
+                (6, "Bar (repeated 2 times)".to_owned())
+            ],
+            summarize(dedup.drain())
+        );
+        assert!(dedup.drain().is_empty());
+    }
+
+    #[test]
+    fn test_deduplicator_distinguishes_correlation_id() {
+        let dedup = Deduplicator::new(Duration::from_secs(10));
+
+        let correlated = |secs, id: Option<&str>| LogEntry {
+            correlation_id: id.map(str::to_owned),
+            ..entry(secs, Level::Info, "m", "Foo")
+        };
+        assert_eq!(1, dedup.check(correlated(0, Some("req-1"))).len());
+        assert_eq!(1, dedup.check(correlated(1, Some("req-2"))).len());
+        assert_eq!(1, dedup.check(correlated(2, None)).len());
+        assert!(dedup.check(correlated(3, Some("req-1"))).is_empty());
+
+        let summaries = dedup.drain();
+        assert_eq!(vec![(3, "Foo (repeated 1 times)".to_owned())], summarize(summaries.clone()));
+        assert_eq!(Some("req-1"), summaries[0].correlation_id.as_deref());
+    }
+
+    #[test]
+    fn test_deduplicator_evicts_least_recently_used() {
+        let dedup = Deduplicator::new(Duration::from_secs(1000));
+
+        assert_eq!(1, dedup.check(entry(0, Level::Info, "m", "First")).len());
+        assert!(dedup.check(entry(1, Level::Info, "m", "First")).is_empty());
+        for i in 0..(DEDUP_CAPACITY - 1) {
+            let message = format!("Message {}", i);
+            assert_eq!(1, dedup.check(entry(2, Level::Info, "m", &message)).len());
+        }
+
+        assert_eq!(
+            vec![(1, "First (repeated 1 times)".to_owned()), (3, "Last".to_owned())],
+            summarize(dedup.check(entry(3, Level::Info, "m", "Last")))
+        );
+    }
+}
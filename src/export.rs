@@ -0,0 +1,826 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Serialization of log entries into interchange formats.
+
+use crate::logger::LogEntry;
+use crate::{Fields, LogEntryRow};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::Level;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+/// Serialization of the optional offset from UTC of an entry as its whole number of seconds.
+mod utc_offset_secs {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use time::UtcOffset;
+
+    pub(super) fn serialize<S: Serializer>(
+        offset: &Option<UtcOffset>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match offset {
+            Some(offset) => serializer.serialize_i32(offset.whole_seconds()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<UtcOffset>, D::Error> {
+        match Option::<i32>::deserialize(deserializer)? {
+            Some(secs) => UtcOffset::from_whole_seconds(secs)
+                .map(Some)
+                .map_err(|e| D::Error::custom(format!("bad utc_offset_secs {}: {}", secs, e))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Serialization of the optional binary payload of an entry as a standard base64 string.
+mod base64_payload {
+    use super::BASE64;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::borrow::Cow;
+
+    pub(super) fn serialize<S: Serializer>(
+        payload: &Option<Cow<'_, [u8]>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match payload {
+            Some(payload) => serializer.serialize_str(&BASE64.encode(payload)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, 'a, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Cow<'a, [u8]>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => BASE64
+                .decode(&encoded)
+                .map(|payload| Some(Cow::Owned(payload)))
+                .map_err(|e| D::Error::custom(format!("bad base64 {}: {}", encoded, e))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Representation of a log entry in JSON, regardless of where it comes from.
+///
+/// The `sequence`, `uptime_nanos`, `utc_offset_secs`, `trace_id`, `span_id`, `ulid`,
+/// `correlation_id`, `payload`, `fields` and `id` fields are omitted if the entry does not have
+/// them.  The `payload` is encoded in base64 and the `fields` are a nested object of strings.
+/// Unknown fields are ignored when parsing.
+#[derive(Deserialize, Serialize)]
+struct JsonEntry<'a> {
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<i64>,
+    hostname: Cow<'a, str>,
+    level: Level,
+    module: Option<Cow<'a, str>>,
+    filename: Option<Cow<'a, str>>,
+    line: Option<u32>,
+    message: Cow<'a, str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uptime_nanos: Option<i64>,
+    #[serde(
+        default,
+        rename = "utc_offset_secs",
+        skip_serializing_if = "Option::is_none",
+        with = "utc_offset_secs"
+    )]
+    utc_offset: Option<UtcOffset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace_id: Option<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    span_id: Option<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ulid: Option<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_payload")]
+    payload: Option<Cow<'a, [u8]>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fields: Option<Cow<'a, Fields>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+}
+
+impl<'a> From<&'a LogEntryRow> for JsonEntry<'a> {
+    fn from(row: &'a LogEntryRow) -> Self {
+        Self {
+            timestamp: row.timestamp,
+            sequence: Some(row.sequence),
+            hostname: Cow::Borrowed(&row.hostname),
+            level: row.level,
+            module: row.module.as_deref().map(Cow::Borrowed),
+            filename: row.filename.as_deref().map(Cow::Borrowed),
+            line: row.line,
+            message: Cow::Borrowed(&row.message),
+            uptime_nanos: row.uptime_nanos,
+            utc_offset: row.utc_offset,
+            trace_id: row.trace_id.as_deref().map(Cow::Borrowed),
+            span_id: row.span_id.as_deref().map(Cow::Borrowed),
+            ulid: row.ulid.as_deref().map(Cow::Borrowed),
+            correlation_id: row.correlation_id.as_deref().map(Cow::Borrowed),
+            payload: row.payload.as_deref().map(Cow::Borrowed),
+            fields: row.fields.as_ref().map(Cow::Borrowed),
+            id: row.id,
+        }
+    }
+}
+
+impl<'a> From<&'a LogEntry> for JsonEntry<'a> {
+    fn from(entry: &'a LogEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            sequence: None,
+            hostname: Cow::Borrowed(&entry.hostname),
+            level: entry.level,
+            module: entry.module.as_deref().map(Cow::Borrowed),
+            filename: entry.filename.as_deref().map(Cow::Borrowed),
+            line: entry.line,
+            message: Cow::Borrowed(&entry.message),
+            uptime_nanos: entry.uptime_nanos,
+            utc_offset: entry.utc_offset,
+            trace_id: entry.trace_id.as_deref().map(Cow::Borrowed),
+            span_id: entry.span_id.as_deref().map(Cow::Borrowed),
+            ulid: entry.ulid.as_deref().map(Cow::Borrowed),
+            correlation_id: entry.correlation_id.as_deref().map(Cow::Borrowed),
+            payload: entry.payload.as_deref().map(Cow::Borrowed),
+            fields: entry.fields.as_ref().map(Cow::Borrowed),
+            id: None,
+        }
+    }
+}
+
+/// Formats `entry` as a single-line JSON object without the trailing newline.
+fn format_json(entry: &JsonEntry<'_>) -> io::Result<String> {
+    serde_json::to_string(entry).map_err(io::Error::other)
+}
+
+/// Formats `row` as a single-line JSON object without the trailing newline.
+pub(crate) fn format_ndjson(row: &LogEntryRow) -> io::Result<String> {
+    format_json(&JsonEntry::from(row))
+}
+
+/// Formats `entry` as a single-line JSON object without the trailing newline.
+///
+/// The result has the same fields as the output of `format_ndjson`.  `sequence` is only known once
+/// the entry is written to the database, so the field is omitted if not provided.
+pub(crate) fn format_entry_ndjson(entry: &LogEntry, sequence: Option<i64>) -> io::Result<String> {
+    let mut json = JsonEntry::from(entry);
+    json.sequence = sequence;
+    format_json(&json)
+}
+
+/// Constructs an error to report invalid JSON input.
+fn invalid_json(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid JSON: {}", message))
+}
+
+/// Parses a single line produced by `format_json`.
+fn parse_json(line: &str) -> io::Result<JsonEntry<'static>> {
+    serde_json::from_str(line).map_err(|e| invalid_json(&e.to_string()))
+}
+
+/// Parses a single line produced by `format_ndjson` back into a row.
+#[cfg(feature = "file")]
+pub(crate) fn parse_ndjson(line: &str) -> io::Result<LogEntryRow> {
+    let json = parse_json(line)?;
+    Ok(LogEntryRow {
+        timestamp: json.timestamp,
+        sequence: json.sequence.ok_or_else(|| invalid_json("missing sequence"))?,
+        hostname: json.hostname.into_owned(),
+        level: json.level,
+        module: json.module.map(Cow::into_owned),
+        filename: json.filename.map(Cow::into_owned),
+        line: json.line,
+        message: json.message.into_owned(),
+        uptime_nanos: json.uptime_nanos,
+        utc_offset: json.utc_offset,
+        trace_id: json.trace_id.map(Cow::into_owned),
+        span_id: json.span_id.map(Cow::into_owned),
+        ulid: json.ulid.map(Cow::into_owned),
+        correlation_id: json.correlation_id.map(Cow::into_owned),
+        payload: json.payload.map(Cow::into_owned),
+        fields: json.fields.map(Cow::into_owned),
+        id: json.id,
+    })
+}
+
+/// Parses a single line produced by `format_entry_ndjson` back into an entry.
+///
+/// The `sequence` and `id` fields are ignored if present because they are assigned by the database
+/// that stores the entry.
+pub(crate) fn parse_entry_ndjson(line: &str) -> io::Result<LogEntry> {
+    let json = parse_json(line)?;
+    Ok(LogEntry {
+        timestamp: json.timestamp,
+        hostname: json.hostname.into(),
+        level: json.level,
+        module: json.module.map(|module| Cow::Owned(module.into_owned())),
+        filename: json.filename.map(|filename| Cow::Owned(filename.into_owned())),
+        line: json.line,
+        message: json.message.into_owned(),
+        uptime_nanos: json.uptime_nanos,
+        utc_offset: json.utc_offset,
+        trace_id: json.trace_id.map(Cow::into_owned),
+        span_id: json.span_id.map(Cow::into_owned),
+        ulid: json.ulid.map(Cow::into_owned),
+        correlation_id: json.correlation_id.map(Cow::into_owned),
+        payload: json.payload.map(Cow::into_owned),
+        fields: json.fields.map(Cow::into_owned),
+    })
+}
+
+/// Formats to render the timestamps of the textual representation of log entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestampFormat {
+    /// Seconds since the Unix epoch followed by a dot and the unpadded nanoseconds within that
+    /// second, as in `1000.123456000`.  This is ambiguous to the human eye but is stable for tests
+    /// written against older versions of this crate.
+    Raw,
+
+    /// RFC 3339 string in UTC with as many fractional digits as necessary, as in
+    /// `1970-01-01T00:16:40.123456Z`.
+    #[default]
+    Rfc3339,
+
+    /// RFC 3339 string in the local time of the host that recorded the entry, as in
+    /// `1970-01-01T02:16:40.123456+02:00`, or in UTC if the entry does not carry its offset.
+    Rfc3339Local,
+}
+
+/// Formats `row` as a single line of text with its timestamp rendered according to `format`.
+///
+/// The layout of the line matches the one returned by `Db::get_log_entries`.
+pub(crate) fn format_text(row: &LogEntryRow, format: TimestampFormat) -> io::Result<String> {
+    let timestamp = match format {
+        TimestampFormat::Raw => {
+            format!("{}.{}", row.timestamp.unix_timestamp(), row.timestamp.nanosecond())
+        }
+        TimestampFormat::Rfc3339 => row.timestamp.format(&Rfc3339).map_err(io::Error::other)?,
+        TimestampFormat::Rfc3339Local => {
+            row.local_timestamp().format(&Rfc3339).map_err(io::Error::other)?
+        }
+    };
+    Ok(format!(
+        "{} {} {} {} {}:{} {}",
+        timestamp,
+        row.hostname,
+        row.level as usize,
+        row.module.as_deref().unwrap_or("NO-MODULE"),
+        row.filename.as_deref().unwrap_or("NO-FILENAME"),
+        row.line.map(i64::from).unwrap_or(-1),
+        row.message
+    ))
+}
+
+/// Header row of the CSV output produced by `write_csv_header`.
+const CSV_HEADER: &str = "timestamp,sequence,hostname,level,module,filename,line,message,\
+                          uptime_nanos,utc_offset_secs,trace_id,span_id,ulid,correlation_id,\
+                          payload,fields,id";
+
+/// Appends `s` to `output` as a CSV field, quoting it only if necessary.
+fn push_csv_str(output: &mut String, s: &str) {
+    if s.contains([',', '"', '\n', '\r']) {
+        output.push('"');
+        output.push_str(&s.replace('"', "\"\""));
+        output.push('"');
+    } else {
+        output.push_str(s);
+    }
+}
+
+/// Formats `row` as a single CSV record without the trailing line terminator.
+///
+/// Missing optional fields are represented as empty fields.  The `payload` is encoded in base64
+/// and the structured `fields` as a JSON object.
+pub(crate) fn format_csv(row: &LogEntryRow) -> io::Result<String> {
+    let timestamp = row.timestamp.format(&Rfc3339).map_err(io::Error::other)?;
+
+    let mut output = String::new();
+    push_csv_str(&mut output, &timestamp);
+    output.push_str(&format!(",{},", row.sequence));
+    push_csv_str(&mut output, &row.hostname);
+    output.push(',');
+    push_csv_str(&mut output, row.level.as_str());
+    output.push(',');
+    push_csv_str(&mut output, row.module.as_deref().unwrap_or(""));
+    output.push(',');
+    push_csv_str(&mut output, row.filename.as_deref().unwrap_or(""));
+    output.push(',');
+    if let Some(line) = row.line {
+        output.push_str(&line.to_string());
+    }
+    output.push(',');
+    push_csv_str(&mut output, &row.message);
+    output.push(',');
+    if let Some(uptime_nanos) = row.uptime_nanos {
+        output.push_str(&uptime_nanos.to_string());
+    }
+    output.push(',');
+    if let Some(utc_offset) = row.utc_offset {
+        output.push_str(&utc_offset.whole_seconds().to_string());
+    }
+    output.push(',');
+    push_csv_str(&mut output, row.trace_id.as_deref().unwrap_or(""));
+    output.push(',');
+    push_csv_str(&mut output, row.span_id.as_deref().unwrap_or(""));
+    output.push(',');
+    push_csv_str(&mut output, row.ulid.as_deref().unwrap_or(""));
+    output.push(',');
+    push_csv_str(&mut output, row.correlation_id.as_deref().unwrap_or(""));
+    output.push(',');
+    if let Some(payload) = row.payload.as_deref() {
+        output.push_str(&BASE64.encode(payload));
+    }
+    output.push(',');
+    if let Some(fields) = row.fields.as_ref() {
+        push_csv_str(&mut output, &serde_json::to_string(fields).map_err(io::Error::other)?);
+    }
+    output.push(',');
+    if let Some(id) = row.id {
+        output.push_str(&id.to_string());
+    }
+    Ok(output)
+}
+
+/// Writes the header row of the CSV output to `writer`.
+///
+/// Records are terminated by CRLF as specified by RFC 4180.
+pub(crate) fn write_csv_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    write!(writer, "{}\r\n", CSV_HEADER)
+}
+
+/// Writes `row` to `writer` as a single CSV record terminated by CRLF.
+pub(crate) fn write_csv_record<W: Write>(row: &LogEntryRow, writer: &mut W) -> io::Result<()> {
+    write!(writer, "{}\r\n", format_csv(row)?)
+}
+
+/// Writes `rows` to `writer` in newline-delimited JSON format, one object per entry.
+pub(crate) fn write_ndjson<W: Write>(rows: &[LogEntryRow], writer: &mut W) -> io::Result<()> {
+    for row in rows {
+        writeln!(writer, "{}", format_ndjson(row)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_text() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_123_456_000).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Info,
+            module: Some("the-module".to_owned()),
+            filename: Some("the-file".to_owned()),
+            line: Some(123),
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(
+            "1000.123456000 the-host 3 the-module the-file:123 The message",
+            format_text(&row, TimestampFormat::Raw).unwrap()
+        );
+        assert_eq!(
+            "1970-01-01T00:16:40.123456Z the-host 3 the-module the-file:123 The message",
+            format_text(&row, TimestampFormat::Rfc3339).unwrap()
+        );
+        assert_eq!(
+            "1970-01-01T00:16:40.123456Z the-host 3 the-module the-file:123 The message",
+            format_text(&row, TimestampFormat::Rfc3339Local).unwrap()
+        );
+
+        let row = LogEntryRow { utc_offset: Some(UtcOffset::from_hms(2, 0, 0).unwrap()), ..row };
+        assert_eq!(
+            "1970-01-01T02:16:40.123456+02:00 the-host 3 the-module the-file:123 The message",
+            format_text(&row, TimestampFormat::Rfc3339Local).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_text_optional_fields() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(1000).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Trace,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(
+            "1000.0 the-host 5 NO-MODULE NO-FILENAME:-1 The message",
+            format_text(&row, TimestampFormat::Raw).unwrap()
+        );
+        assert_eq!(
+            "1970-01-01T00:16:40Z the-host 5 NO-MODULE NO-FILENAME:-1 The message",
+            format_text(&row, TimestampFormat::Rfc3339).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_ndjson_all_fields() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_002_000).unwrap(),
+            sequence: 42,
+            hostname: "the-host".into(),
+            level: Level::Warn,
+            module: Some("the-module".to_owned()),
+            filename: Some("the-file".to_owned()),
+            line: Some(123),
+            message: "Some \"quoted\"\ttext\nwith\\escapes\u{1}".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(
+            "{\"timestamp\":\"1970-01-01T00:00:01.000002Z\",\"sequence\":42,\
+             \"hostname\":\"the-host\",\"level\":\"WARN\",\"module\":\"the-module\",\
+             \"filename\":\"the-file\",\"line\":123,\
+             \"message\":\"Some \\\"quoted\\\"\\ttext\\nwith\\\\escapes\\u0001\"}",
+            format_ndjson(&row).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_ndjson_optional_fields() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Error,
+            module: None,
+            filename: None,
+            line: None,
+            message: "".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(
+            "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"the-host\",\
+             \"level\":\"ERROR\",\"module\":null,\"filename\":null,\"line\":null,\"message\":\"\"}",
+            format_ndjson(&row).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_entry_ndjson() {
+        let entry = LogEntry {
+            module: Some("the-module".into()),
+            line: Some(5),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(0).unwrap(),
+                "the-host",
+                Level::Debug,
+                "The message",
+            )
+        };
+        assert_eq!(
+            "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"hostname\":\"the-host\",\"level\":\"DEBUG\",\
+             \"module\":\"the-module\",\"filename\":null,\"line\":5,\"message\":\"The message\"}",
+            format_entry_ndjson(&entry, None).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_parse_ndjson_round_trip() {
+        for row in [
+            LogEntryRow {
+                timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_002_000).unwrap(),
+                sequence: 42,
+                hostname: "the-host".into(),
+                level: Level::Warn,
+                module: Some("the-module".to_owned()),
+                filename: Some("the-file".to_owned()),
+                line: Some(123),
+                message: "Some \"quoted\"\ttext\nwith\\escapes\u{1} and ünicode".to_owned(),
+                uptime_nanos: Some(1_234_567),
+                utc_offset: Some(UtcOffset::from_hms(5, 30, 0).unwrap()),
+                trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_owned()),
+                span_id: Some("00f067aa0ba902b7".to_owned()),
+                ulid: Some("01ARYZ6S41TSV4RRFFQ69G5FAV".to_owned()),
+                correlation_id: Some("req-123".to_owned()),
+                payload: Some(vec![0, 1, 2, 254, 255]),
+                fields: Some(Fields::from([
+                    ("count".to_owned(), "3".to_owned()),
+                    ("path".to_owned(), "/a \"b\"".to_owned()),
+                ])),
+                id: Some(7),
+            },
+            LogEntryRow {
+                timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+                sequence: 0,
+                hostname: "the-host".into(),
+                level: Level::Trace,
+                module: None,
+                filename: None,
+                line: None,
+                message: "".to_owned(),
+                uptime_nanos: None,
+                utc_offset: None,
+                trace_id: None,
+                span_id: None,
+                ulid: None,
+                correlation_id: None,
+                payload: None,
+                fields: None,
+                id: None,
+            },
+        ] {
+            assert_eq!(row, parse_ndjson(&format_ndjson(&row).unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_entry_ndjson_round_trip() {
+        let entry = LogEntry {
+            module: Some(Cow::Borrowed("the-module")),
+            line: Some(123),
+            uptime_nanos: Some(1_234_567),
+            utc_offset: Some(UtcOffset::from_hms(5, 30, 0).unwrap()),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_owned()),
+            span_id: Some("00f067aa0ba902b7".to_owned()),
+            ulid: Some("01ARYZ6S41TSV4RRFFQ69G5FAV".to_owned()),
+            correlation_id: Some("req-123".to_owned()),
+            payload: Some(b"binary\0data".to_vec()),
+            fields: Some(Fields::from([("count".to_owned(), "3".to_owned())])),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp_nanos(1_000_002_000).unwrap(),
+                "the-host",
+                Level::Warn,
+                "Some \"quoted\" text",
+            )
+        };
+        let parsed = parse_entry_ndjson(&format_entry_ndjson(&entry, None).unwrap()).unwrap();
+        assert_eq!(entry.timestamp, parsed.timestamp);
+        assert_eq!(entry.hostname, parsed.hostname);
+        assert_eq!(entry.level, parsed.level);
+        assert_eq!(entry.module, parsed.module);
+        assert_eq!(entry.filename, parsed.filename);
+        assert_eq!(entry.line, parsed.line);
+        assert_eq!(entry.message, parsed.message);
+        assert_eq!(entry.uptime_nanos, parsed.uptime_nanos);
+        assert_eq!(entry.utc_offset, parsed.utc_offset);
+        assert_eq!(entry.trace_id, parsed.trace_id);
+        assert_eq!(entry.span_id, parsed.span_id);
+        assert_eq!(entry.ulid, parsed.ulid);
+        assert_eq!(entry.correlation_id, parsed.correlation_id);
+        assert_eq!(entry.payload, parsed.payload);
+        assert_eq!(entry.fields, parsed.fields);
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_parse_ndjson_errors() {
+        for (line, exp_error) in [
+            ("", "EOF while parsing a value"),
+            ("{\"timestamp\":true}", "invalid type: boolean `true`"),
+            ("{\"message\":\"abc", "EOF while parsing a string"),
+            ("{\"message\":\"\\x\"}", "invalid escape"),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"INFO\",\"message\":\"m\"} extra",
+                "trailing characters",
+            ),
+            ("{}", "missing field `timestamp`"),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"hostname\":\"h\",\"level\":\"INFO\",\
+                 \"message\":\"m\"}",
+                "missing sequence",
+            ),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"BOGUS\",\"message\":\"m\"}",
+                "unknown variant `BOGUS`",
+            ),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"INFO\",\"message\":\"m\",\"utc_offset_secs\":100000}",
+                "bad utc_offset_secs 100000",
+            ),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"INFO\",\"message\":\"m\",\"line\":1.5}",
+                "invalid type: floating point `1.5`",
+            ),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"INFO\",\"message\":{\"nested\":1}}",
+                "invalid type: map",
+            ),
+            (
+                "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+                 \"level\":\"INFO\",\"message\":\"m\",\"payload\":\"Zm9!\"}",
+                "bad base64 Zm9!",
+            ),
+        ] {
+            let e = parse_ndjson(line).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, e.kind());
+            assert!(e.to_string().contains(exp_error), "{} does not contain {}", e, exp_error);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_parse_ndjson_escapes() {
+        let row = parse_ndjson(
+            "{\"timestamp\":\"1970-01-01T00:00:00Z\",\"sequence\":0,\"hostname\":\"h\",\
+             \"level\":\"INFO\",\"message\":\"\\ud83d\\ude00 \\u00fc\\/\",\
+             \"unknown\":[1,{\"a\":2.5}]}",
+        )
+        .unwrap();
+        assert_eq!("\u{1f600} \u{fc}/", row.message);
+    }
+
+    #[test]
+    fn test_format_csv_all_fields() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_002_000).unwrap(),
+            sequence: 42,
+            hostname: "the-host".into(),
+            level: Level::Warn,
+            module: Some("the-module".to_owned()),
+            filename: Some("the-file".to_owned()),
+            line: Some(123),
+            message: "Some \"quoted\", text\nacross lines".to_owned(),
+            uptime_nanos: Some(1234),
+            utc_offset: Some(UtcOffset::from_whole_seconds(-3600).unwrap()),
+            trace_id: Some("the-trace".to_owned()),
+            span_id: Some("the-span".to_owned()),
+            ulid: Some("01ARZ3NDEKTSV4RRFFQ69G5FAV".to_owned()),
+            correlation_id: Some("a,b".to_owned()),
+            payload: Some(vec![1, 2, 3]),
+            fields: Some(Fields::from([
+                ("a".to_owned(), "1".to_owned()),
+                ("b".to_owned(), "x".to_owned()),
+            ])),
+            id: Some(7),
+        };
+        assert_eq!(
+            "1970-01-01T00:00:01.000002Z,42,the-host,WARN,the-module,the-file,123,\
+             \"Some \"\"quoted\"\", text\nacross lines\",1234,-3600,the-trace,the-span,\
+             01ARZ3NDEKTSV4RRFFQ69G5FAV,\"a,b\",AQID,\
+             \"{\"\"a\"\":\"\"1\"\",\"\"b\"\":\"\"x\"\"}\",7",
+            format_csv(&row).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_csv_optional_fields() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Error,
+            module: None,
+            filename: None,
+            line: None,
+            message: "Plain message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(
+            "1970-01-01T00:00:00Z,0,the-host,ERROR,,,,Plain message,,,,,,,,,",
+            format_csv(&row).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_csv_header_and_rows() {
+        let row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Info,
+            module: None,
+            filename: None,
+            line: Some(5),
+            message: "First".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        let mut output = vec![];
+        write_csv_header(&mut output).unwrap();
+        write_csv_record(&row, &mut output).unwrap();
+        write_csv_record(&LogEntryRow { sequence: 1, ..row }, &mut output).unwrap();
+        assert_eq!(
+            "timestamp,sequence,hostname,level,module,filename,line,message,uptime_nanos,\
+             utc_offset_secs,trace_id,span_id,ulid,correlation_id,payload,fields,id\r\n\
+             1970-01-01T00:00:00Z,0,the-host,INFO,,,5,First,,,,,,,,,\r\n\
+             1970-01-01T00:00:00Z,1,the-host,INFO,,,5,First,,,,,,,,,\r\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_ndjson_one_line_per_row() {
+        let mut row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "h".into(),
+            level: Level::Info,
+            module: None,
+            filename: None,
+            line: None,
+            message: "first".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        let mut rows = vec![row.clone()];
+        row.sequence = 1;
+        row.message = "second".to_owned();
+        rows.push(row);
+
+        let mut output = vec![];
+        write_ndjson(&rows, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<&str>>();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].contains("\"sequence\":0,"));
+        assert!(lines[0].contains("\"message\":\"first\""));
+        assert!(lines[1].contains("\"sequence\":1,"));
+        assert!(lines[1].contains("\"message\":\"second\""));
+    }
+}
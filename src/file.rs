@@ -0,0 +1,660 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Implementation of the database abstraction using local files in newline-delimited JSON format.
+
+use crate::export;
+use crate::logger::{
+    LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH, LOG_ENTRY_MAX_HOSTNAME_LENGTH,
+    LOG_ENTRY_MAX_MODULE_LENGTH,
+};
+use crate::{
+    apply_oversize_policy, round_timestamp, truncate_message, truncate_option_str, truncate_str,
+    wrap_sequence, Connection, Db, DbError, LogEntryRow, LogFilter, OversizePolicy, Result,
+    StorageStats,
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::Level;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+
+/// Options to open a file-backed log.
+#[derive(Default)]
+pub struct ConnectionOptions {
+    /// Path to the file where log entries are appended.  The file is created if it does not exist.
+    pub path: PathBuf,
+
+    /// Maximum size of the file before it is rotated, or `None` to never rotate it.
+    ///
+    /// Batches of log entries are never split across files so a file can exceed this size if a
+    /// single batch is larger than the limit.
+    pub max_file_bytes: Option<u64>,
+
+    /// Maximum number of files to keep, including the one being written to.
+    ///
+    /// Rotated files are renamed by appending `.1`, `.2`, etc. to `path`, where higher numbers
+    /// denote older files, and the oldest file is deleted once this limit is reached.  Values of 0
+    /// and 1 are equivalent and cause the file to be emptied on rotation.
+    pub max_files: usize,
+
+    /// Maximum length, in bytes, of the messages to write, or `None` to write them in full.
+    /// Longer messages are handled according to `oversize_policy`.
+    pub max_message_length: Option<usize>,
+
+    /// What to do with the messages longer than `max_message_length`.  Defaults to truncating
+    /// them.
+    pub oversize_policy: OversizePolicy,
+}
+
+/// Factory to open a file-backed log.
+pub fn connect(opts: ConnectionOptions) -> Result<Connection> {
+    FileDb::connect(opts).map(|db| Connection(Arc::from(db)))
+}
+
+/// Converts an I/O error that happened while accessing `path` into a `DbError`.
+fn io_error(path: &Path, e: io::Error) -> DbError {
+    DbError::Other(format!("Failed to access {}: {}", path.display(), e), Some(Box::new(e)))
+}
+
+/// Opens the file at `path` for appending and returns it along with its current size.
+fn open_append(path: &Path) -> Result<(File, u64)> {
+    let file =
+        OpenOptions::new().create(true).append(true).open(path).map_err(|e| io_error(path, e))?;
+    let size = file.metadata().map_err(|e| io_error(path, e))?.len();
+    Ok((file, size))
+}
+
+/// State of the file being written to.
+struct ActiveFile {
+    file: File,
+    size: u64,
+}
+
+/// Set of files that hold the log entries, which are only accessed from blocking threads.
+struct Files {
+    path: PathBuf,
+    max_file_bytes: Option<u64>,
+    max_files: usize,
+    active: Mutex<ActiveFile>,
+}
+
+impl Files {
+    /// Returns the path of the rotated file with index `i`, where 0 is the active file.
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        if i == 0 {
+            return self.path.clone();
+        }
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    }
+
+    /// Renames the files to make room for a new `active` file, deleting the oldest one.
+    fn rotate(&self, active: &mut ActiveFile) -> Result<()> {
+        if self.max_files == 1 {
+            active.file.set_len(0).map_err(|e| io_error(&self.path, e))?;
+            active.size = 0;
+            return Ok(());
+        }
+
+        let oldest = self.rotated_path(self.max_files - 1);
+        match fs::remove_file(&oldest) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(io_error(&oldest, e)),
+        }
+
+        for i in (1..self.max_files).rev() {
+            let from = self.rotated_path(i - 1);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(i)).map_err(|e| io_error(&from, e))?;
+            }
+        }
+
+        let (file, size) = open_append(&self.path)?;
+        *active = ActiveFile { file, size };
+        Ok(())
+    }
+
+    /// Reads all entries from all files that match `filter`, sorted by timestamp and sequence.
+    fn read_all(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        // Hold the lock so that we do not observe partial writes or rotations.
+        let _active = self.active.lock().unwrap();
+
+        let mut rows = vec![];
+        for i in (0..self.max_files).rev() {
+            let path = self.rotated_path(i);
+            let content = match fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_error(&path, e)),
+            };
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                let row = export::parse_ndjson(line).map_err(|e| {
+                    DbError::Serialization(format!("Bad entry in {}: {}", path.display(), e), None)
+                })?;
+                if filter.matches(&row) {
+                    rows.push(row);
+                }
+            }
+        }
+        filter.sort_and_limit(&mut rows);
+        Ok(rows)
+    }
+
+    /// Returns the total size of all files.
+    fn bytes(&self) -> Result<u64> {
+        let _active = self.active.lock().unwrap();
+        let mut bytes = 0;
+        for i in 0..self.max_files {
+            let path = self.rotated_path(i);
+            match fs::metadata(&path) {
+                Ok(metadata) => bytes += metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(io_error(&path, e)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Appends the serialized entries in `batch` to the active file, rotating it first if the
+    /// batch would make it exceed its maximum size.
+    fn append(&self, batch: &str) -> Result<()> {
+        let batch_size = u64::try_from(batch.len()).expect("Batch size must fit in u64");
+
+        let mut active = self.active.lock().unwrap();
+        if let Some(max_file_bytes) = self.max_file_bytes {
+            if active.size > 0 && active.size + batch_size > max_file_bytes {
+                self.rotate(&mut active)?;
+            }
+        }
+        active.file.write_all(batch.as_bytes()).map_err(|e| io_error(&self.path, e))?;
+        active.size += batch_size;
+        Ok(())
+    }
+
+    /// Deletes the rotated files and empties the active file.
+    fn clear(&self) -> Result<()> {
+        let mut active = self.active.lock().unwrap();
+        for i in 1..self.max_files {
+            let path = self.rotated_path(i);
+            match fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(io_error(&path, e)),
+            }
+        }
+        active.file.set_len(0).map_err(|e| io_error(&self.path, e))?;
+        active.size = 0;
+        Ok(())
+    }
+}
+
+/// Runs `op` on `files` in a blocking thread so that file I/O does not stall the runtime.
+async fn run_blocking<T, F>(files: &Arc<Files>, op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Files) -> Result<T> + Send + 'static,
+{
+    let files = files.clone();
+    tokio::task::spawn_blocking(move || op(&files))
+        .await
+        .map_err(|e| DbError::Other(format!("Failed to access the log files: {}", e), None))?
+}
+
+/// A database instance backed by local files.
+struct FileDb {
+    files: Arc<Files>,
+    max_message_length: Option<usize>,
+    oversize_policy: OversizePolicy,
+    rejected: AtomicU64,
+    log_sequence: AtomicU64,
+}
+
+impl FileDb {
+    /// Opens the log file described by `opts`.
+    fn connect(opts: ConnectionOptions) -> Result<Self> {
+        let (file, size) = open_append(&opts.path)?;
+        let files = Files {
+            path: opts.path,
+            max_file_bytes: opts.max_file_bytes,
+            max_files: opts.max_files.max(1),
+            active: Mutex::from(ActiveFile { file, size }),
+        };
+        Ok(Self {
+            files: Arc::from(files),
+            max_message_length: opts.max_message_length,
+            oversize_policy: opts.oversize_policy,
+            rejected: AtomicU64::new(0),
+            log_sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Reads all entries from all files that match `filter`, sorted by timestamp and sequence.
+    async fn read_all(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        let filter = filter.clone();
+        run_blocking(&self.files, move |files| files.read_all(&filter)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Db for FileDb {
+    async fn create_schema(&self) -> Result<()> {
+        // Files have no schema.
+        Ok(())
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        // Files have no schema.
+        Ok(())
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        // Files have no schema.
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn get_log_entries(&self) -> Result<Vec<String>> {
+        self.read_all(&LogFilter::default())
+            .await?
+            .iter()
+            .map(|row| export::format_text(row, export::TimestampFormat::Raw))
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(|e| DbError::Serialization(e.to_string(), None))
+    }
+
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        self.read_all(filter).await
+    }
+
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        // Entries must be sorted across all files so there is no way to avoid reading them all,
+        // but at least defer doing so until the stream is polled.
+        let files = self.files.clone();
+        let filter = filter.clone();
+        stream::once(
+            async move { run_blocking(&files, move |files| files.read_all(&filter)).await },
+        )
+        .flat_map(|result| match result {
+            Ok(rows) => stream::iter(rows.into_iter().map(Ok)).boxed(),
+            Err(e) => stream::once(async move { Err(e) }).boxed(),
+        })
+        .boxed()
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        let filter = LogFilter { limit: None, ..filter.clone() };
+        let mut counts: BTreeMap<Level, u64> = BTreeMap::default();
+        for row in self.read_all(&filter).await? {
+            *counts.entry(row.level).or_default() += 1;
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        let rows = self.read_all(&LogFilter::default()).await?;
+        let hostnames = rows.into_iter().map(|row| row.hostname).collect::<BTreeSet<String>>();
+        Ok(hostnames.into_iter().collect())
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        let rows = self.read_all(&LogFilter::default()).await?;
+        let modules = rows.into_iter().filter_map(|row| row.module).collect::<BTreeSet<String>>();
+        Ok(modules.into_iter().collect())
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        let rows = self.read_all(&LogFilter::default()).await?;
+        match (rows.first(), rows.last()) {
+            (Some(oldest), Some(newest)) => Ok(Some((oldest.timestamp, newest.timestamp))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        let rows = u64::try_from(self.read_all(&LogFilter::default()).await?.len())
+            .expect("Number of entries must fit in u64");
+        let bytes = run_blocking(&self.files, Files::bytes).await?;
+        Ok(StorageStats { rows, bytes })
+    }
+
+    async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
+        self.put_prepared_log_entries(self.prepare_log_entries(entries)).await
+    }
+
+    fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+        apply_oversize_policy(
+            entries,
+            self.max_message_length,
+            self.oversize_policy,
+            &self.rejected,
+        )
+    }
+
+    async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).map_err(|e| {
+            DbError::Other(
+                format!("Cannot insert {} log entries at once: {}", entries.len(), e),
+                None,
+            )
+        })?;
+        if nentries == 0 {
+            return Ok(());
+        }
+        let mut sequence = self.log_sequence.fetch_add(nentries, Ordering::SeqCst);
+
+        let mut batch = String::new();
+        for (_part, mut entry) in entries.into_iter() {
+            // Truncate the contents to match the database-backed implementations.
+            entry.module =
+                truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH).map(Cow::Owned);
+            entry.filename =
+                truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH).map(Cow::Owned);
+            entry.hostname = truncate_str(&entry.hostname, LOG_ENTRY_MAX_HOSTNAME_LENGTH).into();
+            truncate_message(&mut entry.message, self.max_message_length);
+            entry.timestamp = round_timestamp(entry.timestamp)?;
+
+            let line = export::format_entry_ndjson(&entry, Some(wrap_sequence(sequence)))
+                .map_err(|e| DbError::Serialization(e.to_string(), None))?;
+            batch.push_str(&line);
+            batch.push('\n');
+            sequence = sequence.wrapping_add(1);
+        }
+        run_blocking(&self.files, move |files| files.append(&batch)).await
+    }
+
+    async fn archive_log_entries_before(&self, _ts: OffsetDateTime) -> Result<u64> {
+        Err(DbError::Other("Archiving log entries is not supported by files".to_owned(), None))
+    }
+
+    async fn delete_log_entries(&self, _filter: &LogFilter) -> Result<u64> {
+        Err(DbError::Other("Deleting log entries is not supported by files".to_owned(), None))
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        run_blocking(&self.files, Files::clear).await?;
+        self.log_sequence.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        // Entries are not written via SQL statements so there is no limit.
+        usize::MAX
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "file"
+    }
+
+    fn rejected_entries(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        // There is nothing to reclaim as files only grow until they are rotated.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{self, new_entry};
+    use tempfile::TempDir;
+
+    /// Test context to allow automatic cleanup of the test files.
+    struct FileTestContext {
+        _dir: TempDir,
+        db: FileDb,
+    }
+
+    impl testutils::TestContext for FileTestContext {
+        fn db(&self) -> &(dyn Db + Send + Sync) {
+            &self.db
+        }
+    }
+
+    /// Initializes the test database.
+    fn setup() -> Box<dyn testutils::TestContext> {
+        let dir = tempfile::tempdir().unwrap();
+        let db = FileDb::connect(ConnectionOptions {
+            path: dir.path().join("log.jsonl"),
+            ..Default::default()
+        })
+        .unwrap();
+        Box::from(FileTestContext { _dir: dir, db })
+    }
+
+    #[test]
+    fn test_filedb_log_entries_none() {
+        testutils::test_log_entries_none(setup());
+    }
+
+    #[test]
+    fn test_filedb_log_entries_individual() {
+        testutils::test_log_entries_individual(setup());
+    }
+
+    #[test]
+    fn test_filedb_log_entries_combined() {
+        testutils::test_log_entries_combined(setup());
+    }
+
+    #[test]
+    fn test_filedb_log_entries_long_strings() {
+        testutils::test_log_entries_long_strings(setup(), None);
+    }
+
+    #[test]
+    fn test_filedb_query_log_entries_sequence() {
+        testutils::test_query_log_entries_sequence(setup());
+    }
+
+    #[test]
+    fn test_filedb_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup());
+    }
+
+    #[test]
+    fn test_filedb_query_log_entries_message_contains() {
+        testutils::test_query_log_entries_message_contains(setup());
+    }
+
+    #[test]
+    fn test_filedb_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup());
+    }
+
+    #[test]
+    fn test_filedb_stream_log_entries() {
+        testutils::test_stream_log_entries(setup());
+    }
+
+    #[test]
+    fn test_filedb_clear_log_entries() {
+        testutils::test_clear_log_entries(setup());
+    }
+
+    #[test]
+    fn test_filedb_maintenance() {
+        testutils::test_maintenance(setup());
+    }
+
+    #[test]
+    fn test_filedb_distinct_hostnames_and_modules() {
+        testutils::test_distinct_hostnames_and_modules(setup());
+    }
+
+    #[test]
+    fn test_filedb_time_bounds() {
+        testutils::test_time_bounds(setup());
+    }
+
+    #[test]
+    fn test_filedb_storage_stats() {
+        testutils::test_storage_stats(setup());
+    }
+
+    #[test]
+    fn test_filedb_empty_message() {
+        testutils::test_empty_message(setup());
+    }
+
+    #[test]
+    fn test_filedb_large_line_number() {
+        testutils::test_large_line_number(setup());
+    }
+
+    #[test]
+    fn test_filedb_level_counts() {
+        testutils::test_level_counts(setup());
+    }
+
+    #[test]
+    fn test_filedb_utc_offset() {
+        testutils::test_utc_offset(setup());
+    }
+
+    #[test]
+    fn test_filedb_uptime_nanos() {
+        testutils::test_uptime_nanos(setup());
+    }
+
+    #[test]
+    fn test_filedb_trace_ids() {
+        testutils::test_trace_ids(setup());
+    }
+
+    #[test]
+    fn test_filedb_crate_names() {
+        testutils::test_crate_names(setup());
+    }
+
+    #[test]
+    fn test_filedb_module_filter() {
+        testutils::test_module_filter(setup());
+    }
+
+    #[test]
+    fn test_filedb_correlation_ids() {
+        testutils::test_correlation_ids(setup());
+    }
+
+    #[test]
+    fn test_filedb_payloads() {
+        testutils::test_payloads(setup());
+    }
+
+    #[test]
+    fn test_filedb_fields() {
+        testutils::test_fields(setup());
+    }
+
+    #[test]
+    fn test_filedb_ulids() {
+        testutils::test_ulids(setup());
+    }
+
+    #[test]
+    fn test_filedb_verify_schema() {
+        testutils::test_verify_schema(setup());
+    }
+
+    #[tokio::test]
+    async fn test_filedb_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+        let db = FileDb::connect(ConnectionOptions {
+            path: path.clone(),
+            max_file_bytes: Some(1),
+            max_files: 3,
+            ..Default::default()
+        })
+        .unwrap();
+
+        for i in 0..5 {
+            db.put_log_entries(vec![new_entry(i, &format!("Entry {}", i))]).await.unwrap();
+        }
+
+        assert!(path.exists());
+        assert!(db.files.rotated_path(1).exists());
+        assert!(db.files.rotated_path(2).exists());
+        assert!(!db.files.rotated_path(3).exists());
+
+        let messages = db
+            .query_log_entries(&LogFilter::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.message)
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["Entry 2", "Entry 3", "Entry 4"], messages);
+    }
+
+    #[tokio::test]
+    async fn test_filedb_rotation_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+        let db = FileDb::connect(ConnectionOptions {
+            path: path.clone(),
+            max_file_bytes: Some(1),
+            max_files: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        db.put_log_entries(vec![new_entry(0, "Entry 0"), new_entry(1, "Entry 1")]).await.unwrap();
+        db.put_log_entries(vec![new_entry(2, "Entry 2")]).await.unwrap();
+
+        assert!(!db.files.rotated_path(1).exists());
+        let messages = db
+            .query_log_entries(&LogFilter::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.message)
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["Entry 2"], messages);
+    }
+
+    #[tokio::test]
+    async fn test_filedb_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+
+        let db = FileDb::connect(ConnectionOptions { path: path.clone(), ..Default::default() })
+            .unwrap();
+        db.put_log_entries(vec![new_entry(0, "Entry 0")]).await.unwrap();
+        drop(db);
+
+        let db = FileDb::connect(ConnectionOptions { path, ..Default::default() }).unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 1")]).await.unwrap();
+        assert_eq!(
+            vec![
+                "0.0 fake-host 3 NO-MODULE NO-FILENAME:-1 Entry 0".to_owned(),
+                "1.0 fake-host 3 NO-MODULE NO-FILENAME:-1 Entry 1".to_owned(),
+            ],
+            db.get_log_entries().await.unwrap()
+        );
+    }
+}
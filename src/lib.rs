@@ -21,21 +21,60 @@
 #![warn(unused, unused_extern_crates, unused_import_braces, unused_qualifications)]
 #![warn(unsafe_code)]
 
+use futures::stream::BoxStream;
+use log::Level;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::Arc;
+use time::OffsetDateTime;
 
 mod clocks;
+mod dedup;
+mod export;
+pub use export::TimestampFormat;
+mod metrics;
+pub use metrics::Metrics;
 pub(crate) mod logger;
-use crate::logger::LogEntry;
-pub use logger::{init, Handle};
+pub use logger::{
+    init, init_with_options, CapturedFields, ChannelKind, EntryTransform, FilenameTransform,
+    FlushError, FlushGuard, FlushStats, Handle, InternalError, InternalErrorHandler,
+    InternalErrorKind, LogEntry, LoggerOptions, MessageFormatter, OverflowPolicy, PersistCallback,
+    TraceContext, TraceContextExtractor, UtcOffsetSource,
+};
+mod mirror;
+pub use mirror::{Mirror, MirrorFormat};
+pub mod multi;
+mod query;
+pub use query::{LogEntryRow, LogFilter, SortOrder, StorageStats};
 #[cfg(test)]
 mod testutils;
+mod ulid;
 
-#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
-compile_error!("one of the features ['postgres', 'sqlite'] must be enabled");
+#[cfg(not(any(
+    feature = "file",
+    feature = "net",
+    feature = "postgres",
+    feature = "sqlite",
+    feature = "stderr"
+)))]
+compile_error!(
+    "one of the features ['file', 'net', 'postgres', 'sqlite', 'stderr'] must be enabled"
+);
+#[cfg(feature = "file")]
+pub mod file;
+#[cfg(feature = "net")]
+pub mod net;
 #[cfg(feature = "postgres")]
 pub mod postgres;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
+#[cfg(feature = "stderr")]
+pub mod stderr;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tracing")]
+pub mod tracing;
 
 /// Opaque type representing a connection to the logging database.
 #[derive(Clone)]
@@ -46,10 +85,262 @@ impl Connection {
     pub async fn create_schema(&self) -> Result<()> {
         self.0.create_schema().await
     }
+
+    /// Checks that the database schema has the columns that the logger expects.
+    ///
+    /// Returns an error that lists all missing columns and columns with unexpected types, which is
+    /// easier to act upon than the errors that writes would otherwise hit later on.
+    pub async fn verify_schema(&self) -> Result<()> {
+        self.0.verify_schema().await
+    }
+
+    /// Adds the columns and indexes that newer versions of the logger expect to a schema created
+    /// by an older version, keeping the existing log entries.
+    ///
+    /// All of these columns are nullable or have a default value, so existing entries simply lack
+    /// the data.  Also creates the tables that the backend is configured to use but that do not
+    /// exist yet, such as those added to the SQLite `level_tables`.  Fails without changing the
+    /// schema if it differs from the expected one in other ways, such as in the types of the
+    /// columns that it already has.
+    pub async fn migrate_schema(&self) -> Result<()> {
+        self.0.migrate_schema().await
+    }
+
+    /// Initializes the database schema if it does not exist yet and checks that it has the
+    /// columns that the logger expects.
+    ///
+    /// If the schema exists but does not match, this migrates it with `migrate_schema` and, if
+    /// that cannot fix the differences, returns the error from `verify_schema` as recreating it
+    /// would fail anyway.
+    pub async fn ensure_schema(&self) -> Result<()> {
+        match self.verify_schema().await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if self.create_schema().await.is_err() && self.migrate_schema().await.is_err() {
+                    return Err(e);
+                }
+                self.verify_schema().await
+            }
+        }
+    }
+}
+
+/// What to do with the log entries whose messages exceed the maximum length configured in the
+/// connection options of a backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OversizePolicy {
+    /// Truncates the messages to the maximum length.
+    #[default]
+    Truncate,
+
+    /// Splits the messages into as many consecutive entries as necessary, all of them but the last
+    /// ending with a `[...]` continuation marker.
+    Split,
+
+    /// Discards the entries.  Use `Handle::rejected_entries` to know how many were discarded.
+    Reject,
+}
+
+/// Mapping between log levels and the integers that represent them in the database.
+///
+/// The default mapping follows the order of `log::Level`, from 1 for errors to 5 for traces.
+/// Other mappings allow sharing the database with tools that expect a different severity scheme,
+/// such as the one of syslog.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LevelEncoding([i64; 5]);
+
+impl Default for LevelEncoding {
+    fn default() -> Self {
+        Self([1, 2, 3, 4, 5])
+    }
+}
+
+impl LevelEncoding {
+    /// Creates a mapping that stores errors, warnings, info, debug and trace entries as the
+    /// corresponding elements of `values`.
+    ///
+    /// The values must be strictly increasing or strictly decreasing so that ranges of levels can
+    /// be queried in the database.  For example, `[3, 4, 6, 7, 8]` follows the syslog severities,
+    /// with traces one past debug.
+    pub fn new(values: [i64; 5]) -> Result<Self> {
+        let increasing = values.windows(2).all(|pair| pair[0] < pair[1]);
+        let decreasing = values.windows(2).all(|pair| pair[0] > pair[1]);
+        if !increasing && !decreasing {
+            return Err(DbError::Other(
+                format!("Invalid level encoding {:?}: values must be strictly monotonic", values),
+                None,
+            ));
+        }
+        Ok(Self(values))
+    }
+
+    /// Returns the integer that represents `level` in the database.
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    pub(crate) fn encode(&self, level: Level) -> i64 {
+        self.0[level as usize - 1]
+    }
+
+    /// Converts the integer that represents a level in the database back into a `Level`.
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    pub(crate) fn decode(&self, value: i64) -> Result<Level> {
+        match self.0.iter().position(|v| *v == value) {
+            Some(i) => query::level_from_int(i as i64 + 1),
+            None => Err(DbError::Serialization(
+                format!("Invalid log level {} in database", value),
+                None,
+            )),
+        }
+    }
+}
+
+/// Structured fields of a log entry, such as the key-values of a `log` record, sorted by key.
+pub type Fields = BTreeMap<String, String>;
+
+/// Serialization of the structured fields of the log entries into the bytes stored in the
+/// `fields` column of a database.
+///
+/// The default is `JsonFieldCodec`, which keeps the fields readable by other tools.  Deployments
+/// that store many fields can implement this with a more compact format, such as MessagePack or
+/// CBOR, and pass it in the connection options of their backend.  Changing the codec of an
+/// existing database makes the fields already in it unreadable.
+pub trait FieldCodec: Send + Sync {
+    /// Serializes `fields` into the bytes to store in the database.
+    fn encode(&self, fields: &Fields) -> Result<Vec<u8>>;
+
+    /// Deserializes the bytes previously produced by `encode` back into fields.
+    fn decode(&self, data: &[u8]) -> Result<Fields>;
+}
+
+/// Codec that stores the structured fields as a JSON object of strings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonFieldCodec;
+
+impl FieldCodec for JsonFieldCodec {
+    fn encode(&self, fields: &Fields) -> Result<Vec<u8>> {
+        serde_json::to_vec(fields).map_err(|e| {
+            DbError::Serialization(format!("Cannot encode fields as JSON: {}", e), Some(e.into()))
+        })
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Fields> {
+        serde_json::from_slice(data).map_err(|e| {
+            DbError::Serialization(
+                format!("Invalid JSON fields in database: {}", e),
+                Some(e.into()),
+            )
+        })
+    }
+}
+
+/// Underlying error that caused a `DbError`, such as a `sqlx::Error` or an `io::Error`.
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors returned by this library.
+///
+/// Errors are classified into broad categories so that callers can decide how to react to them
+/// (for example, by retrying on connection problems).  The `Display` representation of an error is
+/// the message of the underlying failure, and the failure itself, if any, is available via
+/// `Error::source` so that callers can inspect it (for example, by downcasting to `sqlx::Error`).
+#[derive(Debug)]
+pub enum DbError {
+    /// Failure to establish or use a connection to the database.
+    Connection(String, Option<ErrorSource>),
+
+    /// Violation of a database constraint, such as a duplicate primary key.
+    Constraint(String, Option<ErrorSource>),
+
+    /// Failure to convert data to or from its database representation.
+    Serialization(String, Option<ErrorSource>),
+
+    /// Any other error.
+    Other(String, Option<ErrorSource>),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Connection(message, _) => write!(f, "{}", message),
+            DbError::Constraint(message, _) => write!(f, "{}", message),
+            DbError::Serialization(message, _) => write!(f, "{}", message),
+            DbError::Other(message, _) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl DbError {
+    /// Returns an error of the same kind as this one with `message` and this error as its source.
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn wrap(self, message: String) -> DbError {
+        let kind: fn(String, Option<ErrorSource>) -> DbError = match &self {
+            DbError::Connection(..) => DbError::Connection,
+            DbError::Constraint(..) => DbError::Constraint,
+            DbError::Serialization(..) => DbError::Serialization,
+            DbError::Other(..) => DbError::Other,
+        };
+        kind(message, Some(Box::new(self)))
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let source = match self {
+            DbError::Connection(_, source) => source,
+            DbError::Constraint(_, source) => source,
+            DbError::Serialization(_, source) => source,
+            DbError::Other(_, source) => source,
+        };
+        source.as_ref().map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        let kind: fn(String, Option<ErrorSource>) -> DbError = match &e {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => DbError::Connection,
+
+            #[cfg(feature = "sqlite")]
+            sqlx::Error::Database(e) if is_sqlite_busy(e.as_ref()) => DbError::Connection,
+
+            sqlx::Error::Database(e) => match e.kind() {
+                sqlx::error::ErrorKind::Other => DbError::Other,
+                _ => DbError::Constraint,
+            },
+
+            sqlx::Error::ColumnDecode { .. }
+            | sqlx::Error::Decode(_)
+            | sqlx::Error::Encode(_)
+            | sqlx::Error::TypeNotFound { .. } => DbError::Serialization,
+
+            _ => DbError::Other,
+        };
+        kind(e.to_string(), Some(Box::new(e)))
+    }
+}
+
+/// Returns true if `e` is an SQLite error caused by a lock held by another connection, which goes
+/// away once that connection completes its operation.
+#[cfg(feature = "sqlite")]
+fn is_sqlite_busy(e: &dyn sqlx::error::DatabaseError) -> bool {
+    const SQLITE_BUSY: i32 = 5;
+    const SQLITE_LOCKED: i32 = 6;
+
+    if e.try_downcast_ref::<sqlx::sqlite::SqliteError>().is_none() {
+        return false;
+    }
+    // Extended result codes carry the primary result code in their lowest byte.
+    match e.code().and_then(|code| code.parse::<i32>().ok()) {
+        Some(code) => matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED),
+        None => false,
+    }
 }
 
 /// Result type for this library.
-pub(crate) type Result<T> = std::result::Result<T, String>;
+pub(crate) type Result<T> = std::result::Result<T, DbError>;
 
 /// Abstraction over the database connection.
 #[async_trait::async_trait]
@@ -57,14 +348,55 @@ pub(crate) trait Db {
     /// Initializes the database schema.
     async fn create_schema(&self) -> Result<()>;
 
+    /// Checks that the columns of the database schema match the ones that this code expects.
+    async fn verify_schema(&self) -> Result<()>;
+
+    /// Adds the nullable columns and the indexes that are missing from a schema created by an
+    /// older version of this code.
+    async fn migrate_schema(&self) -> Result<()>;
+
     /// Returns the sorted list of all log entries in the database.
     ///
     /// Given that this is exposed for testing purposes only, this just returns a flat textual
     /// representation of the log entry and does not try to deserialize it as a `LogEntry`.  This
     /// is for simplicity given that a `LogEntry` keeps references to static strings and we cannot
     /// obtain those from the database.
+    #[cfg(test)]
     async fn get_log_entries(&self) -> Result<Vec<String>>;
 
+    /// Returns the log entries in the database that match `filter` sorted by timestamp and
+    /// sequence.
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>>;
+
+    /// Returns a stream over the log entries in the database that match `filter`, sorted as
+    /// requested by the filter.
+    ///
+    /// Rows are fetched lazily as the stream is consumed so that large result sets do not have to
+    /// be materialized in memory.
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>>;
+
+    /// Returns the number of log entries in the database that match `filter` for each level.
+    ///
+    /// Levels without entries are omitted and the results are sorted by level, from most to least
+    /// severe.  The `order` and `limit` of the filter are ignored.
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>>;
+
+    /// Returns the sorted list of distinct hostnames present in the log.
+    async fn distinct_hostnames(&self) -> Result<Vec<String>>;
+
+    /// Returns the sorted list of distinct modules present in the log.
+    ///
+    /// Entries without a module are not represented in the returned list.
+    async fn distinct_modules(&self) -> Result<Vec<String>>;
+
+    /// Returns the timestamps of the oldest and newest log entries in the database, including
+    /// archived ones, or `None` if there are no entries.
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>>;
+
+    /// Returns the number of log entries in the database, including archived ones, and an estimate
+    /// of the space that they occupy.
+    async fn storage_stats(&self) -> Result<StorageStats>;
+
     /// Appends a series of `entries` to the log.
     ///
     /// All entries are inserted at once into the database to avoid unnecessary round trips for each
@@ -73,15 +405,912 @@ pub(crate) trait Db {
     /// This takes a `Vec` instead of a slice for efficiency, as the writes may have to truncate the
     /// entries.
     async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()>;
+
+    /// Applies the handling of oversized messages of the database to `entries` and returns the
+    /// entries to write, each tagged with the position of the part of its original entry.
+    ///
+    /// Callers that may write the same batch more than once must call this only once and then
+    /// use `put_prepared_log_entries` so that rejected entries are not counted again on every
+    /// attempt.
+    ///
+    /// The default is for backends that do not limit the length of the messages.
+    fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+        entries.into_iter().map(|entry| (0, entry)).collect()
+    }
+
+    /// Appends a series of `entries` that were returned by `prepare_log_entries` to the log.
+    ///
+    /// The default is for backends that do not limit the length of the messages.
+    async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+        self.put_log_entries(entries.into_iter().map(|(_part, entry)| entry).collect()).await
+    }
+
+    /// Marks all log entries recorded before `ts` as archived and returns how many were marked.
+    ///
+    /// Archived entries are kept in the database but are hidden from queries unless the filter
+    /// asks for them.
+    async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64>;
+
+    /// Deletes the log entries that match `filter` and returns how many were deleted.
+    ///
+    /// The `order` and `limit` of the filter are ignored, and callers are expected to have
+    /// validated it with `LogFilter::check_deletable`.
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64>;
+
+    /// Deletes all log entries, including archived ones, and restarts the sequence numbers of new
+    /// entries from zero.
+    ///
+    /// Unlike `delete_log_entries`, this does not report how many entries were deleted, which
+    /// allows backends to discard the contents of the log wholesale.
+    async fn clear_log_entries(&self) -> Result<()>;
+
+    /// Returns the maximum number of bind parameters that a single statement can have.
+    ///
+    /// Writes of large batches are split into multiple statements, within the same transaction,
+    /// to not exceed this limit.
+    fn max_params_per_statement(&self) -> usize;
+
+    /// Returns a short name that identifies the type of the database, such as `sqlite`.
+    fn backend_name(&self) -> &'static str;
+
+    /// Returns the integer that represents `level` in the database.
+    ///
+    /// The default follows the order of `log::Level`, from 1 for errors to 5 for traces.  Backends
+    /// can override this and `decode_level` to store a different severity scheme, such as the one
+    /// of syslog, as long as the encoding is monotonic so that ranges of levels can be queried.
+    fn encode_level(&self, level: Level) -> i64 {
+        level as i64
+    }
+
+    /// Converts the integer that represents a level in the database back into a `Level`.
+    ///
+    /// This must be the inverse of `encode_level`.
+    fn decode_level(&self, level: i64) -> Result<Level> {
+        query::level_from_int(level)
+    }
+
+    /// Serializes the structured `fields` of an entry into the bytes stored in the database.
+    ///
+    /// The default uses `JsonFieldCodec`.  Backends can override this and `decode_fields` to use
+    /// the `FieldCodec` configured in their connection options.
+    fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+        JsonFieldCodec.encode(fields)
+    }
+
+    /// Deserializes the structured fields of an entry from the bytes stored in the database.
+    ///
+    /// This must be the inverse of `encode_fields`.
+    fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+        JsonFieldCodec.decode(data)
+    }
+
+    /// Returns the number of entries discarded by `OversizePolicy::Reject` since the connection was
+    /// established.
+    ///
+    /// The default is for backends that do not limit the length of the messages.
+    fn rejected_entries(&self) -> u64 {
+        0
+    }
+
+    /// Returns true if writes skip log entries that already exist in the database.
+    ///
+    /// Duplicates are identified by the ULID of the entries, so the logger refuses to use such a
+    /// database unless it records ULIDs.  The default is for backends that never skip entries.
+    fn ignores_duplicates(&self) -> bool {
+        false
+    }
+
+    /// Reclaims unused space and refreshes the query planner statistics of the database.
+    ///
+    /// This can be expensive and may block writers while it runs.
+    async fn maintenance(&self) -> Result<()>;
+}
+
+/// Converts a raw sequence counter `value` into a sequence number that fits in the database.
+///
+/// Sequence numbers are only used to disambiguate log entries with identical timestamps, so we let
+/// them wrap around to zero once they exceed `i64::MAX` (the largest value that the databases can
+/// store) instead of failing to write entries in very long-running processes.  Wrapping is safe
+/// for ordering purposes because it would take hundreds of years of continuous logging for two
+/// entries with the same timestamp to straddle the wraparound point.
+pub(crate) fn wrap_sequence(value: u64) -> i64 {
+    i64::try_from(value & (i64::MAX as u64)).expect("Masked sequence must fit in i64")
+}
+
+/// Returns the number of rows with `params_per_row` parameters each that fit in a statement that
+/// can have at most `max_params` parameters.  This is always at least one.
+pub(crate) fn rows_per_statement(max_params: usize, params_per_row: usize) -> usize {
+    (max_params / params_per_row).max(1)
+}
+
+/// Splits `items` into consecutive chunks of at most `size` elements each.
+pub(crate) fn into_chunks<T>(mut items: Vec<T>, size: usize) -> Vec<Vec<T>> {
+    let mut chunks = vec![];
+    while items.len() > size {
+        let rest = items.split_off(size);
+        chunks.push(items);
+        items = rest;
+    }
+    if !items.is_empty() {
+        chunks.push(items);
+    }
+    chunks
+}
+
+/// Truncates `s` to at most `max_len` bytes without splitting a multi-byte character.
+fn truncate_at_char_boundary(s: &mut String, max_len: usize) {
+    if s.len() > max_len {
+        let mut end = max_len;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
 }
 
 /// Fits the string in `input` within the specified `max_len`.
-fn truncate_option_str(input: Option<String>, max_len: usize) -> Option<String> {
+///
+/// Static strings captured from log records are only copied here, when they are about to be
+/// written, to keep the logging path free of allocations.
+fn truncate_option_str(input: Option<Cow<'static, str>>, max_len: usize) -> Option<String> {
     match input {
-        Some(mut s) => {
-            s.truncate(max_len);
+        Some(s) => {
+            let mut s = s.into_owned();
+            truncate_at_char_boundary(&mut s, max_len);
             Some(s)
         }
         None => None,
     }
 }
+
+/// Copies the string in `input` and fits it within the specified `max_len`.
+fn truncate_str(input: &str, max_len: usize) -> String {
+    let mut s = input.to_owned();
+    truncate_at_char_boundary(&mut s, max_len);
+    s
+}
+
+/// Marker appended to all but the last of the entries into which `OversizePolicy::Split` breaks an
+/// oversized message.
+#[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+const CONTINUATION_MARKER: &str = " [...]";
+
+/// Breaks `entry` into as many entries as necessary for their messages to fit within `max_len`.
+///
+/// If `max_len` is too short to make progress, the last entry keeps the rest of the message, which
+/// is then truncated when written.
+#[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+fn split_entry(mut entry: LogEntry, max_len: usize) -> Vec<LogEntry> {
+    let message = std::mem::take(&mut entry.message);
+    let chunk_len = max_len.saturating_sub(CONTINUATION_MARKER.len());
+
+    let mut parts = vec![];
+    let mut rest = message.as_str();
+    while rest.len() > max_len {
+        let mut end = chunk_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            break;
+        }
+        parts.push(format!("{}{}", &rest[..end], CONTINUATION_MARKER));
+        rest = &rest[end..];
+    }
+    parts.push(rest.to_owned());
+
+    // The payload is attached to the first part only so that it is not stored repeatedly.
+    let mut payload = entry.payload.take();
+    parts
+        .into_iter()
+        .map(|message| LogEntry { message, payload: payload.take(), ..entry.clone() })
+        .collect()
+}
+
+/// Applies `policy` to the `entries` whose messages are longer than `max_len`, if there is a
+/// limit, and returns the entries to write.
+///
+/// Each returned entry is paired with its position among the parts into which
+/// `OversizePolicy::Split` broke its original entry, or 0 if it was not split, which tells the
+/// parts apart when deduplicating entries.  Entries discarded by `OversizePolicy::Reject` are
+/// counted in `rejected`.  Entries subject to `OversizePolicy::Truncate` are returned as is
+/// because they are truncated when written.
+#[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+fn apply_oversize_policy(
+    entries: Vec<LogEntry>,
+    max_len: Option<usize>,
+    policy: OversizePolicy,
+    rejected: &std::sync::atomic::AtomicU64,
+) -> Vec<(u32, LogEntry)> {
+    let max_len = match (max_len, policy) {
+        (Some(max_len), OversizePolicy::Split | OversizePolicy::Reject)
+            if entries.iter().any(|entry| entry.message.len() > max_len) =>
+        {
+            max_len
+        }
+        _ => return entries.into_iter().map(|entry| (0, entry)).collect(),
+    };
+
+    let mut result = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.message.len() <= max_len {
+            result.push((0, entry));
+        } else if policy == OversizePolicy::Split {
+            result.extend((0..).zip(split_entry(entry, max_len)));
+        } else {
+            rejected.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+    result
+}
+
+/// Delay before the first retry to connect to a database if not configured.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+const DEFAULT_CONNECT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Maximum delay between two attempts to connect to a database.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+const MAX_CONNECT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Runs `connect` until it succeeds or until it fails `retries + 1` times, waiting `delay` (or
+/// `DEFAULT_CONNECT_RETRY_DELAY`) after the first failure and twice as long after every subsequent
+/// one, up to `MAX_CONNECT_RETRY_DELAY`.
+///
+/// All errors are retried because a database that is still starting up may reject connections in
+/// various ways, and every retried failure is reported to `on_error`.  The error of the last
+/// attempt is returned as is if there are no retries, or otherwise wrapped in an error of the same
+/// kind that tells how many attempts were made.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+async fn connect_with_retries<T, F, Fut>(
+    retries: usize,
+    delay: Option<std::time::Duration>,
+    on_error: &InternalErrorHandler,
+    mut connect: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut delay = delay.unwrap_or(DEFAULT_CONNECT_RETRY_DELAY);
+    let mut attempt = 0;
+    loop {
+        match connect().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                logger::report(
+                    on_error,
+                    InternalErrorKind::Connection,
+                    format!(
+                        "Failed to connect to the database (attempt {} of {}): {}; \
+                         retrying in {:?}",
+                        attempt,
+                        retries + 1,
+                        e,
+                        delay
+                    ),
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2).min(MAX_CONNECT_RETRY_DELAY);
+            }
+            Err(e) if retries == 0 => return Err(e),
+            Err(e) => {
+                let message = format!(
+                    "Failed to connect to the database after {} attempts: {}",
+                    retries + 1,
+                    e
+                );
+                return Err(e.wrap(message));
+            }
+        }
+    }
+}
+
+/// Truncates `message` to fit within `max_len`, if there is a limit.
+fn truncate_message(message: &mut String, max_len: Option<usize>) {
+    if let Some(max_len) = max_len {
+        truncate_at_char_boundary(message, max_len);
+    }
+}
+
+/// Rounds `ts` to the next microsecond to emulate the behavior of the `postgres` implementation.
+#[cfg(any(feature = "file", feature = "stderr"))]
+fn round_timestamp(ts: OffsetDateTime) -> Result<OffsetDateTime> {
+    let nanos = ts.unix_timestamp_nanos();
+
+    let nanos_only = nanos % 1000;
+    let mut nanos = nanos / 1000 * 1000;
+    if nanos_only > 0 {
+        nanos += 1000;
+    }
+
+    OffsetDateTime::from_unix_timestamp_nanos(nanos)
+        .map_err(|e| DbError::Serialization(e.to_string(), None))
+}
+
+/// Compares the `actual` columns of `table`, given as name and type pairs, against the `expected`
+/// ones and returns an error that lists all mismatches, if any.
+///
+/// Types are compared case-insensitively and columns that are not expected are ignored.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn check_columns(
+    table: &str,
+    expected: &[(&str, &str)],
+    actual: &[(String, String)],
+) -> Result<()> {
+    if actual.is_empty() {
+        return Err(DbError::Other(format!("Table {} does not exist", table), None));
+    }
+
+    let mut problems = vec![];
+    for (name, expected_type) in expected {
+        match actual.iter().find(|(actual_name, _)| actual_name == name) {
+            None => problems.push(format!("missing column {} {}", name, expected_type)),
+            Some((_, actual_type)) if !actual_type.eq_ignore_ascii_case(expected_type) => problems
+                .push(format!(
+                    "column {} has type {} but {} was expected",
+                    name, actual_type, expected_type
+                )),
+            Some(_) => (),
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(DbError::Other(
+            format!(
+                "Schema of table {} does not match the expected one: {}",
+                table,
+                problems.join("; ")
+            ),
+            None,
+        ))
+    }
+}
+
+/// Returns the definitions of the `migratable` columns, given as name and definition pairs, that
+/// are missing from the `actual` columns of `table`.
+///
+/// Fails, like `check_columns`, if any of the `expected` columns that cannot be migrated is
+/// missing or has the wrong type, as adding columns would not make the schema usable.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn missing_columns<'a>(
+    table: &str,
+    expected: &[(&str, &str)],
+    migratable: &[(&str, &'a str)],
+    actual: &[(String, String)],
+) -> Result<Vec<&'a str>> {
+    let fixed = expected
+        .iter()
+        .filter(|(name, _)| !migratable.iter().any(|(migratable_name, _)| migratable_name == name))
+        .copied()
+        .collect::<Vec<(&str, &str)>>();
+    check_columns(table, &fixed, actual)?;
+
+    Ok(migratable
+        .iter()
+        .filter(|(name, _)| !actual.iter().any(|(actual_name, _)| actual_name == name))
+        .map(|(_, definition)| *definition)
+        .collect())
+}
+
+/// Maximum number of characters of the first message of a batch to include in its description.
+const DESCRIBED_MESSAGE_LENGTH: usize = 80;
+
+/// Describes the `entries` of a batch so that errors about its loss or insertion tell which entries
+/// were affected: the number of entries, their timestamp range, and the beginning of the first
+/// message.
+fn describe_entries<'a>(entries: impl Iterator<Item = &'a LogEntry>) -> String {
+    let mut entries = entries.peekable();
+    let first = match entries.peek() {
+        Some(first) => *first,
+        None => return "no entries".to_owned(),
+    };
+
+    let (mut count, mut oldest, mut newest) = (0, first.timestamp, first.timestamp);
+    for entry in entries {
+        count += 1;
+        oldest = oldest.min(entry.timestamp);
+        newest = newest.max(entry.timestamp);
+    }
+
+    let mut message = first.message.chars().take(DESCRIBED_MESSAGE_LENGTH).collect::<String>();
+    if message.len() < first.message.len() {
+        message.push_str("...");
+    }
+
+    format!("{} entries, timestamps {} to {}, first message {:?}", count, oldest, newest, message)
+}
+
+/// Describes the batch of `entries`, tagged with their sequence numbers and part positions, so
+/// that errors about its insertion can identify which entries were affected.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn describe_batch(entries: &[(u64, u32, LogEntry)]) -> String {
+    match (entries.first(), entries.last()) {
+        (Some((first_sequence, _, _)), Some((last_sequence, _, _))) => format!(
+            "sequences {} to {}, {}",
+            wrap_sequence(*first_sequence),
+            wrap_sequence(*last_sequence),
+            describe_entries(entries.iter().map(|(_, _, entry)| entry))
+        ),
+        _ => "empty batch".to_owned(),
+    }
+}
+
+/// Checks that inserting the `expected` number of entries described by `batch` into `table`
+/// affected `affected` rows.
+///
+/// When `ignore_duplicates` is true, the insertion skips entries that already exist, so fewer
+/// affected rows than expected are legitimate.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn check_inserted_rows(
+    table: &str,
+    batch: &str,
+    expected: u64,
+    affected: u64,
+    ignore_duplicates: bool,
+) -> Result<()> {
+    if affected == expected || (ignore_duplicates && affected < expected) {
+        return Ok(());
+    }
+    Err(DbError::Other(
+        format!(
+            "Log entries insertion into {} created {} rows but expected {} ({}{})",
+            table,
+            affected,
+            expected,
+            batch,
+            if ignore_duplicates { ", ignoring duplicates" } else { "" }
+        ),
+        None,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_level_encoding_default() {
+        let encoding = LevelEncoding::default();
+        for (level, value) in [
+            (Level::Error, 1),
+            (Level::Warn, 2),
+            (Level::Info, 3),
+            (Level::Debug, 4),
+            (Level::Trace, 5),
+        ] {
+            assert_eq!(value, encoding.encode(level));
+            assert_eq!(level, encoding.decode(value).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_level_encoding_custom() {
+        let encoding = LevelEncoding::new([3, 4, 6, 7, 8]).unwrap();
+        assert_eq!(3, encoding.encode(Level::Error));
+        assert_eq!(6, encoding.encode(Level::Info));
+        assert_eq!(Level::Trace, encoding.decode(8).unwrap());
+        match encoding.decode(5) {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("Invalid log level 5")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let encoding = LevelEncoding::new([50, 40, 30, 20, 10]).unwrap();
+        assert_eq!(Level::Warn, encoding.decode(40).unwrap());
+    }
+
+    #[test]
+    fn test_level_encoding_not_monotonic() {
+        for values in [[1, 2, 2, 3, 4], [1, 3, 2, 4, 5], [5, 5, 5, 5, 5]] {
+            match LevelEncoding::new(values) {
+                Err(DbError::Other(e, _)) => assert!(e.contains("strictly monotonic")),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dberror_display() {
+        assert_eq!("foo", DbError::Connection("foo".to_owned(), None).to_string());
+        assert_eq!("bar", DbError::Constraint("bar".to_owned(), None).to_string());
+        assert_eq!("baz", DbError::Serialization("baz".to_owned(), None).to_string());
+        assert_eq!("qux", DbError::Other("qux".to_owned(), None).to_string());
+    }
+
+    #[test]
+    fn test_dberror_from_sqlx() {
+        match DbError::from(sqlx::Error::PoolTimedOut) {
+            DbError::Connection(e, _) => assert_eq!(sqlx::Error::PoolTimedOut.to_string(), e),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        match DbError::from(sqlx::Error::RowNotFound) {
+            DbError::Other(e, _) => assert_eq!(sqlx::Error::RowNotFound.to_string(), e),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        match DbError::from(sqlx::Error::ColumnNotFound("foo".to_owned())) {
+            DbError::Other(e, _) => assert!(e.contains("foo")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        match DbError::from(sqlx::Error::Decode("bad value".into())) {
+            DbError::Serialization(e, _) => assert!(e.contains("bad value")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_dberror_source() {
+        use std::error::Error;
+
+        assert!(DbError::Other("foo".to_owned(), None).source().is_none());
+
+        let e = DbError::from(sqlx::Error::PoolTimedOut);
+        match e.source().and_then(|e| e.downcast_ref::<sqlx::Error>()) {
+            Some(sqlx::Error::PoolTimedOut) => (),
+            e => panic!("Unexpected source {:?}", e),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_check_columns() {
+        let expected = [("a", "INTEGER"), ("b", "TEXT")];
+        let column = |name: &str, ty: &str| (name.to_owned(), ty.to_owned());
+
+        check_columns("t", &expected, &[column("a", "integer"), column("b", "TEXT")]).unwrap();
+        check_columns(
+            "t",
+            &expected,
+            &[column("b", "TEXT"), column("c", "x"), column("a", "INTEGER")],
+        )
+        .unwrap();
+
+        match check_columns("t", &expected, &[]) {
+            Err(DbError::Other(e, _)) => assert_eq!("Table t does not exist", e),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match check_columns("t", &expected, &[column("a", "TEXT")]) {
+            Err(DbError::Other(e, _)) => assert_eq!(
+                "Schema of table t does not match the expected one: \
+                 column a has type TEXT but INTEGER was expected; missing column b TEXT",
+                e
+            ),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_missing_columns() {
+        let expected = [("a", "INTEGER"), ("b", "TEXT"), ("c", "TEXT")];
+        let migratable = [("b", "b TEXT"), ("c", "c TEXT NOT NULL DEFAULT ''")];
+        let column = |name: &str, ty: &str| (name.to_owned(), ty.to_owned());
+
+        assert!(missing_columns(
+            "t",
+            &expected,
+            &migratable,
+            &[column("a", "INTEGER"), column("b", "TEXT"), column("c", "TEXT")]
+        )
+        .unwrap()
+        .is_empty());
+        assert_eq!(
+            vec!["c TEXT NOT NULL DEFAULT ''"],
+            missing_columns(
+                "t",
+                &expected,
+                &migratable,
+                &[column("a", "INTEGER"), column("b", "TEXT")]
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            vec!["b TEXT", "c TEXT NOT NULL DEFAULT ''"],
+            missing_columns("t", &expected, &migratable, &[column("a", "INTEGER")]).unwrap()
+        );
+
+        match missing_columns("t", &expected, &migratable, &[column("a", "TEXT")]) {
+            Err(DbError::Other(e, _)) => assert_eq!(
+                "Schema of table t does not match the expected one: \
+                 column a has type TEXT but INTEGER was expected",
+                e
+            ),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    async fn test_connect_with_retries_eventually_succeeds() {
+        let errors = Arc::from(std::sync::Mutex::new(vec![]));
+        let on_error: InternalErrorHandler = {
+            let errors = errors.clone();
+            Arc::new(move |error| errors.lock().unwrap().push(error))
+        };
+        let mut attempts = 0;
+        let result = connect_with_retries(2, Some(std::time::Duration::ZERO), &on_error, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err(DbError::Connection(format!("Attempt {} failed", attempt), None))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(3, result.unwrap());
+        assert_eq!(3, attempts);
+        assert_eq!(
+            vec![
+                InternalError {
+                    kind: InternalErrorKind::Connection,
+                    message: "Failed to connect to the database (attempt 1 of 3): \
+                              Attempt 1 failed; retrying in 0ns"
+                        .to_owned(),
+                },
+                InternalError {
+                    kind: InternalErrorKind::Connection,
+                    message: "Failed to connect to the database (attempt 2 of 3): \
+                              Attempt 2 failed; retrying in 0ns"
+                        .to_owned(),
+                },
+            ],
+            *errors.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    async fn test_connect_with_retries_gives_up() {
+        let mut attempts = 0;
+        let on_error = logger::stderr_error_handler();
+        let result: Result<()> =
+            connect_with_retries(1, Some(std::time::Duration::ZERO), &on_error, || {
+                attempts += 1;
+                let attempt = attempts;
+                async move { Err(DbError::Other(format!("Attempt {} failed", attempt), None)) }
+            })
+            .await;
+        match result {
+            Err(e @ DbError::Other(..)) => {
+                assert_eq!(
+                    "Failed to connect to the database after 2 attempts: Attempt 2 failed",
+                    e.to_string()
+                );
+                let source = std::error::Error::source(&e).unwrap();
+                assert_eq!("Attempt 2 failed", source.to_string());
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(2, attempts);
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    async fn test_connect_with_retries_none() {
+        let on_error = logger::stderr_error_handler();
+        let result: Result<()> = connect_with_retries(0, None, &on_error, || async {
+            Err(DbError::Other("Immediate failure".to_owned(), None))
+        })
+        .await;
+        match result {
+            Err(DbError::Other(e, _)) => assert_eq!("Immediate failure", e),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_describe_batch() {
+        let entry = |secs: i64| {
+            LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+                "fake-host",
+                Level::Info,
+                "msg",
+            )
+        };
+
+        assert_eq!("empty batch", describe_batch(&[]));
+        assert_eq!(
+            format!(
+                "sequences 5 to 5, 1 entries, timestamps {} to {}, first message \"msg\"",
+                entry(10).timestamp,
+                entry(10).timestamp
+            ),
+            describe_batch(&[(5, 0, entry(10))])
+        );
+        assert_eq!(
+            format!(
+                "sequences 5 to 7, 3 entries, timestamps {} to {}, first message \"msg\"",
+                entry(10).timestamp,
+                entry(20).timestamp
+            ),
+            describe_batch(&[(5, 0, entry(10)), (6, 0, entry(15)), (7, 1, entry(20))])
+        );
+    }
+
+    #[test]
+    fn test_describe_entries() {
+        let entry = |secs, message: &str| {
+            LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+                "fake-host",
+                Level::Info,
+                message.to_owned(),
+            )
+        };
+
+        assert_eq!("no entries", describe_entries([].iter()));
+        assert_eq!(
+            format!(
+                "2 entries, timestamps {} to {}, first message \"The \\\"first\\\"\"",
+                entry(10, "").timestamp,
+                entry(20, "").timestamp,
+            ),
+            describe_entries([entry(20, "The \"first\""), entry(10, "The second")].iter())
+        );
+
+        let long = "ñ".repeat(DESCRIBED_MESSAGE_LENGTH + 1);
+        let description = describe_entries([entry(10, &long)].iter());
+        assert!(description.starts_with("1 entries, "));
+        assert!(description.ends_with(&format!("\"{}...\"", "ñ".repeat(DESCRIBED_MESSAGE_LENGTH))));
+    }
+
+    #[test]
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    fn test_check_inserted_rows() {
+        check_inserted_rows("t", "the-batch", 3, 3, false).unwrap();
+        check_inserted_rows("t", "the-batch", 3, 3, true).unwrap();
+        check_inserted_rows("t", "the-batch", 3, 0, true).unwrap();
+
+        match check_inserted_rows("t", "the-batch", 3, 2, false) {
+            Err(DbError::Other(e, _)) => {
+                assert_eq!(
+                    "Log entries insertion into t created 2 rows but expected 3 (the-batch)",
+                    e
+                )
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match check_inserted_rows("t", "the-batch", 3, 4, true) {
+            Err(DbError::Other(e, _)) => assert_eq!(
+                "Log entries insertion into t created 4 rows but expected 3 \
+                 (the-batch, ignoring duplicates)",
+                e
+            ),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    /// Creates a new log entry for tests with the given `message`.
+    #[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+    fn new_entry(message: &str) -> LogEntry {
+        LogEntry::new(
+            OffsetDateTime::from_unix_timestamp(1).unwrap(),
+            "fake-host",
+            Level::Info,
+            message.to_owned(),
+        )
+    }
+
+    #[test]
+    #[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+    fn test_apply_oversize_policy() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let messages = |entries: Vec<(u32, LogEntry)>| {
+            entries.into_iter().map(|(_part, entry)| entry.message).collect::<Vec<String>>()
+        };
+        let entries = || vec![new_entry("short"), new_entry("0123456789abcdefghij")];
+
+        let rejected = AtomicU64::new(0);
+        assert_eq!(
+            vec!["short", "0123456789abcdefghij"],
+            messages(apply_oversize_policy(entries(), None, OversizePolicy::Reject, &rejected))
+        );
+        assert_eq!(
+            vec!["short", "0123456789abcdefghij"],
+            messages(apply_oversize_policy(
+                entries(),
+                Some(10),
+                OversizePolicy::Truncate,
+                &rejected
+            ))
+        );
+        assert_eq!(0, rejected.load(Ordering::SeqCst));
+
+        assert_eq!(
+            vec!["short"],
+            messages(apply_oversize_policy(entries(), Some(10), OversizePolicy::Reject, &rejected))
+        );
+        assert_eq!(1, rejected.load(Ordering::SeqCst));
+
+        let split = apply_oversize_policy(entries(), Some(10), OversizePolicy::Split, &rejected);
+        assert_eq!(vec![0, 0, 1, 2, 3], split.iter().map(|(part, _)| *part).collect::<Vec<u32>>());
+        assert_eq!(
+            vec!["short", "0123 [...]", "4567 [...]", "89ab [...]", "cdefghij"],
+            messages(split)
+        );
+        assert_eq!(1, rejected.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+    fn test_split_entry_char_boundaries() {
+        let parts = split_entry(new_entry("ñññññ"), 9)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["ñ [...]", "ññññ"], parts);
+    }
+
+    #[test]
+    #[cfg(any(feature = "file", feature = "postgres", feature = "sqlite"))]
+    fn test_split_entry_no_room_for_marker() {
+        let parts = split_entry(new_entry("0123456789"), 4)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["0123456789"], parts);
+    }
+
+    #[test]
+    fn test_truncate_message() {
+        let mut message = "0123456789".to_owned();
+        truncate_message(&mut message, None);
+        assert_eq!("0123456789", message);
+        truncate_message(&mut message, Some(20));
+        assert_eq!("0123456789", message);
+        truncate_message(&mut message, Some(4));
+        assert_eq!("0123", message);
+    }
+
+    #[test]
+    fn test_truncate_message_char_boundaries() {
+        for (max_len, exp_message) in
+            [(0, ""), (1, ""), (2, "ñ"), (3, "ñ"), (4, "ññ"), (10, "ññññ")]
+        {
+            let mut message = "ññññ".to_owned();
+            truncate_message(&mut message, Some(max_len));
+            assert_eq!(exp_message, message, "Unexpected truncation to {} bytes", max_len);
+        }
+        assert_eq!("a", truncate_str("añ", 2));
+        assert_eq!(Some("a".to_owned()), truncate_option_str(Some(Cow::Borrowed("añ")), 2));
+    }
+
+    #[test]
+    fn test_rows_per_statement() {
+        assert_eq!(6553, rows_per_statement(65535, 10));
+        assert_eq!(1, rows_per_statement(65535, 65535));
+        assert_eq!(1, rows_per_statement(5, 10));
+    }
+
+    #[test]
+    fn test_into_chunks() {
+        assert!(into_chunks(Vec::<u8>::new(), 2).is_empty());
+        assert_eq!(vec![vec![1, 2]], into_chunks(vec![1, 2], 2));
+        assert_eq!(vec![vec![1, 2], vec![3, 4], vec![5]], into_chunks(vec![1, 2, 3, 4, 5], 2));
+    }
+
+    #[test]
+    fn test_wrap_sequence_in_range() {
+        assert_eq!(0, wrap_sequence(0));
+        assert_eq!(12345, wrap_sequence(12345));
+        assert_eq!(i64::MAX - 1, wrap_sequence(i64::MAX as u64 - 1));
+        assert_eq!(i64::MAX, wrap_sequence(i64::MAX as u64));
+    }
+
+    #[test]
+    fn test_wrap_sequence_overflow() {
+        assert_eq!(0, wrap_sequence(i64::MAX as u64 + 1));
+        assert_eq!(1, wrap_sequence(i64::MAX as u64 + 2));
+        assert_eq!(i64::MAX, wrap_sequence(u64::MAX));
+        assert_eq!(0, wrap_sequence(u64::MAX.wrapping_add(1)));
+    }
+}
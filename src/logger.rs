@@ -17,21 +17,47 @@
 //!
 //! The code in this module tries to be resilient to errors that it might itself cause.  To that
 //! end, errors are logged and ignored.  And because we cannot rely on the logging facilities to be
-//! functional (and because using them would cause us to recurse), any errors are printed to
-//! `stderr`.
+//! functional (and because using them would cause us to recurse), any errors are handed to the
+//! `internal_error_handler`, which prints them to `stderr` by default.
 
 use crate::clocks::{Clock, SystemClock};
-use crate::{Connection, Db, Result};
+use crate::dedup::Deduplicator;
+use crate::metrics::{Metrics, MetricsRecorder};
+use crate::mirror::{Mirror, MirrorFormat};
+use crate::ulid::UlidGenerator;
+use crate::{
+    describe_entries, export, Connection, Db, DbError, Fields, LogEntryRow, LogFilter, Result,
+    SortOrder, StorageStats, TimestampFormat,
+};
+use futures::{FutureExt, Stream, TryStreamExt};
 use gethostname::gethostname;
-use log::{Level, Log, Metadata, Record};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::any::Any;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
 use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, RecvTimeoutError};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use time::OffsetDateTime;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-/// Maximum number of log calls we can ingest without blocking.
+/// Default maximum number of log calls we can ingest without blocking.
 ///
 /// Ingesting a log entry into the `recorder` is a CPU-bound operation that does not involve any
 /// I/O so a small size should be sufficient.
@@ -40,363 +66,5312 @@ const CHANNEL_SIZE: usize = 128;
 /// Maximum number of log entries to batch in each database write.
 const MAX_BATCH_SIZE: usize = 128;
 
-/// Maximum delay between log flushes.
+/// Default maximum delay between log flushes.
 const MAX_FLUSH_DELAY_SECS: u64 = 5;
 
+/// Maximum time to wait for the entry describing a panic to be persisted.
+///
+/// This is bounded because the panic may have happened in a context that prevents the recorder
+/// from making progress, in which case we must not hang the panicking thread.
+const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default number of times to retry writing a batch of log entries after a transient failure.
+const DEFAULT_MAX_WRITE_RETRIES: usize = 2;
+
+/// Default number of times to retry writing the last batch of log entries when the logger stops.
+const DEFAULT_SHUTDOWN_RETRIES: usize = 10;
+
+/// Default minimum backward jump of the clock between consecutive entries that is reported.
+const DEFAULT_CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Delay between consecutive attempts to write a batch of log entries.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 /// Default log level when `RUST_LOG` is not set.
 const DEFAULT_LOG_LEVEL: Level = Level::Warn;
 
+/// Default hostname to attach to log entries when the real hostname cannot be determined.
+const DEFAULT_HOSTNAME_FALLBACK: &str = "invalid-hostname";
+
 // Maximum sizes of the corresponding fields in the schema.
 // TODO(jmmv): We should not impose the restrictions of one backend (postgres) on others (sqlite).
 pub(crate) const LOG_ENTRY_MAX_HOSTNAME_LENGTH: usize = 64;
 pub(crate) const LOG_ENTRY_MAX_MODULE_LENGTH: usize = 64;
 pub(crate) const LOG_ENTRY_MAX_FILENAME_LENGTH: usize = 256;
+
+/// Maximum size of the messages kept by backends that do not let callers configure it.
+///
+/// Other backends take the limit from their `max_message_length` connection option.
+#[cfg(feature = "stderr")]
 pub(crate) const LOG_ENTRY_MAX_MESSAGE_LENGTH: usize = 4096;
 
-/// Contents of a log entry.
-#[derive(Debug)]
-pub(crate) struct LogEntry {
-    pub(crate) timestamp: OffsetDateTime,
-    pub(crate) hostname: String,
-    pub(crate) level: Level,
-    pub(crate) module: Option<String>,
-    pub(crate) filename: Option<String>,
-    pub(crate) line: Option<u32>,
-    pub(crate) message: String,
-}
+/// Contents of a log entry before it is persisted.
+///
+/// Entries are exposed to the `entry_transforms` of `LoggerOptions`, which can modify any of
+/// these fields.  Strings that exceed the limits of the database are truncated when written.
+///
+/// New fields may be added in future versions, so entries must be created with `LogEntry::new`
+/// and then have their optional fields set as needed.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LogEntry {
+    /// Time at which the entry was emitted.
+    pub timestamp: OffsetDateTime,
 
-#[derive(Debug)]
-/// Types of requests that can be sent to the `recorder` background task.
-enum Action {
-    /// Asks the recorder to stop immediately.
-    Stop,
+    /// Name of the host that emitted the entry, shared among all entries of the same logger.
+    pub hostname: Arc<str>,
 
-    /// Asks the recorder to flush any pending messages and waits for completion.
-    Flush,
+    /// Severity of the entry.
+    pub level: Level,
 
-    /// Asks the recorder to persist the provided log entry.
-    Record(LogEntry),
+    /// Module that emitted the entry, if captured.
+    pub module: Option<Cow<'static, str>>,
+
+    /// Source file that emitted the entry, if captured.
+    pub filename: Option<Cow<'static, str>>,
+
+    /// Line in the source file that emitted the entry, if captured.
+    pub line: Option<u32>,
+
+    /// Formatted message of the entry.
+    pub message: String,
+
+    /// Nanoseconds elapsed since the logger was initialized, if known.
+    pub uptime_nanos: Option<i64>,
+
+    /// Local offset from UTC of the host that emitted the entry, if known.
+    pub utc_offset: Option<UtcOffset>,
+
+    /// Identifier of the distributed trace that was active when the entry was emitted, if known.
+    pub trace_id: Option<String>,
+
+    /// Identifier of the span that was active when the entry was emitted, if known.
+    pub span_id: Option<String>,
+
+    /// ULID generated for the entry when it was emitted, if requested.
+    pub ulid: Option<String>,
+
+    /// Correlation identifier attached by the application to the entry, if any.
+    pub correlation_id: Option<String>,
+
+    /// Binary payload attached to the entry, such as a serialized representation of the event
+    /// that it describes, if any.
+    pub payload: Option<Vec<u8>>,
+
+    /// Structured fields attached to the entry, such as the key-values of the `log` record, if
+    /// any.  Stored in the database through the `FieldCodec` of the backend.
+    pub fields: Option<Fields>,
 }
 
-/// Writes all `entries` to the `db` in a single transaction.
-async fn write_all(db: Arc<dyn Db + Send + Sync + 'static>, entries: Vec<LogEntry>) {
-    if let Err(e) = db.put_log_entries(entries).await {
-        eprintln!("Failed to write log entries: {}", e);
+impl LogEntry {
+    /// Creates a new entry emitted at `timestamp` by `hostname` with the given `level` and
+    /// `message`, leaving all optional fields unset.
+    pub fn new(
+        timestamp: OffsetDateTime,
+        hostname: impl Into<Arc<str>>,
+        level: Level,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            timestamp,
+            hostname: hostname.into(),
+            level,
+            module: None,
+            filename: None,
+            line: None,
+            message: message.into(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+        }
     }
 }
 
-/// Background task that persists log entries to the database.
-///
-/// This task consumes log requests from the `action_rx` channel.  If any of these requests is a
-/// flush or stop, then the requester can wait for completion by waiting on the `done_rx` channel.
-///
-/// Errors that occur here are dumped to stderr as we cannot do anything else about them.
+/// Statistics about the log entries persisted by the recorder, as returned by `Handle::flush`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FlushStats {
+    /// Number of log entries written to the database since the previous flush.
+    pub written: usize,
+}
+
+/// Error returned by `Handle::flush` when some log entries could not be written.
 ///
-/// Any log messages triggered by this routine must be filtered out at the logger level or else we
-/// may enter an infinite loop.
-async fn recorder(
-    db: Arc<dyn Db + Send + Sync + 'static>,
-    action_rx: mpsc::Receiver<Action>,
-    done_tx: mpsc::SyncSender<()>,
-) {
-    let mut buffer = vec![];
-    let mut writers = vec![];
+/// Only the last error is kept, along with a count of the failed batches, so that the size of
+/// the error does not grow with the number of failed writes.
+#[derive(Clone, Debug)]
+pub struct FlushError {
+    /// Number of batches of log entries that could not be written since the previous flush.
+    pub failed_batches: usize,
 
-    let timeout = Duration::new(MAX_FLUSH_DELAY_SECS, 0);
-    loop {
-        let auto_flush;
-        let action = match action_rx.recv_timeout(timeout) {
-            Ok(action) => {
-                auto_flush = false;
-                action
-            }
-            Err(RecvTimeoutError::Timeout) => {
-                auto_flush = true;
-                Action::Flush
-            }
-            Err(RecvTimeoutError::Disconnected) => {
-                eprintln!("Failed to get log entry due to closed channel; terminating logger");
-                break;
-            }
-        };
+    /// Number of log entries written to the database since the previous flush.
+    pub written: usize,
 
-        match action {
-            Action::Stop => break,
+    /// Error that caused the last failed batch to be discarded.
+    pub last_error: Arc<DbError>,
+}
 
-            Action::Flush => {
-                if !buffer.is_empty() {
-                    let batch = buffer.split_off(0);
-                    let db = db.clone();
-                    writers.push(tokio::spawn(async move { write_all(db, batch).await }));
-                }
-                assert!(buffer.is_empty());
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to write {} batches of log entries ({} entries written); last error: {}",
+            self.failed_batches, self.written, self.last_error
+        )
+    }
+}
 
-                for writer in writers.split_off(0) {
-                    if let Err(e) = writer.await {
-                        eprintln!("Failed to write batched entries: {}", e);
-                    }
-                }
-                assert!(writers.is_empty());
+impl std::error::Error for FlushError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.last_error.as_ref())
+    }
+}
 
-                if !auto_flush {
-                    done_tx.send(()).unwrap();
-                }
-            }
+/// Result of a flush as reported by the `recorder` background task.
+pub(crate) type FlushResult = std::result::Result<FlushStats, FlushError>;
 
-            Action::Record(entry) => {
-                buffer.push(entry);
-
-                if buffer.len() == MAX_BATCH_SIZE {
-                    let batch = buffer.split_off(0);
-                    let db = db.clone();
-                    // TODO(jmmv): Should probably have some protection here and above to prevent
-                    // the number of writers from growing unboundedly.
-                    writers.push(tokio::spawn(async move { write_all(db, batch).await }));
-                    assert!(buffer.is_empty());
+#[derive(Debug)]
+/// Types of requests that can be sent to the `recorder` background task.
+pub(crate) enum Action {
+    /// Asks the recorder to persist any pending messages and to stop.  The recorder acknowledges
+    /// completion via the given channel.
+    Stop(mpsc::SyncSender<()>),
+
+    /// Asks the recorder to flush any pending messages.  The recorder acknowledges completion via
+    /// the given channel, reporting how many entries were written since the previous flush and
+    /// any errors that prevented others from being written.
+    Flush(mpsc::SyncSender<FlushResult>),
+
+    /// Asks the recorder to flush any pending messages and to acknowledge completion via the
+    /// given channel once no requests are queued and no writes are in flight, reporting the same
+    /// results as `Flush`.
+    Quiesce(mpsc::SyncSender<FlushResult>),
+
+    /// Asks the recorder to persist the provided log entry.
+    Record(Box<LogEntry>),
+}
+
+/// Capacity of the channel through which log entries reach the `recorder` background task.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChannelKind {
+    /// The channel holds up to the given number of pending entries and logging blocks the caller
+    /// when it is full, until the recorder catches up.
+    Bounded(usize),
+
+    /// The channel accepts any number of pending entries so logging never blocks the caller.
+    ///
+    /// This absorbs bursts of entries but trades off memory: entries that sit in the channel are
+    /// not subject to `max_buffered_entries` nor `max_batch_bytes`, which only bound the entries
+    /// that the recorder has already received, so memory usage can grow without limits if entries
+    /// are produced faster than the recorder can ingest them.
+    Unbounded,
+}
+
+impl Default for ChannelKind {
+    fn default() -> Self {
+        ChannelKind::Bounded(CHANNEL_SIZE)
+    }
+}
+
+/// Sender of a single channel to the `recorder` background task.
+#[derive(Clone)]
+enum ChannelSender {
+    /// Sender of a channel created for `ChannelKind::Bounded`.
+    Bounded(tokio::sync::mpsc::Sender<Action>),
+
+    /// Sender of a channel created for `ChannelKind::Unbounded`.
+    Unbounded(tokio::sync::mpsc::UnboundedSender<Action>),
+}
+
+impl ChannelSender {
+    /// Sends `action` to the recorder, blocking if the channel is bounded and full.
+    fn send(&self, action: Action) -> std::result::Result<(), mpsc::SendError<Action>> {
+        match self {
+            ChannelSender::Bounded(tx) => match tx.try_send(action) {
+                Ok(()) => Ok(()),
+                // `blocking_send` panics when called from within a runtime, which is where most
+                // log calls come from, so wait for room in the channel without going through it.
+                Err(TrySendError::Full(action)) => {
+                    futures::executor::block_on(tx.send(action)).map_err(|e| mpsc::SendError(e.0))
                 }
-            }
+                Err(TrySendError::Closed(action)) => Err(mpsc::SendError(action)),
+            },
+            ChannelSender::Unbounded(tx) => tx.send(action).map_err(|e| mpsc::SendError(e.0)),
         }
     }
-
-    drop(db);
-    done_tx.send(()).unwrap();
 }
 
-/// Returns true if `record` was potentially emitted by the code in `recorder`, which would cause us
-/// to enter an infinite loop if not filtered out.
-fn is_recorder_log(record: &Record) -> bool {
-    // TODO(jmmv): Instead of blacklisting these modules, we should try to use tokio::task_local
-    // to avoid log statements triggered by us.
-    let module = match record.module_path() {
-        Some(module) => module,
-        None => return true,
-    };
-    (module.starts_with("rustls::") || module.starts_with("sqlx::"))
-        || (record.level() >= Level::Trace
-            && (module.starts_with("async_io::")
-                || module.starts_with("async_std::")
-                || module.starts_with("polling")))
+/// Receiver of a single channel to the `recorder` background task.
+pub(crate) enum ChannelReceiver {
+    /// Receiver of a channel created for `ChannelKind::Bounded`.
+    Bounded(tokio::sync::mpsc::Receiver<Action>),
+
+    /// Receiver of a channel created for `ChannelKind::Unbounded`.
+    Unbounded(tokio::sync::mpsc::UnboundedReceiver<Action>),
 }
 
-/// Fetches the value of `RUST_LOG` or returns a default value if not available.
-fn env_rust_log() -> Level {
-    match env::var("RUST_LOG") {
-        Ok(level) => match Level::from_str(&level) {
-            Ok(level) => level,
-            Err(e) => {
-                eprintln!("Invalid RUST_LOG value: {}", e);
-                DEFAULT_LOG_LEVEL
-            }
-        },
-        Err(env::VarError::NotPresent) => DEFAULT_LOG_LEVEL,
-        Err(e) => {
-            eprintln!("Invalid RUST_LOG value: {}", e);
-            DEFAULT_LOG_LEVEL
+impl ChannelReceiver {
+    /// Waits for the next action, or returns `None` if all senders are gone.
+    async fn recv(&mut self) -> Option<Action> {
+        match self {
+            ChannelReceiver::Bounded(rx) => rx.recv().await,
+            ChannelReceiver::Unbounded(rx) => rx.recv().await,
+        }
+    }
+
+    /// Returns the next action if one is already queued.
+    pub(crate) fn try_recv(&mut self) -> std::result::Result<Action, TryRecvError> {
+        match self {
+            ChannelReceiver::Bounded(rx) => rx.try_recv(),
+            ChannelReceiver::Unbounded(rx) => rx.try_recv(),
         }
     }
 }
 
-/// An opaque handler to maintain the logger's backing task alive.
+/// Function to start a new recorder and to return the sender of the channel that feeds it.
+type RecorderSpawner = Arc<dyn Fn() -> ChannelSender + Send + Sync + 'static>;
+
+/// Sending side of the channel through which requests reach the `recorder` background task.
 ///
-/// Once this object goes out of scope, the logger's database persisting logic stops and attempts
-/// to log may fail or get stuck.
-// TODO(jmmv): Modify integration tests to check what happens and possibly refactor this to *not*
-// expose this type at all.
-pub struct Handle {
-    db: Connection,
-    action_tx: mpsc::SyncSender<Action>,
-    done_rx: Arc<Mutex<mpsc::Receiver<()>>>,
+/// All clones share the same channel.  If the sender can restart the recorder and the recorder
+/// dies without having been asked to stop, the channel is replaced by one that feeds a new recorder
+/// the first time a send fails, and the send is retried on it.
+#[derive(Clone)]
+pub(crate) struct ActionSender {
+    /// Current channel to the recorder along with the number of times it has been replaced.
+    channel: Arc<RwLock<(u64, ChannelSender)>>,
+
+    /// Function to start a new recorder if the current one dies, if any.
+    respawn: Option<RecorderSpawner>,
+
+    /// Whether the recorder has been asked to stop, in which case it must not be restarted.
+    stopped: Arc<AtomicBool>,
 }
 
-impl Handle {
-    /// Returns the sorted list of all log entries in the database.
-    ///
-    /// Given that this is exposed for testing purposes only, this just returns a flat textual
-    /// representation of the log entry and does not try to deserialize it as a `LogEntry`.  This
-    /// is for simplicity given that a `LogEntry` keeps references to static strings and we cannot
-    /// obtain those from the database.
-    pub async fn get_log_entries(&self) -> Result<Vec<String>> {
-        self.db.0.get_log_entries().await
+impl ActionSender {
+    /// Creates a new sender that sends requests via `tx` and that uses `respawn`, if any, to
+    /// restart the recorder.
+    fn new(tx: ChannelSender, respawn: Option<RecorderSpawner>) -> Self {
+        Self {
+            channel: Arc::from(RwLock::from((0, tx))),
+            respawn,
+            stopped: Arc::from(AtomicBool::new(false)),
+        }
     }
 
-    /// Flushes pending records to the backend DB
-    pub fn flush(&self) {
-        let done_rx = self.done_rx.lock().unwrap();
-        self.action_tx.send(Action::Flush).unwrap();
-        done_rx.recv().unwrap();
+    /// Sends `action` to the recorder, blocking if the channel is bounded and full.
+    pub(crate) fn send(&self, action: Action) -> std::result::Result<(), mpsc::SendError<Action>> {
+        if let Action::Stop(_) = action {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        let (generation, tx) = self.channel.read().unwrap().clone();
+        let action = match tx.send(action) {
+            Ok(()) => return Ok(()),
+            Err(e) => e.0,
+        };
+
+        let respawn = match &self.respawn {
+            Some(respawn) if !self.stopped.load(Ordering::SeqCst) => respawn,
+            _ => return Err(mpsc::SendError(action)),
+        };
+        let tx = {
+            let mut channel = self.channel.write().unwrap();
+            // Another sender may have restarted the recorder while we waited for the lock.
+            if channel.0 == generation {
+                *channel = (generation.wrapping_add(1), respawn());
+            }
+            channel.1.clone()
+        };
+        tx.send(action)
     }
 }
 
-impl Drop for Handle {
-    fn drop(&mut self) {
-        let done_rx = self.done_rx.lock().unwrap();
-        self.action_tx.send(Action::Flush).unwrap();
-        done_rx.recv().unwrap();
-        self.action_tx.send(Action::Stop).unwrap();
-        done_rx.recv().unwrap();
+/// Creates a new channel of the given `kind` to send requests to a recorder.
+fn channel_of_kind(kind: ChannelKind) -> (ChannelSender, ChannelReceiver) {
+    match kind {
+        ChannelKind::Bounded(size) => {
+            let (tx, rx) = tokio::sync::mpsc::channel(size);
+            (ChannelSender::Bounded(tx), ChannelReceiver::Bounded(rx))
+        }
+        ChannelKind::Unbounded => {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            (ChannelSender::Unbounded(tx), ChannelReceiver::Unbounded(rx))
+        }
     }
 }
 
-/// Implementation of a database-backed logger.
+/// Creates a new channel of the given `kind` to send requests to the `recorder`.
 ///
-/// There should only be one instance of this object, which is persisted in a global `Box` owned by
-/// the `log` crate.  As a result, this object gets never dropped.
-struct DbLogger {
-    hostname: String,
-    action_tx: mpsc::SyncSender<Action>,
-    done_rx: Arc<Mutex<mpsc::Receiver<()>>>,
-    clock: Arc<dyn Clock + Send + Sync + 'static>,
+/// The returned sender does not restart the recorder if it dies.
+#[cfg(all(test, any(feature = "sqlite", feature = "tracing")))]
+pub(crate) fn action_channel(kind: ChannelKind) -> (ActionSender, ChannelReceiver) {
+    let (tx, rx) = channel_of_kind(kind);
+    (ActionSender::new(tx, None), rx)
 }
 
-impl DbLogger {
-    /// Creates a new logger backed by `db` that obtains timestamps from `clock` and that sets the
-    /// hostname of the entries to `hostname`.
-    async fn new(
-        hostname: String,
-        db: Connection,
-        clock: Arc<dyn Clock + Send + Sync + 'static>,
-    ) -> Self {
-        let (action_tx, action_rx) = mpsc::sync_channel(CHANNEL_SIZE);
-        let (done_tx, done_rx) = mpsc::sync_channel(1);
+/// Reasons why writing a batch of log entries can fail.
+#[derive(Debug)]
+enum WriteError {
+    /// The database rejected the write.
+    Db(DbError),
 
-        tokio::spawn(async move {
-            recorder(db.0, action_rx, done_tx).await;
-        });
+    /// The write panicked with the given message.
+    Panic(String),
+}
 
-        let done_rx = Arc::from(Mutex::from(done_rx));
-        Self { hostname, action_tx, done_rx, clock }
+impl WriteError {
+    /// Returns true if writing the same batch again may succeed.
+    ///
+    /// Connection problems are usually transient and panics may be caused by transient conditions
+    /// too, but any other database error would just happen again.
+    fn is_retriable(&self) -> bool {
+        match self {
+            WriteError::Db(DbError::Connection(..)) => true,
+            WriteError::Db(_) => false,
+            WriteError::Panic(_) => true,
+        }
     }
 }
 
-impl Log for DbLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+impl From<WriteError> for DbError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::Db(e) => e,
+            WriteError::Panic(_) => DbError::Other(e.to_string(), None),
+        }
     }
+}
 
-    fn log(&self, record: &Record) {
-        if !self.enabled(record.metadata()) {
-            return;
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Db(e) => write!(f, "{}", e),
+            WriteError::Panic(message) => write!(f, "write panicked: {}", message),
         }
+    }
+}
 
-        let now = self.clock.now_utc();
+/// Writes all `entries`, as returned by `Db::prepare_log_entries` for the original `persisted`
+/// entries, to the `db` in a single transaction and, if it succeeds, records how long the write
+/// took in `metrics` and passes the original entries to `on_persist`.
+///
+/// `persisted` only needs to be provided if `on_persist` is set.  `nentries` is the number of
+/// original entries.
+///
+/// Panics raised by the database code or by `on_persist` are caught so that they cannot take down
+/// the recorder.  Only the former are returned as errors because the entries are persisted by the
+/// time `on_persist` runs: the latter are reported to `on_error`.
+async fn write_all(
+    db: &Arc<dyn Db + Send + Sync + 'static>,
+    entries: Vec<(u32, LogEntry)>,
+    nentries: usize,
+    persisted: Option<&[LogEntry]>,
+    metrics: &MetricsRecorder,
+    on_persist: Option<&PersistCallback>,
+    on_error: &InternalErrorHandler,
+) -> std::result::Result<(), WriteError> {
+    let start = Instant::now();
+    match AssertUnwindSafe(db.put_prepared_log_entries(entries)).catch_unwind().await {
+        Ok(Ok(())) => {
+            metrics.record(nentries, start.elapsed());
+            if let (Some(on_persist), Some(persisted)) = (on_persist, persisted) {
+                if let Err(payload) =
+                    panic::catch_unwind(AssertUnwindSafe(|| on_persist(persisted)))
+                {
+                    report(
+                        on_error,
+                        InternalErrorKind::Callback,
+                        format!("on_persist callback panicked: {}", panic_message(&*payload)),
+                    );
+                }
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => Err(WriteError::Db(e)),
+        Err(payload) => Err(WriteError::Panic(panic_message(&*payload))),
+    }
+}
 
-        // Skip logs emitted by the database-persistence code as they would cause us to recurse and
-        // never finish logging.
-        if is_recorder_log(record) {
-            if record.level() <= Level::Warn {
-                eprintln!(
-                    "Non-persisted log entry: {:?} {} {:?} {:?}:{:?} {}",
-                    now,
-                    record.level(),
-                    record.module_path_static(),
-                    record.file_static(),
-                    record.line(),
-                    record.args(),
+/// Writes `batch` to the `db`, retrying up to `max_retries` times if the failures are transient,
+/// and records the successful write in `metrics` and via `on_persist`.
+///
+/// The handling of oversized messages of the `db` is applied once to the `batch` before the first
+/// attempt.  Errors are reported to `on_error` and the batch is discarded once it cannot be
+/// retried any longer, in which case the last error is returned.  The message for a discarded
+/// batch describes the lost entries.
+async fn write_with_retries(
+    db: &Arc<dyn Db + Send + Sync + 'static>,
+    batch: Vec<LogEntry>,
+    max_retries: usize,
+    metrics: &MetricsRecorder,
+    on_persist: Option<&PersistCallback>,
+    on_error: &InternalErrorHandler,
+) -> std::result::Result<(), WriteError> {
+    let nentries = batch.len();
+    // The database consumes the entries so keep a copy only if someone wants to see them.
+    let persisted = on_persist.map(|_| batch.clone());
+    let mut batch = db.prepare_log_entries(batch);
+
+    let mut attempt = 0;
+    loop {
+        // Keep a copy of the batch while we may still need it for a retry.  Once we give it away
+        // for the last time, keep its description instead in case its entries are lost.
+        let (entries, description) = if attempt < max_retries {
+            (batch.clone(), None)
+        } else {
+            let description = describe_entries(batch.iter().map(|(_part, entry)| entry));
+            (mem::take(&mut batch), Some(description))
+        };
+        let result =
+            write_all(db, entries, nentries, persisted.as_deref(), metrics, on_persist, on_error)
+                .await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_retriable() && attempt < max_retries => {
+                attempt += 1;
+                report(
+                    on_error,
+                    InternalErrorKind::Write,
+                    format!(
+                        "Failed to write log entries (attempt {} of {}): {}",
+                        attempt,
+                        max_retries + 1,
+                        e
+                    ),
                 );
+                tokio::time::sleep(WRITE_RETRY_DELAY).await;
+            }
+            Err(e) => {
+                let description = match description {
+                    Some(description) => description,
+                    // Non-retriable errors can happen before the last attempt.
+                    None => describe_entries(batch.iter().map(|(_part, entry)| entry)),
+                };
+                report(
+                    on_error,
+                    InternalErrorKind::Write,
+                    format!("Failed to write log entries: {}; lost {}", e, description),
+                );
+                return Err(e);
             }
-            return;
         }
-        let entry = LogEntry {
-            timestamp: now,
-            hostname: self.hostname.clone(),
-            level: record.level(),
-            module: Some(record.module_path().unwrap_or("").to_owned()),
-            filename: Some(record.file().unwrap_or("").to_owned()),
-            line: record.line(),
-            message: format!("{}", record.args()),
-        };
-        self.action_tx.send(Action::Record(entry)).unwrap();
     }
+}
 
-    fn flush(&self) {
-        let done_rx = self.done_rx.lock().unwrap();
-        self.action_tx.send(Action::Flush).unwrap();
-        done_rx.recv().unwrap();
+/// Writer tasks spawned by the recorder that have not been waited for yet.
+struct Writers {
+    /// Handles of the writer tasks in the order in which they were spawned.
+    handles: Vec<JoinHandle<WriteResult>>,
+
+    /// Whether every writer waits for the previous one to complete before writing its batch.
+    ordered: bool,
+
+    /// Notification of the completion of the most recently spawned writer, if `ordered`.
+    last_done: Option<oneshot::Receiver<()>>,
+
+    /// Function to invoke with every batch that the writers persist successfully.
+    on_persist: Option<PersistCallback>,
+
+    /// Function to report the errors that the writers encounter.
+    on_error: InternalErrorHandler,
+}
+
+impl Writers {
+    /// Creates an empty set of writers that, if `ordered`, commit their batches one at a time,
+    /// that pass the batches they persist to `on_persist`, and that report errors to `on_error`.
+    fn new(
+        ordered: bool,
+        on_persist: Option<PersistCallback>,
+        on_error: InternalErrorHandler,
+    ) -> Self {
+        Self { handles: vec![], ordered, last_done: None, on_persist, on_error }
     }
 }
 
-/// Configures the global logger to use a new instance backed by the database connection `db`.
+/// Spawns a new task to write `batch` to the `db` and tracks it in `writers`.
 ///
-/// Logger configuration happens via environment variables and tries to respect the same
-/// variables that `env_logger` recognizes.  Misconfigured variables result in a fatal error.
-pub async fn init(db: Connection) -> Handle {
-    let max_level = env_rust_log();
+/// If `writers` are ordered, the new task waits for the previous one to complete, successfully or
+/// not, so that batches are committed in the order in which they were spawned.
+///
+/// `in_flight` is increased by the number of entries in `batch` until the write completes, which
+/// includes any retries allowed by `max_retries`.  The duration of the write is recorded in
+/// `metrics`.
+fn spawn_writer(
+    db: &Arc<dyn Db + Send + Sync + 'static>,
+    batch: Vec<LogEntry>,
+    writers: &mut Writers,
+    in_flight: &Arc<AtomicUsize>,
+    metrics: &Arc<MetricsRecorder>,
+    max_retries: usize,
+) {
+    let db = db.clone();
+    let in_flight = in_flight.clone();
+    let metrics = metrics.clone();
+    let on_persist = writers.on_persist.clone();
+    let on_error = writers.on_error.clone();
+    let nentries = batch.len();
+    in_flight.fetch_add(nentries, Ordering::SeqCst);
 
-    let hostname =
-        gethostname().into_string().unwrap_or_else(|_e| String::from("invalid-hostname"));
+    let previous = writers.last_done.take();
+    let done_tx = if writers.ordered {
+        let (done_tx, done_rx) = oneshot::channel();
+        writers.last_done = Some(done_rx);
+        Some(done_tx)
+    } else {
+        None
+    };
+
+    writers.handles.push(tokio::spawn(async move {
+        if let Some(previous) = previous {
+            // An error means that the previous writer is gone, which is as good as done.
+            let _ = previous.await;
+        }
+        let result =
+            write_with_retries(&db, batch, max_retries, &metrics, on_persist.as_ref(), &on_error)
+                .await;
+        in_flight.fetch_sub(nentries, Ordering::SeqCst);
+        if let Some(done_tx) = done_tx {
+            // An error means that no writer is waiting for this one.
+            let _ = done_tx.send(());
+        }
+        result.map(|()| nentries)
+    }));
+}
 
-    let logger = DbLogger::new(hostname, db.clone(), Arc::from(SystemClock::default())).await;
-    let handle =
-        Handle { db, action_tx: logger.action_tx.clone(), done_rx: logger.done_rx.clone() };
+/// Persists all entries in `buffer` to the `db` and waits for all pending `writers` to complete,
+/// accumulating their results into `outcome`.
+///
+/// Failed writes are retried up to `max_retries` times and successful ones are recorded in
+/// `metrics`.
+async fn drain(
+    db: &Arc<dyn Db + Send + Sync + 'static>,
+    buffer: &mut VecDeque<LogEntry>,
+    outcome: &mut WriteOutcome,
+    writers: &mut Writers,
+    in_flight: &Arc<AtomicUsize>,
+    metrics: &Arc<MetricsRecorder>,
+    max_retries: usize,
+) {
+    if !buffer.is_empty() {
+        let batch = Vec::from(mem::take(buffer));
+        spawn_writer(db, batch, writers, in_flight, metrics, max_retries);
+    }
+    assert!(buffer.is_empty());
 
-    log::set_boxed_logger(Box::from(logger)).expect("Logger should not have been set up yet");
-    log::set_max_level(max_level.to_level_filter());
-    handle
+    for writer in writers.handles.split_off(0) {
+        match writer.await {
+            Ok(Ok(nentries)) => outcome.written += nentries,
+            // The writer already reported this error.
+            Ok(Err(e)) => outcome.fail(e.into()),
+            Err(e) => {
+                report(
+                    &writers.on_error,
+                    InternalErrorKind::Write,
+                    format!("Failed to write batched entries: {}", e),
+                );
+                outcome.fail(DbError::Other(e.to_string(), None));
+            }
+        }
+    }
+    assert!(writers.handles.is_empty());
+    writers.last_done = None;
 }
 
-#[cfg(test)]
-#[cfg(feature = "sqlite")]
-mod tests {
-    //! Unit-tests for the database-backed logger.
-    //!
-    //! These tests do not verify the interaction with the `log` facade because it's impossible to
-    //! do so in this context: the logger is a global resource and we cannot run independent tests
-    //! for it.  See the separate `logger_test.rs` program for integration tests against the real
-    //! test database.
+/// Result of a writer task: the number of entries written or the reason why they were not.
+type WriteResult = std::result::Result<usize, WriteError>;
 
-    use super::*;
-    use crate::clocks::MonotonicClock;
-    use crate::sqlite;
-    use log::RecordBuilder;
+/// Results of the writes completed since the last flush requested via `Action::Flush`.
+#[derive(Default)]
+struct WriteOutcome {
+    /// Number of entries written successfully.
+    written: usize,
 
-    /// Sets up the logger backing it with an in-memory database and a fake clock.
-    async fn setup() -> (DbLogger, Connection) {
-        let db = sqlite::connect(sqlite::ConnectionOptions { uri: ":memory:".to_owned() })
-            .await
-            .unwrap();
-        db.create_schema().await.unwrap();
-        let clock = Arc::from(MonotonicClock::new(1000));
-        (DbLogger::new("fake-hostname".to_owned(), db.clone(), clock).await, db)
+    /// Number of batches that could not be written.
+    failed_batches: usize,
+
+    /// Error that caused the last failed batch to be discarded.
+    last_error: Option<DbError>,
+}
+
+impl WriteOutcome {
+    /// Records that a batch was discarded because of `error`.
+    fn fail(&mut self, error: DbError) {
+        self.failed_batches += 1;
+        self.last_error = Some(error);
     }
 
-    /// Emits one single log entry at every possible level.
-    fn emit_all_log_levels(logger: &dyn Log) {
-        for (level, message) in &[
-            (Level::Error, "An error message"),
-            (Level::Warn, "A warning message"),
-            (Level::Info, "An info message"),
-            (Level::Debug, "A debug message"),
-            (Level::Trace, "A trace message"),
-        ] {
-            logger.log(
-                &RecordBuilder::new()
-                    .level(*level)
-                    .module_path_static(Some("the-module"))
-                    .file_static(Some("the-file"))
-                    .line(Some(123))
-                    .args(format_args!("{}", message))
-                    .build(),
-            );
+    /// Converts the accumulated results into the result of a flush and resets them.
+    fn take(&mut self) -> FlushResult {
+        let outcome = mem::take(self);
+        match outcome.last_error {
+            None => Ok(FlushStats { written: outcome.written }),
+            Some(last_error) => Err(FlushError {
+                failed_batches: outcome.failed_batches,
+                written: outcome.written,
+                last_error: Arc::new(last_error),
+            }),
         }
     }
+}
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn test_all_log_levels() {
-        let (logger, db) = setup().await;
+/// Appends `entry` in newline-delimited JSON format to the spill file at `path`, which is opened
+/// into `file` unless it is already open.
+fn spill(file: &mut Option<io::BufWriter<File>>, path: &Path, entry: &LogEntry) -> io::Result<()> {
+    let line = export::format_entry_ndjson(entry, None)?;
+    let file = match file {
+        Some(file) => file,
+        None => {
+            let opened = OpenOptions::new().create(true).append(true).open(path)?;
+            file.insert(io::BufWriter::new(opened))
+        }
+    };
+    writeln!(file, "{}", line)
+}
 
-        emit_all_log_levels(&logger);
+/// Applies the overflow policy to the entries that do not fit in memory.
+///
+/// The spill file, if any, is opened on first use and kept open, and writes to it are buffered
+/// until `flush` is called so that overflowing entries do not cost a system call each.
+struct Overflow {
+    /// Policy to apply to the entries.
+    policy: OverflowPolicy,
 
-        logger.flush();
-        let entries = db.0.get_log_entries().await.unwrap();
-        assert_eq!(
-            vec![
-                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
-                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
-                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+    /// Open handle to the spill file, or `None` if not opened yet or if it failed.
+    spill: Option<io::BufWriter<File>>,
+
+    /// Counter of entries that were lost.
+    dropped: Arc<AtomicU64>,
+
+    /// Handler for the failures to apply the policy.
+    on_error: InternalErrorHandler,
+}
+
+impl Overflow {
+    /// Creates a new handler of overflowing entries that applies `policy`, that counts the lost
+    /// entries in `dropped`, and that reports failures to `on_error`.
+    fn new(
+        policy: OverflowPolicy,
+        dropped: Arc<AtomicU64>,
+        on_error: InternalErrorHandler,
+    ) -> Self {
+        Self { policy, spill: None, dropped, on_error }
+    }
+
+    /// Gets rid of an `entry` that does not fit in memory according to the policy.
+    fn handle(&mut self, entry: LogEntry) {
+        if let OverflowPolicy::SpillToFile(path) = &self.policy {
+            match spill(&mut self.spill, path, &entry) {
+                Ok(()) => return,
+                Err(e) => {
+                    // Reopen the file on the next attempt in case the problem was with the handle.
+                    self.spill = None;
+                    report(
+                        &self.on_error,
+                        InternalErrorKind::Overflow,
+                        format!("Failed to spill log entry to {}: {}", path.display(), e),
+                    );
+                }
+            }
+        }
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Writes the buffered entries to the spill file, if any.
+    fn flush(&mut self) {
+        let file = match self.spill.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        if let Err(e) = file.flush() {
+            self.spill = None;
+            if let OverflowPolicy::SpillToFile(path) = &self.policy {
+                report(
+                    &self.on_error,
+                    InternalErrorKind::Overflow,
+                    format!("Failed to spill log entries to {}: {}", path.display(), e),
+                );
+            }
+        }
+    }
+}
+
+/// Configuration of the `recorder` background task.
+#[derive(Clone)]
+struct RecorderOptions {
+    /// Maximum time that an entry can wait in the buffer before being written, or `None` to only
+    /// flush full batches and on explicit request.
+    flush_delay: Option<Duration>,
+
+    /// Maximum number of entries that can be buffered or being written at any given time.
+    max_buffered_entries: Option<usize>,
+
+    /// What to do with the entries that exceed `max_buffered_entries`.
+    overflow_policy: OverflowPolicy,
+
+    /// Size of the messages in a batch above which the batch is written even if it is not full.
+    max_batch_bytes: Option<usize>,
+
+    /// Number of error entries in a batch at which the batch is written even if it is not full.
+    flush_on_errors: Option<usize>,
+
+    /// Destination to which to copy the entries as they are received.
+    mirror: Mirror,
+
+    /// Format of the entries copied to `mirror`.
+    mirror_format: MirrorFormat,
+
+    /// Number of times to retry writing a batch after a transient failure.
+    max_write_retries: usize,
+
+    /// Whether to commit the batches one at a time in the order in which they were filled.
+    ordered_writes: bool,
+
+    /// Number of times to retry writing the last batch after a transient failure when stopping.
+    shutdown_retries: usize,
+
+    /// Maximum time to wait for the pending writes when stopping, or `None` to wait for all of
+    /// them to complete.
+    shutdown_timeout: Option<Duration>,
+
+    /// Functions to apply, in order, to every entry as it is received.
+    entry_transforms: Vec<EntryTransform>,
+
+    /// Function to invoke with every batch of entries after it is persisted.
+    on_persist: Option<PersistCallback>,
+
+    /// Function to report the problems that the recorder and its writers encounter.
+    internal_error_handler: InternalErrorHandler,
+
+    /// Capacity of the channel through which entries reach the recorder.
+    channel: ChannelKind,
+
+    /// Whether to start a new recorder if the current one dies unexpectedly.
+    restart_recorder: bool,
+
+    /// Whether to run the recorder and its writers on a runtime owned by the logger instead of on
+    /// the runtime that creates the logger.
+    dedicated_runtime: bool,
+}
+
+impl Default for RecorderOptions {
+    fn default() -> Self {
+        Self {
+            flush_delay: Some(Duration::from_secs(MAX_FLUSH_DELAY_SECS)),
+            max_buffered_entries: None,
+            overflow_policy: OverflowPolicy::default(),
+            max_batch_bytes: None,
+            flush_on_errors: None,
+            mirror: Mirror::default(),
+            mirror_format: MirrorFormat::default(),
+            max_write_retries: DEFAULT_MAX_WRITE_RETRIES,
+            ordered_writes: false,
+            shutdown_retries: DEFAULT_SHUTDOWN_RETRIES,
+            shutdown_timeout: None,
+            entry_transforms: vec![],
+            on_persist: None,
+            internal_error_handler: stderr_error_handler(),
+            channel: ChannelKind::default(),
+            restart_recorder: true,
+            dedicated_runtime: false,
+        }
+    }
+}
+
+/// Takes the flush requests at the front of `action_rx` and appends their channels to `waiters`.
+///
+/// These requests can be acknowledged by the flush that just completed because no entries were
+/// recorded after it and before them.  Returns the first action that is not a flush request, if
+/// any, so that the caller can process it next.
+fn take_queued_flushes(
+    action_rx: &mut ChannelReceiver,
+    waiters: &mut Vec<mpsc::SyncSender<FlushResult>>,
+) -> Option<Action> {
+    loop {
+        match action_rx.try_recv() {
+            Ok(Action::Flush(done_tx)) => waiters.push(done_tx),
+            Ok(action) => return Some(action),
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Sends `result` to all requesters in `waiters`, which are emptied, reporting any requesters that
+/// are gone to `on_error`.
+fn acknowledge_quiesce(
+    waiters: &mut Vec<mpsc::SyncSender<FlushResult>>,
+    result: FlushResult,
+    on_error: &InternalErrorHandler,
+) {
+    for done_tx in waiters.drain(..) {
+        if done_tx.send(result.clone()).is_err() {
+            report(
+                on_error,
+                InternalErrorKind::Channel,
+                "Failed to acknowledge quiesce; requester is gone".to_owned(),
+            );
+        }
+    }
+}
+
+/// Waits for the next action in `action_rx` for up to `timeout`, or indefinitely if `None`.
+async fn wait_for_action(
+    action_rx: &mut ChannelReceiver,
+    timeout: Option<Duration>,
+) -> std::result::Result<Action, RecvTimeoutError> {
+    let action = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, action_rx.recv()).await {
+            Ok(action) => action,
+            Err(_) => return Err(RecvTimeoutError::Timeout),
+        },
+        None => action_rx.recv().await,
+    };
+    action.ok_or(RecvTimeoutError::Disconnected)
+}
+
+/// Background task that persists log entries to the database.
+///
+/// This task consumes log requests from the `action_rx` channel.  Flush and stop requests carry
+/// their own single-use channel on which the requester waits for completion, so concurrent
+/// requesters cannot observe each other's acknowledgements.  Flush requests that queue up while
+/// a flush is in progress are acknowledged together once it completes.  Quiesce requests are only
+/// acknowledged once the channel is empty after a flush, so entries that keep arriving delay them.
+///
+/// Errors that occur here are reported to the internal error handler in `opts` as we cannot do
+/// anything else about them.
+///
+/// The number of entries kept in memory is bounded by `opts`.  Entries that do not fit are handed
+/// to the configured overflow policy and, if lost, are accounted for in `dropped`.  The writes of
+/// the batches are measured in `metrics`.
+///
+/// Any log messages triggered by this routine must be filtered out at the logger level or else we
+/// may enter an infinite loop.
+async fn recorder(
+    db: Arc<dyn Db + Send + Sync + 'static>,
+    mut action_rx: ChannelReceiver,
+    opts: RecorderOptions,
+    dropped: Arc<AtomicU64>,
+    metrics: Arc<MetricsRecorder>,
+) {
+    let mut buffer = VecDeque::new();
+    let mut overflow =
+        Overflow::new(opts.overflow_policy.clone(), dropped, opts.internal_error_handler.clone());
+    let mut buffer_bytes = 0;
+    let mut buffer_errors = 0;
+    let mut deadline: Option<Instant> = None;
+    let mut writers = Writers::new(
+        opts.ordered_writes,
+        opts.on_persist.clone(),
+        opts.internal_error_handler.clone(),
+    );
+    let in_flight = Arc::from(AtomicUsize::new(0));
+    let mut outcome = WriteOutcome::default();
+    let mut pending = None;
+    let mut quiescers = vec![];
+
+    let done_tx = loop {
+        if pending.is_none() && !quiescers.is_empty() {
+            // Only flush once all queued requests have been processed, and only consider the
+            // recorder idle if no new requests arrived while the writes were in flight.
+            pending = action_rx.try_recv().ok();
+            if pending.is_none() {
+                drain(
+                    &db,
+                    &mut buffer,
+                    &mut outcome,
+                    &mut writers,
+                    &in_flight,
+                    &metrics,
+                    opts.max_write_retries,
+                )
+                .await;
+                overflow.flush();
+                buffer_bytes = 0;
+                buffer_errors = 0;
+                deadline = None;
+
+                pending = action_rx.try_recv().ok();
+                if pending.is_none() {
+                    acknowledge_quiesce(
+                        &mut quiescers,
+                        outcome.take(),
+                        &opts.internal_error_handler,
+                    );
+                }
+            }
+            continue;
+        }
+
+        // The flush delay counts from the arrival of the oldest buffered entry, so there is no
+        // need to wake up while the buffer is empty.
+        let next_action = match pending.take() {
+            Some(action) => Ok(action),
+            None => {
+                let timeout =
+                    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                wait_for_action(&mut action_rx, timeout).await
+            }
+        };
+        let action = match next_action {
+            Ok(action) => action,
+            Err(RecvTimeoutError::Timeout) => {
+                drain(
+                    &db,
+                    &mut buffer,
+                    &mut outcome,
+                    &mut writers,
+                    &in_flight,
+                    &metrics,
+                    opts.max_write_retries,
+                )
+                .await;
+                overflow.flush();
+                buffer_bytes = 0;
+                buffer_errors = 0;
+                deadline = None;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                report(
+                    &opts.internal_error_handler,
+                    InternalErrorKind::Channel,
+                    "Failed to get log entry due to closed channel; terminating logger".to_owned(),
+                );
+                return;
+            }
+        };
+
+        match action {
+            Action::Stop(done_tx) => {
+                // Persist any entries recorded since the last flush before terminating or else
+                // they would be lost.  This is the last chance to do so, hence the separate retry
+                // policy.
+                let drained = drain(
+                    &db,
+                    &mut buffer,
+                    &mut outcome,
+                    &mut writers,
+                    &in_flight,
+                    &metrics,
+                    opts.shutdown_retries,
+                );
+                match opts.shutdown_timeout {
+                    Some(timeout) => {
+                        if tokio::time::timeout(timeout, drained).await.is_err() {
+                            report(
+                                &opts.internal_error_handler,
+                                InternalErrorKind::Write,
+                                format!(
+                                    "Timed out after {:?} while persisting log entries on shutdown",
+                                    timeout
+                                ),
+                            );
+                        }
+                    }
+                    None => drained.await,
+                }
+                overflow.flush();
+                acknowledge_quiesce(&mut quiescers, outcome.take(), &opts.internal_error_handler);
+                break done_tx;
+            }
+
+            Action::Flush(done_tx) => {
+                drain(
+                    &db,
+                    &mut buffer,
+                    &mut outcome,
+                    &mut writers,
+                    &in_flight,
+                    &metrics,
+                    opts.max_write_retries,
+                )
+                .await;
+                overflow.flush();
+                buffer_bytes = 0;
+                buffer_errors = 0;
+                deadline = None;
+
+                let mut waiters = vec![done_tx];
+                pending = take_queued_flushes(&mut action_rx, &mut waiters);
+                let result = outcome.take();
+                for done_tx in waiters {
+                    if done_tx.send(result.clone()).is_err() {
+                        report(
+                            &opts.internal_error_handler,
+                            InternalErrorKind::Channel,
+                            "Failed to acknowledge flush; requester is gone".to_owned(),
+                        );
+                    }
+                }
+            }
+
+            Action::Quiesce(done_tx) => quiescers.push(done_tx),
+
+            Action::Record(entry) => {
+                let mut entry = *entry;
+                for transform in &opts.entry_transforms {
+                    transform(&mut entry);
+                }
+                opts.mirror.write(&entry, &opts.mirror_format);
+
+                if let Some(max) = opts.max_buffered_entries {
+                    let is_full = |buffer: &VecDeque<LogEntry>| {
+                        buffer.len() + in_flight.load(Ordering::SeqCst) >= max
+                    };
+
+                    while !buffer.is_empty() && is_full(&buffer) {
+                        let oldest = buffer.pop_front().expect("Buffer cannot be empty");
+                        buffer_bytes -= oldest.message.len();
+                        if oldest.level == Level::Error {
+                            buffer_errors -= 1;
+                        }
+                        overflow.handle(oldest);
+                    }
+
+                    if is_full(&buffer) {
+                        // All entries in memory belong to batches that are already being written,
+                        // so the only entry we can get rid of is the new one.
+                        overflow.handle(entry);
+                        continue;
+                    }
+                }
+
+                if buffer.is_empty() {
+                    deadline = opts.flush_delay.map(|delay| Instant::now() + delay);
+                }
+                buffer_bytes += entry.message.len();
+                if entry.level == Level::Error {
+                    buffer_errors += 1;
+                }
+                buffer.push_back(entry);
+
+                let too_large = matches!(opts.max_batch_bytes, Some(max) if buffer_bytes >= max);
+                let too_many_errors =
+                    matches!(opts.flush_on_errors, Some(max) if buffer_errors >= max);
+                if buffer.len() == MAX_BATCH_SIZE || too_large || too_many_errors {
+                    let batch = Vec::from(mem::take(&mut buffer));
+                    spawn_writer(
+                        &db,
+                        batch,
+                        &mut writers,
+                        &in_flight,
+                        &metrics,
+                        opts.max_write_retries,
+                    );
+                    buffer_bytes = 0;
+                    buffer_errors = 0;
+                    deadline = None;
+                    assert!(buffer.is_empty());
+                }
+            }
+        }
+    };
+
+    // Close the channel before acknowledging the stop so that any later send fails instead of
+    // queuing an action that nobody will process.
+    drop(action_rx);
+    drop(db);
+    if done_tx.send(()).is_err() {
+        report(
+            &opts.internal_error_handler,
+            InternalErrorKind::Channel,
+            "Failed to acknowledge stop; requester is gone".to_owned(),
+        );
+    }
+}
+
+/// Sends the request built by `action` to the recorder via `action_tx`, waits for the recorder
+/// to acknowledge its completion, and returns the acknowledgement.
+fn send_and_wait<T>(action_tx: &ActionSender, action: fn(mpsc::SyncSender<T>) -> Action) -> T {
+    let (done_tx, done_rx) = mpsc::sync_channel(1);
+    action_tx.send(action(done_tx)).unwrap();
+    done_rx.recv().unwrap()
+}
+
+/// Same as `send_and_wait` but waits for the acknowledgement on a blocking thread so that the
+/// recorder can make progress if it runs on the same runtime as the caller.
+async fn send_and_wait_async<T: Send + 'static>(
+    action_tx: &ActionSender,
+    action: fn(mpsc::SyncSender<T>) -> Action,
+) -> T {
+    let (done_tx, done_rx) = mpsc::sync_channel(1);
+    action_tx.send(action(done_tx)).unwrap();
+    tokio::task::spawn_blocking(move || done_rx.recv().unwrap()).await.unwrap()
+}
+
+/// Maximum level of the entries to record, shared among the logger, its handle and its layers so
+/// that it can be adjusted at runtime.
+#[derive(Clone)]
+pub(crate) struct SharedLevel(Arc<AtomicUsize>);
+
+impl SharedLevel {
+    /// Creates a new shared level initialized to `filter`.
+    pub(crate) fn new(filter: LevelFilter) -> Self {
+        Self(Arc::from(AtomicUsize::new(filter as usize)))
+    }
+
+    /// Returns true if entries at `level` should be recorded.
+    pub(crate) fn enabled(&self, level: Level) -> bool {
+        level as usize <= self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current maximum level.
+    pub(crate) fn get(&self) -> LevelFilter {
+        let filter = self.0.load(Ordering::Relaxed);
+        LevelFilter::iter()
+            .find(|candidate| *candidate as usize == filter)
+            .expect("Stored filters always come from a valid LevelFilter")
+    }
+
+    /// Updates the maximum level to `filter`.
+    fn set(&self, filter: LevelFilter) {
+        self.0.store(filter as usize, Ordering::Relaxed);
+    }
+}
+
+/// Returns true if an entry emitted from `module` at `level` was potentially emitted by the code in
+/// `recorder`, which would cause us to enter an infinite loop if not filtered out.
+pub(crate) fn is_recorder_module(module: Option<&str>, level: Level) -> bool {
+    // TODO(jmmv): Instead of blacklisting these modules, we should try to use tokio::task_local
+    // to avoid log statements triggered by us.
+    let module = match module {
+        Some(module) => module,
+        None => return true,
+    };
+    (module.starts_with("rustls::") || module.starts_with("sqlx::"))
+        || (cfg!(feature = "net")
+            && (module.starts_with("reqwest::")
+                || module.starts_with("hyper::")
+                || module.starts_with("hyper_util::")
+                || module.starts_with("h2::")))
+        || (level >= Level::Trace
+            && (module.starts_with("async_io::")
+                || module.starts_with("async_std::")
+                || module.starts_with("polling")))
+}
+
+/// Returns the `static_value` of a record field if available, or a copy of its `value` otherwise.
+///
+/// The fields of records emitted via the `log` macros are static, so this avoids allocations in
+/// the common case.
+fn static_or_owned(
+    static_value: Option<&'static str>,
+    value: Option<&str>,
+) -> Option<Cow<'static, str>> {
+    match static_value {
+        Some(static_value) => Some(Cow::Borrowed(static_value)),
+        None => value.map(|value| Cow::Owned(value.to_owned())),
+    }
+}
+
+/// Returns true if `record` was potentially emitted by the code in `recorder`, which would cause us
+/// to enter an infinite loop if not filtered out.
+///
+/// sqlx emits records with targets that differ from its modules, such as `sqlx::query` from
+/// `sqlx_core::logger`, so check both.
+fn is_recorder_log(record: &Record) -> bool {
+    is_recorder_module(Some(record.target()), record.level())
+        || is_recorder_module(record.module_path(), record.level())
+}
+
+/// Pairs of module names and the level filters to apply to the entries they emit.
+type ModuleFilters = Vec<(String, LevelFilter)>;
+
+/// Maximum levels of the entries to record from specific modules, which take precedence over the
+/// global maximum level.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ModuleLevels(Arc<ModuleFilters>);
+
+impl ModuleLevels {
+    /// Creates a new set of per-module levels from `levels`, where later entries for the same
+    /// module override earlier ones.
+    fn new(levels: ModuleFilters) -> Self {
+        let mut unique: ModuleFilters = Vec::with_capacity(levels.len());
+        for (module, filter) in levels {
+            match unique.iter_mut().find(|(other, _)| *other == module) {
+                Some(existing) => existing.1 = filter,
+                None => unique.push((module, filter)),
+            }
+        }
+        // Sort the most specific modules first so that they take precedence over their parents.
+        unique.sort_by_key(|(module, _filter)| Reverse(module.len()));
+        Self(Arc::new(unique))
+    }
+
+    /// Returns the level filter for the entries emitted from `target`, if any module matches it.
+    fn get(&self, target: &str) -> Option<LevelFilter> {
+        self.0
+            .iter()
+            .find(|(module, _filter)| match target.strip_prefix(module.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            })
+            .map(|(_module, filter)| *filter)
+    }
+
+    /// Returns true if entries at `level` emitted from `target` should be recorded, falling back
+    /// to `max_level` if no module matches `target`.
+    fn enabled(&self, target: &str, level: Level, max_level: &SharedLevel) -> bool {
+        match self.get(target) {
+            Some(filter) => level <= filter,
+            None => max_level.enabled(level),
+        }
+    }
+
+    /// Returns the most verbose level filter of all modules.
+    fn max(&self) -> LevelFilter {
+        self.0.iter().map(|(_module, filter)| *filter).max().unwrap_or(LevelFilter::Off)
+    }
+}
+
+/// Logging configuration derived from `RUST_LOG`.
+#[derive(Debug, PartialEq)]
+struct RustLog {
+    /// Maximum level of the entries from modules without a specific level.
+    level: LevelFilter,
+
+    /// Maximum levels of the entries from specific modules.
+    modules: ModuleLevels,
+}
+
+/// Parses `value`, which follows the syntax of `RUST_LOG`, into an optional global level and a
+/// list of per-module levels.
+///
+/// `value` is a comma-separated list of directives.  Each directive is either a level or `off`,
+/// which sets the global level; a module name followed by `=` and a level or `off`, which sets
+/// the level of that module and its submodules; or a bare module name, which records all entries
+/// from it.
+fn parse_rust_log_directives(
+    value: &str,
+) -> std::result::Result<(Option<LevelFilter>, ModuleFilters), String> {
+    let mut level = None;
+    let mut modules = vec![];
+    for directive in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((module, filter)) => {
+                let module = module.trim();
+                if module.is_empty() {
+                    return Err(format!("missing module name in '{}'", directive));
+                }
+                let filter = LevelFilter::from_str(filter.trim())
+                    .map_err(|e| format!("bad level in '{}': {}", directive, e))?;
+                modules.push((module.to_owned(), filter));
+            }
+            None => match LevelFilter::from_str(directive) {
+                Ok(directive_level) => level = Some(directive_level),
+                Err(_) => modules.push((directive.to_owned(), LevelFilter::Trace)),
+            },
+        }
+    }
+    Ok((level, modules))
+}
+
+/// Computes the logging configuration to use given the `value` of `RUST_LOG` and the logger
+/// `opts`.
+fn parse_rust_log(
+    value: std::result::Result<String, env::VarError>,
+    opts: &LoggerOptions,
+) -> RustLog {
+    let default =
+        RustLog { level: opts.default_level.to_level_filter(), modules: ModuleLevels::default() };
+    if opts.ignore_env {
+        return default;
+    }
+
+    match value {
+        Ok(value) => match parse_rust_log_directives(&value) {
+            Ok((level, modules)) => RustLog {
+                level: level.unwrap_or(opts.default_level.to_level_filter()),
+                modules: ModuleLevels::new(modules),
+            },
+            Err(e) => {
+                report(
+                    &opts.error_handler(),
+                    InternalErrorKind::Config,
+                    format!("Invalid RUST_LOG value: {}", e),
+                );
+                default
+            }
+        },
+        Err(env::VarError::NotPresent) => default,
+        Err(e) => {
+            report(
+                &opts.error_handler(),
+                InternalErrorKind::Config,
+                format!("Invalid RUST_LOG value: {}", e),
+            );
+            default
+        }
+    }
+}
+
+/// Fetches the value of `RUST_LOG` or returns the default values in `opts` if not available.
+fn env_rust_log(opts: &LoggerOptions) -> RustLog {
+    parse_rust_log(env::var("RUST_LOG"), opts)
+}
+
+/// Computes the mirror format to use given the `value` of `DB_LOGGER_FORMAT` and the logger
+/// `opts`.
+fn parse_db_logger_format(
+    value: std::result::Result<String, env::VarError>,
+    opts: &LoggerOptions,
+) -> MirrorFormat {
+    if let Some(format) = opts.mirror_format {
+        return format;
+    }
+    if opts.ignore_env {
+        return MirrorFormat::default();
+    }
+
+    match value {
+        Ok(format) => match MirrorFormat::from_str(&format) {
+            Ok(format) => format,
+            Err(e) => {
+                report(
+                    &opts.error_handler(),
+                    InternalErrorKind::Config,
+                    format!("Invalid DB_LOGGER_FORMAT value: {}", e),
+                );
+                MirrorFormat::default()
+            }
+        },
+        Err(env::VarError::NotPresent) => MirrorFormat::default(),
+        Err(e) => {
+            report(
+                &opts.error_handler(),
+                InternalErrorKind::Config,
+                format!("Invalid DB_LOGGER_FORMAT value: {}", e),
+            );
+            MirrorFormat::default()
+        }
+    }
+}
+
+/// Fetches the value of `DB_LOGGER_FORMAT` or returns the default format if not available.
+fn env_db_logger_format(opts: &LoggerOptions) -> MirrorFormat {
+    parse_db_logger_format(env::var("DB_LOGGER_FORMAT"), opts)
+}
+
+/// Returns the hostname in `hostname`, as returned by `gethostname`, or `fallback` if it is not
+/// valid UTF-8.
+fn hostname_or(hostname: std::result::Result<String, OsString>, fallback: &str) -> String {
+    hostname.unwrap_or_else(|_e| fallback.to_owned())
+}
+
+/// Returns true if `name` is a fully-qualified version of the short `hostname`.
+///
+/// Reverse lookups can return names that have nothing to do with the host, such as those of other
+/// interfaces or `localhost`, so we only accept names that start with the short hostname.
+#[cfg(feature = "fqdn")]
+fn is_fqdn_of(name: &str, hostname: &str) -> bool {
+    match name.split_once('.') {
+        Some((short, domain)) => short.eq_ignore_ascii_case(hostname) && !domain.is_empty(),
+        None => false,
+    }
+}
+
+/// Returns the fully-qualified domain name of `hostname`, or `None` if it cannot be determined.
+///
+/// The name is obtained by resolving `hostname` to its addresses and then doing a reverse lookup
+/// of each of them until one yields a qualified version of `hostname`.  This blocks on the system
+/// resolver.
+#[cfg(feature = "fqdn")]
+fn resolve_fqdn(hostname: &str) -> Option<String> {
+    let addrs = dns_lookup::lookup_host(hostname).ok()?;
+    addrs
+        .iter()
+        .filter(|addr| !addr.is_loopback())
+        .filter_map(|addr| dns_lookup::lookup_addr(addr).ok())
+        .find(|name| is_fqdn_of(name, hostname))
+}
+
+/// Determines the hostname to attach to log entries based on `opts`.
+async fn get_hostname(opts: &LoggerOptions) -> String {
+    if !opts.record_hostname {
+        return String::new();
+    }
+
+    let hostname = match gethostname().into_string() {
+        Ok(hostname) if opts.resolve_fqdn => hostname,
+        result => return hostname_or(result, &opts.hostname_fallback),
+    };
+
+    qualify_hostname(hostname, opts).await
+}
+
+/// Returns the fully-qualified version of `hostname`, or `hostname` itself if it cannot be
+/// determined.
+#[cfg(feature = "fqdn")]
+async fn qualify_hostname(hostname: String, opts: &LoggerOptions) -> String {
+    let short = hostname.clone();
+    match tokio::task::spawn_blocking(move || resolve_fqdn(&short)).await {
+        Ok(Some(fqdn)) => fqdn,
+        Ok(None) => hostname,
+        Err(e) => {
+            report(
+                &opts.error_handler(),
+                InternalErrorKind::Config,
+                format!("Failed to resolve the FQDN of {}: {}", hostname, e),
+            );
+            hostname
+        }
+    }
+}
+
+/// Reports that `resolve_fqdn` cannot be honored without the `fqdn` feature and returns
+/// `hostname` as is.
+#[cfg(not(feature = "fqdn"))]
+async fn qualify_hostname(hostname: String, opts: &LoggerOptions) -> String {
+    report(
+        &opts.error_handler(),
+        InternalErrorKind::Config,
+        "resolve_fqdn requires the fqdn feature; recording the short hostname".to_owned(),
+    );
+    hostname
+}
+
+/// Policy to apply to log entries that do not fit in memory.
+///
+/// Entries are kept in memory while they wait to be persisted, and if the database is unavailable
+/// for an extended period of time, they can accumulate until `LoggerOptions::max_buffered_entries`
+/// is reached.  At that point, the oldest entries are handed to this policy to make room for new
+/// ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discards the oldest entries.  Use `Handle::dropped_entries` to know how many were lost.
+    #[default]
+    DropOldest,
+
+    /// Appends the oldest entries to the given file in newline-delimited JSON format for later
+    /// replay.  Entries that cannot be written to the file are discarded as with `DropOldest`.
+    ///
+    /// The file is kept open and written to in chunks, which reach it whenever the buffered
+    /// entries are flushed to the database, whether that succeeds or not.
+    SpillToFile(PathBuf),
+}
+
+/// Source of the local offset from UTC to record alongside the timestamps of log entries.
+///
+/// Timestamps are always stored as absolute instants.  The offset is only informational: it allows
+/// rendering the timestamps in the local time of the host that recorded them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UtcOffsetSource {
+    /// Does not record any offset.
+    #[default]
+    None,
+
+    /// Records the offset of the system's local timezone, as determined during initialization.
+    ///
+    /// Determining the local offset is not possible on some platforms, such as Linux, once the
+    /// process has started multiple threads.  This is always the case when initializing the logger
+    /// from a multi-threaded Tokio runtime, such as the one set up by `#[tokio::main]`, and then
+    /// no offset is recorded and the failure is reported to the internal error handler.  Use
+    /// `UtcOffsetSource::fixed_from_system` before starting the runtime to avoid this.  Changes to
+    /// the offset, such as those caused by daylight saving time, are not picked up until the
+    /// logger is reinitialized.
+    System,
+
+    /// Records the given offset.
+    Fixed(UtcOffset),
+}
+
+impl UtcOffsetSource {
+    /// Determines the offset of the system's local timezone right away and returns it as a fixed
+    /// source, or returns `UtcOffsetSource::None` if it cannot be determined.
+    ///
+    /// Call this while the process is still single-threaded, such as at the beginning of `main`
+    /// before starting the Tokio runtime, and pass the result to `LoggerOptions::utc_offset`.
+    pub fn fixed_from_system() -> Self {
+        match UtcOffset::current_local_offset() {
+            Ok(offset) => UtcOffsetSource::Fixed(offset),
+            Err(_) => UtcOffsetSource::None,
+        }
+    }
+
+    /// Returns the offset to record as determined by this source, reporting failures to determine
+    /// it to `on_error`.
+    fn resolve(self, on_error: &InternalErrorHandler) -> Option<UtcOffset> {
+        match self {
+            UtcOffsetSource::None => None,
+            UtcOffsetSource::System => match UtcOffset::current_local_offset() {
+                Ok(offset) => Some(offset),
+                Err(e) => {
+                    report(
+                        on_error,
+                        InternalErrorKind::Config,
+                        format!(
+                            "Failed to determine the local UTC offset; not recording it: {}",
+                            e
+                        ),
+                    );
+                    None
+                }
+            },
+            UtcOffsetSource::Fixed(offset) => Some(offset),
+        }
+    }
+}
+
+/// Transformation to apply to the source filenames of log entries before storing them.
+///
+/// The filenames come from the paths that the compiler embedded in the binary, which are often
+/// long absolute paths from the build machine.  These waste space in the database and leak the
+/// directory structure of the build environment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FilenameTransform {
+    /// Stores filenames as they are.
+    #[default]
+    Keep,
+
+    /// Stores only the last component of the filenames.
+    Basename,
+
+    /// Removes the given prefix, such as the workspace root, from the filenames that start with
+    /// it, along with any leading path separators that remain.  Other filenames are stored as they
+    /// are.
+    StripPrefix(String),
+}
+
+impl FilenameTransform {
+    /// Applies the transformation to `filename`.
+    ///
+    /// All transformations keep a suffix of `filename` so the result borrows from the input.
+    pub(crate) fn apply<'a>(&self, filename: &'a str) -> &'a str {
+        let is_separator = |c: char| c == '/' || c == '\\';
+        match self {
+            Self::Keep => filename,
+            Self::Basename => filename.rsplit(is_separator).next().unwrap_or(filename),
+            Self::StripPrefix(prefix) => match filename.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.trim_start_matches(is_separator),
+                None => filename,
+            },
+        }
+    }
+
+    /// Same as `apply` but avoids copying `filename` if it is static.
+    fn apply_cow(&self, filename: Cow<'static, str>) -> Cow<'static, str> {
+        match filename {
+            Cow::Borrowed(filename) => Cow::Borrowed(self.apply(filename)),
+            Cow::Owned(filename) => Cow::Owned(self.apply(&filename).to_owned()),
+        }
+    }
+}
+
+/// Optional fields of the log entries to capture.
+///
+/// Fields that are not captured are stored as `NULL` and are not even extracted from the log
+/// records, which saves space in the database and work in the logging path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CapturedFields {
+    /// Whether to capture the module that emitted the entry.  Enabled by default.
+    pub module: bool,
+
+    /// Whether to capture the source file that emitted the entry.  Enabled by default.
+    pub filename: bool,
+
+    /// Whether to capture the line in the source file that emitted the entry.  Enabled by
+    /// default.
+    pub line: bool,
+
+    /// Whether to capture the key-values of the log records as the structured fields of the
+    /// entries.  Enabled by default.
+    pub key_values: bool,
+}
+
+impl Default for CapturedFields {
+    fn default() -> Self {
+        Self { module: true, filename: true, line: true, key_values: true }
+    }
+}
+
+/// Function to rewrite the message of a log entry before it is stored.
+pub type MessageFormatter = Arc<dyn Fn(&str) -> String + Send + Sync + 'static>;
+
+/// Function to modify a log entry before it is persisted.
+pub type EntryTransform = Arc<dyn Fn(&mut LogEntry) + Send + Sync + 'static>;
+
+/// Function to observe a batch of log entries after it has been persisted.
+pub type PersistCallback = Arc<dyn Fn(&[LogEntry]) + Send + Sync + 'static>;
+
+/// Categories of the problems that the logger encounters while operating.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InternalErrorKind {
+    /// Log entries could not be written to the database, or could only be written after retrying.
+    Write,
+
+    /// Log entries that did not fit in memory could not be handed to the `OverflowPolicy`.
+    Overflow,
+
+    /// A request could not reach the background task that persists log entries, or its requester
+    /// went away before getting an answer.
+    Channel,
+
+    /// The background task that persists log entries died or could not be started.
+    Recorder,
+
+    /// A function provided via `LoggerOptions`, such as `on_persist`, panicked.
+    Callback,
+
+    /// The configuration or the environment could not be fully honored, such as when `RUST_LOG`
+    /// is invalid.
+    Config,
+
+    /// The system clock went backwards.
+    Clock,
+
+    /// The code that persists log entries emitted a warning or error of its own, which is not
+    /// persisted because doing so would recurse.
+    Recursion,
+
+    /// An attempt to connect to the database failed and is going to be retried, as configured by
+    /// the `connect_retries` option of the backends.
+    Connection,
+}
+
+/// Problem that the logger encountered while operating, as passed to
+/// `LoggerOptions::internal_error_handler`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InternalError {
+    /// Category of the problem.
+    pub kind: InternalErrorKind,
+
+    /// Human-readable description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Function to handle the problems that the logger encounters while operating.
+pub type InternalErrorHandler = Arc<dyn Fn(InternalError) + Send + Sync + 'static>;
+
+/// Returns the default handler of internal errors, which prints them to stderr.
+pub(crate) fn stderr_error_handler() -> InternalErrorHandler {
+    Arc::new(|error: InternalError| eprintln!("{}", error))
+}
+
+/// Reports a problem of the given `kind` described by `message` to `handler`.
+///
+/// The handler must not take down the logger, so if it panics, the problem is printed to stderr
+/// along with the panic.
+pub(crate) fn report(handler: &InternalErrorHandler, kind: InternalErrorKind, message: String) {
+    let error = InternalError { kind, message };
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(error.clone()))) {
+        eprintln!("{}", error);
+        eprintln!("internal_error_handler panicked: {}", panic_message(&*payload));
+    }
+}
+
+/// Identifiers of the distributed trace and span that are active on the current thread.
+///
+/// Both identifiers are stored verbatim.  To correlate entries with OpenTelemetry traces, use the
+/// lowercase hexadecimal representation of the W3C trace context: 32 digits for the trace and 16
+/// digits for the span.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceContext {
+    /// Identifier of the trace.
+    pub trace_id: String,
+
+    /// Identifier of the span within the trace.
+    pub span_id: String,
+}
+
+/// Function to obtain the trace context that is active on the current thread, if any.
+pub type TraceContextExtractor = Arc<dyn Fn() -> Option<TraceContext> + Send + Sync + 'static>;
+
+/// Options to configure the behavior of the logger.
+///
+/// The maximum log level is determined with the following precedence: if `ignore_env` is true,
+/// `default_level` is always used; otherwise, the level is taken from `RUST_LOG` if set and valid;
+/// and, if not, `default_level` is used.  `RUST_LOG` can also set the levels of specific modules,
+/// which take precedence over the global level and which are not available programmatically.
+///
+/// Similarly, the format of the mirrored entries is taken from `mirror_format` if set; otherwise,
+/// unless `ignore_env` is true, from `DB_LOGGER_FORMAT` if set and valid; and, if not, from
+/// `MirrorFormat::default()`.
+pub struct LoggerOptions {
+    /// Log level to use when `RUST_LOG` is not set or is invalid.  Defaults to `Level::Warn`.
+    pub default_level: Level,
+
+    /// Whether to ignore `RUST_LOG` and `DB_LOGGER_FORMAT` and force the use of `default_level`
+    /// and `mirror_format`.
+    pub ignore_env: bool,
+
+    /// Maximum time that a log entry can wait in memory before being persisted.  Defaults to 5
+    /// seconds and supports sub-second values for near-real-time logging.
+    ///
+    /// Entries are written in batches: a batch is persisted when it fills up, when `Handle::flush`
+    /// is called, or when this delay expires, whichever happens first.  Setting this to `None`
+    /// disables the timed flushes, which reduces the number of writes to the database at the cost
+    /// of durability: entries may then stay in memory indefinitely if the program logs little, and
+    /// will be lost if the program crashes before they are flushed.
+    pub flush_delay: Option<Duration>,
+
+    /// Maximum number of log entries to keep in memory while they wait to be persisted, or `None`
+    /// to not impose any limit.  Defaults to `None`.
+    pub max_buffered_entries: Option<usize>,
+
+    /// What to do with the log entries that exceed `max_buffered_entries`.  Defaults to
+    /// `OverflowPolicy::DropOldest`.
+    pub overflow_policy: OverflowPolicy,
+
+    /// Total size of the messages in a batch above which the batch is written even if it does not
+    /// have the maximum number of entries yet, or `None` to only limit batches by their number of
+    /// entries.  Defaults to `None`.
+    ///
+    /// Use this to prevent large messages from producing oversized database transactions.  An
+    /// entry whose message alone exceeds this size is written in a batch of its own.
+    pub max_batch_bytes: Option<usize>,
+
+    /// Number of error-level log entries in a batch at which the batch is written even if it does
+    /// not have the maximum number of entries yet, or `None` to treat errors like any other entry.
+    /// Defaults to `None`.
+    ///
+    /// Use this to persist errors promptly without giving up on batching the rest of the entries.
+    pub flush_on_errors: Option<usize>,
+
+    /// Transformation to apply to the source filenames of the log entries.  Defaults to
+    /// `FilenameTransform::Keep`.
+    pub filename_transform: FilenameTransform,
+
+    /// Optional fields of the log entries to capture.  Defaults to capturing all of them.
+    pub captured_fields: CapturedFields,
+
+    /// Window during which identical log entries are collapsed, or `None` to record all entries.
+    /// Defaults to `None`.
+    ///
+    /// Entries are identical if they have the same level, module, message and correlation
+    /// identifier.  Only the first occurrence of an entry within the window is recorded
+    /// immediately: the repetitions are counted and summarized in a single entry with a
+    /// `(repeated N times)` suffix once the window closes, which is detected when new entries
+    /// arrive or when the logger is flushed.
+    pub dedup_window: Option<Duration>,
+
+    /// Function to rewrite the formatted message of every log entry, or `None` to store messages
+    /// verbatim.  Defaults to `None`.
+    ///
+    /// The function runs on the thread that emits the entry, before the entry is handed to the
+    /// background recorder, so it is suitable to redact secrets that must never be kept in memory
+    /// longer than necessary nor reach durable storage.  Keep it cheap as it is invoked on every
+    /// log call.  It also applies to the events captured by the `tracing` layer.
+    pub message_formatter: Option<MessageFormatter>,
+
+    /// Function to obtain the trace context to attach to every log entry, or `None` to not record
+    /// trace and span identifiers.  Defaults to `None`.
+    ///
+    /// The function runs on the thread that emits the entry so it can query thread-local state,
+    /// such as the current OpenTelemetry context.  Keep it cheap as it is invoked on every log
+    /// call.  It also applies to the events captured by the `tracing` layer and to panics.
+    pub trace_context: Option<TraceContextExtractor>,
+
+    /// Whether to attach a ULID to every log entry.  Defaults to false.
+    ///
+    /// ULIDs are generated when the entries are emitted and are globally sortable and unique, so
+    /// they allow merging the entries of several hosts into a single store without comparing
+    /// their per-host sequence numbers.  They can be read via `LogEntryRow::ulid` and used for
+    /// pagination via `LogFilter::after_ulid`.
+    pub record_ulid: bool,
+
+    /// Destination to which to copy the log entries in addition to persisting them.  Defaults to
+    /// `Mirror::Disabled`.
+    pub mirror: Mirror,
+
+    /// Fields to include in the entries copied to `mirror`, or `None` to use the format given in
+    /// `DB_LOGGER_FORMAT`.  Defaults to `None`.
+    pub mirror_format: Option<MirrorFormat>,
+
+    /// Hostname to attach to the log entries if the real hostname cannot be determined.  Defaults
+    /// to `invalid-hostname`.
+    pub hostname_fallback: String,
+
+    /// Whether to attach the fully-qualified domain name of the host to the log entries instead of
+    /// its short hostname.  Defaults to false.
+    ///
+    /// The FQDN is obtained via a reverse DNS lookup during initialization, which may be slow if
+    /// the resolver is misconfigured.  If the lookup fails, the short hostname is used.
+    ///
+    /// The lookup requires the `fqdn` feature.  Without it, enabling this option reports a
+    /// configuration error and the short hostname is used.
+    pub resolve_fqdn: bool,
+
+    /// Whether to attach the hostname to the log entries at all.  Defaults to true.
+    ///
+    /// When false, the hostname is not even queried and entries are recorded with an empty
+    /// hostname, which keeps details about the infrastructure out of shared databases.  Such
+    /// entries can be selected by setting `LogFilter::hostname` to the empty string.  This
+    /// overrides `hostname_fallback` and `resolve_fqdn`.
+    pub record_hostname: bool,
+
+    /// Number of times to retry writing a batch of log entries after a transient failure, such as
+    /// a connection problem or a panic in the database code.  Defaults to 2.
+    ///
+    /// Batches that fail with other errors, or that exhaust their retries, are discarded.  Retries
+    /// require keeping a copy of every batch until it is written, so set this to zero to avoid
+    /// that cost.
+    pub max_write_retries: usize,
+
+    /// Whether to commit the batches of log entries one at a time in the order in which they were
+    /// filled.  Defaults to false.
+    ///
+    /// Full batches are written by concurrent tasks, so a slow write can be committed after a
+    /// later one.  Sorting by timestamp and sequence always reconstructs the right order, but
+    /// consumers that tail the database by commit order, such as via `LogFilter::after_id`, can
+    /// observe the entries out of order.  Enabling this makes every write wait for the previous
+    /// one, including its retries, at the cost of throughput under heavy load.
+    pub ordered_writes: bool,
+
+    /// Number of times to retry writing the last batch of log entries after a transient failure
+    /// when the logger stops, which happens once the last clone of the `Handle` is dropped.
+    /// Defaults to 10.
+    ///
+    /// This is separate from `max_write_retries` because there are no later chances to persist
+    /// the entries on shutdown, so it pays off to try harder than in normal operation, where
+    /// retries make flushes slow.  Batches that were already being written when the logger
+    /// started stopping keep using `max_write_retries`.
+    pub shutdown_retries: usize,
+
+    /// Maximum time to wait for the pending log entries to be persisted when the logger stops, or
+    /// `None` to wait until all writes and their retries complete.  Defaults to `None`.
+    ///
+    /// Dropping the last clone of the `Handle` blocks until the entries are persisted, so set this
+    /// to bound how long that takes.  Entries that are not persisted by then are lost.
+    pub shutdown_timeout: Option<Duration>,
+
+    /// Source of the local offset from UTC to record with every log entry.  Defaults to
+    /// `UtcOffsetSource::None`.
+    pub utc_offset: UtcOffsetSource,
+
+    /// Whether to start a new background task to persist log entries if the current one dies
+    /// unexpectedly, such as due to a panic in an entry transform.  Defaults to true.
+    ///
+    /// The entries that the dead task held in memory are lost, but logging keeps working instead of
+    /// panicking on every subsequent call.
+    pub restart_recorder: bool,
+
+    /// Whether to persist log entries from a runtime owned by the logger instead of from the
+    /// runtime that initializes it.  Defaults to false.
+    ///
+    /// By default, the background task that persists log entries competes with the tasks of the
+    /// application for the threads of its runtime and, on a current-thread runtime, cannot make
+    /// progress while the blocking calls of the `Handle`, such as `flush`, wait for it.  While
+    /// idle, it waits for new entries asynchronously so that it does not hold the runtime back.
+    /// When enabled, the task runs on a dedicated thread and writes the batches from a
+    /// separate runtime with a single worker thread, which isolates logging from the scheduling of
+    /// the application.  If the runtime cannot be created, the logger falls back to the runtime
+    /// that initializes it.
+    ///
+    /// This makes current-thread runtimes usable with the SQLite backend, whose connections do
+    /// their I/O on threads of their own.  The PostgreSQL connections opened before the logger
+    /// starts, such as those used to set up the schema, are bound to the runtime that opened them
+    /// and stall while that runtime is blocked, so keep using a multi-threaded runtime with it.
+    pub dedicated_runtime: bool,
+
+    /// Minimum backward jump of the system clock between consecutive log entries to report to the
+    /// `internal_error_handler`, or `None` to not report them.  Defaults to 1 second.
+    ///
+    /// Such jumps, as caused by NTP corrections, give entries timestamps earlier than those of the
+    /// entries emitted before them.  Each jump is reported once.
+    pub clock_jump_threshold: Option<Duration>,
+
+    /// Whether to replace the timestamps of the log entries that are earlier than those of the
+    /// entries emitted before them with the latest timestamp seen so far.  Defaults to false.
+    ///
+    /// This keeps the timestamps non-decreasing so that sorting by them matches the order in which
+    /// the entries were emitted, at the cost of recording inaccurate times until the clock catches
+    /// up after a backward jump.
+    pub clamp_backward_timestamps: bool,
+
+    /// Whether to check that the database schema matches the one that the logger expects during
+    /// initialization, which then fails with a description of the mismatches, if any.  Defaults
+    /// to false.
+    pub verify_schema: bool,
+
+    /// Whether to create the database schema during initialization if it does not exist yet, and
+    /// to check that it matches the one that the logger expects.  Initialization fails if either
+    /// fails, which guarantees that the logger can write from its first entry.  Defaults to false.
+    pub ensure_schema: bool,
+
+    /// Capacity of the channel through which log entries reach the background task that persists
+    /// them.  Defaults to a bounded channel that blocks logging calls when 128 entries are pending.
+    ///
+    /// See `ChannelKind::Unbounded` for the memory trade-off of never blocking.
+    pub channel: ChannelKind,
+
+    /// Functions to enrich or otherwise modify the log entries before they are persisted, which
+    /// are applied in order.  Defaults to none.
+    ///
+    /// Unlike `message_formatter`, these run in the background recorder and not on the thread that
+    /// emits the entry, which keeps logging cheap but means that they cannot inspect the state of
+    /// the emitting thread.  They also apply to the copies sent to `mirror`.
+    pub entry_transforms: Vec<EntryTransform>,
+
+    /// Function to invoke with every batch of log entries after it has been persisted, such as to
+    /// update application metrics or to forward a copy of the entries elsewhere.  Defaults to
+    /// `None`.
+    ///
+    /// The function runs synchronously on the task that wrote the batch, after the write succeeds,
+    /// so keep it cheap: the next writes wait for it when `ordered_writes` is set.  It receives the
+    /// entries as they were handed to the database, before any truncation or splitting done by the
+    /// backend.  Batches that fail to be written are not passed to it.
+    pub on_persist: Option<PersistCallback>,
+
+    /// Function to handle the problems that the logger encounters while operating, such as failed
+    /// writes or an invalid `RUST_LOG`.  Defaults to `None`, which prints them to stderr.
+    ///
+    /// Set this to feed the health of the logger into your monitoring, or to keep these messages
+    /// when stderr is not captured.  The function runs synchronously on whichever thread or task
+    /// hit the problem, including the background task that persists log entries, so keep it cheap
+    /// and do not log through this logger from it: those entries could wait behind the very
+    /// problem that is being reported.
+    ///
+    /// The failed attempts to connect that the `connect_retries` option of the backends retries
+    /// happen before the logger exists, so they are reported to the `internal_error_handler` of
+    /// the backend's `ConnectionOptions` instead, which can be set to this same function.
+    pub internal_error_handler: Option<InternalErrorHandler>,
+
+    /// Whether to record an `Info` entry describing the configuration of the logger, such as its
+    /// hostname, level and backend, as soon as it is initialized.  Defaults to false.
+    ///
+    /// This marks the start of every session of the program in the logs, which helps to interpret
+    /// the entries that follow.  The entry is recorded regardless of the configured level.
+    pub startup_entry: bool,
+
+    /// Whether to wait for every error-level log entry to be persisted before returning from the
+    /// logging call that emitted it.  Defaults to false.
+    ///
+    /// This trades latency for durability on the error path: the entry is not lost if the
+    /// program crashes right after logging it, and neither are the entries that were buffered
+    /// before it.  Entries of other levels remain asynchronous.  The caller blocks for as long as
+    /// the write takes, including its retries, so this is not suitable for hot loops that fail
+    /// frequently.  Only applies to the entries emitted via the `log` facade, and requires the
+    /// background recorder to run on a thread other than the caller's: use a multi-threaded
+    /// runtime or enable `dedicated_runtime`.
+    pub sync_errors: bool,
+
+    /// Key of the key-values of log records whose value to store as the correlation identifier
+    /// of their entries, or `None` to not record correlation identifiers.  Defaults to `None`.
+    ///
+    /// This is a lightweight way to tag the entries that belong to the same request, such as with
+    /// `log::info!(correlation_id = request_id; "Processing request")`, and to find them later via
+    /// `LogFilter::correlation_id` thanks to a dedicated indexed column.  Entries that lack the key
+    /// have no correlation identifier.  Only applies to the records emitted via the `log` facade.
+    pub correlation_id_key: Option<String>,
+}
+
+impl Default for LoggerOptions {
+    fn default() -> Self {
+        Self {
+            default_level: DEFAULT_LOG_LEVEL,
+            ignore_env: false,
+            flush_delay: Some(Duration::from_secs(MAX_FLUSH_DELAY_SECS)),
+            max_buffered_entries: None,
+            overflow_policy: OverflowPolicy::default(),
+            max_batch_bytes: None,
+            flush_on_errors: None,
+            filename_transform: FilenameTransform::default(),
+            captured_fields: CapturedFields::default(),
+            dedup_window: None,
+            message_formatter: None,
+            trace_context: None,
+            record_ulid: false,
+            mirror: Mirror::default(),
+            mirror_format: None,
+            hostname_fallback: DEFAULT_HOSTNAME_FALLBACK.to_owned(),
+            resolve_fqdn: false,
+            record_hostname: true,
+            max_write_retries: DEFAULT_MAX_WRITE_RETRIES,
+            ordered_writes: false,
+            shutdown_retries: DEFAULT_SHUTDOWN_RETRIES,
+            shutdown_timeout: None,
+            utc_offset: UtcOffsetSource::default(),
+            restart_recorder: true,
+            dedicated_runtime: false,
+            clock_jump_threshold: Some(DEFAULT_CLOCK_JUMP_THRESHOLD),
+            clamp_backward_timestamps: false,
+            verify_schema: false,
+            ensure_schema: false,
+            channel: ChannelKind::default(),
+            entry_transforms: vec![],
+            on_persist: None,
+            internal_error_handler: None,
+            startup_entry: false,
+            sync_errors: false,
+            correlation_id_key: None,
+        }
+    }
+}
+
+impl LoggerOptions {
+    /// Returns the handler of internal errors to use, falling back to printing them to stderr.
+    fn error_handler(&self) -> InternalErrorHandler {
+        self.internal_error_handler.clone().unwrap_or_else(stderr_error_handler)
+    }
+}
+
+/// An opaque handler to maintain the logger's backing task alive.
+///
+/// The handle can be cloned to share it across different parts of an application.  Once the last
+/// clone goes out of scope, the logger's database persisting logic stops and attempts to log may
+/// fail or get stuck.
+// TODO(jmmv): Modify integration tests to check what happens and possibly refactor this to *not*
+// expose this type at all.
+pub struct Handle {
+    db: Connection,
+    builder: EntryBuilder,
+    action_tx: ActionSender,
+    dropped: Arc<AtomicU64>,
+    metrics: Arc<MetricsRecorder>,
+    max_level: SharedLevel,
+    module_levels: ModuleLevels,
+    dedup: Option<Arc<Deduplicator>>,
+    #[cfg(feature = "tracing")]
+    tracing_layer: crate::tracing::DbLayer,
+
+    /// Number of live clones of this handle, used to stop the recorder only when the last one
+    /// goes away.
+    clones: Arc<AtomicUsize>,
+}
+
+impl Handle {
+    /// Creates the first handle to control `logger`, which is backed by `db`.
+    fn new(logger: &DbLogger, db: Connection) -> Self {
+        Self {
+            db,
+            builder: logger.builder.clone(),
+            action_tx: logger.action_tx.clone(),
+            dropped: logger.dropped.clone(),
+            metrics: logger.metrics.clone(),
+            max_level: logger.max_level.clone(),
+            module_levels: logger.module_levels.clone(),
+            dedup: logger.dedup.clone(),
+            #[cfg(feature = "tracing")]
+            tracing_layer: crate::tracing::DbLayer::new(
+                logger.action_tx.clone(),
+                logger.builder.clone(),
+                logger.max_level.clone(),
+            ),
+            clones: Arc::from(AtomicUsize::new(1)),
+        }
+    }
+
+    /// Returns the sorted list of all log entries in the database.
+    ///
+    /// Given that this is exposed for testing purposes only, this just returns a flat textual
+    /// representation of the log entry and does not try to deserialize it as a `LogEntry`.  This
+    /// is for simplicity given that a `LogEntry` keeps references to static strings and we cannot
+    /// obtain those from the database.
+    ///
+    /// Timestamps are rendered as RFC 3339 strings.  Use `get_log_entries_with_format` to choose
+    /// a different representation, and `query_log_entries` to obtain structured entries.
+    pub async fn get_log_entries(&self) -> Result<Vec<String>> {
+        self.get_log_entries_with_format(TimestampFormat::default()).await
+    }
+
+    /// Returns the sorted list of all log entries in the database with their timestamps rendered
+    /// according to `format`.
+    ///
+    /// See `get_log_entries` for details.
+    pub async fn get_log_entries_with_format(
+        &self,
+        format: TimestampFormat,
+    ) -> Result<Vec<String>> {
+        let rows = self.db.0.query_log_entries(&LogFilter::default()).await?;
+        rows.iter()
+            .map(|row| export::format_text(row, format))
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(|e| DbError::Serialization(format!("Failed to format entries: {}", e), None))
+    }
+
+    /// Returns the log entries in the database that match `filter` as structured rows.
+    ///
+    /// See `LogFilter` for details on how level ranges are interpreted.
+    ///
+    /// Rows are sorted by timestamp and then by sequence number.  Consumers that re-sort these
+    /// entries must use the sequence number as the tiebreaker to preserve the order in which
+    /// entries with identical timestamps were recorded.
+    pub async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        self.db.0.query_log_entries(filter).await
+    }
+
+    /// Returns the `n` most recent log entries in chronological order.
+    ///
+    /// This fetches the entries in reverse order with a limit, which is served by the index on
+    /// the timestamp and sequence columns, so it is cheap even on large databases.
+    pub async fn tail(&self, n: usize) -> Result<Vec<LogEntryRow>> {
+        let filter =
+            LogFilter { order: SortOrder::Descending, limit: Some(n), ..Default::default() };
+        let mut rows = self.db.0.query_log_entries(&filter).await?;
+        rows.reverse();
+        Ok(rows)
+    }
+
+    /// Returns a stream over the log entries in the database that match `filter` as structured
+    /// rows.
+    ///
+    /// This is the same as `query_log_entries` but rows are fetched lazily as the stream is
+    /// consumed, which allows processing large result sets with bounded memory.  The stream stops
+    /// after yielding the first error.
+    ///
+    /// With SQLite, the stream holds exclusive access to the database for as long as it is alive,
+    /// so the logger cannot persist any entries until the stream is exhausted or dropped.  Do not
+    /// flush the logger while consuming the stream or the flush will never complete.
+    pub fn stream_log_entries(
+        &self,
+        filter: &LogFilter,
+    ) -> impl Stream<Item = Result<LogEntryRow>> + Send + 'static {
+        self.db.0.stream_log_entries(filter)
+    }
+
+    /// Writes all log entries in the database to `writer` in newline-delimited JSON format.
+    ///
+    /// Each line is a JSON object with the same fields as `LogEntryRow`, including the `sequence`
+    /// number needed to order entries that share the same timestamp.
+    pub async fn export_ndjson<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let rows = self.db.0.query_log_entries(&LogFilter::default()).await?;
+        export::write_ndjson(&rows, writer)
+            .map_err(|e| DbError::Other(format!("Failed to export entries: {}", e), None))
+    }
+
+    /// Writes the log entries in the database that match `filter` to `writer` in CSV format.
+    ///
+    /// The output starts with a header row and contains one record per entry with the same
+    /// columns as `LogEntryRow`.  Timestamps are rendered as RFC 3339 strings, payloads are
+    /// encoded in base64, missing optional fields are left empty, and fields containing commas,
+    /// quotes or line breaks are quoted.
+    ///
+    /// Entries are fetched with `stream_log_entries` and written to `writer` one at a time so
+    /// memory usage stays bounded regardless of the number of entries; wrap `writer` in a
+    /// `BufWriter` if needed.  The same caveats as for `stream_log_entries` apply.
+    pub async fn export_csv<W: Write>(&self, writer: &mut W, filter: &LogFilter) -> Result<()> {
+        let write_error =
+            |e: io::Error| DbError::Other(format!("Failed to export entries: {}", e), None);
+        export::write_csv_header(writer).map_err(write_error)?;
+        let mut rows = self.db.0.stream_log_entries(filter);
+        while let Some(row) = rows.try_next().await? {
+            export::write_csv_record(&row, writer).map_err(write_error)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of log entries that match `filter` for each level.
+    ///
+    /// The counting happens in the database so this is much cheaper than querying the entries and
+    /// counting them.  Levels without matching entries are omitted, and the results are sorted
+    /// from the most to the least severe level.  The `order` and `limit` of the filter are ignored.
+    pub async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        self.db.0.level_counts(filter).await
+    }
+
+    /// Returns the sorted list of distinct hostnames that have recorded log entries.
+    pub async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        self.db.0.distinct_hostnames().await
+    }
+
+    /// Returns the sorted list of distinct modules that have recorded log entries.
+    ///
+    /// Entries recorded without a module do not contribute any value to this list.
+    pub async fn distinct_modules(&self) -> Result<Vec<String>> {
+        self.db.0.distinct_modules().await
+    }
+
+    /// Returns the timestamps of the oldest and newest log entries, including archived ones, or
+    /// `None` if there are no entries.
+    ///
+    /// This is much cheaper than querying the entries to find the range of time that they cover.
+    pub async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        self.db.0.time_bounds().await
+    }
+
+    /// Returns the number of log entries, including archived ones, and an estimate of the space
+    /// that they occupy, which is useful to alert before the log fills up the disk.
+    ///
+    /// The size is approximate and is computed differently by each backend.  On SQLite, this is
+    /// the size of the pages in use by the whole database file, including indexes and any other
+    /// tables stored in it.  On PostgreSQL, this is the total size of the table, including its
+    /// indexes and TOAST data.  Files report the size of the active and rotated files.  The stderr
+    /// backend does not persist anything and reports the entries it keeps in memory with zero
+    /// bytes.
+    pub async fn storage_stats(&self) -> Result<StorageStats> {
+        self.db.0.storage_stats().await
+    }
+
+    /// Marks all log entries recorded before `ts` as archived and returns how many were marked.
+    ///
+    /// Archived entries are not deleted: they are only hidden from `query_log_entries` and
+    /// `stream_log_entries` unless the filter sets `include_archived`.  This allows keeping old
+    /// entries for retention purposes without having them get in the way of normal queries.
+    ///
+    /// Only SQLite supports this for now.  Other backends return an error.
+    pub async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64> {
+        self.db.0.archive_log_entries_before(ts).await
+    }
+
+    /// Deletes the log entries that match `filter` and returns how many were deleted.
+    ///
+    /// Entries are selected the same way `query_log_entries` selects them, including the handling
+    /// of archived entries, but the filter cannot have a `limit`.  To prevent accidents, a filter
+    /// without any criteria is rejected unless it sets `confirm_delete_all`.
+    ///
+    /// Files do not support this and return an error.  The stderr backend can only delete the
+    /// entries it keeps in memory.
+    pub async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        self.db.0.delete_log_entries(filter).await
+    }
+
+    /// Deletes all log entries, including archived ones, and restarts the sequence numbers of new
+    /// entries from zero.
+    ///
+    /// This is intended to start from a clean slate, such as between test runs, without having to
+    /// recreate the schema.  Entries that are still buffered in the logger are not affected and
+    /// will be written after this completes: call `flush` first to discard them too.
+    ///
+    /// On SQLite, writes of the logger are serialized with this operation as they are with any
+    /// other.  On PostgreSQL, this truncates the table, which waits for the writes in progress to
+    /// commit but does not otherwise synchronize with the logger: a batch that is being prepared
+    /// at that moment may be written afterwards with the sequence numbers it got before the
+    /// restart.  Files are emptied and their rotated copies are deleted.  The stderr backend can
+    /// only clear the entries it keeps in memory.
+    pub async fn clear_log_entries(&self) -> Result<()> {
+        self.db.0.clear_log_entries().await
+    }
+
+    /// Reclaims unused space and refreshes the query planner statistics of the database.
+    ///
+    /// This is intended to be invoked periodically, such as from a scheduled job, after pruning
+    /// old entries.  On SQLite, this runs `VACUUM` and `PRAGMA optimize`, which can be expensive
+    /// for large databases and which blocks writers while it runs: log entries keep accumulating
+    /// in memory in the meantime.  On PostgreSQL, this is a no-op because the server's autovacuum
+    /// takes care of this.
+    pub async fn maintenance(&self) -> Result<()> {
+        self.db.0.maintenance().await
+    }
+
+    /// Returns the number of log entries that have been lost because they did not fit in memory
+    /// and could not be handled by the `OverflowPolicy`.
+    pub fn dropped_entries(&self) -> u64 {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of log entries that the backend has discarded because their messages
+    /// exceeded its maximum length and its `OversizePolicy` is `Reject`.
+    pub fn rejected_entries(&self) -> u64 {
+        self.db.0.rejected_entries()
+    }
+
+    /// Returns measurements of the batches of log entries written to the database so far, which
+    /// help tune `LoggerOptions::max_batch_bytes` and `LoggerOptions::flush_delay`.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+
+    /// Returns a `tracing_subscriber::Layer` that persists `tracing` events to the same database
+    /// as the `log` records.
+    ///
+    /// Flushing the handle also flushes the events captured by the layer.
+    #[cfg(feature = "tracing")]
+    pub fn tracing_layer(&self) -> crate::tracing::DbLayer {
+        self.tracing_layer.clone()
+    }
+
+    /// Changes the maximum level of the entries to record to `level`.
+    ///
+    /// This takes effect immediately for all subsequent log calls and `tracing` events, which
+    /// allows temporarily increasing the verbosity of a running program to diagnose problems.  The
+    /// new level overrides the one computed at initialization time from `RUST_LOG` and the
+    /// `LoggerOptions`, but not the levels that `RUST_LOG` sets for specific modules.
+    pub fn set_level(&self, level: Level) {
+        self.max_level.set(level.to_level_filter());
+        log::set_max_level(level.to_level_filter().max(self.module_levels.max()));
+    }
+
+    /// Returns the maximum level of the entries that are recorded.
+    ///
+    /// This reflects the level computed at initialization time or the one most recently passed to
+    /// `set_level`, which is `LevelFilter::Off` if `RUST_LOG` turned off all modules without a
+    /// specific level.
+    pub fn max_level(&self) -> LevelFilter {
+        self.max_level.get()
+    }
+
+    /// Returns the hostname attached to the recorded entries.
+    pub fn hostname(&self) -> &str {
+        &self.builder.hostname
+    }
+
+    /// Submits a fully-formed `entry` to the recorder, bypassing the `log` facade.
+    ///
+    /// This is useful to replay imported logs or to forward entries received from other processes
+    /// while preserving their own timestamps and hostnames.  The entry is persisted as is
+    /// regardless of the maximum log level and of the module that it claims to come from, but it
+    /// is still subject to the configured `entry_transforms` and `mirror`.
+    pub fn record(&self, entry: LogEntry) {
+        self.action_tx.send(Action::Record(Box::new(entry))).unwrap();
+    }
+
+    /// Submits the entries stored in newline-delimited JSON format in the file at `path`, such as
+    /// those spilled by `OverflowPolicy::SpillToFile`, to the recorder as `record` does.
+    ///
+    /// Returns the number of submitted entries.  The whole file is read and parsed before
+    /// submitting any entry, so a malformed line fails the replay without recording anything.
+    /// The file is left untouched: move it away before replaying it if the logger may still
+    /// spill to it, or else the entries that overflow during the replay end up in a file that
+    /// has already been replayed.
+    pub async fn replay_ndjson(&self, path: &Path) -> Result<usize> {
+        let path = path.to_owned();
+        let entries = tokio::task::spawn_blocking(move || {
+            let replay_error = |e: io::Error| {
+                DbError::Other(format!("Failed to replay {}: {}", path.display(), e), None)
+            };
+            let contents = std::fs::read_to_string(&path).map_err(replay_error)?;
+            contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| export::parse_entry_ndjson(line).map_err(replay_error))
+                .collect::<Result<Vec<LogEntry>>>()
+        })
+        .await
+        .map_err(|e| DbError::Other(format!("Failed to replay entries: {}", e), None))??;
+
+        let nentries = entries.len();
+        for entry in entries {
+            self.record(entry);
+        }
+        Ok(nentries)
+    }
+
+    /// Records an entry at `level` from `module` with `message` and the binary `payload` attached
+    /// to it, which is not possible via the `log` macros.
+    ///
+    /// The entry is built like those emitted via the `log` facade, with `module` as its module,
+    /// which is typically `module_path!()`, and with the filename and line of the caller.  It is
+    /// only recorded if `level` is enabled for `module` by the per-module levels of `RUST_LOG` or,
+    /// if none applies, by the maximum log level.  The payload is stored as is and exported in
+    /// base64.
+    #[track_caller]
+    pub fn record_with_payload(&self, level: Level, module: &str, message: &str, payload: Vec<u8>) {
+        if !self.module_levels.enabled(module, level, &self.max_level) {
+            return;
+        }
+        let location = panic::Location::caller();
+        let mut entry = self.builder.build(
+            level,
+            Some(Cow::Owned(module.to_owned())),
+            Some(Cow::Borrowed(location.file())),
+            Some(location.line()),
+            message.to_owned(),
+        );
+        entry.payload = Some(payload);
+        self.record(entry);
+    }
+
+    /// Installs a panic hook that records panics as log entries before invoking the previously
+    /// installed hook.
+    ///
+    /// Panics bypass the `log` facade so, without this, they never reach the database.  The hook
+    /// records an `Error` entry with the panic message and the location of the panic and then
+    /// waits for the entry to be persisted, as the process may be about to terminate.  The wait is
+    /// bounded to prevent hangs if the panic prevents the recorder from making progress.
+    pub fn install_panic_hook(&self) {
+        let recorder =
+            PanicRecorder { builder: self.builder.clone(), action_tx: self.action_tx.clone() };
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location();
+            recorder.record(
+                &panic_message(info.payload()),
+                location.map(|l| l.file()),
+                location.map(|l| l.line()),
+            );
+            previous(info);
+        }));
+    }
+
+    /// Flushes pending records to the backend DB.
+    ///
+    /// Returns the number of entries written since the previous flush, or an error counting the
+    /// writes that failed in that period, whose entries were thus lost, and carrying the last one.
+    pub fn flush(&self) -> std::result::Result<FlushStats, FlushError> {
+        record_repeated(self.dedup.as_deref(), &self.action_tx);
+        send_and_wait(&self.action_tx, Action::Flush)
+    }
+
+    /// Flushes pending records to the backend DB without blocking the calling thread.
+    ///
+    /// This is the same as `flush` but, as the wait happens on a blocking thread, it can be used
+    /// on a current-thread runtime that also runs the background task that persists the entries.
+    pub async fn flush_async(&self) -> std::result::Result<FlushStats, FlushError> {
+        record_repeated(self.dedup.as_deref(), &self.action_tx);
+        send_and_wait_async(&self.action_tx, Action::Flush).await
+    }
+
+    /// Flushes pending records to the backend DB and waits for the recorder to become idle.
+    ///
+    /// Unlike `flush`, which only covers the entries that reached the recorder before the request,
+    /// this also persists the entries enqueued concurrently with it and only returns once no
+    /// entries are queued and no writes are in flight.  This makes it suitable to synchronize with
+    /// the recorder in tests, but it may never return if other threads log continuously.
+    ///
+    /// Returns the same results as `flush`.
+    pub fn quiesce(&self) -> std::result::Result<FlushStats, FlushError> {
+        record_repeated(self.dedup.as_deref(), &self.action_tx);
+        send_and_wait(&self.action_tx, Action::Quiesce)
+    }
+
+    /// Returns a guard that flushes pending records to the backend DB when it goes out of scope.
+    ///
+    /// This is a shorthand to ensure that the entries logged within a scope, such as the handling
+    /// of a request, are persisted once the scope ends regardless of how it exits.
+    ///
+    /// Dropping the guard blocks the calling thread until the flush completes, as `flush` does.
+    /// On a current-thread runtime, that thread is the only one that can run the background task
+    /// that persists the entries, so dropping the guard deadlocks unless the task runs elsewhere
+    /// via `LoggerOptions::dedicated_runtime`.  Use `flush_scope_async` on such runtimes instead.
+    pub fn flush_scope(&self) -> FlushGuard<'_> {
+        FlushGuard { handle: self }
+    }
+
+    /// Runs `future` and flushes pending records to the backend DB once it completes, even if it
+    /// panics, and returns its output.
+    ///
+    /// This is the asynchronous alternative to `flush_scope` and flushes as `flush_async` does, so
+    /// it can be used on a current-thread runtime that also runs the background task that
+    /// persists the entries.
+    pub async fn flush_scope_async<F: Future>(&self, future: F) -> F::Output {
+        let result = AssertUnwindSafe(future).catch_unwind().await;
+        // Write errors have already been reported by the recorder.
+        let _ = self.flush_async().await;
+        match result {
+            Ok(output) => output,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+/// Guard returned by `Handle::flush_scope` that flushes pending records to the backend DB when
+/// dropped.
+///
+/// Dropping the guard blocks, which deadlocks on a current-thread runtime unless
+/// `LoggerOptions::dedicated_runtime` is enabled.  Use `Handle::flush_scope_async` there instead.
+#[must_use = "the flush happens when the guard is dropped"]
+pub struct FlushGuard<'a> {
+    handle: &'a Handle,
+}
+
+impl Drop for FlushGuard<'_> {
+    fn drop(&mut self) {
+        // Write errors have already been reported by the recorder.
+        let _ = self.handle.flush();
+    }
+}
+
+/// Extracts the message of a panic from its `payload`.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    }
+}
+
+/// State needed by the panic hook to record panics.
+struct PanicRecorder {
+    builder: EntryBuilder,
+    action_tx: ActionSender,
+}
+
+impl PanicRecorder {
+    /// Records a panic with `message` that happened at `filename`:`line` and waits for it to be
+    /// persisted.
+    fn record(&self, message: &str, filename: Option<&str>, line: Option<u32>) {
+        let thread = thread::current();
+        let (trace_id, span_id) = self.builder.trace_ids();
+        let timestamp = self.builder.now();
+        let entry = LogEntry {
+            timestamp,
+            hostname: self.builder.hostname.clone(),
+            level: Level::Error,
+            module: None,
+            filename: filename.map(|filename| Cow::Owned(filename.to_owned())),
+            line,
+            message: format!(
+                "thread '{}' panicked: {}",
+                thread.name().unwrap_or("<unnamed>"),
+                message
+            ),
+            uptime_nanos: Some(self.builder.uptime_nanos()),
+            utc_offset: self.builder.utc_offset,
+            trace_id,
+            span_id,
+            ulid: self.builder.ulid(timestamp),
+            correlation_id: None,
+            payload: None,
+            fields: None,
+        };
+
+        let on_error = &self.builder.internal_error_handler;
+        if let Err(e) = self.action_tx.send(Action::Record(Box::new(entry))) {
+            report(on_error, InternalErrorKind::Channel, format!("Failed to record panic: {}", e));
+            return;
+        }
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        if let Err(e) = self.action_tx.send(Action::Flush(done_tx)) {
+            report(
+                on_error,
+                InternalErrorKind::Channel,
+                format!("Failed to flush panic log entry: {}", e),
+            );
+            return;
+        }
+        if let Err(e) = done_rx.recv_timeout(PANIC_FLUSH_TIMEOUT) {
+            report(
+                on_error,
+                InternalErrorKind::Channel,
+                format!("Failed to wait for panic log entry to be persisted: {}", e),
+            );
+        }
+    }
+}
+
+/// Sends the summaries of the repeated entries pending in `dedup`, if any, to the recorder via
+/// `action_tx`.
+fn record_repeated(dedup: Option<&Deduplicator>, action_tx: &ActionSender) {
+    if let Some(dedup) = dedup {
+        for entry in dedup.drain() {
+            action_tx.send(Action::Record(Box::new(entry))).unwrap();
+        }
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        self.clones.fetch_add(1, Ordering::SeqCst);
+        Self {
+            db: self.db.clone(),
+            builder: self.builder.clone(),
+            action_tx: self.action_tx.clone(),
+            dropped: self.dropped.clone(),
+            metrics: self.metrics.clone(),
+            max_level: self.max_level.clone(),
+            module_levels: self.module_levels.clone(),
+            dedup: self.dedup.clone(),
+            #[cfg(feature = "tracing")]
+            tracing_layer: self.tracing_layer.clone(),
+            clones: self.clones.clone(),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if self.clones.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return;
+        }
+
+        record_repeated(self.dedup.as_deref(), &self.action_tx);
+        // Stopping persists all pending entries with the shutdown retry policy.  Write errors are
+        // reported by the recorder.
+        send_and_wait(&self.action_tx, Action::Stop);
+    }
+}
+
+/// Detector of backward jumps in the timestamps of consecutive log entries.
+pub(crate) struct ClockWatch {
+    /// Minimum backward jump to report, if any.
+    threshold: Option<Duration>,
+
+    /// Whether to replace timestamps earlier than the latest one seen with the latest one.
+    clamp: bool,
+
+    /// Latest timestamp seen so far, in nanoseconds since the epoch.
+    latest: AtomicI64,
+
+    /// Whether a backward jump has been reported and the timestamps have not caught up yet, which
+    /// prevents reporting the same jump for every entry.
+    behind: AtomicBool,
+}
+
+impl ClockWatch {
+    /// Creates a new detector that reports jumps larger than `threshold` and that, if `clamp` is
+    /// true, makes timestamps non-decreasing.
+    pub(crate) fn new(threshold: Option<Duration>, clamp: bool) -> Self {
+        Self { threshold, clamp, latest: AtomicI64::new(i64::MIN), behind: AtomicBool::new(false) }
+    }
+
+    /// Compares `ts` to the latest timestamp seen so far and returns the timestamp to use.
+    ///
+    /// Backward jumps larger than the threshold are reported to `on_error`.
+    fn check(&self, ts: OffsetDateTime, on_error: &InternalErrorHandler) -> OffsetDateTime {
+        if self.threshold.is_none() && !self.clamp {
+            return ts;
+        }
+
+        let nanos = i64::try_from(ts.unix_timestamp_nanos()).unwrap_or(i64::MAX);
+        let latest = self.latest.fetch_max(nanos, Ordering::SeqCst);
+        if nanos >= latest {
+            self.behind.store(false, Ordering::SeqCst);
+            return ts;
+        }
+
+        let jump = Duration::from_nanos(latest.abs_diff(nanos));
+        if matches!(self.threshold, Some(threshold) if jump > threshold)
+            && !self.behind.swap(true, Ordering::SeqCst)
+        {
+            report(
+                on_error,
+                InternalErrorKind::Clock,
+                format!(
+                    "System clock went backwards by {:?}; log entries may be out of order",
+                    jump
+                ),
+            );
+        }
+
+        if self.clamp {
+            OffsetDateTime::from_unix_timestamp_nanos(i128::from(latest)).unwrap_or(ts)
+        } else {
+            ts
+        }
+    }
+}
+
+/// Settings shared by all the sources of log entries to turn their events into `LogEntry`s.
+#[derive(Clone)]
+pub(crate) struct EntryBuilder {
+    /// Hostname to attach to the entries.
+    pub(crate) hostname: Arc<str>,
+
+    /// Source of the timestamps of the entries.
+    pub(crate) clock: Arc<dyn Clock + Send + Sync + 'static>,
+
+    /// Monotonic reading of `clock` when the logger was initialized, used to compute the uptime of
+    /// the entries.
+    pub(crate) start: Duration,
+
+    /// Rewrite to apply to the source filenames of the entries.
+    pub(crate) filename_transform: FilenameTransform,
+
+    /// Fields of the entries' origin that are stored.
+    pub(crate) captured_fields: CapturedFields,
+
+    /// Optional rewrite to apply to the messages of the entries.
+    pub(crate) message_formatter: Option<MessageFormatter>,
+
+    /// Optional source of the trace context to attach to the entries.
+    pub(crate) trace_context: Option<TraceContextExtractor>,
+
+    /// Optional generator of the ULIDs to attach to the entries, shared by all clones of the
+    /// builder so that they remain monotonic.
+    pub(crate) ulid_generator: Option<Arc<UlidGenerator>>,
+
+    /// Local offset from UTC to attach to the entries, if any.
+    pub(crate) utc_offset: Option<UtcOffset>,
+
+    /// Detector of backward jumps of `clock`, shared by all clones of the builder.
+    pub(crate) clock_watch: Arc<ClockWatch>,
+
+    /// Function to report the problems found while building or sending the entries.
+    pub(crate) internal_error_handler: InternalErrorHandler,
+}
+
+impl EntryBuilder {
+    /// Creates a new builder that sets the hostname of the entries to `hostname`, that obtains
+    /// timestamps from `clock`, and that rewrites their filenames with `filename_transform`.
+    ///
+    /// The uptime of the entries is measured from the moment this is called.
+    pub(crate) fn new(
+        hostname: String,
+        clock: Arc<dyn Clock + Send + Sync + 'static>,
+        filename_transform: FilenameTransform,
+    ) -> Self {
+        let start = clock.monotonic();
+        Self {
+            hostname: Arc::from(hostname),
+            clock,
+            start,
+            filename_transform,
+            captured_fields: CapturedFields::default(),
+            message_formatter: None,
+            trace_context: None,
+            ulid_generator: None,
+            utc_offset: None,
+            clock_watch: Arc::from(ClockWatch::new(Some(DEFAULT_CLOCK_JUMP_THRESHOLD), false)),
+            internal_error_handler: stderr_error_handler(),
+        }
+    }
+
+    /// Returns the timestamp for a new entry, adjusted for backward jumps of the clock.
+    pub(crate) fn now(&self) -> OffsetDateTime {
+        self.clock_watch.check(self.clock.now_utc(), &self.internal_error_handler)
+    }
+
+    /// Returns the number of nanoseconds elapsed since the builder was created, saturating at
+    /// `i64::MAX` (which would take almost three centuries to reach).
+    pub(crate) fn uptime_nanos(&self) -> i64 {
+        let elapsed = self.clock.monotonic().saturating_sub(self.start);
+        i64::try_from(elapsed.as_nanos()).unwrap_or(i64::MAX)
+    }
+
+    /// Returns the trace and span identifiers to attach to an entry emitted from the current
+    /// thread.
+    pub(crate) fn trace_ids(&self) -> (Option<String>, Option<String>) {
+        match self.trace_context.as_ref().and_then(|extractor| extractor()) {
+            Some(context) => (Some(context.trace_id), Some(context.span_id)),
+            None => (None, None),
+        }
+    }
+
+    /// Returns the ULID to attach to an entry emitted at `timestamp`, if ULIDs are generated.
+    pub(crate) fn ulid(&self, timestamp: OffsetDateTime) -> Option<String> {
+        self.ulid_generator.as_ref().map(|generator| generator.generate(timestamp))
+    }
+
+    /// Builds a new entry timestamped now with the given `level`, origin, and `message`.
+    ///
+    /// The origin fields are dropped if they are not captured, the filename is rewritten, and the
+    /// message is passed through the formatter.
+    pub(crate) fn build(
+        &self,
+        level: Level,
+        module: Option<Cow<'static, str>>,
+        filename: Option<Cow<'static, str>>,
+        line: Option<u32>,
+        message: String,
+    ) -> LogEntry {
+        let fields = self.captured_fields;
+        let (trace_id, span_id) = self.trace_ids();
+        let timestamp = self.now();
+        LogEntry {
+            timestamp,
+            hostname: self.hostname.clone(),
+            level,
+            module: fields.module.then(|| module.unwrap_or(Cow::Borrowed(""))),
+            filename: fields
+                .filename
+                .then(|| self.filename_transform.apply_cow(filename.unwrap_or(Cow::Borrowed("")))),
+            line: line.filter(|_| fields.line),
+            message: match self.message_formatter.as_ref() {
+                Some(formatter) => formatter(&message),
+                None => message,
+            },
+            uptime_nanos: Some(self.uptime_nanos()),
+            utc_offset: self.utc_offset,
+            trace_id,
+            span_id,
+            ulid: self.ulid(timestamp),
+            correlation_id: None,
+            payload: None,
+            fields: None,
+        }
+    }
+}
+
+/// Runtime owned by the logger to write log entries away from the application's runtime.
+struct DedicatedRuntime(Option<tokio::runtime::Runtime>);
+
+impl DedicatedRuntime {
+    /// Creates a new runtime with a single worker thread, which runs the writers spawned by the
+    /// recorder while the recorder itself runs on its own thread via `Runtime::block_on`.
+    fn new() -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("db_logger-writer")
+            .enable_all()
+            .build()?;
+        Ok(Self(Some(runtime)))
+    }
+
+    /// Returns the underlying runtime.
+    fn get(&self) -> &tokio::runtime::Runtime {
+        self.0.as_ref().expect("Runtime only taken on drop")
+    }
+}
+
+impl Drop for DedicatedRuntime {
+    fn drop(&mut self) {
+        // Dropping a runtime waits for its tasks, which is not allowed from an async context and
+        // the logger may well be dropped from one.  There is nothing left to wait for anyway
+        // because the recorder drains its writers before terminating.
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Runtime on which the recorder runs.
+#[derive(Clone)]
+enum RecorderRuntime {
+    /// The recorder is a task of the runtime that created the logger.
+    Shared(tokio::runtime::Handle),
+
+    /// The recorder runs on its own thread and spawns its writers on a runtime owned by the logger.
+    Dedicated(Arc<DedicatedRuntime>),
+}
+
+impl RecorderRuntime {
+    /// Creates a dedicated runtime if requested by `dedicated`, or uses the current runtime
+    /// otherwise or if the dedicated runtime cannot be created, which is reported to `on_error`.
+    fn new(dedicated: bool, on_error: &InternalErrorHandler) -> Self {
+        if dedicated {
+            match DedicatedRuntime::new() {
+                Ok(runtime) => return RecorderRuntime::Dedicated(Arc::from(runtime)),
+                Err(e) => report(
+                    on_error,
+                    InternalErrorKind::Recorder,
+                    format!("Failed to create dedicated runtime for the logger: {}", e),
+                ),
+            }
+        }
+        RecorderRuntime::Shared(tokio::runtime::Handle::current())
+    }
+
+    /// Runs `recorder` to completion in the background, reporting failures to start it to
+    /// `on_error`.
+    fn spawn<F>(&self, recorder: F, on_error: &InternalErrorHandler)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self {
+            RecorderRuntime::Shared(handle) => {
+                handle.spawn(recorder);
+            }
+            RecorderRuntime::Dedicated(runtime) => {
+                let runtime = runtime.clone();
+                let result = thread::Builder::new()
+                    .name("db_logger-recorder".to_owned())
+                    .spawn(move || runtime.get().block_on(recorder));
+                if let Err(e) = result {
+                    // The recorder's channel is gone so the logger notices the missing recorder.
+                    report(
+                        on_error,
+                        InternalErrorKind::Recorder,
+                        format!("Failed to start thread for the logger: {}", e),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The logger installed by `init_with_options`, which is never dropped.
+static GLOBAL_LOGGER: OnceLock<DbLogger> = OnceLock::new();
+
+/// Logger registered with the `log` facade that forwards to `GLOBAL_LOGGER`.
+///
+/// This exists so that `init_with_options` can claim the facade before spawning the recorder: if
+/// another logger is already installed, initialization fails without leaving anything running.
+/// Records that arrive before `GLOBAL_LOGGER` is set are dropped, but none should because the
+/// maximum level of the facade stays at `Off` until then.
+struct GlobalLogger;
+
+impl Log for GlobalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        GLOBAL_LOGGER.get().is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = GLOBAL_LOGGER.get() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = GLOBAL_LOGGER.get() {
+            logger.flush();
+        }
+    }
+}
+
+/// Implementation of a database-backed logger.
+///
+/// There should only be one instance of this object, which is persisted in `GLOBAL_LOGGER`.  As a
+/// result, this object gets never dropped.
+struct DbLogger {
+    builder: EntryBuilder,
+    action_tx: ActionSender,
+    dropped: Arc<AtomicU64>,
+    metrics: Arc<MetricsRecorder>,
+    max_level: SharedLevel,
+    module_levels: ModuleLevels,
+    dedup: Option<Arc<Deduplicator>>,
+    sync_errors: bool,
+    correlation_id_key: Option<String>,
+}
+
+impl DbLogger {
+    /// Creates a new logger backed by `db` that obtains timestamps from `clock`, that sets the
+    /// hostname of the entries to `hostname`, and that rewrites their filenames with
+    /// `filename_transform`.  The recorder is configured with `opts`.
+    async fn new(
+        hostname: String,
+        db: Connection,
+        clock: Arc<dyn Clock + Send + Sync + 'static>,
+        filename_transform: FilenameTransform,
+        opts: RecorderOptions,
+    ) -> Self {
+        let dropped = Arc::from(AtomicU64::new(0));
+        let metrics = Arc::from(MetricsRecorder::default());
+
+        let kind = opts.channel;
+        let restart_recorder = opts.restart_recorder;
+        let on_error = opts.internal_error_handler.clone();
+        let spawn_recorder = {
+            let dropped = dropped.clone();
+            let metrics = metrics.clone();
+            let runtime = RecorderRuntime::new(opts.dedicated_runtime, &on_error);
+            move || {
+                let (tx, action_rx) = channel_of_kind(kind);
+                let db = db.0.clone();
+                let opts = opts.clone();
+                let dropped = dropped.clone();
+                let metrics = metrics.clone();
+                let on_error = opts.internal_error_handler.clone();
+                runtime.spawn(
+                    async move {
+                        recorder(db, action_rx, opts, dropped, metrics).await;
+                    },
+                    &on_error,
+                );
+                tx
+            }
+        };
+        let tx = spawn_recorder();
+        let respawn: Option<RecorderSpawner> = if restart_recorder {
+            let on_error = on_error.clone();
+            Some(Arc::new(move || {
+                report(
+                    &on_error,
+                    InternalErrorKind::Recorder,
+                    "Log recorder died unexpectedly; restarting it".to_owned(),
+                );
+                spawn_recorder()
+            }))
+        } else {
+            None
+        };
+        let action_tx = ActionSender::new(tx, respawn);
+
+        let max_level = SharedLevel::new(LevelFilter::Trace);
+        let mut builder = EntryBuilder::new(hostname, clock, filename_transform);
+        builder.internal_error_handler = on_error;
+        Self {
+            builder,
+            action_tx,
+            dropped,
+            metrics,
+            max_level,
+            module_levels: ModuleLevels::default(),
+            dedup: None,
+            sync_errors: false,
+            correlation_id_key: None,
+        }
+    }
+}
+
+impl Log for DbLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.module_levels.enabled(metadata.target(), metadata.level(), &self.max_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Skip logs emitted by the database-persistence code as they would cause us to recurse and
+        // never finish logging.
+        if is_recorder_log(record) {
+            if record.level() <= Level::Warn {
+                report(
+                    &self.builder.internal_error_handler,
+                    InternalErrorKind::Recursion,
+                    format!(
+                        "Non-persisted log entry: {:?} {} {:?} {:?}:{:?} {}",
+                        self.builder.clock.now_utc(),
+                        record.level(),
+                        record.module_path_static(),
+                        record.file_static(),
+                        record.line(),
+                        record.args(),
+                    ),
+                );
+            }
+            return;
+        }
+        let level = record.level();
+        let mut message = format_message(record.args());
+        let (correlation_id, fields) = visit_key_values(
+            record,
+            &mut message,
+            self.correlation_id_key.as_deref(),
+            self.builder.captured_fields.key_values,
+        );
+        let mut entry = self.builder.build(
+            level,
+            static_or_owned(record.module_path_static(), record.module_path()),
+            static_or_owned(record.file_static(), record.file()),
+            record.line(),
+            message,
+        );
+        entry.correlation_id = correlation_id;
+        entry.fields = fields;
+        match self.dedup.as_deref() {
+            Some(dedup) => {
+                for entry in dedup.check(entry) {
+                    self.action_tx.send(Action::Record(Box::new(entry))).unwrap();
+                }
+            }
+            None => self.action_tx.send(Action::Record(Box::new(entry))).unwrap(),
+        }
+
+        if self.sync_errors && level == Level::Error {
+            // Write errors have already been reported by the recorder.
+            let _ = send_and_wait(&self.action_tx, Action::Flush);
+        }
+    }
+
+    fn flush(&self) {
+        record_repeated(self.dedup.as_deref(), &self.action_tx);
+        // Write errors have already been reported by the recorder.
+        let _ = send_and_wait(&self.action_tx, Action::Flush);
+    }
+}
+
+/// Formats the message of a log record from its `args`.
+///
+/// Messages without arguments are copied as is, which skips the formatting machinery.
+fn format_message(args: &fmt::Arguments) -> String {
+    match args.as_str() {
+        Some(message) => message.to_owned(),
+        None => args.to_string(),
+    }
+}
+
+/// Builds the entry that marks the start of a logging session with `builder`, which records entries
+/// up to `max_level` into a database of type `backend`.
+fn startup_entry(builder: &EntryBuilder, max_level: LevelFilter, backend: &str) -> LogEntry {
+    let message = format!(
+        "Logging started: version={} hostname={} level={} batch_size={} backend={}",
+        env!("CARGO_PKG_VERSION"),
+        builder.hostname,
+        max_level,
+        MAX_BATCH_SIZE,
+        backend
+    );
+    builder.build(Level::Info, Some(Cow::Borrowed(module_path!())), None, None, message)
+}
+
+/// Visitor of the key-values of a log record that appends the errors among them to a message and
+/// that extracts the correlation identifier and the structured fields.
+struct KeyValuesVisitor<'a> {
+    /// Message to extend.
+    message: &'a mut String,
+
+    /// Key that holds the correlation identifier, if any.
+    correlation_id_key: Option<&'a str>,
+
+    /// Correlation identifier found so far.
+    correlation_id: Option<String>,
+
+    /// Fields collected so far, or `None` if they are not captured.
+    fields: Option<Fields>,
+}
+
+impl<'kvs> VisitSource<'kvs> for KeyValuesVisitor<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        if self.correlation_id.is_none() && self.correlation_id_key == Some(key.as_str()) {
+            self.correlation_id = Some(value.to_string());
+        }
+        if let Some(fields) = self.fields.as_mut() {
+            fields.insert(key.as_str().to_owned(), value.to_string());
+        }
+
+        let mut next = value.to_borrowed_error();
+        while let Some(err) = next {
+            self.message.push_str(": ");
+            self.message.push_str(&err.to_string());
+            next = err.source();
+        }
+        Ok(())
+    }
+}
+
+/// Appends the errors attached to `record` as key-values to `message`, each followed by its chain
+/// of sources, so that the causes of the errors are not lost.
+///
+/// Returns the value of the first key-value named `correlation_id_key`, if any, and all the
+/// key-values as structured fields if `capture_fields` is true and the record has any.
+fn visit_key_values(
+    record: &Record,
+    message: &mut String,
+    correlation_id_key: Option<&str>,
+    capture_fields: bool,
+) -> (Option<String>, Option<Fields>) {
+    let mut visitor = KeyValuesVisitor {
+        message,
+        correlation_id_key,
+        correlation_id: None,
+        fields: capture_fields.then(Fields::new),
+    };
+    // The visitor never fails.
+    let _ = record.key_values().visit(&mut visitor);
+    (visitor.correlation_id, visitor.fields.filter(|fields| !fields.is_empty()))
+}
+
+/// Configures the global logger to use a new instance backed by the database connection `db`.
+///
+/// Logger configuration happens via environment variables and tries to respect the same
+/// variables that `env_logger` recognizes.  Misconfigured variables result in a fatal error.
+pub async fn init(db: Connection) -> Handle {
+    // The default options do not touch the database so initialization cannot fail.
+    init_with_options(db, LoggerOptions::default())
+        .await
+        .expect("Initialization with the default options cannot fail")
+}
+
+/// Configures the global logger to use a new instance backed by the database connection `db` and
+/// customized with `opts`.
+///
+/// See `init` for details on how environment variables are handled, and see `LoggerOptions` for
+/// details on how the options interact with them.
+///
+/// Returns an error, without installing the logger, if `opts` requests to set up or verify the
+/// database schema and that fails, or if `db` ignores duplicate entries but `opts` does not enable
+/// `record_ulid`, without which duplicates cannot be identified.  Also returns an error, without
+/// starting the background task that persists the entries, if a logger has already been installed
+/// in the `log` facade.
+pub async fn init_with_options(db: Connection, opts: LoggerOptions) -> Result<Handle> {
+    if db.0.ignores_duplicates() && !opts.record_ulid {
+        return Err(DbError::Other(
+            "ignore_duplicates requires LoggerOptions::record_ulid to identify duplicate entries"
+                .to_owned(),
+            None,
+        ));
+    }
+
+    let RustLog { level: max_level, modules: module_levels } = env_rust_log(&opts);
+    let mirror_format = env_db_logger_format(&opts);
+
+    if opts.ensure_schema {
+        db.ensure_schema().await?;
+    } else if opts.verify_schema {
+        db.verify_schema().await?;
+    }
+
+    let hostname = get_hostname(&opts).await;
+
+    // Claim the facade before spawning the recorder so that a failure leaves nothing running.
+    log::set_logger(&GlobalLogger).map_err(|e| {
+        DbError::Other(format!("Cannot install the logger: {}", e), Some(Box::new(e)))
+    })?;
+
+    let on_error = opts.error_handler();
+    let recorder_opts = RecorderOptions {
+        flush_delay: opts.flush_delay,
+        max_buffered_entries: opts.max_buffered_entries,
+        overflow_policy: opts.overflow_policy,
+        max_batch_bytes: opts.max_batch_bytes,
+        flush_on_errors: opts.flush_on_errors,
+        mirror: opts.mirror,
+        mirror_format,
+        max_write_retries: opts.max_write_retries,
+        ordered_writes: opts.ordered_writes,
+        shutdown_retries: opts.shutdown_retries,
+        shutdown_timeout: opts.shutdown_timeout,
+        entry_transforms: opts.entry_transforms,
+        on_persist: opts.on_persist,
+        internal_error_handler: on_error.clone(),
+        channel: opts.channel,
+        restart_recorder: opts.restart_recorder,
+        dedicated_runtime: opts.dedicated_runtime,
+    };
+    let mut logger = DbLogger::new(
+        hostname,
+        db.clone(),
+        Arc::from(SystemClock::default()),
+        opts.filename_transform,
+        recorder_opts,
+    )
+    .await;
+    logger.max_level.set(max_level);
+    logger.module_levels = module_levels;
+    logger.dedup = opts.dedup_window.map(|window| Arc::from(Deduplicator::new(window)));
+    logger.sync_errors = opts.sync_errors;
+    logger.correlation_id_key = opts.correlation_id_key;
+    logger.builder.message_formatter = opts.message_formatter;
+    logger.builder.trace_context = opts.trace_context;
+    logger.builder.ulid_generator = opts.record_ulid.then(|| Arc::from(UlidGenerator::new()));
+    logger.builder.captured_fields = opts.captured_fields;
+    logger.builder.utc_offset = opts.utc_offset.resolve(&on_error);
+    logger.builder.clock_watch =
+        Arc::from(ClockWatch::new(opts.clock_jump_threshold, opts.clamp_backward_timestamps));
+    if opts.startup_entry {
+        // Send the entry straight to the recorder so that it precedes any other entry and so that
+        // it is not subject to the filters of the `log` facade.
+        let entry = startup_entry(&logger.builder, max_level, db.0.backend_name());
+        logger.action_tx.send(Action::Record(Box::new(entry))).unwrap();
+    }
+    let handle = Handle::new(&logger, db);
+
+    if GLOBAL_LOGGER.set(logger).is_err() {
+        unreachable!("The facade only accepts one logger so GLOBAL_LOGGER cannot be set yet");
+    }
+    log::set_max_level(max_level.max(handle.module_levels.max()));
+    Ok(handle)
+}
+
+#[cfg(test)]
+#[cfg(feature = "sqlite")]
+mod tests {
+    //! Unit-tests for the database-backed logger.
+    //!
+    //! These tests do not verify the interaction with the `log` facade because it's impossible to
+    //! do so in this context: the logger is a global resource and we cannot run independent tests
+    //! for it.  See the separate `logger_test.rs` program for integration tests against the real
+    //! test database.
+
+    use super::*;
+    use crate::clocks::MonotonicClock;
+    use crate::sqlite;
+    use log::RecordBuilder;
+
+    /// Sets up an in-memory database.
+    async fn setup_db() -> Connection {
+        let db = sqlite::connect(sqlite::ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        db
+    }
+
+    /// Sets up the logger backing it with an in-memory database and a fake clock, and configures
+    /// the recorder with `opts`.
+    async fn setup_with_options(opts: RecorderOptions) -> (DbLogger, Connection) {
+        let db = setup_db().await;
+        let clock = Arc::from(MonotonicClock::new(1000));
+        let logger = DbLogger::new(
+            "fake-hostname".to_owned(),
+            db.clone(),
+            clock,
+            FilenameTransform::default(),
+            opts,
+        )
+        .await;
+        (logger, db)
+    }
+
+    /// Sets up the logger backing it with an in-memory database and a fake clock.
+    async fn setup() -> (DbLogger, Connection) {
+        setup_with_options(RecorderOptions::default()).await
+    }
+
+    /// Receiver of the messages of every batch that the recorder persists.
+    type PersistedRx = tokio::sync::mpsc::UnboundedReceiver<Vec<String>>;
+
+    /// Returns an `on_persist` callback that sends the messages of every persisted batch to the
+    /// returned receiver, which lets tests wait for background writes without polling.
+    fn persisted_channel() -> (PersistCallback, PersistedRx) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let on_persist: PersistCallback = Arc::new(move |entries: &[LogEntry]| {
+            // An error means that the test no longer cares about persisted entries.
+            let _ = tx.send(entries.iter().map(|entry| entry.message.clone()).collect());
+        });
+        (on_persist, rx)
+    }
+
+    /// Waits until `persisted` reports at least `count` entries and returns their messages in the
+    /// order in which they were persisted.
+    async fn wait_persisted(persisted: &mut PersistedRx, count: usize) -> Vec<String> {
+        let mut messages = vec![];
+        while messages.len() < count {
+            let batch = tokio::time::timeout(Duration::from_secs(10), persisted.recv())
+                .await
+                .expect("Timed out waiting for entries to be persisted")
+                .expect("The recorder is gone");
+            messages.extend(batch);
+        }
+        messages
+    }
+
+    /// Database that panics on the first `panics` writes and then forwards all operations to
+    /// another database.
+    ///
+    /// Writes that include an entry with the message in `hold`, if any, wait for its notification
+    /// before proceeding.
+    struct PanickingDb {
+        inner: Connection,
+        panics: AtomicUsize,
+        hold: Option<(String, Arc<tokio::sync::Notify>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl Db for PanickingDb {
+        async fn create_schema(&self) -> Result<()> {
+            self.inner.0.create_schema().await
+        }
+
+        async fn verify_schema(&self) -> Result<()> {
+            self.inner.0.verify_schema().await
+        }
+
+        async fn migrate_schema(&self) -> Result<()> {
+            self.inner.0.migrate_schema().await
+        }
+
+        async fn get_log_entries(&self) -> Result<Vec<String>> {
+            self.inner.0.get_log_entries().await
+        }
+
+        async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+            self.inner.0.query_log_entries(filter).await
+        }
+
+        fn stream_log_entries(
+            &self,
+            filter: &LogFilter,
+        ) -> futures::stream::BoxStream<'static, Result<LogEntryRow>> {
+            self.inner.0.stream_log_entries(filter)
+        }
+
+        async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+            self.inner.0.level_counts(filter).await
+        }
+
+        async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+            self.inner.0.distinct_hostnames().await
+        }
+
+        async fn distinct_modules(&self) -> Result<Vec<String>> {
+            self.inner.0.distinct_modules().await
+        }
+
+        async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+            self.inner.0.time_bounds().await
+        }
+
+        async fn storage_stats(&self) -> Result<StorageStats> {
+            self.inner.0.storage_stats().await
+        }
+
+        async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
+            self.put_prepared_log_entries(self.prepare_log_entries(entries)).await
+        }
+
+        fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+            self.inner.0.prepare_log_entries(entries)
+        }
+
+        async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+            if let Some((message, release)) = &self.hold {
+                if entries.iter().any(|(_, entry)| entry.message == *message) {
+                    release.notified().await;
+                }
+            }
+            let panics = self.panics.load(Ordering::SeqCst);
+            if panics > 0 {
+                self.panics.store(panics - 1, Ordering::SeqCst);
+                panic!("Injected write failure");
+            }
+            self.inner.0.put_prepared_log_entries(entries).await
+        }
+
+        async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64> {
+            self.inner.0.archive_log_entries_before(ts).await
+        }
+
+        async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+            self.inner.0.delete_log_entries(filter).await
+        }
+
+        async fn clear_log_entries(&self) -> Result<()> {
+            self.inner.0.clear_log_entries().await
+        }
+
+        fn max_params_per_statement(&self) -> usize {
+            self.inner.0.max_params_per_statement()
+        }
+
+        fn backend_name(&self) -> &'static str {
+            self.inner.0.backend_name()
+        }
+
+        fn rejected_entries(&self) -> u64 {
+            self.inner.0.rejected_entries()
+        }
+
+        fn ignores_duplicates(&self) -> bool {
+            self.inner.0.ignores_duplicates()
+        }
+
+        fn encode_level(&self, level: Level) -> i64 {
+            self.inner.0.encode_level(level)
+        }
+
+        fn decode_level(&self, level: i64) -> Result<Level> {
+            self.inner.0.decode_level(level)
+        }
+
+        fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+            self.inner.0.encode_fields(fields)
+        }
+
+        fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+            self.inner.0.decode_fields(data)
+        }
+
+        async fn maintenance(&self) -> Result<()> {
+            self.inner.0.maintenance().await
+        }
+    }
+
+    /// Sets up the logger backed by a database whose first `panics` writes panic, and configures
+    /// the recorder with `opts`.
+    async fn setup_panicking(panics: usize, opts: RecorderOptions) -> (DbLogger, Connection) {
+        setup_faulty(panics, None, opts).await
+    }
+
+    /// Sets up the logger backed by a database whose writes of the entry with `message` wait for
+    /// `release` to be notified, and configures the recorder with `opts`.
+    async fn setup_holding(
+        message: &str,
+        release: Arc<tokio::sync::Notify>,
+        opts: RecorderOptions,
+    ) -> (DbLogger, Connection) {
+        setup_faulty(0, Some((message.to_owned(), release)), opts).await
+    }
+
+    /// Sets up the logger backed by a `PanickingDb` configured with `panics` and `hold`, and
+    /// configures the recorder with `opts`.
+    async fn setup_faulty(
+        panics: usize,
+        hold: Option<(String, Arc<tokio::sync::Notify>)>,
+        opts: RecorderOptions,
+    ) -> (DbLogger, Connection) {
+        let db = setup_db().await;
+        let panicking = Connection(Arc::from(PanickingDb {
+            inner: db.clone(),
+            panics: AtomicUsize::new(panics),
+            hold,
+        }));
+        let logger = DbLogger::new(
+            "fake-hostname".to_owned(),
+            panicking,
+            Arc::from(MonotonicClock::new(1000)),
+            FilenameTransform::default(),
+            opts,
+        )
+        .await;
+        (logger, db)
+    }
+
+    /// Emits one single log entry at every possible level.
+    fn emit_all_log_levels(logger: &dyn Log) {
+        for (level, message) in &[
+            (Level::Error, "An error message"),
+            (Level::Warn, "A warning message"),
+            (Level::Info, "An info message"),
+            (Level::Debug, "A debug message"),
+            (Level::Trace, "A trace message"),
+        ] {
+            logger.log(
+                &RecordBuilder::new()
+                    .level(*level)
+                    .module_path_static(Some("the-module"))
+                    .file_static(Some("the-file"))
+                    .line(Some(123))
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stop_persists_buffered_entries() {
+        let (logger, db) = setup().await;
+
+        emit_all_log_levels(&logger);
+
+        // Stop the recorder without flushing first: the entries should be persisted anyway.
+        send_and_wait(&logger.action_tx, Action::Stop);
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+                "1003.0 fake-hostname 4 the-module the-file:123 A debug message".to_owned(),
+                "1004.0 fake-hostname 5 the-module the-file:123 A trace message".to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_log_entry_new() {
+        let timestamp = OffsetDateTime::from_unix_timestamp(1000).unwrap();
+        let entry = LogEntry::new(timestamp, "the-host", Level::Warn, "the message");
+        assert_eq!(timestamp, entry.timestamp);
+        assert_eq!("the-host", entry.hostname.as_ref());
+        assert_eq!(Level::Warn, entry.level);
+        assert_eq!("the message", entry.message);
+        assert!(entry.module.is_none());
+        assert!(entry.ulid.is_none());
+        assert!(entry.payload.is_none());
+    }
+
+    #[test]
+    fn test_parse_rust_log_from_env() {
+        let opts = LoggerOptions::default();
+        assert_eq!(LevelFilter::Debug, parse_rust_log(Ok("debug".to_owned()), &opts).level);
+        assert_eq!(LevelFilter::Trace, parse_rust_log(Ok("TRACE".to_owned()), &opts).level);
+    }
+
+    #[test]
+    fn test_parse_rust_log_default() {
+        let opts = LoggerOptions { default_level: Level::Info, ..Default::default() };
+        assert_eq!(LevelFilter::Info, parse_rust_log(Err(env::VarError::NotPresent), &opts).level);
+        assert_eq!(LevelFilter::Info, parse_rust_log(Ok("bogus=what".to_owned()), &opts).level);
+
+        let opts = LoggerOptions::default();
+        assert_eq!(LevelFilter::Warn, parse_rust_log(Err(env::VarError::NotPresent), &opts).level);
+    }
+
+    #[test]
+    fn test_parse_rust_log_ignore_env() {
+        let opts =
+            LoggerOptions { default_level: Level::Info, ignore_env: true, ..Default::default() };
+        assert_eq!(
+            RustLog { level: LevelFilter::Info, modules: ModuleLevels::default() },
+            parse_rust_log(Ok("trace,foo=debug".to_owned()), &opts)
+        );
+        assert_eq!(LevelFilter::Info, parse_rust_log(Err(env::VarError::NotPresent), &opts).level);
+    }
+
+    #[test]
+    fn test_parse_rust_log_modules() {
+        let opts = LoggerOptions { default_level: Level::Info, ..Default::default() };
+
+        let rust_log = parse_rust_log(Ok("foo=debug, foo::bar=off,baz,error".to_owned()), &opts);
+        assert_eq!(LevelFilter::Error, rust_log.level);
+        assert_eq!(
+            ModuleLevels::new(vec![
+                ("foo".to_owned(), LevelFilter::Debug),
+                ("foo::bar".to_owned(), LevelFilter::Off),
+                ("baz".to_owned(), LevelFilter::Trace),
+            ]),
+            rust_log.modules
+        );
+
+        let rust_log = parse_rust_log(Ok("foo=debug".to_owned()), &opts);
+        assert_eq!(LevelFilter::Info, rust_log.level);
+        assert_eq!(LevelFilter::Debug, rust_log.modules.max());
+    }
+
+    #[test]
+    fn test_parse_rust_log_off() {
+        let opts = LoggerOptions { default_level: Level::Info, ..Default::default() };
+
+        let rust_log = parse_rust_log(Ok("off".to_owned()), &opts);
+        assert_eq!(LevelFilter::Off, rust_log.level);
+        assert_eq!(ModuleLevels::default(), rust_log.modules);
+
+        let rust_log = parse_rust_log(Ok("OFF,foo=debug".to_owned()), &opts);
+        assert_eq!(LevelFilter::Off, rust_log.level);
+        assert_eq!(Some(LevelFilter::Debug), rust_log.modules.get("foo"));
+    }
+
+    #[test]
+    fn test_parse_rust_log_directives_errors() {
+        for (value, exp_error) in
+            [("=debug", "missing module name"), ("foo=bogus", "bad level in 'foo=bogus'")]
+        {
+            let e = parse_rust_log_directives(value).unwrap_err();
+            assert!(e.contains(exp_error), "{} does not contain {}", e, exp_error);
+        }
+    }
+
+    #[test]
+    fn test_module_levels_get() {
+        let levels = ModuleLevels::new(vec![
+            ("foo".to_owned(), LevelFilter::Debug),
+            ("foo::bar".to_owned(), LevelFilter::Off),
+            ("foo".to_owned(), LevelFilter::Info),
+        ]);
+        assert_eq!(Some(LevelFilter::Info), levels.get("foo"));
+        assert_eq!(Some(LevelFilter::Info), levels.get("foo::baz"));
+        assert_eq!(Some(LevelFilter::Off), levels.get("foo::bar"));
+        assert_eq!(Some(LevelFilter::Off), levels.get("foo::bar::qux"));
+        assert_eq!(None, levels.get("foobar"));
+        assert_eq!(None, levels.get("other"));
+        assert_eq!(LevelFilter::Info, levels.max());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_module_levels_filter_entries() {
+        let (mut logger, db) = setup().await;
+        logger.max_level.set(LevelFilter::Warn);
+        logger.module_levels = ModuleLevels::new(vec![
+            ("chatty".to_owned(), LevelFilter::Off),
+            ("verbose".to_owned(), LevelFilter::Debug),
+        ]);
+
+        for module in ["chatty::inner", "verbose", "other"] {
+            for level in [Level::Error, Level::Info, Level::Debug] {
+                logger.log(
+                    &RecordBuilder::new()
+                        .level(level)
+                        .target(module)
+                        .module_path(Some(module))
+                        .file_static(Some("the-file"))
+                        .line(Some(123))
+                        .args(format_args!("{}", level))
+                        .build(),
+                );
+            }
+        }
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 verbose the-file:123 ERROR".to_owned(),
+                "1001.0 fake-hostname 3 verbose the-file:123 INFO".to_owned(),
+                "1002.0 fake-hostname 4 verbose the-file:123 DEBUG".to_owned(),
+                "1003.0 fake-hostname 1 other the-file:123 ERROR".to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_parse_db_logger_format_from_env() {
+        let opts = LoggerOptions::default();
+        assert_eq!(
+            MirrorFormat::default(),
+            parse_db_logger_format(Err(env::VarError::NotPresent), &opts)
+        );
+        assert_eq!(
+            MirrorFormat { timestamp: false, module: true, color: true },
+            parse_db_logger_format(Ok("no-timestamp,color".to_owned()), &opts)
+        );
+        assert_eq!(MirrorFormat::default(), parse_db_logger_format(Ok("bogus".to_owned()), &opts));
+    }
+
+    #[test]
+    fn test_parse_db_logger_format_overrides() {
+        let format = MirrorFormat { timestamp: false, module: false, color: false };
+        let opts = LoggerOptions { mirror_format: Some(format), ..Default::default() };
+        assert_eq!(format, parse_db_logger_format(Ok("color".to_owned()), &opts));
+
+        let opts = LoggerOptions { ignore_env: true, ..Default::default() };
+        assert_eq!(MirrorFormat::default(), parse_db_logger_format(Ok("color".to_owned()), &opts));
+    }
+
+    #[tokio::test]
+    async fn test_get_hostname_not_recorded() {
+        let opts = LoggerOptions {
+            record_hostname: false,
+            hostname_fallback: "fallback".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!("", get_hostname(&opts).await);
+    }
+
+    #[test]
+    fn test_hostname_or() {
+        assert_eq!("the-host", hostname_or(Ok("the-host".to_owned()), "fallback"));
+        assert_eq!("fallback", hostname_or(Err(OsString::from("bad")), "fallback"));
+    }
+
+    #[cfg(feature = "fqdn")]
+    #[test]
+    fn test_is_fqdn_of() {
+        assert!(is_fqdn_of("the-host.example.com", "the-host"));
+        assert!(is_fqdn_of("The-Host.example.com", "the-host"));
+        assert!(!is_fqdn_of("the-host", "the-host"));
+        assert!(!is_fqdn_of("the-host.", "the-host"));
+        assert!(!is_fqdn_of("localhost.localdomain", "the-host"));
+        assert!(!is_fqdn_of("the-host-2.example.com", "the-host"));
+    }
+
+    #[test]
+    fn test_is_recorder_module() {
+        assert!(is_recorder_module(None, Level::Info));
+        assert!(is_recorder_module(Some("sqlx::query"), Level::Error));
+        assert!(is_recorder_module(Some("rustls::client"), Level::Info));
+        assert!(is_recorder_module(Some("polling::epoll"), Level::Trace));
+        assert!(!is_recorder_module(Some("polling::epoll"), Level::Debug));
+        assert!(!is_recorder_module(Some("the-module"), Level::Trace));
+        assert!(!is_recorder_module(Some("hyperdrive::engine"), Level::Trace));
+    }
+
+    #[test]
+    fn test_is_recorder_log_checks_target() {
+        let record = Record::builder()
+            .level(Level::Debug)
+            .target("sqlx::query")
+            .module_path_static(Some("sqlx_core::logger"))
+            .build();
+        assert!(is_recorder_log(&record));
+
+        let record = Record::builder()
+            .level(Level::Debug)
+            .target("the-target")
+            .module_path_static(Some("the-module"))
+            .build();
+        assert!(!is_recorder_log(&record));
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn test_is_recorder_module_net() {
+        for module in ["reqwest::connect", "hyper::proto::h1", "hyper_util::client", "h2::codec"] {
+            assert!(is_recorder_module(Some(module), Level::Debug), "{} not filtered", module);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_all_log_levels() {
+        let (logger, db) = setup().await;
+
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+                "1003.0 fake-hostname 4 the-module the-file:123 A debug message".to_owned(),
+                "1004.0 fake-hostname 5 the-module the-file:123 A trace message".to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_overflow_drop_oldest() {
+        let (logger, db) = setup_with_options(RecorderOptions {
+            max_buffered_entries: Some(3),
+            overflow_policy: OverflowPolicy::DropOldest,
+            ..Default::default()
+        })
+        .await;
+
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+                "1003.0 fake-hostname 4 the-module the-file:123 A debug message".to_owned(),
+                "1004.0 fake-hostname 5 the-module the-file:123 A trace message".to_owned(),
+            ],
+            entries
+        );
+        assert_eq!(2, logger.dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_overflow_spill_to_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let spill_file = temp.path().join("spill.ndjson");
+        let (logger, db) = setup_with_options(RecorderOptions {
+            max_buffered_entries: Some(3),
+            overflow_policy: OverflowPolicy::SpillToFile(spill_file.clone()),
+            ..Default::default()
+        })
+        .await;
+
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!(0, logger.dropped.load(Ordering::SeqCst));
+
+        let spilled = std::fs::read_to_string(spill_file).unwrap();
+        let lines = spilled.lines().collect::<Vec<&str>>();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].contains("\"message\":\"An error message\""));
+        assert!(lines[1].contains("\"message\":\"A warning message\""));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_overflow_spill_to_file_and_replay() {
+        let temp = tempfile::tempdir().unwrap();
+        let spill_file = temp.path().join("spill.ndjson");
+        let (logger, db) = setup_with_options(RecorderOptions {
+            max_buffered_entries: Some(3),
+            overflow_policy: OverflowPolicy::SpillToFile(spill_file.clone()),
+            ..Default::default()
+        })
+        .await;
+        let handle = Handle::new(&logger, db.clone());
+
+        emit_all_log_levels(&logger);
+        logger.flush();
+        assert_eq!(3, db.0.get_log_entries().await.unwrap().len());
+
+        let replay_file = temp.path().join("replay.ndjson");
+        std::fs::rename(&spill_file, &replay_file).unwrap();
+        assert_eq!(2, handle.replay_ndjson(&replay_file).await.unwrap());
+        handle.flush().unwrap();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+                "1003.0 fake-hostname 4 the-module the-file:123 A debug message".to_owned(),
+                "1004.0 fake-hostname 5 the-module the-file:123 A trace message".to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_replay_ndjson_errors() {
+        let temp = tempfile::tempdir().unwrap();
+        let (logger, db) = setup().await;
+        let handle = Handle::new(&logger, db.clone());
+
+        match handle.replay_ndjson(&temp.path().join("missing.ndjson")).await {
+            Err(DbError::Other(e, _)) => assert!(e.contains("missing.ndjson"), "{}", e),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let bad_file = temp.path().join("bad.ndjson");
+        std::fs::write(&bad_file, "{}\n").unwrap();
+        match handle.replay_ndjson(&bad_file).await {
+            Err(DbError::Other(e, _)) => assert!(e.contains("missing field `timestamp`"), "{}", e),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        handle.flush().unwrap();
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_delay_expires() {
+        let (on_persist, mut persisted) = persisted_channel();
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: Some(Duration::from_millis(1)),
+            on_persist: Some(on_persist),
+            ..Default::default()
+        })
+        .await;
+
+        emit_all_log_levels(&logger);
+
+        // Do not call flush here.  The entries should show up eventually.
+        wait_persisted(&mut persisted, 5).await;
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_delay_subsecond() {
+        let (on_persist, mut persisted) = persisted_channel();
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: Some(Duration::from_millis(100)),
+            on_persist: Some(on_persist),
+            ..Default::default()
+        })
+        .await;
+
+        let start = Instant::now();
+        emit_all_log_levels(&logger);
+
+        wait_persisted(&mut persisted, 5).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_delay_steady_traffic() {
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .await;
+
+        // Entries arrive more often than the flush delay, but the delay counts from the oldest
+        // entry so they must be written while the traffic continues.
+        let mut flushed = false;
+        for _ in 0..50 {
+            emit_all_log_levels(&logger);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if !db.0.get_log_entries().await.unwrap().is_empty() {
+                flushed = true;
+                break;
+            }
+        }
+        assert!(flushed, "Entries were not flushed while logging continuously");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_delay_disabled() {
+        let (logger, db) =
+            setup_with_options(RecorderOptions { flush_delay: None, ..Default::default() }).await;
+
+        emit_all_log_levels(&logger);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+
+        logger.flush();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_max_batch_bytes_writes_early() {
+        let (on_persist, mut persisted) = persisted_channel();
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: None,
+            max_batch_bytes: Some(30),
+            on_persist: Some(on_persist),
+            ..Default::default()
+        })
+        .await;
+
+        emit_all_log_levels(&logger);
+
+        // The first two pairs of messages exceed the limit so they must be written without an
+        // explicit flush, but the last message must remain buffered.
+        assert_eq!(4, wait_persisted(&mut persisted, 4).await.len());
+        assert_eq!(4, db.0.get_log_entries().await.unwrap().len());
+
+        logger.flush();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_on_errors_writes_early() {
+        let (on_persist, mut persisted) = persisted_channel();
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: Some(Duration::from_secs(3600)),
+            flush_on_errors: Some(2),
+            on_persist: Some(on_persist),
+            ..Default::default()
+        })
+        .await;
+
+        // The second error completes the threshold so the entries up to it must be written well
+        // before the flush delay expires, but the entries after it must remain buffered.
+        emit_all_log_levels(&logger);
+        emit_all_log_levels(&logger);
+        assert_eq!(6, wait_persisted(&mut persisted, 6).await.len());
+        assert_eq!(6, db.0.get_log_entries().await.unwrap().len());
+
+        logger.flush();
+        assert_eq!(10, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sync_errors() {
+        let (mut logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        })
+        .await;
+        logger.sync_errors = true;
+
+        // The error is the first entry emitted so it must be persisted as soon as the logging call
+        // returns, but the entries of lower levels that follow it must remain buffered.
+        emit_all_log_levels(&logger);
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(1, entries.len());
+        assert!(entries[0].contains("An error message"), "Unexpected entry {}", entries[0]);
+
+        logger.flush();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[test]
+    fn test_filename_transform_keep() {
+        let transform = FilenameTransform::Keep;
+        assert_eq!("/build/src/main.rs", transform.apply("/build/src/main.rs"));
+    }
+
+    #[test]
+    fn test_filename_transform_basename() {
+        let transform = FilenameTransform::Basename;
+        assert_eq!("main.rs", transform.apply("/build/src/main.rs"));
+        assert_eq!("main.rs", transform.apply("C:\\build\\src\\main.rs"));
+        assert_eq!("main.rs", transform.apply("main.rs"));
+        assert_eq!("", transform.apply(""));
+    }
+
+    #[test]
+    fn test_filename_transform_strip_prefix() {
+        let transform = FilenameTransform::StripPrefix("/build".to_owned());
+        assert_eq!("src/main.rs", transform.apply("/build/src/main.rs"));
+        assert_eq!("/other/src/main.rs", transform.apply("/other/src/main.rs"));
+
+        let transform = FilenameTransform::StripPrefix("/build/".to_owned());
+        assert_eq!("src/main.rs", transform.apply("/build/src/main.rs"));
+    }
+
+    #[test]
+    fn test_filename_transform_apply_cow() {
+        let transform = FilenameTransform::Basename;
+        match transform.apply_cow(Cow::Borrowed("/build/src/main.rs")) {
+            Cow::Borrowed(filename) => assert_eq!("main.rs", filename),
+            Cow::Owned(filename) => panic!("Static filename {} was copied", filename),
+        }
+        assert_eq!("main.rs", transform.apply_cow(Cow::Owned("/build/src/main.rs".to_owned())));
+    }
+
+    #[test]
+    fn test_static_or_owned() {
+        match static_or_owned(Some("static"), Some("static")) {
+            Some(Cow::Borrowed(value)) => assert_eq!("static", value),
+            value => panic!("Unexpected value {:?}", value),
+        }
+        match static_or_owned(None, Some("dynamic")) {
+            Some(Cow::Owned(value)) => assert_eq!("dynamic", value),
+            value => panic!("Unexpected value {:?}", value),
+        }
+        assert_eq!(None, static_or_owned(None, None));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_max_level_changes_at_runtime() {
+        let (logger, db) = setup().await;
+
+        logger.max_level.set(LevelFilter::Warn);
+        emit_all_log_levels(&logger);
+        logger.max_level.set(LevelFilter::Info);
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1002.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1003.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1004.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_panic_message() {
+        assert_eq!("static", panic_message(&"static"));
+        assert_eq!("owned", panic_message(&"owned".to_owned()));
+        assert_eq!("Box<dyn Any>", panic_message(&42));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_panic_recorder_persists_entry() {
+        let (logger, db) = setup().await;
+
+        let recorder =
+            PanicRecorder { builder: logger.builder.clone(), action_tx: logger.action_tx.clone() };
+        thread::Builder::new()
+            .name("the-thread".to_owned())
+            .spawn(move || recorder.record("Oops", Some("the-file"), Some(42)))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        // The recorder must have flushed the entry already.
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec!["1000.0 fake-hostname 1 NO-MODULE the-file:42 thread 'the-thread' panicked: Oops"
+                .to_owned()],
+            entries
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_trace_context_extractor() {
+        let (mut logger, db) = setup().await;
+        let extractor: TraceContextExtractor = Arc::new(|| {
+            thread::current().name().filter(|name| name.starts_with("traced")).map(|name| {
+                TraceContext { trace_id: format!("{}-trace", name), span_id: "the-span".to_owned() }
+            })
+        });
+        logger.builder.trace_context = Some(extractor);
+
+        let logger = Arc::from(logger);
+        for name in ["traced-1", "untraced"] {
+            let logger = logger.clone();
+            thread::Builder::new()
+                .name(name.to_owned())
+                .spawn(move || {
+                    logger.log(
+                        &RecordBuilder::new()
+                            .level(Level::Info)
+                            .module_path_static(Some("the-module"))
+                            .args(format_args!("From {}", name))
+                            .build(),
+                    );
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+        }
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![
+                (Some("traced-1-trace"), Some("the-span"), "From traced-1"),
+                (None, None, "From untraced"),
+            ],
+            rows.iter()
+                .map(|row| (row.trace_id.as_deref(), row.span_id.as_deref(), row.message.as_str()))
+                .collect::<Vec<(Option<&str>, Option<&str>, &str)>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_record_ulid() {
+        let (mut logger, db) = setup().await;
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .args(format_args!("No ULID"))
+                .build(),
+        );
+        logger.builder.ulid_generator = Some(Arc::from(UlidGenerator::new()));
+        for i in 0..3 {
+            logger.log(
+                &RecordBuilder::new()
+                    .level(Level::Info)
+                    .module_path_static(Some("the-module"))
+                    .args(format_args!("Entry {}", i))
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(None, rows[0].ulid);
+        assert!(rows[1..].iter().all(|row| row.ulid.as_ref().unwrap().len() == 26));
+
+        let filter = LogFilter { after_ulid: Some(("".to_owned(), 0)), ..Default::default() };
+        let rows = db.0.query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 1", "Entry 2"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+
+        let filter = LogFilter {
+            after_ulid: Some((rows[0].ulid.clone().unwrap(), rows[0].sequence)),
+            ..Default::default()
+        };
+        let rows = db.0.query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 1", "Entry 2"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_dedup_collapses_repeated_entries() {
+        let (mut logger, db) = setup().await;
+        logger.dedup = Some(Arc::from(Deduplicator::new(Duration::from_secs(100))));
+
+        emit_all_log_levels(&logger);
+        emit_all_log_levels(&logger);
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec![
+                "1000.0 fake-hostname 1 the-module the-file:123 An error message".to_owned(),
+                "1001.0 fake-hostname 2 the-module the-file:123 A warning message".to_owned(),
+                "1002.0 fake-hostname 3 the-module the-file:123 An info message".to_owned(),
                 "1003.0 fake-hostname 4 the-module the-file:123 A debug message".to_owned(),
                 "1004.0 fake-hostname 5 the-module the-file:123 A trace message".to_owned(),
+                "1010.0 fake-hostname 1 the-module the-file:123 An error message (repeated 2 times)"
+                    .to_owned(),
+                "1011.0 fake-hostname 2 the-module the-file:123 A warning message (repeated 2 times)"
+                    .to_owned(),
+                "1012.0 fake-hostname 3 the-module the-file:123 An info message (repeated 2 times)"
+                    .to_owned(),
+                "1013.0 fake-hostname 4 the-module the-file:123 A debug message (repeated 2 times)"
+                    .to_owned(),
+                "1014.0 fake-hostname 5 the-module the-file:123 A trace message (repeated 2 times)"
+                    .to_owned(),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_shared_level_get_set() {
+        let level = SharedLevel::new(LevelFilter::Warn);
+        assert_eq!(LevelFilter::Warn, level.get());
+        for expected in LevelFilter::iter() {
+            level.clone().set(expected);
+            assert_eq!(expected, level.get());
+        }
+        level.set(LevelFilter::Off);
+        assert!(!level.enabled(Level::Error));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_message_formatter_redacts_before_storage() {
+        let (mut logger, db) = setup().await;
+        let formatter: MessageFormatter =
+            Arc::new(|message: &str| message.replace("hunter2", "[REDACTED]"));
+        logger.builder.message_formatter = Some(formatter);
+
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .file_static(Some("the-file"))
+                .line(Some(123))
+                .args(format_args!("Password is {}", "hunter2"))
+                .build(),
+        );
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec!["1000.0 fake-hostname 3 the-module the-file:123 Password is [REDACTED]".to_owned()],
+            entries
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_flushes() {
+        let (logger, db) = setup().await;
+
+        thread::scope(|s| {
+            for i in 0..16 {
+                let logger = &logger;
+                s.spawn(move || {
+                    for j in 0..10 {
+                        logger.log(
+                            &RecordBuilder::new()
+                                .level(Level::Info)
+                                .module_path_static(Some("the-module"))
+                                .args(format_args!("Thread {} entry {}", i, j))
+                                .build(),
+                        );
+                        logger.flush();
+                    }
+                });
+            }
+        });
+
+        // Every flush must have returned and, once all of them completed, all entries must have
+        // been persisted.
+        assert_eq!(160, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_empty_message_is_persisted() {
+        let (logger, db) = setup().await;
+
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .file_static(Some("the-file"))
+                .line(Some(123))
+                .args(format_args!(""))
+                .build(),
+        );
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(vec!["1000.0 fake-hostname 3 the-module the-file:123 ".to_owned()], entries);
+    }
+
+    /// Error for tests that carries an optional cause.
+    #[derive(Debug)]
+    struct ChainedError {
+        message: &'static str,
+        source: Option<Box<ChainedError>>,
+    }
+
+    impl fmt::Display for ChainedError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.message)
+        }
+    }
+
+    impl std::error::Error for ChainedError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.source.as_deref().map(|e| e as &(dyn std::error::Error + 'static))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_error_source_chains() {
+        let (logger, db) = setup().await;
+
+        let err = ChainedError {
+            message: "outer",
+            source: Some(Box::from(ChainedError {
+                message: "middle",
+                source: Some(Box::from(ChainedError { message: "inner", source: None })),
+            })),
+        };
+        let kvs = [("err", Value::from_dyn_error(&err)), ("count", Value::from(3))];
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Error)
+                .module_path_static(Some("the-module"))
+                .file_static(Some("the-file"))
+                .line(Some(123))
+                .args(format_args!("Operation failed"))
+                .key_values(&kvs)
+                .build(),
+        );
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(
+            vec!["1000.0 fake-hostname 1 the-module the-file:123 Operation failed: outer: middle: inner"
+                .to_owned()],
+            entries
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_correlation_id_key() {
+        let (mut logger, db) = setup().await;
+        logger.correlation_id_key = Some("request".to_owned());
+
+        let with_str = [("count", Value::from(3)), ("request", Value::from("req-1"))];
+        let with_int = [("request", Value::from(42))];
+        for kvs in [&with_str[..], &[], &with_int[..]] {
+            logger.log(
+                &RecordBuilder::new()
+                    .level(Level::Info)
+                    .module_path_static(Some("the-module"))
+                    .args(format_args!("Handling request"))
+                    .key_values(&kvs)
+                    .build(),
+            );
+        }
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some("req-1"), None, Some("42")],
+            rows.iter().map(|row| row.correlation_id.as_deref()).collect::<Vec<Option<&str>>>()
+        );
+        assert!(rows.iter().all(|row| row.message == "Handling request"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_key_values_as_fields() {
+        let (mut logger, db) = setup().await;
+
+        let kvs = [("count", Value::from(3)), ("user", Value::from("jmmv"))];
+        for key_values in [true, false] {
+            logger.builder.captured_fields.key_values = key_values;
+            for kvs in [&kvs[..], &[]] {
+                logger.log(
+                    &RecordBuilder::new()
+                        .level(Level::Info)
+                        .module_path_static(Some("the-module"))
+                        .args(format_args!("Handling request"))
+                        .key_values(&kvs)
+                        .build(),
+                );
+            }
+        }
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        let fields = Fields::from([
+            ("count".to_owned(), "3".to_owned()),
+            ("user".to_owned(), "jmmv".to_owned()),
+        ]);
+        assert_eq!(
+            vec![Some(fields), None, None, None],
+            rows.into_iter().map(|row| row.fields).collect::<Vec<Option<Fields>>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_captured_fields_disabled() {
+        let (mut logger, db) = setup().await;
+        logger.builder.captured_fields =
+            CapturedFields { module: true, filename: false, line: false, key_values: true };
+        emit_all_log_levels(&logger);
+        logger.builder.captured_fields =
+            CapturedFields { module: false, filename: true, line: true, key_values: true };
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(10, entries.len());
+        assert_eq!("1000.0 fake-hostname 1 the-module NO-FILENAME:-1 An error message", entries[0]);
+        assert_eq!("1005.0 fake-hostname 1 NO-MODULE the-file:123 An error message", entries[5]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_uptime_nanos_monotonic() {
+        let (logger, db) = setup().await;
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(5, rows.len());
+        let uptimes = rows.iter().map(|row| row.uptime_nanos.unwrap()).collect::<Vec<i64>>();
+        for pair in uptimes.windows(2) {
+            assert!(pair[0] <= pair[1], "Uptimes went backwards: {:?}", uptimes);
+        }
+        assert!(uptimes[4] <= logger.builder.uptime_nanos());
+    }
+
+    #[test]
+    fn test_entry_builder_shares_hostname() {
+        let builder = EntryBuilder::new(
+            "fake-hostname".to_owned(),
+            Arc::from(MonotonicClock::new(1000)),
+            FilenameTransform::default(),
+        );
+        let entry1 = builder.build(Level::Info, None, None, None, "First".to_owned());
+        let entry2 = builder.build(Level::Info, None, None, None, "Second".to_owned());
+        assert_eq!("fake-hostname", &*entry1.hostname);
+        assert!(Arc::ptr_eq(&entry1.hostname, &entry2.hostname));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_uptime_nanos_from_clock() {
+        let (logger, db) = setup().await;
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        let uptimes = rows.iter().map(|row| row.uptime_nanos.unwrap()).collect::<Vec<i64>>();
+        assert_eq!(vec![1_000_000, 2_000_000, 3_000_000, 4_000_000, 5_000_000], uptimes);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_write_panic_is_retried() {
+        let (logger, db) = setup_panicking(1, RecorderOptions::default()).await;
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_write_retries_reject_oversized_entries_once() {
+        let db = sqlite::connect(sqlite::ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            max_message_length: Some(10),
+            oversize_policy: crate::OversizePolicy::Reject,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        let panicking = Connection(Arc::from(PanickingDb {
+            inner: db.clone(),
+            panics: AtomicUsize::new(2),
+            hold: None,
+        }));
+        let logger = DbLogger::new(
+            "fake-hostname".to_owned(),
+            panicking,
+            Arc::from(MonotonicClock::new(1000)),
+            FilenameTransform::default(),
+            RecorderOptions::default(),
+        )
+        .await;
+
+        emit_all_log_levels(&logger);
+        logger.flush();
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+        assert_eq!(5, db.0.rejected_entries());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_write_panic_without_retries_does_not_stop_recorder() {
+        let opts = RecorderOptions { max_write_retries: 0, ..Default::default() };
+        let (logger, db) = setup_panicking(1, opts).await;
+        emit_all_log_levels(&logger);
+        logger.flush();
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+
+        emit_all_log_levels(&logger);
+        logger.flush();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_ordered_writes() {
+        for (ordered_writes, exp_messages) in
+            [(false, ["Second", "First"]), (true, ["First", "Second"])]
+        {
+            // Write every entry in its own batch and hold the first one back until released.
+            let (on_persist, mut persisted) = persisted_channel();
+            let release = Arc::from(tokio::sync::Notify::new());
+            let opts = RecorderOptions {
+                flush_on_errors: Some(1),
+                ordered_writes,
+                on_persist: Some(on_persist),
+                ..Default::default()
+            };
+            let (logger, db) = setup_holding("First", release.clone(), opts).await;
+            for message in ["First", "Second"] {
+                logger.log(
+                    &RecordBuilder::new()
+                        .level(Level::Error)
+                        .module_path_static(Some("the-module"))
+                        .args(format_args!("{}", message))
+                        .build(),
+                );
+            }
+
+            if !ordered_writes {
+                // The second write does not wait for the first one, which is still held back.
+                assert_eq!(vec!["Second"], wait_persisted(&mut persisted, 1).await);
+            }
+            release.notify_one();
+
+            logger.flush();
+            let filter = LogFilter { after_id: Some(0), ..Default::default() };
+            let rows = db.0.query_log_entries(&filter).await.unwrap();
+            assert_eq!(
+                exp_messages.to_vec(),
+                rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_recorder_does_not_block_current_thread_runtime() {
+        // Errors are written right away, after which the recorder has nothing to wait for but new
+        // entries.  It must not hold the only thread of the runtime while it does so, or neither
+        // the write nor this test would ever make progress.
+        let (on_persist, mut persisted) = persisted_channel();
+        let opts = RecorderOptions {
+            flush_on_errors: Some(1),
+            on_persist: Some(on_persist),
+            ..Default::default()
+        };
+        let (logger, db) = setup_with_options(opts).await;
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Error)
+                .module_path_static(Some("the-module"))
+                .args(format_args!("An error"))
+                .build(),
+        );
+
+        assert_eq!(vec!["An error"], wait_persisted(&mut persisted, 1).await);
+        assert_eq!(1, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[test]
+    fn test_idle_recorder_does_not_block_runtime_shutdown() {
+        // Without a flush delay, the recorder waits for new entries without a deadline.  Dropping
+        // the runtime waits for its blocking threads, so the wait must not happen on one even if
+        // the logger remains alive.
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (logger, _db) = runtime.block_on(async {
+            let opts = RecorderOptions { flush_delay: None, ..Default::default() };
+            let (logger, db) = setup_with_options(opts).await;
+            logger.flush();
+            (logger, db)
+        });
+
+        let start = Instant::now();
+        drop(runtime);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(logger);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stop_uses_shutdown_retries() {
+        let opts = RecorderOptions {
+            flush_delay: None,
+            max_write_retries: 0,
+            shutdown_retries: 1,
+            ..Default::default()
+        };
+        let (logger, db) = setup_panicking(1, opts).await;
+        emit_all_log_levels(&logger);
+
+        send_and_wait(&logger.action_tx, Action::Stop);
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stop_without_shutdown_retries() {
+        let opts = RecorderOptions {
+            flush_delay: None,
+            max_write_retries: 2,
+            shutdown_retries: 0,
+            ..Default::default()
+        };
+        let (logger, db) = setup_panicking(1, opts).await;
+        emit_all_log_levels(&logger);
+
+        send_and_wait(&logger.action_tx, Action::Stop);
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stop_honors_shutdown_timeout() {
+        let opts = RecorderOptions {
+            flush_delay: None,
+            shutdown_retries: 1000,
+            shutdown_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let (logger, db) = setup_panicking(1000, opts).await;
+        emit_all_log_levels(&logger);
+
+        let start = Instant::now();
+        send_and_wait(&logger.action_tx, Action::Stop);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_take_queued_flushes() {
+        let (action_tx, mut action_rx) = action_channel(ChannelKind::Bounded(10));
+        let mut done_rxs = vec![];
+        for _ in 0..2 {
+            let (done_tx, done_rx) = mpsc::sync_channel(1);
+            action_tx.send(Action::Flush(done_tx)).unwrap();
+            done_rxs.push(done_rx);
+        }
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        action_tx.send(Action::Stop(done_tx)).unwrap();
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        action_tx.send(Action::Flush(done_tx)).unwrap();
+
+        let mut waiters = vec![];
+        match take_queued_flushes(&mut action_rx, &mut waiters) {
+            Some(Action::Stop(_)) => (),
+            action => panic!("Unexpected action {:?}", action),
+        }
+        assert_eq!(2, waiters.len());
+
+        let mut waiters = vec![];
+        assert!(take_queued_flushes(&mut action_rx, &mut waiters).is_none());
+        assert_eq!(1, waiters.len());
+    }
+
+    #[test]
+    fn test_action_channel_bounded() {
+        let (action_tx, mut action_rx) = action_channel(ChannelKind::Bounded(1));
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        action_tx.send(Action::Flush(done_tx)).unwrap();
+        match &action_tx.channel.read().unwrap().1 {
+            ChannelSender::Bounded(tx) => {
+                let (done_tx, _done_rx) = mpsc::sync_channel(1);
+                match tx.try_send(Action::Flush(done_tx)) {
+                    Err(TrySendError::Full(_)) => (),
+                    r => panic!("Unexpected result {:?}", r),
+                }
+            }
+            ChannelSender::Unbounded(_) => panic!("Expected a bounded channel"),
+        }
+        assert!(matches!(action_rx.try_recv(), Ok(Action::Flush(_))));
+    }
+
+    #[test]
+    fn test_action_channel_unbounded() {
+        let (action_tx, mut action_rx) = action_channel(ChannelKind::Unbounded);
+        for _ in 0..(CHANNEL_SIZE * 4) {
+            let (done_tx, _done_rx) = mpsc::sync_channel(1);
+            action_tx.send(Action::Flush(done_tx)).unwrap();
+        }
+        let mut count = 0;
+        while action_rx.try_recv().is_ok() {
+            count += 1;
+        }
+        assert_eq!(CHANNEL_SIZE * 4, count);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_unbounded_channel() {
+        let opts = RecorderOptions { channel: ChannelKind::Unbounded, ..Default::default() };
+        let (logger, db) = setup_with_options(opts).await;
+        for _ in 0..(CHANNEL_SIZE * 2) {
+            emit_all_log_levels(&logger);
+        }
+
+        logger.flush();
+        assert_eq!(CHANNEL_SIZE * 2 * 5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_metrics() {
+        let (logger, _db) = setup().await;
+        assert_eq!(0, logger.metrics.snapshot().batches);
+
+        emit_all_log_levels(&logger);
+        logger.flush();
+        emit_all_log_levels(&logger);
+        logger.flush();
+
+        let metrics = logger.metrics.snapshot();
+        assert_eq!(2, metrics.batches);
+        assert_eq!(10, metrics.entries);
+        assert!(metrics.min_latency.unwrap() <= metrics.max_latency.unwrap());
+        assert_eq!(Some(&(8, 2)), metrics.batch_sizes.iter().find(|(bound, _)| *bound == 8));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_clones_share_recorder() {
+        let (logger, db) = setup().await;
+        let handle = Handle::new(&logger, db.clone());
+
+        let clone = handle.clone();
+        emit_all_log_levels(&logger);
+        drop(handle);
+        assert_eq!(FlushStats { written: 5 }, clone.flush().unwrap());
+
+        // Dropping the last clone stops the recorder.
+        drop(clone);
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        let mut stopped = false;
+        for _ in 0..100 {
+            if logger.action_tx.send(Action::Flush(done_tx.clone())).is_err() {
+                stopped = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(stopped);
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[test]
+    fn test_clock_watch_disabled() {
+        let watch = ClockWatch::new(None, false);
+        let on_error = stderr_error_handler();
+        let later = OffsetDateTime::from_unix_timestamp(100).unwrap();
+        let earlier = OffsetDateTime::from_unix_timestamp(50).unwrap();
+        assert_eq!(later, watch.check(later, &on_error));
+        assert_eq!(earlier, watch.check(earlier, &on_error));
+    }
+
+    #[test]
+    fn test_clock_watch_reports_without_clamping() {
+        let watch = ClockWatch::new(Some(Duration::from_secs(1)), false);
+        let errors = Arc::from(std::sync::Mutex::new(vec![]));
+        let on_error: InternalErrorHandler = {
+            let errors = errors.clone();
+            Arc::new(move |error: InternalError| errors.lock().unwrap().push(error.kind))
+        };
+        let ts = |secs| OffsetDateTime::from_unix_timestamp(secs).unwrap();
+        assert_eq!(ts(100), watch.check(ts(100), &on_error));
+        assert!(!watch.behind.load(Ordering::SeqCst));
+        assert_eq!(ts(50), watch.check(ts(50), &on_error));
+        assert!(watch.behind.load(Ordering::SeqCst));
+        assert_eq!(ts(49), watch.check(ts(49), &on_error));
+        assert_eq!(ts(101), watch.check(ts(101), &on_error));
+        assert!(!watch.behind.load(Ordering::SeqCst));
+        assert_eq!(vec![InternalErrorKind::Clock], *errors.lock().unwrap());
+    }
+
+    #[test]
+    fn test_clock_watch_ignores_small_jumps() {
+        let watch = ClockWatch::new(Some(Duration::from_secs(10)), false);
+        let on_error = stderr_error_handler();
+        let ts = |secs| OffsetDateTime::from_unix_timestamp(secs).unwrap();
+        watch.check(ts(100), &on_error);
+        assert_eq!(ts(95), watch.check(ts(95), &on_error));
+        assert!(!watch.behind.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_clock_watch_clamps() {
+        let watch = ClockWatch::new(None, true);
+        let on_error = stderr_error_handler();
+        let ts = |secs| OffsetDateTime::from_unix_timestamp(secs).unwrap();
+        assert_eq!(ts(100), watch.check(ts(100), &on_error));
+        assert_eq!(ts(100), watch.check(ts(50), &on_error));
+        assert_eq!(ts(100), watch.check(ts(99), &on_error));
+        assert_eq!(ts(101), watch.check(ts(101), &on_error));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_startup_entry() {
+        let (logger, db) = setup().await;
+
+        let entry = startup_entry(&logger.builder, LevelFilter::Debug, db.0.backend_name());
+        logger.action_tx.send(Action::Record(Box::new(entry))).unwrap();
+
+        logger.flush();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!(Level::Info, rows[0].level);
+        assert_eq!(Some("db_logger::logger"), rows[0].module.as_deref());
+        assert_eq!(
+            format!(
+                "Logging started: version={} hostname=fake-hostname level=DEBUG batch_size=128 \
+                 backend=sqlite",
+                env!("CARGO_PKG_VERSION")
+            ),
+            rows[0].message
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_record() {
+        let (logger, db) = setup().await;
+        let handle = Handle::new(&logger, db.clone());
+
+        // The module would cause the entry to be discarded if it went through the `log` facade.
+        handle.record(LogEntry {
+            module: Some(Cow::Borrowed("sqlx::query")),
+            filename: Some(Cow::Borrowed("the-file")),
+            line: Some(7),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(12345).unwrap(),
+                "other-host",
+                Level::Trace,
+                "Replayed",
+            )
+        });
+
+        assert_eq!(FlushStats { written: 1 }, handle.flush().unwrap());
+        assert_eq!(
+            vec!["12345.0 other-host 5 sqlx::query the-file:7 Replayed".to_owned()],
+            db.0.get_log_entries().await.unwrap()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_record_with_payload() {
+        let (mut logger, db) = setup().await;
+        logger.max_level.set(LevelFilter::Info);
+        logger.module_levels = ModuleLevels::new(vec![
+            ("verbose".to_owned(), LevelFilter::Debug),
+            ("quiet".to_owned(), LevelFilter::Error),
+        ]);
+        let handle = Handle::new(&logger, db.clone());
+
+        let line = line!() + 1;
+        handle.record_with_payload(Level::Warn, "the-crate::mod", "With payload", vec![0, 1, 2]);
+        handle.record_with_payload(Level::Debug, "the-crate::mod", "Filtered out", vec![3]);
+        handle.record_with_payload(Level::Debug, "verbose::mod", "Verbose module", vec![4]);
+        handle.record_with_payload(Level::Warn, "quiet::mod", "Quiet module", vec![5]);
+
+        handle.flush().unwrap();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(2, rows.len());
+        assert_eq!(Level::Warn, rows[0].level);
+        assert_eq!("With payload", rows[0].message);
+        assert_eq!(Some("the-crate::mod"), rows[0].module.as_deref());
+        assert_eq!(Some(file!()), rows[0].filename.as_deref());
+        assert_eq!(Some(line), rows[0].line);
+        assert_eq!(Some(vec![0, 1, 2]), rows[0].payload);
+        assert_eq!("Verbose module", rows[1].message);
+        assert_eq!(Some(vec![4]), rows[1].payload);
+
+        let filter = LogFilter { module: Some("verbose".to_owned()), ..Default::default() };
+        let rows = db.0.query_log_entries(&filter).await.unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!("Verbose module", rows[0].message);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_quiesce() {
+        let (logger, db) = setup_with_options(RecorderOptions {
+            flush_delay: None,
+            max_batch_bytes: Some(30),
+            ..Default::default()
+        })
+        .await;
+        let handle = Handle::new(&logger, db.clone());
+
+        // Some of these entries are written by batches spawned before the quiesce request, which
+        // must wait for them as well.
+        emit_all_log_levels(&logger);
+        assert_eq!(FlushStats { written: 5 }, handle.quiesce().unwrap());
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+
+        assert_eq!(FlushStats { written: 0 }, handle.quiesce().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_delete_log_entries_requires_confirmation() {
+        let (logger, db) = setup().await;
+        let handle = Handle::new(&logger, db.clone());
+        emit_all_log_levels(&logger);
+        handle.flush().unwrap();
+
+        assert!(handle.delete_log_entries(&LogFilter::default()).await.is_err());
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+
+        let filter = LogFilter { confirm_delete_all: true, ..Default::default() };
+        assert_eq!(5, handle.delete_log_entries(&filter).await.unwrap());
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handle_clear_log_entries() {
+        let (logger, db) = setup().await;
+        let handle = Handle::new(&logger, db.clone());
+        emit_all_log_levels(&logger);
+        handle.flush().unwrap();
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+
+        handle.clear_log_entries().await.unwrap();
+        assert!(db.0.get_log_entries().await.unwrap().is_empty());
+
+        emit_all_log_levels(&logger);
+        handle.flush().unwrap();
+        let rows = db.0.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], rows.iter().map(|row| row.sequence).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_scope() {
+        let (logger, db) =
+            setup_with_options(RecorderOptions { flush_delay: None, ..Default::default() }).await;
+        let handle = Handle::new(&logger, db.clone());
+
+        {
+            let _guard = handle.flush_scope();
+            emit_all_log_levels(&logger);
+            assert!(db.0.get_log_entries().await.unwrap().is_empty());
+        }
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_flush_async_on_current_thread_runtime() {
+        let (logger, db) =
+            setup_with_options(RecorderOptions { flush_delay: None, ..Default::default() }).await;
+        let handle = Handle::new(&logger, db.clone());
+
+        emit_all_log_levels(&logger);
+        assert_eq!(5, handle.flush_async().await.unwrap().written);
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+
+        // Dropping the handle blocks until the recorder stops, which needs this runtime.
+        tokio::task::spawn_blocking(move || drop(handle)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_scope_async() {
+        let (logger, db) =
+            setup_with_options(RecorderOptions { flush_delay: None, ..Default::default() }).await;
+        let handle = Handle::new(&logger, db.clone());
+
+        let count = handle
+            .flush_scope_async(async {
+                emit_all_log_levels(&logger);
+                db.0.get_log_entries().await.unwrap().len()
+            })
+            .await;
+        assert_eq!(0, count);
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+
+        // Dropping the handle blocks until the recorder stops, which needs this runtime.
+        tokio::task::spawn_blocking(move || drop(handle)).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_entry_transforms() {
+        let opts = RecorderOptions {
+            entry_transforms: vec![
+                Arc::new(|entry: &mut LogEntry| entry.hostname = "other-hostname".into()),
+                Arc::new(|entry: &mut LogEntry| entry.message.push_str(" [first]")),
+                Arc::new(|entry: &mut LogEntry| entry.message.push_str(" [second]")),
             ],
-            entries
+            ..Default::default()
+        };
+        let (logger, db) = setup_with_options(opts).await;
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(5, entries.len());
+        assert_eq!(
+            "1000.0 other-hostname 1 the-module the-file:123 An error message [first] [second]",
+            entries[0]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_on_persist() {
+        let persisted = Arc::from(std::sync::Mutex::new(vec![]));
+        let opts = RecorderOptions {
+            on_persist: Some({
+                let persisted = persisted.clone();
+                Arc::new(move |entries: &[LogEntry]| {
+                    let mut persisted = persisted.lock().unwrap();
+                    persisted.extend(entries.iter().map(|entry| entry.message.clone()));
+                })
+            }),
+            ..Default::default()
+        };
+        let (logger, _db) = setup_with_options(opts).await;
+        emit_all_log_levels(&logger);
+
+        logger.flush();
+        assert_eq!(
+            vec![
+                "An error message",
+                "A warning message",
+                "An info message",
+                "A debug message",
+                "A trace message",
+            ],
+            *persisted.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_on_persist_skips_failed_writes() {
+        let calls = Arc::from(AtomicUsize::new(0));
+        let opts = RecorderOptions {
+            max_write_retries: 0,
+            on_persist: Some({
+                let calls = calls.clone();
+                Arc::new(move |_entries: &[LogEntry]| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    panic!("Callbacks must not break the logger");
+                })
+            }),
+            ..Default::default()
+        };
+        let (logger, db) = setup_panicking(1, opts).await;
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .args(format_args!("Lost"))
+                .build(),
+        );
+        assert!(send_and_wait(&logger.action_tx, Action::Flush).is_err());
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .args(format_args!("Kept"))
+                .build(),
+        );
+        send_and_wait(&logger.action_tx, Action::Flush).unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!(1, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_internal_error_handler() {
+        let errors = Arc::from(std::sync::Mutex::new(vec![]));
+        let opts = RecorderOptions {
+            max_write_retries: 1,
+            internal_error_handler: {
+                let errors = errors.clone();
+                Arc::new(move |error: InternalError| errors.lock().unwrap().push(error))
+            },
+            ..Default::default()
+        };
+        let (logger, _db) = setup_panicking(2, opts).await;
+        emit_all_log_levels(&logger);
+        assert!(send_and_wait(&logger.action_tx, Action::Flush).is_err());
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(2, errors.len());
+        assert!(errors.iter().all(|error| error.kind == InternalErrorKind::Write));
+        assert!(errors[0].message.contains("attempt 1 of 2"), "Unexpected {}", errors[0]);
+        assert!(errors[1].message.contains("lost 5 entries"), "Unexpected {}", errors[1]);
+    }
+
+    #[test]
+    fn test_report_catches_panics() {
+        let handler: InternalErrorHandler =
+            Arc::new(|_error: InternalError| panic!("Handlers must not break the logger"));
+        report(&handler, InternalErrorKind::Write, "Some problem".to_owned());
+    }
+
+    #[tokio::test]
+    async fn test_dedicated_runtime() {
+        let writer_threads = Arc::from(std::sync::Mutex::new(vec![]));
+        let opts = RecorderOptions {
+            dedicated_runtime: true,
+            on_persist: Some({
+                let writer_threads = writer_threads.clone();
+                Arc::new(move |_entries: &[LogEntry]| {
+                    let name = thread::current().name().map(str::to_owned);
+                    writer_threads.lock().unwrap().push(name);
+                })
+            }),
+            ..Default::default()
+        };
+        let (logger, db) = setup_with_options(opts).await;
+
+        // Blocking on the flush would deadlock on this current-thread runtime if the recorder
+        // shared it.
+        emit_all_log_levels(&logger);
+        logger.flush();
+        emit_all_log_levels(&logger);
+        logger.flush();
+        assert_eq!(10, db.0.get_log_entries().await.unwrap().len());
+
+        let writer_threads = writer_threads.lock().unwrap();
+        assert_eq!(2, writer_threads.len());
+        assert!(
+            writer_threads.iter().all(|name| name.as_deref() == Some("db_logger-writer")),
+            "Unexpected writer threads {:?}",
+            *writer_threads
+        );
+    }
+
+    /// Sets up a logger whose recorder panics when it receives an entry with the message "Die".
+    async fn setup_killable(restart_recorder: bool) -> (DbLogger, Connection) {
+        let opts = RecorderOptions {
+            entry_transforms: vec![Arc::new(|entry: &mut LogEntry| {
+                if entry.message == "Die" {
+                    panic!("Recorder killed on request");
+                }
+            })],
+            restart_recorder,
+            ..Default::default()
+        };
+        setup_with_options(opts).await
+    }
+
+    /// Kills the recorder of a logger set up with `setup_killable` and waits for it to be gone.
+    fn kill_recorder(logger: &DbLogger) {
+        logger.log(
+            &RecordBuilder::new()
+                .level(Level::Info)
+                .module_path_static(Some("the-module"))
+                .args(format_args!("Die"))
+                .build(),
+        );
+        // Either the recorder dies before processing the flush, in which case the request is
+        // discarded along with the channel that holds it, or the request reaches a new recorder.
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        if logger.action_tx.send(Action::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_restart_recorder_after_death() {
+        let (logger, db) = setup_killable(true).await;
+        kill_recorder(&logger);
+
+        emit_all_log_levels(&logger);
+        logger.flush();
+        let entries = db.0.get_log_entries().await.unwrap();
+        assert_eq!(5, entries.len());
+        assert_eq!("1001.0 fake-hostname 1 the-module the-file:123 An error message", entries[0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_restart_recorder_disabled() {
+        let (logger, _db) = setup_killable(false).await;
+        kill_recorder(&logger);
+
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        assert!(logger.action_tx.send(Action::Flush(done_tx)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_restart_recorder_not_after_stop() {
+        let (logger, _db) = setup().await;
+        send_and_wait(&logger.action_tx, Action::Stop);
+
+        let (done_tx, _done_rx) = mpsc::sync_channel(1);
+        assert!(logger.action_tx.send(Action::Flush(done_tx)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_flushes_are_coalesced() {
+        let (logger, db) = setup().await;
+        emit_all_log_levels(&logger);
+
+        let logger = Arc::from(logger);
+        let mut threads = vec![];
+        for _ in 0..10 {
+            let logger = logger.clone();
+            threads.push(thread::spawn(move || send_and_wait(&logger.action_tx, Action::Flush)));
+        }
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(5, db.0.get_log_entries().await.unwrap().len());
+    }
+
+    #[test]
+    fn test_utc_offset_source_fixed_from_system() {
+        let expected = match UtcOffset::current_local_offset() {
+            Ok(offset) => UtcOffsetSource::Fixed(offset),
+            Err(_) => UtcOffsetSource::None,
+        };
+        assert_eq!(expected, UtcOffsetSource::fixed_from_system());
+    }
+
+    #[test]
+    fn test_write_error_is_retriable() {
+        assert!(WriteError::Db(DbError::Connection("foo".to_owned(), None)).is_retriable());
+        assert!(!WriteError::Db(DbError::Constraint("foo".to_owned(), None)).is_retriable());
+        assert!(WriteError::Panic("foo".to_owned()).is_retriable());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_stats() {
+        let (logger, _db) = setup().await;
+        emit_all_log_levels(&logger);
+        assert_eq!(
+            FlushStats { written: 5 },
+            send_and_wait(&logger.action_tx, Action::Flush).unwrap()
+        );
+        assert_eq!(
+            FlushStats { written: 0 },
+            send_and_wait(&logger.action_tx, Action::Flush).unwrap()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flush_reports_write_errors() {
+        let opts = RecorderOptions { max_write_retries: 0, ..Default::default() };
+        let (logger, _db) = setup_panicking(1, opts).await;
+        emit_all_log_levels(&logger);
+        let err = send_and_wait(&logger.action_tx, Action::Flush).unwrap_err();
+        assert_eq!(1, err.failed_batches);
+        assert_eq!(0, err.written);
+        match err.last_error.as_ref() {
+            DbError::Other(message, _) => assert!(message.contains("write panicked")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(err.to_string().contains("Failed to write 1 batches of log entries"));
+
+        emit_all_log_levels(&logger);
+        assert_eq!(
+            FlushStats { written: 5 },
+            send_and_wait(&logger.action_tx, Action::Flush).unwrap()
         );
     }
 }
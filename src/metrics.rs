@@ -0,0 +1,171 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Measurements of the writes of batches of log entries to the database.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Inclusive upper bounds of the buckets of the batch size histogram.
+const BATCH_SIZE_BUCKETS: [usize; 9] = [1, 2, 4, 8, 16, 32, 64, 128, usize::MAX];
+
+/// Snapshot of the measurements of the batches successfully written to the database.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Number of batches written.
+    pub batches: u64,
+
+    /// Number of log entries written across all batches.
+    pub entries: u64,
+
+    /// Time taken by the fastest write, or `None` if no batches have been written yet.
+    pub min_latency: Option<Duration>,
+
+    /// Time taken by the slowest write, or `None` if no batches have been written yet.
+    pub max_latency: Option<Duration>,
+
+    /// Average time taken by the writes, or `None` if no batches have been written yet.
+    pub avg_latency: Option<Duration>,
+
+    /// Time taken by the most recent write, or `None` if no batches have been written yet.
+    pub last_latency: Option<Duration>,
+
+    /// Histogram of the number of entries per batch as pairs of the inclusive upper bound of each
+    /// bucket and the number of batches that fell in it.  The last bucket is unbounded and is
+    /// represented by `usize::MAX`.
+    pub batch_sizes: Vec<(usize, u64)>,
+}
+
+/// Accumulates measurements of batch writes from concurrent writers.
+///
+/// All updates use relaxed atomics to keep their overhead low, which means that a snapshot taken
+/// while writes complete may reflect some updates of a write but not others.
+pub(crate) struct MetricsRecorder {
+    batches: AtomicU64,
+    entries: AtomicU64,
+    total_nanos: AtomicU64,
+    min_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    last_nanos: AtomicU64,
+    batch_sizes: [AtomicU64; BATCH_SIZE_BUCKETS.len()],
+}
+
+impl Default for MetricsRecorder {
+    fn default() -> Self {
+        Self {
+            batches: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            total_nanos: AtomicU64::new(0),
+            min_nanos: AtomicU64::new(u64::MAX),
+            max_nanos: AtomicU64::new(0),
+            last_nanos: AtomicU64::new(0),
+            batch_sizes: Default::default(),
+        }
+    }
+}
+
+impl MetricsRecorder {
+    /// Records that a batch of `nentries` was written in `elapsed` time.
+    pub(crate) fn record(&self, nentries: usize, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.min_nanos.fetch_min(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.last_nanos.store(nanos, Ordering::Relaxed);
+        self.entries.fetch_add(u64::try_from(nentries).unwrap_or(u64::MAX), Ordering::Relaxed);
+
+        let bucket = BATCH_SIZE_BUCKETS
+            .iter()
+            .position(|bound| nentries <= *bound)
+            .expect("The last bucket must be unbounded");
+        self.batch_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+
+        // Count the batch last so that readers do not see a batch without its latency.
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the measurements accumulated so far.
+    pub(crate) fn snapshot(&self) -> Metrics {
+        let batch_sizes = BATCH_SIZE_BUCKETS
+            .iter()
+            .zip(self.batch_sizes.iter())
+            .map(|(bound, count)| (*bound, count.load(Ordering::Relaxed)))
+            .collect();
+
+        let batches = self.batches.load(Ordering::Relaxed);
+        if batches == 0 {
+            return Metrics { batch_sizes, ..Default::default() };
+        }
+        let total_nanos = self.total_nanos.load(Ordering::Relaxed);
+        Metrics {
+            batches,
+            entries: self.entries.load(Ordering::Relaxed),
+            min_latency: Some(Duration::from_nanos(self.min_nanos.load(Ordering::Relaxed))),
+            max_latency: Some(Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed))),
+            avg_latency: Some(Duration::from_nanos(total_nanos / batches)),
+            last_latency: Some(Duration::from_nanos(self.last_nanos.load(Ordering::Relaxed))),
+            batch_sizes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_recorder_empty() {
+        let metrics = MetricsRecorder::default().snapshot();
+        assert_eq!(0, metrics.batches);
+        assert_eq!(0, metrics.entries);
+        assert_eq!(None, metrics.min_latency);
+        assert_eq!(None, metrics.max_latency);
+        assert_eq!(None, metrics.avg_latency);
+        assert_eq!(None, metrics.last_latency);
+        assert_eq!(BATCH_SIZE_BUCKETS.len(), metrics.batch_sizes.len());
+        assert!(metrics.batch_sizes.iter().all(|(_, count)| *count == 0));
+    }
+
+    #[test]
+    fn test_metrics_recorder_record() {
+        let recorder = MetricsRecorder::default();
+        recorder.record(1, Duration::from_millis(30));
+        recorder.record(100, Duration::from_millis(10));
+        recorder.record(128, Duration::from_millis(50));
+        recorder.record(500, Duration::from_millis(30));
+
+        let metrics = recorder.snapshot();
+        assert_eq!(4, metrics.batches);
+        assert_eq!(729, metrics.entries);
+        assert_eq!(Some(Duration::from_millis(10)), metrics.min_latency);
+        assert_eq!(Some(Duration::from_millis(50)), metrics.max_latency);
+        assert_eq!(Some(Duration::from_millis(30)), metrics.avg_latency);
+        assert_eq!(Some(Duration::from_millis(30)), metrics.last_latency);
+        assert_eq!(
+            vec![
+                (1, 1),
+                (2, 0),
+                (4, 0),
+                (8, 0),
+                (16, 0),
+                (32, 0),
+                (64, 0),
+                (128, 2),
+                (usize::MAX, 1)
+            ],
+            metrics.batch_sizes
+        );
+    }
+}
@@ -0,0 +1,252 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Mirroring of log entries to the console as they are recorded.
+
+use crate::logger::LogEntry;
+use log::Level;
+use std::io::{self, Write};
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+
+/// Destination to which log entries are copied in addition to being persisted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Mirror {
+    /// Log entries are only persisted.
+    #[default]
+    Disabled,
+
+    /// Log entries are also printed to stderr in a human-readable format.
+    Stderr,
+
+    /// Log entries are also printed to stdout in a human-readable format with every line prefixed
+    /// by the syslog priority of the entry, as in `<4>`, so that the systemd journal records the
+    /// right `PRIORITY` when capturing the output of a service.
+    ///
+    /// The journal only extracts the priority from its stream protocol, so fields such as the
+    /// source location are not recorded separately and should be enabled in the `MirrorFormat` if
+    /// desired.
+    Journald,
+}
+
+/// Fields to include in the human-readable lines printed by a `Mirror`.
+///
+/// This can be parsed from a comma-separated list of the names of the fields to enable, which can
+/// each be prefixed by `no-` to disable them instead.  Fields that are not mentioned keep their
+/// default values.  For example, `no-timestamp,color` prints lines without timestamps and with
+/// colored levels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MirrorFormat {
+    /// Whether to include the timestamp of the entry.  Enabled by default.
+    pub timestamp: bool,
+
+    /// Whether to include the module that emitted the entry.  Enabled by default.
+    pub module: bool,
+
+    /// Whether to color the level of the entry with ANSI escape sequences.  Disabled by default.
+    pub color: bool,
+}
+
+impl Default for MirrorFormat {
+    fn default() -> Self {
+        Self { timestamp: true, module: true, color: false }
+    }
+}
+
+impl FromStr for MirrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = MirrorFormat::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (name, enabled) = match item.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (item, true),
+            };
+            match name {
+                "timestamp" => format.timestamp = enabled,
+                "module" => format.module = enabled,
+                "color" => format.color = enabled,
+                _ => return Err(format!("Unknown format field '{}'", item)),
+            }
+        }
+        Ok(format)
+    }
+}
+
+/// Returns the ANSI escape sequence to color `level`.
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[34m",
+        Level::Trace => "\x1b[36m",
+    }
+}
+
+/// Returns the syslog severity that corresponds to `level`, as expected in the `PRIORITY` field of
+/// the systemd journal.
+fn syslog_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats `entry` as a human-readable line according to `format`.
+pub(crate) fn format_line(entry: &LogEntry, format: &MirrorFormat) -> String {
+    let mut header = vec![];
+    if format.timestamp {
+        match entry.timestamp.format(&Rfc3339) {
+            Ok(timestamp) => header.push(timestamp),
+            Err(e) => header.push(format!("<invalid timestamp: {}>", e)),
+        }
+    }
+    if format.color {
+        header.push(format!("{}{:<5}\x1b[0m", level_color(entry.level), entry.level));
+    } else {
+        header.push(format!("{:<5}", entry.level));
+    }
+    if format.module {
+        if let Some(module) = entry.module.as_ref() {
+            header.push(module.to_string());
+        }
+    }
+    format!("[{}] {}", header.join(" "), entry.message)
+}
+
+/// Formats `entry` like `format_line` but prefixes every line with the syslog priority of the
+/// entry so that the systemd journal does not treat continuation lines as informational.
+pub(crate) fn format_journald_line(entry: &LogEntry, format: &MirrorFormat) -> String {
+    let prefix = format!("<{}>", syslog_priority(entry.level));
+    format_line(entry, format)
+        .split('\n')
+        .map(|line| format!("{}{}", prefix, line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl Mirror {
+    /// Copies `entry` to this mirror using `format`.
+    ///
+    /// Errors are ignored because there is nowhere else to report them.
+    pub(crate) fn write(&self, entry: &LogEntry, format: &MirrorFormat) {
+        match self {
+            Mirror::Disabled => (),
+            Mirror::Stderr => {
+                let _ = writeln!(io::stderr().lock(), "{}", format_line(entry, format));
+            }
+            Mirror::Journald => {
+                let _ = writeln!(io::stdout().lock(), "{}", format_journald_line(entry, format));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    /// Creates a log entry for tests.
+    fn entry(module: Option<&str>) -> LogEntry {
+        LogEntry {
+            module: module.map(|module| module.to_owned().into()),
+            filename: Some("the-file".into()),
+            line: Some(123),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(1000).unwrap(),
+                "the-host",
+                Level::Warn,
+                "The message",
+            )
+        }
+    }
+
+    #[test]
+    fn test_mirror_format_from_str_ok() {
+        assert_eq!(MirrorFormat::default(), MirrorFormat::from_str("").unwrap());
+        assert_eq!(
+            MirrorFormat { timestamp: false, module: true, color: true },
+            MirrorFormat::from_str("no-timestamp, color").unwrap()
+        );
+        assert_eq!(
+            MirrorFormat { timestamp: true, module: false, color: false },
+            MirrorFormat::from_str("timestamp,no-module,").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mirror_format_from_str_errors() {
+        assert_eq!(
+            "Unknown format field 'hostname'",
+            MirrorFormat::from_str("color,hostname").unwrap_err()
+        );
+        assert_eq!("Unknown format field 'no-'", MirrorFormat::from_str("no-").unwrap_err());
+    }
+
+    #[test]
+    fn test_format_line_default() {
+        assert_eq!(
+            "[1970-01-01T00:16:40Z WARN  the-module] The message",
+            format_line(&entry(Some("the-module")), &MirrorFormat::default())
+        );
+        assert_eq!(
+            "[1970-01-01T00:16:40Z WARN ] The message",
+            format_line(&entry(None), &MirrorFormat::default())
+        );
+    }
+
+    #[test]
+    fn test_format_line_customized() {
+        let format = MirrorFormat { timestamp: false, module: false, color: false };
+        assert_eq!("[WARN ] The message", format_line(&entry(Some("the-module")), &format));
+
+        let format = MirrorFormat { timestamp: false, module: true, color: true };
+        assert_eq!(
+            "[\x1b[33mWARN \x1b[0m the-module] The message",
+            format_line(&entry(Some("the-module")), &format)
+        );
+    }
+
+    #[test]
+    fn test_syslog_priority() {
+        assert_eq!(3, syslog_priority(Level::Error));
+        assert_eq!(4, syslog_priority(Level::Warn));
+        assert_eq!(6, syslog_priority(Level::Info));
+        assert_eq!(7, syslog_priority(Level::Debug));
+        assert_eq!(7, syslog_priority(Level::Trace));
+    }
+
+    #[test]
+    fn test_format_journald_line() {
+        let format = MirrorFormat { timestamp: false, module: true, color: false };
+        assert_eq!(
+            "<4>[WARN  the-module] The message",
+            format_journald_line(&entry(Some("the-module")), &format)
+        );
+
+        let mut multiline = entry(None);
+        multiline.level = Level::Error;
+        multiline.message = "First line\nSecond line".to_owned();
+        assert_eq!(
+            "<3>[ERROR] First line\n<3>Second line",
+            format_journald_line(&multiline, &format)
+        );
+    }
+}
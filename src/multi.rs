@@ -0,0 +1,411 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Implementation of the database abstraction that writes log entries to several databases.
+//!
+//! This is meant for transition periods, such as migrations from one backend to another, during
+//! which the same entries have to be written to all of them.  Reads are served by the primary
+//! database only.
+
+use crate::logger::{report, stderr_error_handler, LogEntry};
+use crate::{
+    Connection, Db, Fields, InternalErrorHandler, InternalErrorKind, LogEntryRow, LogFilter,
+    Result, StorageStats,
+};
+use futures::future;
+use futures::stream::BoxStream;
+use log::Level;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+/// Condition for a write to the set of databases to be considered successful.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WritePolicy {
+    /// All databases must accept the entries.
+    ///
+    /// When some databases fail, the write is retried on all of them, so those that had accepted
+    /// the entries may end up with duplicates unless they are configured to ignore them.
+    #[default]
+    All,
+
+    /// At least one database must accept the entries.  Failures in the others are reported to the
+    /// internal error handler given to `connect` and the entries are lost for them.
+    Any,
+}
+
+/// Factory to write log entries to the `primary` database and all `secondaries` at once, with the
+/// success of each write determined by `policy`.
+///
+/// Queries are only served by `primary`, but schema operations and maintenance are applied to all
+/// databases.  The failures that `policy` tolerates are reported to `internal_error_handler`, or
+/// printed to stderr if `None`, so pass the same handler as in `LoggerOptions` to see them.
+pub fn connect(
+    primary: Connection,
+    secondaries: Vec<Connection>,
+    policy: WritePolicy,
+    internal_error_handler: Option<InternalErrorHandler>,
+) -> Connection {
+    let secondaries = secondaries.into_iter().map(|conn| conn.0).collect();
+    let on_error = internal_error_handler.unwrap_or_else(stderr_error_handler);
+    Connection(Arc::from(MultiDb { primary: primary.0, secondaries, policy, on_error }))
+}
+
+/// A database instance that fans out writes to several other instances.
+struct MultiDb {
+    primary: Arc<dyn Db + Send + Sync + 'static>,
+    secondaries: Vec<Arc<dyn Db + Send + Sync + 'static>>,
+    policy: WritePolicy,
+    on_error: InternalErrorHandler,
+}
+
+impl MultiDb {
+    /// Returns an iterator over all databases, starting with the primary.
+    fn all(&self) -> impl Iterator<Item = &Arc<dyn Db + Send + Sync + 'static>> {
+        std::iter::once(&self.primary).chain(self.secondaries.iter())
+    }
+}
+
+#[async_trait::async_trait]
+impl Db for MultiDb {
+    async fn create_schema(&self) -> Result<()> {
+        for db in self.all() {
+            db.create_schema().await?;
+        }
+        Ok(())
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        for db in self.all() {
+            db.verify_schema().await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        for db in self.all() {
+            db.migrate_schema().await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn get_log_entries(&self) -> Result<Vec<String>> {
+        self.primary.get_log_entries().await
+    }
+
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        self.primary.query_log_entries(filter).await
+    }
+
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        self.primary.stream_log_entries(filter)
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        self.primary.level_counts(filter).await
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        self.primary.distinct_hostnames().await
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        self.primary.distinct_modules().await
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        self.primary.time_bounds().await
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        self.primary.storage_stats().await
+    }
+
+    async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
+        let writes = self.all().map(|db| db.put_log_entries(entries.clone()));
+        let results = future::join_all(writes).await;
+
+        let mut first_error = None;
+        let mut succeeded = false;
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(()) => succeeded = true,
+                Err(e) => {
+                    if self.policy == WritePolicy::Any {
+                        report(
+                            &self.on_error,
+                            InternalErrorKind::Write,
+                            format!("Failed to write log entries to database {}: {}", i, e),
+                        );
+                    }
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match (first_error, self.policy) {
+            (None, _) => Ok(()),
+            (Some(_), WritePolicy::Any) if succeeded => Ok(()),
+            (Some(e), _) => Err(e),
+        }
+    }
+
+    async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64> {
+        let archived = self.primary.archive_log_entries_before(ts).await?;
+        for db in &self.secondaries {
+            db.archive_log_entries_before(ts).await?;
+        }
+        Ok(archived)
+    }
+
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        let deleted = self.primary.delete_log_entries(filter).await?;
+        for db in &self.secondaries {
+            db.delete_log_entries(filter).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        for db in self.all() {
+            db.clear_log_entries().await?;
+        }
+        Ok(())
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        self.all().map(|db| db.max_params_per_statement()).min().unwrap_or(usize::MAX)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "multi"
+    }
+
+    fn rejected_entries(&self) -> u64 {
+        self.primary.rejected_entries()
+    }
+
+    fn ignores_duplicates(&self) -> bool {
+        self.all().any(|db| db.ignores_duplicates())
+    }
+
+    fn encode_level(&self, level: Level) -> i64 {
+        self.primary.encode_level(level)
+    }
+
+    fn decode_level(&self, level: i64) -> Result<Level> {
+        self.primary.decode_level(level)
+    }
+
+    fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+        self.primary.encode_fields(fields)
+    }
+
+    fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+        self.primary.decode_fields(data)
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        for db in self.all() {
+            db.maintenance().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "sqlite")]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use crate::testutils::{self, new_entry};
+    use crate::InternalError;
+
+    /// Test context to hold the fan-out database.
+    struct MultiTestContext {
+        db: MultiDb,
+
+        /// Runtime on which the database was prepared, which must outlive the setup so that the
+        /// in-memory databases do not lose their connections.
+        _runtime: tokio::runtime::Runtime,
+    }
+
+    #[async_trait::async_trait]
+    impl testutils::TestContext for MultiTestContext {
+        fn db(&self) -> &(dyn Db + Send + Sync) {
+            &self.db
+        }
+    }
+
+    /// Opens a new in-memory SQLite database, initializing its schema if `create_schema` is true.
+    async fn new_sqlite(create_schema: bool) -> Connection {
+        let conn = sqlite::connect(sqlite::ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        if create_schema {
+            conn.create_schema().await.unwrap();
+        }
+        conn
+    }
+
+    /// Creates a new fan-out database on top of two in-memory SQLite databases.
+    async fn new_multi(policy: WritePolicy) -> MultiDb {
+        let primary = new_sqlite(false).await;
+        let secondary = new_sqlite(false).await;
+        MultiDb {
+            primary: primary.0,
+            secondaries: vec![secondary.0],
+            policy,
+            on_error: stderr_error_handler(),
+        }
+    }
+
+    /// Initializes the test database.
+    fn setup() -> Box<dyn testutils::TestContext> {
+        async fn prepare() -> MultiDb {
+            let db = new_multi(WritePolicy::All).await;
+            db.create_schema().await.unwrap();
+            db
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let db = runtime.block_on(prepare());
+        Box::from(MultiTestContext { db, _runtime: runtime })
+    }
+
+    #[test]
+    fn test_multidb_log_entries_none() {
+        testutils::test_log_entries_none(setup());
+    }
+
+    #[test]
+    fn test_multidb_log_entries_individual() {
+        testutils::test_log_entries_individual(setup());
+    }
+
+    #[test]
+    fn test_multidb_log_entries_combined() {
+        testutils::test_log_entries_combined(setup());
+    }
+
+    #[test]
+    fn test_multidb_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup());
+    }
+
+    #[test]
+    fn test_multidb_level_counts() {
+        testutils::test_level_counts(setup());
+    }
+
+    #[test]
+    fn test_multidb_time_bounds() {
+        testutils::test_time_bounds(setup());
+    }
+
+    #[test]
+    fn test_multidb_storage_stats() {
+        testutils::test_storage_stats(setup());
+    }
+
+    #[test]
+    fn test_multidb_empty_message() {
+        testutils::test_empty_message(setup());
+    }
+
+    #[test]
+    fn test_multidb_delete_log_entries() {
+        testutils::test_delete_log_entries(setup());
+    }
+
+    #[test]
+    fn test_multidb_clear_log_entries() {
+        testutils::test_clear_log_entries(setup());
+    }
+
+    #[test]
+    fn test_multidb_large_line_number() {
+        testutils::test_large_line_number(setup());
+    }
+
+    #[test]
+    fn test_multidb_query_log_entries_after_id() {
+        testutils::test_query_log_entries_after_id(setup());
+    }
+
+    #[test]
+    fn test_multidb_verify_schema() {
+        testutils::test_verify_schema(setup());
+    }
+
+    #[tokio::test]
+    async fn test_multidb_writes_to_all() {
+        let db = new_multi(WritePolicy::All).await;
+        db.create_schema().await.unwrap();
+
+        db.put_log_entries(vec![new_entry(1, "first"), new_entry(2, "second")]).await.unwrap();
+
+        for db in db.all() {
+            assert_eq!(2, db.get_log_entries().await.unwrap().len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multidb_policy_all_fails_on_any_error() {
+        let primary = new_sqlite(true).await;
+        let broken = new_sqlite(false).await;
+        let db = MultiDb {
+            primary: primary.0,
+            secondaries: vec![broken.0],
+            policy: WritePolicy::All,
+            on_error: stderr_error_handler(),
+        };
+
+        assert!(db.put_log_entries(vec![new_entry(1, "first")]).await.is_err());
+        assert_eq!(1, db.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_multidb_policy_any_tolerates_errors() {
+        let broken = new_sqlite(false).await;
+        let secondary = new_sqlite(true).await;
+        let errors = Arc::from(std::sync::Mutex::new(vec![]));
+        let db = MultiDb {
+            primary: broken.0,
+            secondaries: vec![secondary.0.clone()],
+            policy: WritePolicy::Any,
+            on_error: {
+                let errors = errors.clone();
+                Arc::new(move |error: InternalError| errors.lock().unwrap().push(error))
+            },
+        };
+
+        db.put_log_entries(vec![new_entry(1, "first")]).await.unwrap();
+        assert_eq!(1, secondary.0.get_log_entries().await.unwrap().len());
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert_eq!(InternalErrorKind::Write, errors[0].kind);
+        assert!(errors[0].message.contains("database 0"), "Unexpected {}", errors[0]);
+    }
+
+    #[tokio::test]
+    async fn test_multidb_policy_any_fails_if_all_fail() {
+        let db = new_multi(WritePolicy::Any).await;
+        assert!(db.put_log_entries(vec![new_entry(1, "first")]).await.is_err());
+    }
+}
@@ -0,0 +1,349 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Implementation of the database abstraction that ships log entries to a remote collector.
+//!
+//! The collector is an HTTP service that owns the real database.  Every batch of entries is sent
+//! in a POST request whose body holds the entries in newline-delimited JSON format, and the
+//! collector feeds them into its own backend via `receive`.  This allows many small nodes to log
+//! into a central database without connecting to it directly.  Reads are not supported: query the
+//! collector's database instead.
+
+use crate::export;
+use crate::logger::LogEntry;
+use crate::{Connection, Db, DbError, LogEntryRow, LogFilter, Result, StorageStats};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::Level;
+use reqwest::{header, Client, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// Content type of the bodies of the requests sent to the collector.
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Options to connect to a remote collector.
+#[derive(Default)]
+pub struct ConnectionOptions {
+    /// URL of the collector endpoint to which batches of log entries are POSTed.
+    pub url: String,
+
+    /// Maximum time to wait for the collector to accept a batch, or `None` to wait indefinitely.
+    ///
+    /// Batches that time out are retried according to `max_write_retries` in `LoggerOptions`.
+    pub timeout: Option<Duration>,
+}
+
+/// Factory to send log entries to a remote collector.
+pub fn connect(opts: ConnectionOptions) -> Result<Connection> {
+    NetDb::connect(opts).map(|db| Connection(Arc::from(db)))
+}
+
+/// Decodes the batch of log entries in the `body` of a request sent by a `net` connection and
+/// writes them to `db`, returning the number of entries written.
+///
+/// Call this from the handler of the collector's endpoint in the web framework of choice.  Reply
+/// with a 400 status if this fails with `DbError::Serialization`, as the batch will never be
+/// valid, and with a 500 status on any other error so that the sender retries the batch.
+pub async fn receive(db: &Connection, body: &str) -> Result<usize> {
+    let mut entries = vec![];
+    for line in body.lines().filter(|line| !line.is_empty()) {
+        let entry = export::parse_entry_ndjson(line)
+            .map_err(|e| DbError::Serialization(format!("Bad log entry in batch: {}", e), None))?;
+        entries.push(entry);
+    }
+    let nentries = entries.len();
+    db.0.put_log_entries(entries).await?;
+    Ok(nentries)
+}
+
+/// Constructs the error returned by the operations that the collector does not serve.
+fn unsupported(what: &str) -> DbError {
+    DbError::Other(format!("{} is not supported by the network backend", what), None)
+}
+
+/// A database instance that sends log entries to a remote collector.
+struct NetDb {
+    client: Client,
+    url: String,
+}
+
+impl NetDb {
+    /// Prepares a client to talk to the collector described by `opts`.
+    fn connect(opts: ConnectionOptions) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(timeout) = opts.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder.build().map_err(|e| {
+            DbError::Connection(format!("Failed to create HTTP client: {}", e), Some(Box::new(e)))
+        })?;
+        Ok(Self { client, url: opts.url })
+    }
+}
+
+#[async_trait::async_trait]
+impl Db for NetDb {
+    async fn create_schema(&self) -> Result<()> {
+        // The collector owns the database and its schema.
+        Ok(())
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        // The collector owns the database and its schema.
+        Ok(())
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        // The collector owns the database and its schema.
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn get_log_entries(&self) -> Result<Vec<String>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    async fn query_log_entries(&self, _filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    fn stream_log_entries(&self, _filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        stream::once(async move { Err(unsupported("Querying log entries")) }).boxed()
+    }
+
+    async fn level_counts(&self, _filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        Err(unsupported("Querying log entries"))
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        Err(unsupported("Querying storage stats"))
+    }
+
+    async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = String::new();
+        for entry in &entries {
+            let line = export::format_entry_ndjson(entry, None)
+                .map_err(|e| DbError::Serialization(e.to_string(), None))?;
+            batch.push_str(&line);
+            batch.push('\n');
+        }
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(batch)
+            .send()
+            .await
+            .map_err(|e| {
+                let message = format!("Failed to send log entries to {}: {}", self.url, e);
+                DbError::Connection(message, Some(Box::new(e)))
+            })?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status == StatusCode::BAD_REQUEST {
+            Err(DbError::Serialization(
+                format!("Collector {} rejected log entries", self.url),
+                None,
+            ))
+        } else {
+            Err(DbError::Connection(
+                format!("Collector {} failed to accept log entries: {}", self.url, status),
+                None,
+            ))
+        }
+    }
+
+    async fn archive_log_entries_before(&self, _ts: OffsetDateTime) -> Result<u64> {
+        Err(unsupported("Archiving log entries"))
+    }
+
+    async fn delete_log_entries(&self, _filter: &LogFilter) -> Result<u64> {
+        Err(unsupported("Deleting log entries"))
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        Err(unsupported("Clearing log entries"))
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        // Entries are not written via SQL statements so there is no limit.
+        usize::MAX
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "net"
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        // The collector is responsible for maintaining its database.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "sqlite")]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use std::borrow::Cow;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Opens a new in-memory SQLite database with its schema initialized.
+    async fn new_sqlite() -> Connection {
+        let conn = sqlite::connect(sqlite::ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        conn.create_schema().await.unwrap();
+        conn
+    }
+
+    /// Creates a new log entry for tests with a `message` recorded at `secs`.
+    fn new_entry(secs: i64, message: &str) -> LogEntry {
+        LogEntry {
+            module: Some(Cow::Borrowed("the-module")),
+            filename: Some(Cow::Borrowed("the-file")),
+            line: Some(123),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+                "edge-host",
+                Level::Warn,
+                message.to_owned(),
+            )
+        }
+    }
+
+    /// Starts a collector that serves a single request by passing its body to `receive` on `db`,
+    /// or by replying with `status` if given.  Returns the URL of the collector.
+    async fn start_collector(db: Connection, status: Option<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/logs", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _addr) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let status = match status {
+                Some(status) => status,
+                None => match receive(&db, &String::from_utf8(body).unwrap()).await {
+                    Ok(_) => 200,
+                    Err(DbError::Serialization(..)) => 400,
+                    Err(_) => 500,
+                },
+            };
+            let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_netdb_put_log_entries() {
+        let backend = new_sqlite().await;
+        let url = start_collector(backend.clone(), None).await;
+
+        let db = NetDb::connect(ConnectionOptions { url, ..Default::default() }).unwrap();
+        db.put_log_entries(vec![new_entry(1, "First"), new_entry(2, "Second")]).await.unwrap();
+
+        assert_eq!(
+            vec![
+                "1.0 edge-host 2 the-module the-file:123 First".to_owned(),
+                "2.0 edge-host 2 the-module the-file:123 Second".to_owned(),
+            ],
+            backend.0.get_log_entries().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_netdb_put_log_entries_errors() {
+        let backend = new_sqlite().await;
+
+        let url = start_collector(backend.clone(), Some(400)).await;
+        let db = NetDb::connect(ConnectionOptions { url, ..Default::default() }).unwrap();
+        match db.put_log_entries(vec![new_entry(1, "First")]).await {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("rejected")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let url = start_collector(backend.clone(), Some(503)).await;
+        let db = NetDb::connect(ConnectionOptions { url, ..Default::default() }).unwrap();
+        match db.put_log_entries(vec![new_entry(1, "First")]).await {
+            Err(DbError::Connection(e, _)) => assert!(e.contains("503")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        assert!(backend.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_receive_bad_body() {
+        let backend = new_sqlite().await;
+        match receive(&backend, "{\"timestamp\":\"bogus\"}\n").await {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("Bad log entry")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(backend.0.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_netdb_queries_unsupported() {
+        let db = NetDb::connect(ConnectionOptions {
+            url: "http://127.0.0.1:1/logs".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(db.query_log_entries(&LogFilter::default()).await.is_err());
+        assert!(db.delete_log_entries(&LogFilter::default()).await.is_err());
+    }
+}
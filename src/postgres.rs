@@ -16,22 +16,89 @@
 //! Implementation of the database abstraction using PostgreSQL.
 
 use crate::logger::{
-    LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH, LOG_ENTRY_MAX_HOSTNAME_LENGTH,
-    LOG_ENTRY_MAX_MESSAGE_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
+    stderr_error_handler, InternalErrorHandler, LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH,
+    LOG_ENTRY_MAX_HOSTNAME_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
 };
-use crate::{truncate_option_str, Connection, Db, Result};
+use crate::query::{
+    crate_name_of, level_count_from_ints, spawn_stream, storage_stats_from_ints,
+    utc_offset_from_secs, Dialect,
+};
+use crate::{
+    apply_oversize_policy, check_columns, check_inserted_rows, connect_with_retries,
+    describe_batch, into_chunks, missing_columns, rows_per_statement, truncate_message,
+    truncate_option_str, truncate_str, wrap_sequence, Connection, Db, DbError, FieldCodec, Fields,
+    JsonFieldCodec, LevelEncoding, LogEntryRow, LogFilter, OversizePolicy, Result, StorageStats,
+};
+use futures::stream::BoxStream;
 use futures::TryStreamExt;
-use sqlx::postgres::{PgConnectOptions, PgPool};
+use log::Level;
+use sqlx::postgres::{PgConnectOptions, PgConnection, PgPool, PgPoolOptions, PgRow};
 use sqlx::Row;
 use std::convert::TryFrom;
 use std::env;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use time::OffsetDateTime;
 
 /// Schema to use to initialize the test database.
 const SCHEMA: &str = include_str!("../schemas/postgres.sql");
 
+/// Maximum number of bind parameters that PostgreSQL accepts in a single statement.
+const MAX_PARAMS_PER_STATEMENT: usize = 65535;
+
+/// Number of bind parameters needed to insert a single log entry.
+const PARAMS_PER_ROW: usize = 19;
+
+/// Header of the binary format of `COPY`: the signature followed by the flags and the length of
+/// the header extension, both zero.
+const COPY_BINARY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+
+/// Unix timestamp of the epoch of PostgreSQL's timestamps, which is 2000-01-01T00:00:00Z.
+const PG_EPOCH_UNIX_SECS: i64 = 946_684_800;
+
+/// Columns of the logs table that this code relies on, along with their types as reported by
+/// `information_schema`.
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("timestamp", "timestamp with time zone"),
+    ("sequence", "bigint"),
+    ("hostname", "text"),
+    ("level", "smallint"),
+    ("level_name", "text"),
+    ("module", "text"),
+    ("filename", "text"),
+    ("line", "integer"),
+    ("message", "text"),
+    ("uptime_nanos", "bigint"),
+    ("utc_offset_secs", "integer"),
+    ("trace_id", "text"),
+    ("span_id", "text"),
+    ("ulid", "text"),
+    ("crate_name", "text"),
+    ("correlation_id", "text"),
+    ("payload", "bytea"),
+    ("fields", "bytea"),
+    ("part", "integer"),
+    ("id", "bigint"),
+];
+
+/// Columns of the logs table that were added after its initial version, along with their
+/// definitions, which `migrate_schema` adds to tables that lack them.
+const MIGRATABLE_COLUMNS: &[(&str, &str)] = &[
+    ("level_name", "level_name TEXT"),
+    ("uptime_nanos", "uptime_nanos BIGINT"),
+    ("utc_offset_secs", "utc_offset_secs INTEGER"),
+    ("trace_id", "trace_id TEXT"),
+    ("span_id", "span_id TEXT"),
+    ("ulid", "ulid TEXT COLLATE \"C\""),
+    ("crate_name", "crate_name TEXT"),
+    ("correlation_id", "correlation_id TEXT"),
+    ("payload", "payload BYTEA"),
+    ("fields", "fields BYTEA"),
+    ("part", "part INTEGER NOT NULL DEFAULT 0"),
+    ("id", "id BIGSERIAL NOT NULL"),
+];
+
 /// Removes SQL-style comments from `input`.
 ///
 /// Useful to pre-process `SCHEMA` before splitting it into separate statements.
@@ -67,7 +134,6 @@ fn strip_sql_comments(input: &str) -> String {
 
 /// Options to establish a connection to a PostgreSQL database.
 #[derive(Default)]
-#[cfg_attr(test, derive(PartialEq))]
 pub struct ConnectionOptions {
     /// Host to connect to.
     pub host: String,
@@ -83,6 +149,90 @@ pub struct ConnectionOptions {
 
     /// Password to establish the connection with.
     pub password: String,
+
+    /// Name to report to the server as the `application_name` of the connections, which shows up
+    /// in `pg_stat_activity` and helps identify which service holds them.  If `None`, no name is
+    /// reported.
+    pub application_name: Option<String>,
+
+    /// Schema that holds the logs table, or `None` to use the server's default `search_path`,
+    /// which usually resolves to `public`.
+    ///
+    /// The schema must already exist: `create_schema` creates the table and its indexes in it but
+    /// does not create the schema itself.  The name must be a lowercase identifier.  Connections
+    /// set their `search_path` to this schema followed by `public`.
+    pub schema: Option<String>,
+
+    /// Whether to silently skip log entries that already exist in the database.
+    ///
+    /// Entries are identified by their timestamp, hostname and ULID, so only entries recorded with
+    /// `LoggerOptions::record_ulid` enabled can be deduplicated.  When enabled, inserts use
+    /// `ON CONFLICT DO NOTHING` so that replaying entries does not fail the whole batch, and the
+    /// number of inserted rows is allowed to be lower than the number of entries.
+    ///
+    /// This requires `LoggerOptions::record_ulid`: initializing the logger without it fails.
+    pub ignore_duplicates: bool,
+
+    /// Minimum number of log entries in a batch to write it with `COPY` instead of with `INSERT`,
+    /// or `None` to always use `INSERT`.  Defaults to `None`.
+    ///
+    /// `COPY` loads rows in bulk and is much faster than `INSERT` for large batches, but it has a
+    /// fixed setup cost that makes it slower for small ones.  It cannot skip rows that already
+    /// exist, so this is ignored when `ignore_duplicates` is enabled.
+    pub copy_threshold: Option<usize>,
+
+    /// Maximum time to wait for a connection from the pool before failing the operation, or
+    /// `None` to use the pool's default.
+    pub acquire_timeout: Option<Duration>,
+
+    /// Maximum time that the server lets any statement run before aborting it, or `None` to use
+    /// the server's default.
+    ///
+    /// Together with `acquire_timeout`, this makes writes to a stuck database fail, and be retried
+    /// according to `max_write_retries` in `LoggerOptions`, instead of stalling the logger.
+    pub statement_timeout: Option<Duration>,
+
+    /// Maximum length, in bytes, of the messages to store, or `None` to store them in full.
+    ///
+    /// Longer messages are handled according to `oversize_policy`.  The `message` column is of
+    /// type `TEXT` so there is no practical limit on its size.
+    pub max_message_length: Option<usize>,
+
+    /// What to do with the messages longer than `max_message_length`.  Defaults to truncating
+    /// them.
+    pub oversize_policy: OversizePolicy,
+
+    /// Integers to store in the `level` column for each level.  Defaults to 1 for errors up to 5
+    /// for traces.
+    ///
+    /// The values must fit in a `SMALLINT` or writes fail.  Changing this on an existing database
+    /// makes the entries already in it unreadable.
+    pub level_encoding: LevelEncoding,
+
+    /// Codec to serialize the structured fields of the entries into the `fields` column, or `None`
+    /// to store them as JSON via `JsonFieldCodec`.
+    ///
+    /// Changing this on an existing database makes the fields already in it unreadable.
+    pub field_codec: Option<Arc<dyn FieldCodec>>,
+
+    /// Number of times to retry establishing the first connection if the database does not accept
+    /// it, such as when the server is still starting up.  Only honored by `connect`.  Defaults to
+    /// zero.
+    ///
+    /// `connect` fails with the error of the last attempt once all attempts fail.  The attempts
+    /// that are retried are reported to `internal_error_handler`.
+    pub connect_retries: usize,
+
+    /// Time to wait before the first retry to connect, which doubles after every failed attempt up
+    /// to 30 seconds, or `None` to wait 500 milliseconds.  Only honored by `connect`.
+    pub connect_retry_delay: Option<Duration>,
+
+    /// Function to handle the failed attempts to connect that `connect_retries` retries.  Defaults
+    /// to `None`, which prints them to stderr.  Only honored by `connect`.
+    ///
+    /// These happen before the logger exists, so set this to the same function as the
+    /// `internal_error_handler` of `LoggerOptions` to handle all problems in one place.
+    pub internal_error_handler: Option<InternalErrorHandler>,
 }
 
 #[cfg(test)]
@@ -94,57 +244,322 @@ impl std::fmt::Debug for ConnectionOptions {
             .field("database", &self.database)
             .field("username", &self.username)
             .field("password", &"scrubbed".to_owned())
+            .field("application_name", &self.application_name)
+            .field("schema", &self.schema)
+            .field("ignore_duplicates", &self.ignore_duplicates)
+            .field("copy_threshold", &self.copy_threshold)
+            .field("acquire_timeout", &self.acquire_timeout)
+            .field("statement_timeout", &self.statement_timeout)
+            .field("max_message_length", &self.max_message_length)
+            .field("oversize_policy", &self.oversize_policy)
+            .field("level_encoding", &self.level_encoding)
+            .field("field_codec", &self.field_codec.is_some())
+            .field("connect_retries", &self.connect_retries)
+            .field("connect_retry_delay", &self.connect_retry_delay)
+            .field("internal_error_handler", &self.internal_error_handler.is_some())
             .finish()
     }
 }
 
+#[cfg(test)]
+impl PartialEq for ConnectionOptions {
+    fn eq(&self, other: &Self) -> bool {
+        // Functions and codecs cannot be compared so only check if they are the same instance.
+        let same_codec = match (&self.field_codec, &other.field_codec) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        let same_handler = match (&self.internal_error_handler, &other.internal_error_handler) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        self.host == other.host
+            && self.port == other.port
+            && self.database == other.database
+            && self.username == other.username
+            && self.password == other.password
+            && self.application_name == other.application_name
+            && self.schema == other.schema
+            && self.ignore_duplicates == other.ignore_duplicates
+            && self.copy_threshold == other.copy_threshold
+            && self.acquire_timeout == other.acquire_timeout
+            && self.statement_timeout == other.statement_timeout
+            && self.max_message_length == other.max_message_length
+            && self.oversize_policy == other.oversize_policy
+            && self.level_encoding == other.level_encoding
+            && same_codec
+            && self.connect_retries == other.connect_retries
+            && self.connect_retry_delay == other.connect_retry_delay
+            && same_handler
+    }
+}
+
 impl ConnectionOptions {
     /// Initializes a set of options from environment variables whose name is prefixed with the
     /// given `prefix`.
     ///
     /// This will use variables such as `<prefix>_HOST`, `<prefix>_PORT`, `<prefix>_DATABASE`,
-    /// `<prefix>_USERNAME` and `<prefix>_PASSWORD`.
+    /// `<prefix>_USERNAME` and `<prefix>_PASSWORD`, plus the optional
+    /// `<prefix>_APPLICATION_NAME` and `<prefix>_SCHEMA`.
     pub fn from_env(prefix: &str) -> Result<ConnectionOptions> {
         fn get_required_var(prefix: &str, suffix: &str) -> Result<String> {
             let name = format!("{}_{}", prefix, suffix);
             match env::var(&name) {
                 Ok(value) => Ok(value),
-                Err(env::VarError::NotPresent) => {
-                    Err(format!("Required environment variable {} not present", name))
-                }
-                Err(env::VarError::NotUnicode(_)) => {
-                    Err(format!("Invalid value in environment variable {}", name))
-                }
+                Err(env::VarError::NotPresent) => Err(DbError::Other(
+                    format!("Required environment variable {} not present", name),
+                    None,
+                )),
+                Err(env::VarError::NotUnicode(_)) => Err(DbError::Other(
+                    format!("Invalid value in environment variable {}", name),
+                    None,
+                )),
             }
         }
+        let schema = env::var(format!("{}_SCHEMA", prefix)).ok();
+        if let Some(schema) = schema.as_deref() {
+            check_schema_name(schema)?;
+        }
         Ok(ConnectionOptions {
             host: get_required_var(prefix, "HOST")?,
             port: get_required_var(prefix, "PORT")?
                 .parse::<u16>()
-                .map_err(|e| format!("Invalid port number: {}", e))?,
+                .map_err(|e| DbError::Other(format!("Invalid port number: {}", e), None))?,
             database: get_required_var(prefix, "DATABASE")?,
             username: get_required_var(prefix, "USERNAME")?,
             password: get_required_var(prefix, "PASSWORD")?,
+            application_name: env::var(format!("{}_APPLICATION_NAME", prefix)).ok(),
+            schema,
+            ignore_duplicates: false,
+            copy_threshold: None,
+            acquire_timeout: None,
+            statement_timeout: None,
+            max_message_length: None,
+            oversize_policy: OversizePolicy::default(),
+            level_encoding: LevelEncoding::default(),
+            field_codec: None,
+            connect_retries: 0,
+            connect_retry_delay: None,
+            internal_error_handler: None,
         })
     }
 }
 
+/// Maximum length of an identifier in PostgreSQL, in bytes.
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Checks that `name` is a valid identifier to use as the name of a schema.
+///
+/// Only lowercase names are accepted because PostgreSQL folds unquoted identifiers to lowercase,
+/// which would make the schema in use differ from the configured one when the user refers to it
+/// without quotes.
+fn check_schema_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid_start = matches!(chars.next(), Some(ch) if ch.is_ascii_lowercase() || ch == '_');
+    let valid_rest = chars.all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '_');
+    if !valid_start || !valid_rest || name.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(DbError::Other(
+            format!(
+                "Invalid schema name '{}': must be a lowercase identifier of up to {} characters",
+                name, MAX_IDENTIFIER_LENGTH
+            ),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the statement to resolve unqualified names in `schema`, which must have been validated
+/// with `check_schema_name`.
+///
+/// The name is quoted so that reserved words such as `user` refer to the schema, and `public` is
+/// kept in the path so that types and functions installed there remain usable.
+fn search_path_statement(schema: &str) -> String {
+    format!("SET search_path TO \"{}\", public", schema)
+}
+
+/// Factory to connect to a PostgreSQL database, waiting until it accepts connections.
+///
+/// Unlike `connect_lazy`, this establishes a connection before returning, retrying according to
+/// `connect_retries` and `connect_retry_delay`, so that the caller can wait for a database that is
+/// still starting up instead of failing its first writes.
+pub async fn connect(opts: ConnectionOptions) -> Result<Connection> {
+    if let Some(schema) = opts.schema.as_deref() {
+        check_schema_name(schema)?;
+    }
+    let retries = opts.connect_retries;
+    let delay = opts.connect_retry_delay;
+    let on_error = opts.internal_error_handler.clone().unwrap_or_else(stderr_error_handler);
+    let db = PostgresDb::connect_lazy(opts, None);
+    connect_with_retries(retries, delay, &on_error, || {
+        let pool = db.pool.clone();
+        async move { pool.acquire().await.map(|_| ()).map_err(DbError::from) }
+    })
+    .await?;
+    Ok(Connection(Arc::from(db)))
+}
+
 /// Factory to connect to a PostgreSQL database.
+///
+/// Connections are established on demand so this never fails, but the first operations fail if the
+/// database is not reachable or if the `schema` name is invalid.  Use `connect` to wait for the
+/// database instead.
+///
+/// When reusing an existing pool via `from_pool`, configure its `search_path` to use a schema other
+/// than the default.
 pub fn connect_lazy(opts: ConnectionOptions) -> Connection {
     Connection(Arc::from(PostgresDb::connect_lazy(opts, None)))
 }
 
+/// Factory to use an existing `pool` to access a PostgreSQL database.
+///
+/// This is useful to share the pool that the application already uses for its own data instead of
+/// opening a second pool to the same database.
+pub fn from_pool(pool: PgPool) -> Connection {
+    Connection(Arc::from(PostgresDb::from_pool(pool, None)))
+}
+
 /// Factory to connect to and initialize a PostgreSQL test database.
 pub async fn setup_test(opts: ConnectionOptions) -> Connection {
     Connection(Arc::from(PostgresTestDb::setup_test(opts).await))
 }
 
+/// Converts a `row` from the logs table into a `LogEntryRow`, interpreting its level and its
+/// structured fields via `db`.
+fn row_to_entry(db: &dyn Db, row: &PgRow) -> Result<LogEntryRow> {
+    let timestamp: OffsetDateTime = row.try_get("timestamp").map_err(DbError::from)?;
+    let sequence: i64 = row.try_get("sequence").map_err(DbError::from)?;
+    let hostname: String = row.try_get("hostname").map_err(DbError::from)?;
+    let level: i16 = row.try_get("level").map_err(DbError::from)?;
+    let module: Option<String> = row.try_get("module").map_err(DbError::from)?;
+    let filename: Option<String> = row.try_get("filename").map_err(DbError::from)?;
+    let line: Option<i32> = row.try_get("line").map_err(DbError::from)?;
+    let message: String = row.try_get("message").map_err(DbError::from)?;
+    let uptime_nanos: Option<i64> = row.try_get("uptime_nanos").map_err(DbError::from)?;
+    let utc_offset_secs: Option<i32> = row.try_get("utc_offset_secs").map_err(DbError::from)?;
+    let trace_id: Option<String> = row.try_get("trace_id").map_err(DbError::from)?;
+    let span_id: Option<String> = row.try_get("span_id").map_err(DbError::from)?;
+    let ulid: Option<String> = row.try_get("ulid").map_err(DbError::from)?;
+    let correlation_id: Option<String> = row.try_get("correlation_id").map_err(DbError::from)?;
+    let payload: Option<Vec<u8>> = row.try_get("payload").map_err(DbError::from)?;
+    let fields: Option<Vec<u8>> = row.try_get("fields").map_err(DbError::from)?;
+    let id: i64 = row.try_get("id").map_err(DbError::from)?;
+
+    Ok(LogEntryRow {
+        timestamp,
+        sequence,
+        hostname,
+        level: db.decode_level(i64::from(level))?,
+        module,
+        filename,
+        line: match line {
+            Some(line) => Some(
+                u32::try_from(line)
+                    .map_err(|_| DbError::Serialization("line out of range".to_owned(), None))?,
+            ),
+            None => None,
+        },
+        message,
+        uptime_nanos,
+        utc_offset: utc_offset_from_secs(utc_offset_secs)?,
+        trace_id,
+        span_id,
+        ulid,
+        correlation_id,
+        payload,
+        fields: fields.map(|fields| db.decode_fields(&fields)).transpose()?,
+        id: Some(id),
+    })
+}
+
+/// Values of the columns of a log entry, truncated to fit the logs table.
+struct RowValues {
+    timestamp: OffsetDateTime,
+    sequence: i64,
+    hostname: String,
+    level: i16,
+    level_name: &'static str,
+    module: Option<String>,
+    filename: Option<String>,
+    line: Option<i32>,
+    message: String,
+    uptime_nanos: Option<i64>,
+    utc_offset_secs: Option<i32>,
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    ulid: Option<String>,
+    crate_name: Option<String>,
+    correlation_id: Option<String>,
+    payload: Option<Vec<u8>>,
+    fields: Option<Vec<u8>>,
+    part: i32,
+}
+
+/// Converts `timestamp` to the number of microseconds since the epoch of PostgreSQL, which is its
+/// binary representation of timestamps.
+fn timestamp_to_pg_micros(timestamp: OffsetDateTime) -> Result<i64> {
+    let epoch = OffsetDateTime::from_unix_timestamp(PG_EPOCH_UNIX_SECS)
+        .expect("PostgreSQL epoch must be valid");
+    i64::try_from((timestamp - epoch).whole_microseconds())
+        .map_err(|_| DbError::Serialization("timestamp out of range".to_owned(), None))
+}
+
+/// Appends a field with `value`, or a null if `None`, to the binary `COPY` data in `data`.
+fn put_copy_field<T: AsRef<[u8]>>(data: &mut Vec<u8>, value: Option<T>) -> Result<()> {
+    match value {
+        Some(value) => {
+            let value = value.as_ref();
+            let len = i32::try_from(value.len())
+                .map_err(|_| DbError::Serialization("field too long".to_owned(), None))?;
+            data.extend_from_slice(&len.to_be_bytes());
+            data.extend_from_slice(value);
+        }
+        None => data.extend_from_slice(&(-1i32).to_be_bytes()),
+    }
+    Ok(())
+}
+
+/// Appends the tuple for `row` to the binary `COPY` data in `data`, with the fields in the same
+/// order as the columns of the `INSERT` statements.
+fn put_copy_row(data: &mut Vec<u8>, row: &RowValues) -> Result<()> {
+    let nfields = i16::try_from(PARAMS_PER_ROW).expect("Number of columns must fit in i16");
+    data.extend_from_slice(&nfields.to_be_bytes());
+    put_copy_field(data, Some(timestamp_to_pg_micros(row.timestamp)?.to_be_bytes()))?;
+    put_copy_field(data, Some(row.sequence.to_be_bytes()))?;
+    put_copy_field(data, Some(&row.hostname))?;
+    put_copy_field(data, Some(row.level.to_be_bytes()))?;
+    put_copy_field(data, Some(row.level_name))?;
+    put_copy_field(data, row.module.as_deref())?;
+    put_copy_field(data, row.filename.as_deref())?;
+    put_copy_field(data, row.line.map(i32::to_be_bytes))?;
+    put_copy_field(data, Some(&row.message))?;
+    put_copy_field(data, row.uptime_nanos.map(i64::to_be_bytes))?;
+    put_copy_field(data, row.utc_offset_secs.map(i32::to_be_bytes))?;
+    put_copy_field(data, row.trace_id.as_deref())?;
+    put_copy_field(data, row.span_id.as_deref())?;
+    put_copy_field(data, row.ulid.as_deref())?;
+    put_copy_field(data, row.crate_name.as_deref())?;
+    put_copy_field(data, row.correlation_id.as_deref())?;
+    put_copy_field(data, row.payload.as_deref())?;
+    put_copy_field(data, row.fields.as_deref())?;
+    put_copy_field(data, Some(row.part.to_be_bytes()))
+}
+
 /// A database instance backed by a PostgreSQL database.
 #[derive(Clone)]
 struct PostgresDb {
     pool: PgPool,
     suffix: Option<u32>,
-    log_sequence: Arc<AtomicI64>,
+    log_sequence: Arc<AtomicU64>,
+    ignore_duplicates: bool,
+    copy_threshold: Option<usize>,
+    max_message_length: Option<usize>,
+    oversize_policy: OversizePolicy,
+    level_encoding: LevelEncoding,
+    field_codec: Arc<dyn FieldCodec>,
+    rejected: Arc<AtomicU64>,
 }
 
 impl PostgresDb {
@@ -156,11 +571,66 @@ impl PostgresDb {
             .database(&opts.database)
             .username(&opts.username)
             .password(&opts.password);
+        let options = match opts.application_name.as_deref() {
+            Some(name) => options.application_name(name),
+            None => options,
+        };
+        let options = match opts.statement_timeout {
+            Some(timeout) => options.options([("statement_timeout", timeout.as_millis())]),
+            None => options,
+        };
+
+        let mut pool_opts = PgPoolOptions::new();
+        if let Some(timeout) = opts.acquire_timeout {
+            pool_opts = pool_opts.acquire_timeout(timeout);
+        }
+        if let Some(schema) = opts.schema {
+            // Setting the search path on every connection makes all queries, including those that
+            // create the schema, resolve the unqualified table and index names in it.  The name
+            // is validated here because this factory cannot fail.
+            pool_opts = pool_opts.after_connect(move |conn, _metadata| {
+                let schema = schema.clone();
+                Box::pin(async move {
+                    check_schema_name(&schema)
+                        .map_err(|e| sqlx::Error::Configuration(e.to_string().into()))?;
+                    sqlx::query(&search_path_statement(&schema)).execute(conn).await?;
+                    Ok(())
+                })
+            });
+        }
+
+        Self {
+            ignore_duplicates: opts.ignore_duplicates,
+            copy_threshold: opts.copy_threshold,
+            max_message_length: opts.max_message_length,
+            oversize_policy: opts.oversize_policy,
+            level_encoding: opts.level_encoding,
+            field_codec: opts.field_codec.unwrap_or_else(|| Arc::from(JsonFieldCodec)),
+            ..Self::from_pool(pool_opts.connect_lazy_with(options), suffix)
+        }
+    }
 
+    /// Creates a new instance backed by an already-established `pool`.
+    fn from_pool(pool: PgPool, suffix: Option<u32>) -> Self {
         Self {
-            pool: PgPool::connect_lazy_with(options),
+            pool,
             suffix,
-            log_sequence: Arc::from(AtomicI64::new(0)),
+            log_sequence: Arc::from(AtomicU64::new(0)),
+            ignore_duplicates: false,
+            copy_threshold: None,
+            max_message_length: None,
+            oversize_policy: OversizePolicy::default(),
+            level_encoding: LevelEncoding::default(),
+            field_codec: Arc::from(JsonFieldCodec),
+            rejected: Arc::from(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the name of the table that holds the log entries, accounting for the `suffix`.
+    fn table_name(&self) -> String {
+        match self.suffix {
+            None => "logs".to_owned(),
+            Some(suffix) => format!("logs_{}", suffix),
         }
     }
 
@@ -172,6 +642,173 @@ impl PostgresDb {
             Some(suffix) => query.replace(" logs", &format!(" logs_{}", suffix)),
         }
     }
+
+    /// Returns the names and types of the columns of the logs table via `executor`.
+    async fn table_columns<'e, E: sqlx::PgExecutor<'e>>(
+        &self,
+        executor: E,
+    ) -> Result<Vec<(String, String)>> {
+        sqlx::query_as(
+            "SELECT column_name, data_type FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = $1",
+        )
+        .bind(self.table_name())
+        .fetch_all(executor)
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Computes the values of the columns for `entry`, which was assigned `sequence` and is the
+    /// `part`-th part of a split entry.
+    fn row_values(&self, sequence: u64, part: u32, mut entry: LogEntry) -> Result<RowValues> {
+        let module = truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH);
+        let crate_name = module.as_deref().map(|module| crate_name_of(module).to_owned());
+        let filename = truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH);
+        truncate_message(&mut entry.message, self.max_message_length);
+
+        let line = match entry.line {
+            Some(n) => Some(
+                i32::try_from(n)
+                    .map_err(|_| DbError::Serialization("line out of range".to_owned(), None))?,
+            ),
+            None => None,
+        };
+        let level = i16::try_from(self.encode_level(entry.level))
+            .map_err(|_| DbError::Serialization("level out of range".to_owned(), None))?;
+        let part = i32::try_from(part)
+            .map_err(|_| DbError::Serialization("part out of range".to_owned(), None))?;
+        let fields = entry.fields.as_ref().map(|fields| self.encode_fields(fields)).transpose()?;
+
+        Ok(RowValues {
+            timestamp: entry.timestamp,
+            sequence: wrap_sequence(sequence),
+            hostname: truncate_str(&entry.hostname, LOG_ENTRY_MAX_HOSTNAME_LENGTH),
+            level,
+            level_name: entry.level.as_str(),
+            module,
+            filename,
+            line,
+            message: entry.message,
+            uptime_nanos: entry.uptime_nanos,
+            utc_offset_secs: entry.utc_offset.map(|offset| offset.whole_seconds()),
+            trace_id: entry.trace_id,
+            span_id: entry.span_id,
+            ulid: entry.ulid,
+            crate_name,
+            correlation_id: entry.correlation_id,
+            payload: entry.payload,
+            fields,
+            part,
+        })
+    }
+
+    /// Inserts all `entries`, tagged with their sequence numbers and part positions, via `conn` in
+    /// a single statement.
+    ///
+    /// The caller is responsible for keeping the number of `entries` small enough to not exceed
+    /// the maximum number of parameters per statement.
+    async fn insert_chunk(
+        &self,
+        conn: &mut PgConnection,
+        entries: Vec<(u64, u32, LogEntry)>,
+    ) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).expect("usize must fit in u64");
+
+        let mut query_str = self.patch_query(
+            "INSERT INTO logs
+                (timestamp, sequence, hostname, level, level_name, module, filename, line,
+                message, uptime_nanos, utc_offset_secs, trace_id, span_id, ulid, crate_name,
+                correlation_id, payload, fields, part)
+            VALUES ",
+        );
+
+        let mut param: usize = 1;
+        for _ in 0..nentries {
+            if param > 1 {
+                query_str.push(',');
+            }
+            query_str.push('(');
+            for i in 1..PARAMS_PER_ROW + 1 {
+                if i == 1 {
+                    query_str += &format!("${}", param);
+                } else {
+                    query_str += &format!(", ${}", param);
+                }
+                param += 1;
+            }
+            query_str.push(')');
+        }
+        if self.ignore_duplicates {
+            query_str.push_str(" ON CONFLICT DO NOTHING");
+        }
+
+        let batch = describe_batch(&entries);
+        let mut query = sqlx::query(&query_str);
+        for (sequence, part, entry) in entries.into_iter() {
+            let row = self.row_values(sequence, part, entry)?;
+            query = query
+                .bind(row.timestamp)
+                .bind(row.sequence)
+                .bind(row.hostname)
+                .bind(row.level)
+                .bind(row.level_name)
+                .bind(row.module)
+                .bind(row.filename)
+                .bind(row.line)
+                .bind(row.message)
+                .bind(row.uptime_nanos)
+                .bind(row.utc_offset_secs)
+                .bind(row.trace_id)
+                .bind(row.span_id)
+                .bind(row.ulid)
+                .bind(row.crate_name)
+                .bind(row.correlation_id)
+                .bind(row.payload)
+                .bind(row.fields)
+                .bind(row.part);
+        }
+
+        let done = query.execute(&mut *conn).await.map_err(DbError::from)?;
+        check_inserted_rows(
+            &self.table_name(),
+            &batch,
+            nentries,
+            done.rows_affected(),
+            self.ignore_duplicates,
+        )
+    }
+
+    /// Loads all `entries`, tagged with their sequence numbers and part positions, via `conn` with
+    /// a single `COPY` in binary format.
+    ///
+    /// Unlike `insert_chunk`, this is not subject to the limit on the number of parameters per
+    /// statement, but it cannot skip duplicate entries.
+    async fn copy_entries(
+        &self,
+        conn: &mut PgConnection,
+        entries: Vec<(u64, u32, LogEntry)>,
+    ) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).expect("usize must fit in u64");
+        let batch = describe_batch(&entries);
+
+        let mut data = COPY_BINARY_HEADER.to_vec();
+        for (sequence, part, entry) in entries.into_iter() {
+            put_copy_row(&mut data, &self.row_values(sequence, part, entry)?)?;
+        }
+        data.extend_from_slice(&(-1i16).to_be_bytes());
+
+        let query_str = self.patch_query(
+            "COPY logs
+                (timestamp, sequence, hostname, level, level_name, module, filename, line,
+                message, uptime_nanos, utc_offset_secs, trace_id, span_id, ulid, crate_name,
+                correlation_id, payload, fields, part)
+            FROM STDIN (FORMAT BINARY)",
+        );
+        let mut copy = conn.copy_in_raw(&query_str).await.map_err(DbError::from)?;
+        copy.send(data).await.map_err(DbError::from)?;
+        let copied = copy.finish().await.map_err(DbError::from)?;
+        check_inserted_rows(&self.table_name(), &batch, nentries, copied, false)
+    }
 }
 
 #[async_trait::async_trait]
@@ -179,25 +816,65 @@ impl Db for PostgresDb {
     async fn create_schema(&self) -> Result<()> {
         let schema = self.patch_query(&strip_sql_comments(SCHEMA));
 
-        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
         for query_str in schema.split(';') {
-            sqlx::query(query_str).execute(&mut *tx).await.map_err(|e| e.to_string())?;
+            sqlx::query(query_str).execute(&mut *tx).await.map_err(DbError::from)?;
+        }
+        tx.commit().await.map_err(DbError::from)
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        let columns = self.table_columns(&self.pool).await?;
+        check_columns(&self.table_name(), EXPECTED_COLUMNS, &columns)
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+
+        let mut columns = self.table_columns(&mut *tx).await?;
+        for (name, data_type) in columns.iter_mut() {
+            // The initial version of the schema stored line numbers in a SMALLINT, which is too
+            // narrow for large source files.
+            if name == "line" && data_type == "smallint" {
+                let query_str = self.patch_query("ALTER TABLE logs ALTER COLUMN line TYPE INTEGER");
+                sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
+                *data_type = "integer".to_owned();
+            }
+        }
+
+        let table = self.table_name();
+        for definition in missing_columns(&table, EXPECTED_COLUMNS, MIGRATABLE_COLUMNS, &columns)? {
+            let query_str =
+                self.patch_query(&format!("ALTER TABLE logs ADD COLUMN {}", definition));
+            sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
+        }
+
+        let schema = self.patch_query(&strip_sql_comments(SCHEMA));
+        for query_str in schema.split(';').map(str::trim) {
+            if query_str.starts_with("CREATE INDEX ")
+                || query_str.starts_with("CREATE UNIQUE INDEX ")
+            {
+                let query_str = query_str.replacen("INDEX ", "INDEX IF NOT EXISTS ", 1);
+                sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
+            }
         }
-        tx.commit().await.map_err(|e| e.to_string())
+
+        tx.commit().await.map_err(DbError::from)
     }
 
+    #[cfg(test)]
     async fn get_log_entries(&self) -> Result<Vec<String>> {
         let query_str = self.patch_query("SELECT * FROM logs ORDER BY timestamp, sequence");
         let mut rows = sqlx::query(&query_str).fetch(&self.pool);
         let mut entries = vec![];
-        while let Some(row) = rows.try_next().await.map_err(|e| e.to_string())? {
-            let timestamp: OffsetDateTime = row.try_get("timestamp").map_err(|e| e.to_string())?;
-            let hostname: String = row.try_get("hostname").map_err(|e| e.to_string())?;
-            let level: i16 = row.try_get("level").map_err(|e| e.to_string())?;
-            let module: Option<String> = row.try_get("module").map_err(|e| e.to_string())?;
-            let filename: Option<String> = row.try_get("filename").map_err(|e| e.to_string())?;
-            let line: Option<i16> = row.try_get("line").map_err(|e| e.to_string())?;
-            let message: String = row.try_get("message").map_err(|e| e.to_string())?;
+        while let Some(row) = rows.try_next().await.map_err(DbError::from)? {
+            let timestamp: OffsetDateTime = row.try_get("timestamp").map_err(DbError::from)?;
+            let hostname: String = row.try_get("hostname").map_err(DbError::from)?;
+            let level: i16 = row.try_get("level").map_err(DbError::from)?;
+            let module: Option<String> = row.try_get("module").map_err(DbError::from)?;
+            let filename: Option<String> = row.try_get("filename").map_err(DbError::from)?;
+            let line: Option<i32> = row.try_get("line").map_err(DbError::from)?;
+            let message: String = row.try_get("message").map_err(DbError::from)?;
 
             entries.push(format!(
                 "{}.{} {} {} {} {}:{} {}",
@@ -214,71 +891,224 @@ impl Db for PostgresDb {
         Ok(entries)
     }
 
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        let where_clause =
+            filter.where_clause(Dialect::Postgres, &|level| self.encode_level(level));
+        let query_str = self.patch_query(&format!(
+            "SELECT * FROM logs{}{}",
+            where_clause.sql,
+            filter.order_clause(&["timestamp", "sequence"])
+        ));
+        let mut query = sqlx::query(&query_str);
+        for param in where_clause.params {
+            query = query.bind(param);
+        }
+        let mut rows = query.fetch(&self.pool);
+        let mut entries = vec![];
+        while let Some(row) = rows.try_next().await.map_err(DbError::from)? {
+            entries.push(row_to_entry(self, &row)?);
+        }
+        Ok(entries)
+    }
+
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        let db = self.clone();
+        let where_clause =
+            filter.where_clause(Dialect::Postgres, &|level| self.encode_level(level));
+        let query_str = self.patch_query(&format!(
+            "SELECT * FROM logs{}{}",
+            where_clause.sql,
+            filter.order_clause(&["timestamp", "sequence"])
+        ));
+        spawn_stream(move |tx| async move {
+            let mut query = sqlx::query(&query_str);
+            for param in where_clause.params {
+                query = query.bind(param);
+            }
+            let mut rows = query.fetch(&db.pool);
+            loop {
+                let result = match rows.try_next().await {
+                    Ok(Some(row)) => row_to_entry(&db, &row),
+                    Ok(None) => break,
+                    Err(e) => Err(DbError::from(e)),
+                };
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        })
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        let where_clause =
+            filter.where_clause(Dialect::Postgres, &|level| self.encode_level(level));
+        let query_str = self.patch_query(&format!(
+            "SELECT level, COUNT(*) FROM logs{} GROUP BY level ORDER BY level",
+            where_clause.sql
+        ));
+        let mut query = sqlx::query_as(&query_str);
+        for param in where_clause.params {
+            query = query.bind(param);
+        }
+        let rows: Vec<(i16, i64)> = query.fetch_all(&self.pool).await.map_err(DbError::from)?;
+        let mut counts = rows
+            .into_iter()
+            .map(|(level, count)| {
+                level_count_from_ints(i64::from(level), count, &|level| self.decode_level(level))
+            })
+            .collect::<Result<Vec<(Level, u64)>>>()?;
+        // The encoding of the levels may not sort them from most to least severe.
+        counts.sort_by_key(|(level, _count)| *level);
+        Ok(counts)
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        let query_str = self.patch_query("SELECT DISTINCT hostname FROM logs ORDER BY hostname");
+        sqlx::query_scalar(&query_str).fetch_all(&self.pool).await.map_err(DbError::from)
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        let query_str = self.patch_query(
+            "SELECT DISTINCT module FROM logs WHERE module IS NOT NULL ORDER BY module",
+        );
+        sqlx::query_scalar(&query_str).fetch_all(&self.pool).await.map_err(DbError::from)
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        let query_str = self.patch_query("SELECT MIN(timestamp), MAX(timestamp) FROM logs");
+        let bounds: (Option<OffsetDateTime>, Option<OffsetDateTime>) =
+            sqlx::query_as(&query_str).fetch_one(&self.pool).await.map_err(DbError::from)?;
+        match bounds {
+            (Some(oldest), Some(newest)) => Ok(Some((oldest, newest))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        let query_str = self
+            .patch_query("SELECT COUNT(*), pg_total_relation_size(CAST($1 AS regclass)) FROM logs");
+        let (rows, bytes): (i64, i64) = sqlx::query_as(&query_str)
+            .bind(self.table_name())
+            .fetch_one(&self.pool)
+            .await
+            .map_err(DbError::from)?;
+        storage_stats_from_ints(rows, bytes)
+    }
+
     async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
-        let nentries = i64::try_from(entries.len())
-            .map_err(|e| format!("Cannot insert {} log entries at once: {}", entries.len(), e))?;
+        self.put_prepared_log_entries(self.prepare_log_entries(entries)).await
+    }
+
+    fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+        apply_oversize_policy(
+            entries,
+            self.max_message_length,
+            self.oversize_policy,
+            &self.rejected,
+        )
+    }
+
+    async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).map_err(|e| {
+            DbError::Other(
+                format!("Cannot insert {} log entries at once: {}", entries.len(), e),
+                None,
+            )
+        })?;
         if nentries == 0 {
             return Ok(());
         }
         let mut sequence = self.log_sequence.fetch_add(nentries, Ordering::SeqCst);
 
-        let mut query_str = self.patch_query(
-            "INSERT INTO logs
-                (timestamp, sequence, hostname, level, module, filename, line, message)
-            VALUES ",
-        );
-        const NPARAMS: usize = 8;
+        let mut batch = Vec::with_capacity(entries.len());
+        for (part, entry) in entries.into_iter() {
+            batch.push((sequence, part, entry));
+            sequence = sequence.wrapping_add(1);
+        }
 
-        let mut param: usize = 1;
-        for _ in 0..nentries {
-            if param > 1 {
-                query_str.push(',');
-            }
-            query_str.push('(');
-            for i in 1..NPARAMS + 1 {
-                if i == 1 {
-                    query_str += &format!("${}", param);
-                } else {
-                    query_str += &format!(", ${}", param);
-                }
-                param += 1;
+        // COPY cannot skip duplicates, so fall back to INSERT when they must be ignored.
+        let use_copy = !self.ignore_duplicates
+            && matches!(self.copy_threshold, Some(threshold) if batch.len() >= threshold);
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        if use_copy {
+            self.copy_entries(&mut tx, batch).await?;
+        } else {
+            // Split large batches into multiple statements to respect the limit on the number of
+            // parameters per statement, but write all of them atomically.
+            let rows = rows_per_statement(self.max_params_per_statement(), PARAMS_PER_ROW);
+            for chunk in into_chunks(batch, rows) {
+                self.insert_chunk(&mut tx, chunk).await?;
             }
-            query_str.push(')');
         }
+        tx.commit().await.map_err(DbError::from)
+    }
+
+    async fn archive_log_entries_before(&self, _ts: OffsetDateTime) -> Result<u64> {
+        Err(DbError::Other(
+            "Archiving log entries is not supported by PostgreSQL yet".to_owned(),
+            None,
+        ))
+    }
 
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        let where_clause =
+            filter.where_clause(Dialect::Postgres, &|level| self.encode_level(level));
+        let query_str = self.patch_query(&format!("DELETE FROM logs{}", where_clause.sql));
         let mut query = sqlx::query(&query_str);
-        for mut entry in entries.into_iter() {
-            let module = truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH);
-            let filename = truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH);
-            entry.hostname.truncate(LOG_ENTRY_MAX_HOSTNAME_LENGTH);
-            entry.message.truncate(LOG_ENTRY_MAX_MESSAGE_LENGTH);
+        for param in where_clause.params {
+            query = query.bind(param);
+        }
+        let done = query.execute(&self.pool).await.map_err(DbError::from)?;
+        Ok(done.rows_affected())
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        // There is no write semaphore to hold here: TRUNCATE waits for the transactions that
+        // write to the table to finish, but batches that already got their sequence numbers may
+        // be written after the counter is reset.
+        let query_str = self.patch_query("TRUNCATE TABLE logs");
+        sqlx::query(&query_str).execute(&self.pool).await.map_err(DbError::from)?;
+        self.log_sequence.store(0, Ordering::SeqCst);
+        Ok(())
+    }
 
-            let line = match entry.line {
-                Some(n) => Some(i16::try_from(n).map_err(|_| "line out of range".to_owned())?),
-                None => None,
-            };
+    fn max_params_per_statement(&self) -> usize {
+        MAX_PARAMS_PER_STATEMENT
+    }
 
-            query = query
-                .bind(entry.timestamp)
-                .bind(sequence)
-                .bind(entry.hostname)
-                .bind(i16::try_from(entry.level as usize).expect("Levels must fit in u16"))
-                .bind(module)
-                .bind(filename)
-                .bind(line)
-                .bind(entry.message);
-
-            sequence += 1;
-        }
-
-        let done = query.execute(&self.pool).await.map_err(|e| e.to_string())?;
-        if done.rows_affected() != u64::try_from(nentries).unwrap() {
-            return Err(format!(
-                "Log entries insertion created {} rows but expected {}",
-                done.rows_affected(),
-                nentries
-            ));
-        }
+    fn encode_level(&self, level: Level) -> i64 {
+        self.level_encoding.encode(level)
+    }
+
+    fn decode_level(&self, level: i64) -> Result<Level> {
+        self.level_encoding.decode(level)
+    }
+
+    fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+        self.field_codec.encode(fields)
+    }
+
+    fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+        self.field_codec.decode(data)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn rejected_entries(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    fn ignores_duplicates(&self) -> bool {
+        self.ignore_duplicates
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        // The server's autovacuum daemon already takes care of reclaiming space and of refreshing
+        // the planner statistics.
         Ok(())
     }
 }
@@ -317,6 +1147,7 @@ impl PostgresTestDb {
         let mut tx = self.0.pool.begin().await.unwrap();
         for query_str in &[
             format!("DROP INDEX logs_{}_by_timestamp", suffix),
+            format!("DROP INDEX logs_{}_by_id", suffix),
             format!("DROP TABLE logs_{}", suffix),
         ] {
             sqlx::query(query_str).execute(&mut *tx).await.unwrap();
@@ -343,13 +1174,106 @@ impl Db for PostgresTestDb {
         self.0.create_schema().await
     }
 
+    async fn verify_schema(&self) -> Result<()> {
+        self.0.verify_schema().await
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        self.0.migrate_schema().await
+    }
+
+    #[cfg(test)]
     async fn get_log_entries(&self) -> Result<Vec<String>> {
         self.0.get_log_entries().await
     }
 
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        self.0.query_log_entries(filter).await
+    }
+
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        self.0.stream_log_entries(filter)
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        self.0.level_counts(filter).await
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        self.0.distinct_hostnames().await
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        self.0.distinct_modules().await
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        self.0.time_bounds().await
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        self.0.storage_stats().await
+    }
+
     async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
         self.0.put_log_entries(entries).await
     }
+
+    fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+        self.0.prepare_log_entries(entries)
+    }
+
+    async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+        self.0.put_prepared_log_entries(entries).await
+    }
+
+    async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64> {
+        self.0.archive_log_entries_before(ts).await
+    }
+
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        self.0.delete_log_entries(filter).await
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        self.0.clear_log_entries().await
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        self.0.max_params_per_statement()
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.0.backend_name()
+    }
+
+    fn rejected_entries(&self) -> u64 {
+        self.0.rejected_entries()
+    }
+
+    fn ignores_duplicates(&self) -> bool {
+        self.0.ignores_duplicates()
+    }
+
+    fn encode_level(&self, level: Level) -> i64 {
+        self.0.encode_level(level)
+    }
+
+    fn decode_level(&self, level: i64) -> Result<Level> {
+        self.0.decode_level(level)
+    }
+
+    fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+        self.0.encode_fields(fields)
+    }
+
+    fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+        self.0.decode_fields(data)
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        self.0.maintenance().await
+    }
 }
 
 #[cfg(test)]
@@ -380,11 +1304,74 @@ mod tests {
                 database: "the-database".to_owned(),
                 username: "the-username".to_owned(),
                 password: "the-password".to_owned(),
+                application_name: None,
+                schema: None,
+                ignore_duplicates: false,
+                copy_threshold: None,
+                acquire_timeout: None,
+                statement_timeout: None,
+                max_message_length: None,
+                oversize_policy: OversizePolicy::default(),
+                level_encoding: LevelEncoding::default(),
+                field_codec: None,
+                connect_retries: 0,
+                connect_retry_delay: None,
+                internal_error_handler: None,
             },
             opts
         );
     }
 
+    #[test]
+    fn test_connectionoptions_from_env_application_name() {
+        let prefix = format!("TEST_{}", rand::random::<u32>());
+        env::set_var(format!("{}_HOST", prefix), "the-host");
+        env::set_var(format!("{}_PORT", prefix), "1234");
+        env::set_var(format!("{}_DATABASE", prefix), "the-database");
+        env::set_var(format!("{}_USERNAME", prefix), "the-username");
+        env::set_var(format!("{}_PASSWORD", prefix), "the-password");
+        env::set_var(format!("{}_APPLICATION_NAME", prefix), "db_logger:the-service");
+        let opts = ConnectionOptions::from_env(&prefix).unwrap();
+        assert_eq!(Some("db_logger:the-service"), opts.application_name.as_deref());
+    }
+
+    #[test]
+    fn test_connectionoptions_from_env_schema() {
+        let prefix = format!("TEST_{}", rand::random::<u32>());
+        env::set_var(format!("{}_HOST", prefix), "the-host");
+        env::set_var(format!("{}_PORT", prefix), "1234");
+        env::set_var(format!("{}_DATABASE", prefix), "the-database");
+        env::set_var(format!("{}_USERNAME", prefix), "the-username");
+        env::set_var(format!("{}_PASSWORD", prefix), "the-password");
+        env::set_var(format!("{}_SCHEMA", prefix), "logging");
+        let opts = ConnectionOptions::from_env(&prefix).unwrap();
+        assert_eq!(Some("logging"), opts.schema.as_deref());
+
+        env::set_var(format!("{}_SCHEMA", prefix), "logging; DROP TABLE logs");
+        match ConnectionOptions::from_env(&prefix) {
+            Ok(_) => panic!("Should have failed"),
+            Err(e) => assert!(e.to_string().contains("Invalid schema name")),
+        }
+    }
+
+    #[test]
+    fn test_check_schema_name() {
+        check_schema_name("logging").unwrap();
+        check_schema_name("_service_2").unwrap();
+        check_schema_name(&"a".repeat(MAX_IDENTIFIER_LENGTH)).unwrap();
+
+        for name in ["", "2logs", "Logging", "my-schema", "a.b", "\"public\"", "public, other"] {
+            assert!(check_schema_name(name).is_err(), "{} should be invalid", name);
+        }
+        assert!(check_schema_name(&"a".repeat(MAX_IDENTIFIER_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_search_path_statement() {
+        assert_eq!("SET search_path TO \"logging\", public", search_path_statement("logging"));
+        assert_eq!("SET search_path TO \"user\", public", search_path_statement("user"));
+    }
+
     /// Runs a test to validate that `ConnectionOptions::from_env` fails when the `missing`
     /// environment variable is not set.
     fn do_connectionoptions_from_env_missing_test(missing: &str) {
@@ -406,7 +1393,9 @@ mod tests {
         }
         match ConnectionOptions::from_env(&prefix) {
             Ok(_) => panic!("Should have failed"),
-            Err(e) => assert!(e.contains(&format!("{}_{} not present", prefix, missing))),
+            Err(e) => {
+                assert!(e.to_string().contains(&format!("{}_{} not present", prefix, missing)))
+            }
         }
     }
 
@@ -445,7 +1434,7 @@ mod tests {
         env::set_var(format!("{}_PASSWORD", prefix), "password");
         match ConnectionOptions::from_env(&prefix) {
             Ok(_) => panic!("Should have failed"),
-            Err(e) => assert!(e.contains("Invalid port number")),
+            Err(e) => assert!(e.to_string().contains("Invalid port number")),
         }
     }
 
@@ -461,15 +1450,54 @@ mod tests {
         }
     }
 
-    /// Initializes the test environment by creating unique tables in the test database.
-    fn setup() -> Box<dyn testutils::TestContext> {
+    /// Initializes the test environment by creating unique tables in the test database, writing
+    /// batches of at least `copy_threshold` entries with `COPY`.
+    fn setup_with_copy_threshold(copy_threshold: Option<usize>) -> Box<dyn testutils::TestContext> {
         let _can_fail = env_logger::builder().is_test(true).try_init();
 
         #[tokio::main]
-        async fn prepare() -> PostgresTestDb {
-            PostgresTestDb::setup_test(ConnectionOptions::from_env("POSTGRES_TEST").unwrap()).await
+        async fn prepare(copy_threshold: Option<usize>) -> PostgresTestDb {
+            let mut opts = ConnectionOptions::from_env("POSTGRES_TEST").unwrap();
+            opts.copy_threshold = copy_threshold;
+            PostgresTestDb::setup_test(opts).await
         }
-        Box::from(PostgresTestContext { db: prepare() })
+        Box::from(PostgresTestContext { db: prepare(copy_threshold) })
+    }
+
+    /// Initializes the test environment by creating unique tables in the test database.
+    fn setup() -> Box<dyn testutils::TestContext> {
+        setup_with_copy_threshold(None)
+    }
+
+    /// Initializes the test environment so that all writes use `COPY`.
+    fn setup_with_copy() -> Box<dyn testutils::TestContext> {
+        setup_with_copy_threshold(Some(1))
+    }
+
+    #[test]
+    fn test_timestamp_to_pg_micros() {
+        let epoch = OffsetDateTime::from_unix_timestamp(PG_EPOCH_UNIX_SECS).unwrap();
+        assert_eq!(0, timestamp_to_pg_micros(epoch).unwrap());
+        assert_eq!(
+            1_000_001,
+            timestamp_to_pg_micros(epoch + Duration::from_nanos(1_000_001_500)).unwrap()
+        );
+        assert_eq!(
+            -PG_EPOCH_UNIX_SECS * 1_000_000,
+            timestamp_to_pg_micros(OffsetDateTime::UNIX_EPOCH).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_put_copy_field() {
+        let mut data = vec![];
+        put_copy_field(&mut data, Some("abc")).unwrap();
+        put_copy_field(&mut data, None::<&str>).unwrap();
+        put_copy_field(&mut data, Some(7i16.to_be_bytes())).unwrap();
+        assert_eq!(
+            vec![0, 0, 0, 3, b'a', b'b', b'c', 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 2, 0, 7],
+            data
+        );
     }
 
     #[test]
@@ -493,6 +1521,252 @@ mod tests {
     #[test]
     #[ignore = "Requires environment configuration and is expensive"]
     fn test_postgresdb_log_entries_long_strings() {
-        testutils::test_log_entries_long_strings(setup());
+        testutils::test_log_entries_long_strings(setup(), None);
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_query_log_entries_sequence() {
+        testutils::test_query_log_entries_sequence(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_query_log_entries_message_contains() {
+        testutils::test_query_log_entries_message_contains(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_stream_log_entries() {
+        testutils::test_stream_log_entries(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_maintenance() {
+        testutils::test_maintenance(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_distinct_hostnames_and_modules() {
+        testutils::test_distinct_hostnames_and_modules(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_time_bounds() {
+        testutils::test_time_bounds(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_storage_stats() {
+        testutils::test_storage_stats(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_empty_message() {
+        testutils::test_empty_message(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_delete_log_entries() {
+        testutils::test_delete_log_entries(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_delete_log_entries_by_module() {
+        testutils::test_delete_log_entries_by_module(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_clear_log_entries() {
+        testutils::test_clear_log_entries(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_large_line_number() {
+        testutils::test_large_line_number(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_query_log_entries_after_id() {
+        testutils::test_query_log_entries_after_id(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_level_counts() {
+        testutils::test_level_counts(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_put_log_entries_exceeds_max_params() {
+        testutils::test_put_log_entries_exceeds_max_params(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_utc_offset() {
+        testutils::test_utc_offset(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_uptime_nanos() {
+        testutils::test_uptime_nanos(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_trace_ids() {
+        testutils::test_trace_ids(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_crate_names() {
+        testutils::test_crate_names(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_module_filter() {
+        testutils::test_module_filter(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_correlation_ids() {
+        testutils::test_correlation_ids(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_payloads() {
+        testutils::test_payloads(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_fields() {
+        testutils::test_fields(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_ulids() {
+        testutils::test_ulids(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_verify_schema() {
+        testutils::test_verify_schema(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_migrate_schema() {
+        testutils::test_migrate_schema(setup());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_log_entries_combined() {
+        testutils::test_log_entries_combined(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_log_entries_long_strings() {
+        testutils::test_log_entries_long_strings(setup_with_copy(), None);
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_empty_message() {
+        testutils::test_empty_message(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_large_line_number() {
+        testutils::test_large_line_number(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_put_log_entries_exceeds_max_params() {
+        testutils::test_put_log_entries_exceeds_max_params(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_utc_offset() {
+        testutils::test_utc_offset(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_uptime_nanos() {
+        testutils::test_uptime_nanos(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_trace_ids() {
+        testutils::test_trace_ids(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_crate_names() {
+        testutils::test_crate_names(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_correlation_ids() {
+        testutils::test_correlation_ids(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_payloads() {
+        testutils::test_payloads(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_fields() {
+        testutils::test_fields(setup_with_copy());
+    }
+
+    #[test]
+    #[ignore = "Requires environment configuration and is expensive"]
+    fn test_postgresdb_copy_ulids() {
+        testutils::test_ulids(setup_with_copy());
     }
 }
@@ -0,0 +1,1174 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Structured access to the log entries persisted in the database.
+
+use crate::{DbError, Fields, Result};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::Level;
+use std::future::Future;
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::mpsc;
+
+/// Number of rows that a stream of query results can read ahead of its consumer.
+const STREAM_BUFFER_SIZE: usize = 64;
+
+/// Contents of a log entry as read back from the database.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntryRow {
+    /// Time at which the entry was recorded, with microsecond resolution.
+    pub timestamp: OffsetDateTime,
+
+    /// Sequence number assigned to the entry by the logger that recorded it.
+    ///
+    /// Timestamps are truncated to microseconds so entries emitted in quick succession may share
+    /// the same timestamp.  The sequence number is monotonically increasing within a single
+    /// process and is the tiebreaker to reconstruct the true order of such entries: always sort by
+    /// `(timestamp, sequence)` and never by `timestamp` alone.
+    pub sequence: i64,
+
+    /// Name of the host that recorded the entry.
+    pub hostname: String,
+
+    /// Severity of the entry.
+    pub level: Level,
+
+    /// Module that emitted the entry, if known.
+    pub module: Option<String>,
+
+    /// Source file that emitted the entry, if known.
+    pub filename: Option<String>,
+
+    /// Line in the source file that emitted the entry, if known.
+    pub line: Option<u32>,
+
+    /// Free-form message of the entry.
+    pub message: String,
+
+    /// Nanoseconds elapsed between the initialization of the logger and the recording of the
+    /// entry, if known.
+    ///
+    /// Unlike `timestamp`, this is derived from a monotonic clock so it is not affected by
+    /// adjustments to the system clock, which makes it suitable to measure the time between
+    /// entries of the same process.  Entries recorded by older versions of this crate lack it.
+    pub uptime_nanos: Option<i64>,
+
+    /// Local offset from UTC of the host that recorded the entry, if it was configured to record
+    /// it via `LoggerOptions::utc_offset`.
+    ///
+    /// This does not alter the meaning of `timestamp`, which is always an absolute instant.
+    pub utc_offset: Option<UtcOffset>,
+
+    /// Identifier of the distributed trace that was active when the entry was recorded, if the
+    /// logger was configured to obtain it via `LoggerOptions::trace_context`.
+    pub trace_id: Option<String>,
+
+    /// Identifier of the span that was active when the entry was recorded, if the logger was
+    /// configured to obtain it via `LoggerOptions::trace_context`.
+    pub span_id: Option<String>,
+
+    /// ULID of the entry, if the logger was configured to generate them via
+    /// `LoggerOptions::record_ulid`.
+    ///
+    /// ULIDs encode the time at which the entries were recorded and sort in the order in which
+    /// they were recorded within a process, with millisecond resolution across processes.  Unlike
+    /// `sequence` and `id`, they are unique across hosts and databases, which makes them suitable
+    /// to merge the entries of several hosts into a single store and to paginate through them via
+    /// `LogFilter::after_ulid`.  Entries split by `OversizePolicy::Split` share the ULID of the
+    /// original entry, so pagination breaks ties by `sequence`.
+    pub ulid: Option<String>,
+
+    /// Correlation identifier attached by the application to the entry, if the logger was
+    /// configured to extract it via `LoggerOptions::correlation_id_key`.
+    pub correlation_id: Option<String>,
+
+    /// Binary payload attached to the entry via `Handle::record_with_payload`, if any.
+    pub payload: Option<Vec<u8>>,
+
+    /// Structured fields attached to the entry, such as the key-values of the `log` record, if
+    /// any.
+    pub fields: Option<Fields>,
+
+    /// Identifier of the entry in the database, if the backend assigns one.
+    ///
+    /// Identifiers are assigned in insertion order and do not change for as long as the entry
+    /// exists, which makes them suitable to reference a specific entry and to paginate through the
+    /// entries via `LogFilter::after_id`.  Note that insertion order may differ from the order of
+    /// the timestamps when several hosts write to the same database.
+    ///
+    /// In PostgreSQL and SQLite, this comes from the `id` column, which SQLite does not expose
+    /// when partitioning by day or routing levels to tables.  Files and stderr do not assign
+    /// identifiers.
+    pub id: Option<i64>,
+}
+
+impl LogEntryRow {
+    /// Returns the timestamp of the entry in the local time of the host that recorded it, or in
+    /// UTC if the offset is not known.
+    pub fn local_timestamp(&self) -> OffsetDateTime {
+        self.timestamp.to_offset(self.utc_offset.unwrap_or(UtcOffset::UTC))
+    }
+
+    /// Returns the name of the crate that emitted the entry, which is the first component of its
+    /// `module`, if known.
+    ///
+    /// The SQL backends store this in the `crate_name` column so that `LogFilter::crate_name` is
+    /// an exact indexed match.
+    pub fn crate_name(&self) -> Option<&str> {
+        self.module.as_deref().map(crate_name_of)
+    }
+}
+
+/// Approximate amount of storage used by the log, as returned by `Handle::storage_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StorageStats {
+    /// Number of log entries in the database, including archived ones.
+    pub rows: u64,
+
+    /// Estimated number of bytes that the log entries occupy, including any indexes.
+    pub bytes: u64,
+}
+
+/// Direction in which to sort the log entries returned by a query.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SortOrder {
+    /// Oldest entries first.
+    #[default]
+    Ascending,
+
+    /// Newest entries first.
+    Descending,
+}
+
+/// Criteria to select a subset of the log entries in the database.
+///
+/// All criteria are optional and are combined with a logical AND.  The default filter selects all
+/// entries in chronological order.
+///
+/// Entries are always sorted by their timestamp and then by their sequence number so that ties are
+/// stable, and `order` flips the direction of both keys at once.  Combining
+/// `SortOrder::Descending` with a `limit` is the efficient way of fetching the latest entries.
+///
+/// Levels are compared following the ordering of `log::Level`, in which *more severe* levels are
+/// *smaller*: `Error < Warn < Info < Debug < Trace`.  This mirrors how levels are stored in the
+/// database, where `ERROR` is 1 and `TRACE` is 5, and means that the range is inverted with respect
+/// to severity: to select "warnings and worse", set `max_level` to `Level::Warn`, which translates
+/// to `level <= 2`, instead of setting `min_level`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogFilter {
+    /// Most severe level to include.  Setting this to `Level::Info` excludes errors and warnings.
+    pub min_level: Option<Level>,
+
+    /// Least severe level to include.  Setting this to `Level::Warn` selects errors and warnings
+    /// only.
+    pub max_level: Option<Level>,
+
+    /// Direction in which to sort the entries.
+    pub order: SortOrder,
+
+    /// Maximum number of entries to return, taken from the start of the sorted results.
+    pub limit: Option<usize>,
+
+    /// Whether to include the entries that have been archived via
+    /// `Handle::archive_log_entries_before`, which are hidden by default.
+    pub include_archived: bool,
+
+    /// Hostname that recorded the entries.
+    ///
+    /// Set this to the empty string to select the entries recorded without a hostname, as
+    /// configured via `LoggerOptions::record_hostname`.
+    pub hostname: Option<String>,
+
+    /// Identifier of the distributed trace during which the entries were recorded.
+    pub trace_id: Option<String>,
+
+    /// Name of the crate that emitted the entries, regardless of the module within it.
+    ///
+    /// This is the first component of the module path, so `"foo"` selects the entries of both
+    /// `foo` and `foo::bar::baz`.  Entries recorded without a module never match.
+    pub crate_name: Option<String>,
+
+    /// Module that emitted the entries, including its submodules.
+    ///
+    /// Like the module directives of `RUST_LOG`, `"foo::bar"` selects the entries of both
+    /// `foo::bar` and `foo::bar::baz` but not those of `foo::barbaz`.  Matching is case-sensitive
+    /// and entries recorded without a module never match.
+    pub module: Option<String>,
+
+    /// Correlation identifier attached by the application to the entries.
+    pub correlation_id: Option<String>,
+
+    /// Substring that the message of the entries must contain.
+    ///
+    /// The text is matched literally, including any `%` and `_` characters, and case-sensitively
+    /// in all backends.
+    pub message_contains: Option<String>,
+
+    /// Whether `Handle::delete_log_entries` may proceed with a filter that has no criteria and
+    /// thus selects all entries.
+    ///
+    /// This guards against wiping the log by accident and is ignored by queries.
+    pub confirm_delete_all: bool,
+
+    /// Identifier of the entry after which to start returning entries, for keyset pagination.
+    ///
+    /// When set, entries are sorted by their `id` instead of by their timestamp, and only those
+    /// that come after this identifier in the requested `order` are returned.  To fetch all pages,
+    /// start with 0 (or `i64::MAX` for `SortOrder::Descending`) and pass the `id` of the last
+    /// entry of each page to fetch the next one.  Unlike offsets, this does not skip nor repeat
+    /// entries when new ones are written between pages.
+    ///
+    /// Entries without an identifier never match.
+    pub after_id: Option<i64>,
+
+    /// ULID and sequence number of the entry after which to start returning entries, for keyset
+    /// pagination across hosts and databases.
+    ///
+    /// When set, entries are sorted by their `ulid` and then by their `sequence` instead of by
+    /// their timestamp or identifier, and only those that come after this pair in the requested
+    /// `order` are returned.  The sequence number breaks the ties between the parts of an entry
+    /// split by `OversizePolicy::Split`, which share its ULID.  To fetch all pages, start with the
+    /// empty string and 0 (or `"Z"` for `SortOrder::Descending`) and pass the `ulid` and
+    /// `sequence` of the last entry of each page to fetch the next one.  This takes precedence
+    /// over `after_id` for sorting.
+    ///
+    /// Entries without a ULID never match.
+    pub after_ulid: Option<(String, i64)>,
+}
+
+/// SQL dialect in which to express the conditions of a `LogFilter`.
+#[cfg(any(feature = "postgres", feature = "sqlite", test))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Dialect {
+    /// PostgreSQL, whose parameters are numbered as `$1`, `$2`, etc.
+    #[cfg(any(feature = "postgres", test))]
+    Postgres,
+
+    /// SQLite, whose parameters are anonymous `?` markers bound in order.
+    #[cfg(any(feature = "sqlite", test))]
+    Sqlite,
+}
+
+/// SQL `WHERE` clause that implements a `LogFilter`, along with the values of its parameters.
+#[cfg(any(feature = "postgres", feature = "sqlite", test))]
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct WhereClause {
+    /// Text of the clause with a leading space, or an empty string if it selects all entries.
+    pub(crate) sql: String,
+
+    /// Values to bind, in order, to the parameters of `sql`.
+    pub(crate) params: Vec<String>,
+}
+
+/// Accumulates the values of the parameters of a SQL statement in the given `dialect`.
+#[cfg(any(feature = "postgres", feature = "sqlite", test))]
+struct Params {
+    dialect: Dialect,
+    values: Vec<String>,
+}
+
+#[cfg(any(feature = "postgres", feature = "sqlite", test))]
+impl Params {
+    /// Adds `value` as the next parameter and returns the placeholder that references it.
+    fn push(&mut self, value: &str) -> String {
+        self.values.push(value.to_owned());
+        match self.dialect {
+            #[cfg(any(feature = "postgres", test))]
+            Dialect::Postgres => format!("${}", self.values.len()),
+            #[cfg(any(feature = "sqlite", test))]
+            Dialect::Sqlite => "?".to_owned(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// Returns the SQL conditions that implement the level range of this filter for levels stored
+    /// as computed by `encode_level`.
+    #[cfg(any(feature = "postgres", feature = "sqlite", test))]
+    fn level_conditions(&self, encode_level: &dyn Fn(Level) -> i64) -> Vec<String> {
+        // The encoding may sort levels in either direction so flip the comparisons if the most
+        // severe level has the largest value.
+        let (min_op, max_op) = if encode_level(Level::Error) <= encode_level(Level::Trace) {
+            (">=", "<=")
+        } else {
+            ("<=", ">=")
+        };
+
+        let mut conditions = vec![];
+        if let Some(level) = self.min_level {
+            conditions.push(format!("level {} {}", min_op, encode_level(level)));
+        }
+        if let Some(level) = self.max_level {
+            conditions.push(format!("level {} {}", max_op, encode_level(level)));
+        }
+        conditions
+    }
+
+    /// Returns the SQL conditions that implement the pagination cursors of this filter, adding
+    /// the values they reference to `params`.
+    #[cfg(any(feature = "postgres", feature = "sqlite", test))]
+    fn id_conditions(&self, params: &mut Params) -> Vec<String> {
+        let op = match self.order {
+            SortOrder::Ascending => ">",
+            SortOrder::Descending => "<",
+        };
+        let mut conditions = vec![];
+        if let Some(id) = self.after_id {
+            conditions.push(format!("id {} {}", op, id));
+        }
+        if let Some((ulid, sequence)) = self.after_ulid.as_ref() {
+            conditions.push(format!(
+                "(ulid, sequence) {} ({}, {})",
+                op,
+                params.push(ulid),
+                sequence
+            ));
+        }
+        conditions
+    }
+
+    /// Returns the SQL conditions that implement the hostname and message search of this filter,
+    /// adding the values they reference to `params`.
+    #[cfg(any(feature = "postgres", feature = "sqlite", test))]
+    fn text_conditions(&self, params: &mut Params) -> Vec<String> {
+        let mut conditions = vec![];
+        if let Some(hostname) = self.hostname.as_deref() {
+            conditions.push(format!("hostname = {}", params.push(hostname)));
+        }
+        if let Some(trace_id) = self.trace_id.as_deref() {
+            conditions.push(format!("trace_id = {}", params.push(trace_id)));
+        }
+        if let Some(crate_name) = self.crate_name.as_deref() {
+            conditions.push(format!("crate_name = {}", params.push(crate_name)));
+        }
+        if let Some(module) = self.module.as_deref() {
+            // Compare a prefix of the column instead of using `LIKE`, which ignores the case of
+            // ASCII letters in SQLite.
+            let prefix = format!("{}::", module);
+            let module = params.push(module);
+            let length = prefix.chars().count();
+            let prefix = params.push(&prefix);
+            conditions.push(format!(
+                "(module = {} OR substr(module, 1, {}) = {})",
+                module, length, prefix
+            ));
+        }
+        if let Some(correlation_id) = self.correlation_id.as_deref() {
+            conditions.push(format!("correlation_id = {}", params.push(correlation_id)));
+        }
+        if let Some(text) = self.message_contains.as_deref() {
+            // Look for the position of the text instead of using `LIKE`, which ignores the case of
+            // ASCII letters in SQLite and would need the wildcards in the text to be escaped.
+            let position = match params.dialect {
+                #[cfg(any(feature = "postgres", test))]
+                Dialect::Postgres => "strpos",
+                #[cfg(any(feature = "sqlite", test))]
+                Dialect::Sqlite => "instr",
+            };
+            conditions.push(format!("{}(message, {}) > 0", position, params.push(text)));
+        }
+        conditions
+    }
+
+    /// Returns the SQL `WHERE` clause, with a leading space, that implements this filter in
+    /// `dialect` for levels stored as computed by `encode_level`, or an empty clause if the filter
+    /// selects all entries.
+    ///
+    /// This is for backends that do not support archival and thus ignores `include_archived`.
+    #[cfg(any(feature = "postgres", test))]
+    pub(crate) fn where_clause(
+        &self,
+        dialect: Dialect,
+        encode_level: &dyn Fn(Level) -> i64,
+    ) -> WhereClause {
+        let mut params = Params { dialect, values: vec![] };
+        let mut conditions = self.level_conditions(encode_level);
+        conditions.extend(self.text_conditions(&mut params));
+        conditions.extend(self.id_conditions(&mut params));
+        Self::join_conditions(conditions, params)
+    }
+
+    /// Same as `where_clause` but, unless `include_archived` is set, also excludes the entries
+    /// marked in the `archived` column.
+    #[cfg(any(feature = "sqlite", test))]
+    pub(crate) fn archivable_where_clause(
+        &self,
+        dialect: Dialect,
+        encode_level: &dyn Fn(Level) -> i64,
+    ) -> WhereClause {
+        let mut params = Params { dialect, values: vec![] };
+        let mut conditions = self.level_conditions(encode_level);
+        conditions.extend(self.text_conditions(&mut params));
+        conditions.extend(self.id_conditions(&mut params));
+        if !self.include_archived {
+            conditions.push("archived = 0".to_owned());
+        }
+        Self::join_conditions(conditions, params)
+    }
+
+    /// Combines all `conditions`, which reference the values in `params`, into a `WHERE` clause
+    /// with a leading space, or returns an empty clause if there are no conditions.
+    #[cfg(any(feature = "postgres", feature = "sqlite", test))]
+    fn join_conditions(conditions: Vec<String>, params: Params) -> WhereClause {
+        if conditions.is_empty() {
+            WhereClause::default()
+        } else {
+            WhereClause {
+                sql: format!(" WHERE {}", conditions.join(" AND ")),
+                params: params.values,
+            }
+        }
+    }
+
+    /// Checks that this filter can be used to delete entries.
+    ///
+    /// Deletions cannot be limited because not all backends support `LIMIT` in `DELETE`
+    /// statements nor be paginated, and a filter without any criteria is rejected unless
+    /// `confirm_delete_all` is set.
+    pub(crate) fn check_deletable(&self) -> Result<()> {
+        if self.limit.is_some() {
+            return Err(DbError::Other("Cannot delete log entries up to a limit".to_owned(), None));
+        }
+        if self.after_id.is_some() || self.after_ulid.is_some() {
+            return Err(DbError::Other("Cannot delete log entries after an id".to_owned(), None));
+        }
+        let has_criteria = self.min_level.is_some()
+            || self.max_level.is_some()
+            || self.hostname.is_some()
+            || self.trace_id.is_some()
+            || self.crate_name.is_some()
+            || self.module.is_some()
+            || self.correlation_id.is_some()
+            || self.message_contains.is_some();
+        if !has_criteria && !self.confirm_delete_all {
+            return Err(DbError::Other(
+                "Refusing to delete all log entries without confirm_delete_all".to_owned(),
+                None,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the SQL `ORDER BY` and `LIMIT` clauses, with a leading space, that sort the results
+    /// by the `keys` columns in the direction requested by this filter.
+    ///
+    /// All `keys` are sorted in the same direction so that the composite index on them can serve
+    /// the query in either direction.  The `keys` are replaced by the `ulid` and `sequence` columns
+    /// when paginating via `after_ulid`, or by the `id` column when paginating via `after_id`.
+    pub(crate) fn order_clause(&self, keys: &[&str]) -> String {
+        let direction = match self.order {
+            SortOrder::Ascending => "ASC",
+            SortOrder::Descending => "DESC",
+        };
+        let keys: &[&str] = if self.after_ulid.is_some() {
+            &["ulid", "sequence"]
+        } else if self.after_id.is_some() {
+            &["id"]
+        } else {
+            keys
+        };
+        let keys = keys.iter().map(|key| format!("{} {}", key, direction)).collect::<Vec<_>>();
+        let mut clause = format!(" ORDER BY {}", keys.join(", "));
+        if let Some(limit) = self.limit {
+            clause.push_str(&format!(" LIMIT {}", limit));
+        }
+        clause
+    }
+
+    /// Sorts `rows` and trims them to the limit requested by this filter.
+    ///
+    /// This is the equivalent of `order_clause` for backends that do not evaluate SQL.
+    #[cfg(any(feature = "file", feature = "stderr", test))]
+    pub(crate) fn sort_and_limit(&self, rows: &mut Vec<LogEntryRow>) {
+        if self.after_ulid.is_some() {
+            rows.sort_by(|a, b| (&a.ulid, a.sequence).cmp(&(&b.ulid, b.sequence)));
+        } else if self.after_id.is_some() {
+            rows.sort_by_key(|row| row.id);
+        } else {
+            rows.sort_by_key(|row| (row.timestamp, row.sequence));
+        }
+        if self.order == SortOrder::Descending {
+            rows.reverse();
+        }
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+    }
+
+    /// Returns true if `row` satisfies this filter.
+    ///
+    /// This is the equivalent of `where_clause` for backends that do not evaluate SQL.
+    #[cfg(any(feature = "file", feature = "stderr", test))]
+    pub(crate) fn matches(&self, row: &LogEntryRow) -> bool {
+        if let Some(level) = self.min_level {
+            if row.level < level {
+                return false;
+            }
+        }
+        if let Some(level) = self.max_level {
+            if row.level > level {
+                return false;
+            }
+        }
+        if let Some(hostname) = self.hostname.as_deref() {
+            if row.hostname != hostname {
+                return false;
+            }
+        }
+        if let Some(trace_id) = self.trace_id.as_deref() {
+            if row.trace_id.as_deref() != Some(trace_id) {
+                return false;
+            }
+        }
+        if let Some(crate_name) = self.crate_name.as_deref() {
+            if row.crate_name() != Some(crate_name) {
+                return false;
+            }
+        }
+        if let Some(module) = self.module.as_deref() {
+            match row.module.as_deref() {
+                Some(row_module) if is_module_or_submodule(row_module, module) => (),
+                _ => return false,
+            }
+        }
+        if let Some(correlation_id) = self.correlation_id.as_deref() {
+            if row.correlation_id.as_deref() != Some(correlation_id) {
+                return false;
+            }
+        }
+        if let Some(text) = self.message_contains.as_deref() {
+            if !row.message.contains(text) {
+                return false;
+            }
+        }
+        if let Some(after_id) = self.after_id {
+            let after = match (row.id, self.order) {
+                (None, _) => false,
+                (Some(id), SortOrder::Ascending) => id > after_id,
+                (Some(id), SortOrder::Descending) => id < after_id,
+            };
+            if !after {
+                return false;
+            }
+        }
+        if let Some((after_ulid, after_sequence)) = self.after_ulid.as_ref() {
+            let after = match (row.ulid.as_deref(), self.order) {
+                (None, _) => false,
+                (Some(ulid), SortOrder::Ascending) => {
+                    (ulid, row.sequence) > (after_ulid.as_str(), *after_sequence)
+                }
+                (Some(ulid), SortOrder::Descending) => {
+                    (ulid, row.sequence) < (after_ulid.as_str(), *after_sequence)
+                }
+            };
+            if !after {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Returns a stream of query results fed by the future returned by `producer`.
+///
+/// The future runs in a separate task and pushes results into the channel that it receives until
+/// it is done or until the channel is closed, which happens when the stream is dropped.  The
+/// channel is bounded so the producer cannot read more than a few rows ahead of the consumer.
+pub(crate) fn spawn_stream<F, Fut>(producer: F) -> BoxStream<'static, Result<LogEntryRow>>
+where
+    F: FnOnce(mpsc::Sender<Result<LogEntryRow>>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+    tokio::spawn(producer(tx));
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|result| (result, rx)) }).boxed()
+}
+
+/// Converts the numerical `level` stored in the database back to a `Level`.
+pub(crate) fn level_from_int(level: i64) -> Result<Level> {
+    match level {
+        1 => Ok(Level::Error),
+        2 => Ok(Level::Warn),
+        3 => Ok(Level::Info),
+        4 => Ok(Level::Debug),
+        5 => Ok(Level::Trace),
+        level => {
+            Err(DbError::Serialization(format!("Invalid log level {} in database", level), None))
+        }
+    }
+}
+
+/// Returns true if `module` is `parent` or one of its submodules.
+#[cfg(any(feature = "file", feature = "stderr", test))]
+fn is_module_or_submodule(module: &str, parent: &str) -> bool {
+    match module.strip_prefix(parent) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// Returns the name of the crate that contains `module`, which is the first component of its path.
+pub(crate) fn crate_name_of(module: &str) -> &str {
+    match module.split_once("::") {
+        Some((crate_name, _rest)) => crate_name,
+        None => module,
+    }
+}
+
+/// Converts the offset from UTC stored in the database as seconds back into a `UtcOffset`.
+pub(crate) fn utc_offset_from_secs(secs: Option<i32>) -> Result<Option<UtcOffset>> {
+    match secs {
+        Some(secs) => UtcOffset::from_whole_seconds(secs).map(Some).map_err(|e| {
+            DbError::Serialization(format!("Invalid UTC offset {}: {}", secs, e), None)
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Converts a `level` and its `count` as returned by a `GROUP BY level` query into the values
+/// returned by `Db::level_counts`, using `decode_level` to interpret the stored level.
+pub(crate) fn level_count_from_ints(
+    level: i64,
+    count: i64,
+    decode_level: &dyn Fn(i64) -> Result<Level>,
+) -> Result<(Level, u64)> {
+    let count = u64::try_from(count).map_err(|_| {
+        DbError::Serialization(format!("Invalid count {} in database", count), None)
+    })?;
+    Ok((decode_level(level)?, count))
+}
+
+/// Converts the number of `rows` and `bytes` as returned by the database into `StorageStats`.
+pub(crate) fn storage_stats_from_ints(rows: i64, bytes: i64) -> Result<StorageStats> {
+    let rows = u64::try_from(rows)
+        .map_err(|_| DbError::Serialization(format!("Invalid count {} in database", rows), None))?;
+    let bytes = u64::try_from(bytes)
+        .map_err(|_| DbError::Serialization(format!("Invalid size {} in database", bytes), None))?;
+    Ok(StorageStats { rows, bytes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `level` the same way as the default implementation of `Db::encode_level`.
+    fn default_encoding(level: Level) -> i64 {
+        level as i64
+    }
+
+    /// Builds the `WhereClause` with the given `sql` text and `params` to compare against.
+    fn clause(sql: &str, params: &[&str]) -> WhereClause {
+        WhereClause {
+            sql: sql.to_owned(),
+            params: params.iter().map(|param| (*param).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_level_from_int_ok() {
+        for level in &[Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace] {
+            assert_eq!(*level, level_from_int(*level as i64).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_level_from_int_invalid() {
+        for level in &[-1, 0, 6] {
+            match level_from_int(*level) {
+                Err(DbError::Serialization(e, _)) => {
+                    assert!(e.contains(&format!("Invalid log level {}", level)))
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_utc_offset_from_secs() {
+        assert_eq!(None, utc_offset_from_secs(None).unwrap());
+        assert_eq!(
+            Some(UtcOffset::from_hms(-3, -30, 0).unwrap()),
+            utc_offset_from_secs(Some(-12600)).unwrap()
+        );
+        match utc_offset_from_secs(Some(100_000)) {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("Invalid UTC offset 100000")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_level_count_from_ints() {
+        assert_eq!((Level::Warn, 5), level_count_from_ints(2, 5, &level_from_int).unwrap());
+        match level_count_from_ints(2, -1, &level_from_int) {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("Invalid count -1")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match level_count_from_ints(0, 5, &level_from_int) {
+            Err(DbError::Serialization(e, _)) => assert!(e.contains("Invalid log level 0")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_log_entry_row_local_timestamp() {
+        let mut row = LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(3600).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Info,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        assert_eq!(UtcOffset::UTC, row.local_timestamp().offset());
+        assert_eq!(1, row.local_timestamp().hour());
+
+        row.utc_offset = Some(UtcOffset::from_hms(-2, 0, 0).unwrap());
+        assert_eq!(row.timestamp, row.local_timestamp());
+        assert_eq!(23, row.local_timestamp().hour());
+    }
+
+    #[test]
+    fn test_log_filter_where_clause_empty() {
+        assert_eq!(
+            WhereClause::default(),
+            LogFilter::default().where_clause(Dialect::Sqlite, &default_encoding)
+        );
+    }
+
+    #[test]
+    fn test_log_filter_matches_levels() {
+        let row = |level| LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+
+        let filter = LogFilter { max_level: Some(Level::Warn), ..Default::default() };
+        assert!(filter.matches(&row(Level::Error)));
+        assert!(filter.matches(&row(Level::Warn)));
+        assert!(!filter.matches(&row(Level::Info)));
+
+        let filter = LogFilter {
+            min_level: Some(Level::Info),
+            max_level: Some(Level::Debug),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&row(Level::Warn)));
+        assert!(filter.matches(&row(Level::Info)));
+        assert!(filter.matches(&row(Level::Debug)));
+        assert!(!filter.matches(&row(Level::Trace)));
+
+        assert!(LogFilter::default().matches(&row(Level::Trace)));
+    }
+
+    #[test]
+    fn test_log_filter_where_clause_levels() {
+        let filter = LogFilter { max_level: Some(Level::Warn), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE level <= 2", &[]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+
+        let filter = LogFilter {
+            min_level: Some(Level::Info),
+            max_level: Some(Level::Debug),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE level >= 3 AND level <= 4", &[]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+    }
+
+    #[test]
+    fn test_log_filter_where_clause_levels_reversed_encoding() {
+        let reversed_encoding = |level: Level| 6 - level as i64;
+
+        let filter = LogFilter { max_level: Some(Level::Warn), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE level >= 4", &[]),
+            filter.where_clause(Dialect::Sqlite, &reversed_encoding)
+        );
+
+        let filter = LogFilter {
+            min_level: Some(Level::Info),
+            max_level: Some(Level::Debug),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE level <= 3 AND level >= 2", &[]),
+            filter.where_clause(Dialect::Sqlite, &reversed_encoding)
+        );
+    }
+
+    #[test]
+    fn test_log_filter_where_clause_message_contains() {
+        let filter = LogFilter {
+            message_contains: Some("connection refused".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE instr(message, ?) > 0", &["connection refused"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(
+            clause(" WHERE strpos(message, $1) > 0", &["connection refused"]),
+            filter.where_clause(Dialect::Postgres, &default_encoding)
+        );
+
+        let filter = LogFilter {
+            max_level: Some(Level::Warn),
+            message_contains: Some("100%_done's \\o/".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE level <= 2 AND instr(message, ?) > 0", &["100%_done's \\o/"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+    }
+
+    #[test]
+    fn test_log_filter_matches_message_contains() {
+        let row = |message: &str| LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Info,
+            module: None,
+            filename: None,
+            line: None,
+            message: message.to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+
+        let filter = LogFilter { message_contains: Some("50%".to_owned()), ..Default::default() };
+        assert!(filter.matches(&row("Progress: 50%")));
+        assert!(!filter.matches(&row("Progress: 500")));
+
+        let filter = LogFilter { message_contains: Some("error".to_owned()), ..Default::default() };
+        assert!(!filter.matches(&row("Fatal ERROR")));
+    }
+
+    #[test]
+    fn test_log_filter_archivable_where_clause() {
+        assert_eq!(
+            clause(" WHERE archived = 0", &[]),
+            LogFilter::default().archivable_where_clause(Dialect::Sqlite, &default_encoding)
+        );
+
+        let filter = LogFilter { max_level: Some(Level::Warn), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE level <= 2 AND archived = 0", &[]),
+            filter.archivable_where_clause(Dialect::Sqlite, &default_encoding)
+        );
+
+        let filter = LogFilter { include_archived: true, ..Default::default() };
+        assert_eq!(
+            WhereClause::default(),
+            filter.archivable_where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(WhereClause::default(), filter.where_clause(Dialect::Sqlite, &default_encoding));
+    }
+
+    #[test]
+    fn test_log_filter_check_deletable() {
+        assert!(LogFilter::default().check_deletable().is_err());
+        assert!(LogFilter { include_archived: true, ..Default::default() }
+            .check_deletable()
+            .is_err());
+        LogFilter { confirm_delete_all: true, ..Default::default() }.check_deletable().unwrap();
+        LogFilter { max_level: Some(Level::Warn), ..Default::default() }.check_deletable().unwrap();
+        LogFilter { message_contains: Some("x".to_owned()), ..Default::default() }
+            .check_deletable()
+            .unwrap();
+
+        let filter = LogFilter {
+            max_level: Some(Level::Warn),
+            limit: Some(10),
+            confirm_delete_all: true,
+            ..Default::default()
+        };
+        assert!(filter.check_deletable().is_err());
+    }
+
+    #[test]
+    fn test_log_filter_hostname() {
+        let filter = LogFilter { hostname: Some("it's".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE hostname = ?", &["it's"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+
+        let filter = LogFilter { hostname: Some("".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE hostname = $1", &[""]),
+            filter.where_clause(Dialect::Postgres, &default_encoding)
+        );
+    }
+
+    #[test]
+    fn test_log_filter_trace_id() {
+        let filter = LogFilter { trace_id: Some("4bf9'".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE trace_id = ?", &["4bf9'"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert!(filter.check_deletable().is_ok());
+    }
+
+    #[test]
+    fn test_log_filter_crate_name() {
+        let filter = LogFilter { crate_name: Some("it's".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE crate_name = ?", &["it's"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert!(filter.check_deletable().is_ok());
+    }
+
+    #[test]
+    fn test_log_filter_module() {
+        let filter = LogFilter { module: Some("it's::ñ".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE (module = ? OR substr(module, 1, 9) = ?)", &["it's::ñ", "it's::ñ::"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(
+            clause(" WHERE (module = $1 OR substr(module, 1, 9) = $2)", &["it's::ñ", "it's::ñ::"]),
+            filter.where_clause(Dialect::Postgres, &default_encoding)
+        );
+        assert!(filter.check_deletable().is_ok());
+    }
+
+    #[test]
+    fn test_is_module_or_submodule() {
+        assert!(is_module_or_submodule("foo", "foo"));
+        assert!(is_module_or_submodule("foo::bar", "foo"));
+        assert!(is_module_or_submodule("foo::bar::baz", "foo::bar"));
+        assert!(!is_module_or_submodule("foobar", "foo"));
+        assert!(!is_module_or_submodule("foo::barbaz", "foo::bar"));
+        assert!(!is_module_or_submodule("Foo", "foo"));
+        assert!(!is_module_or_submodule("foo", "foo::bar"));
+    }
+
+    #[test]
+    fn test_log_filter_correlation_id() {
+        let filter = LogFilter { correlation_id: Some("req'1".to_owned()), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE correlation_id = ?", &["req'1"]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert!(filter.check_deletable().is_ok());
+    }
+
+    #[test]
+    fn test_crate_name_of() {
+        assert_eq!("", crate_name_of(""));
+        assert_eq!("foo", crate_name_of("foo"));
+        assert_eq!("foo", crate_name_of("foo::bar"));
+        assert_eq!("foo", crate_name_of("foo::bar::baz"));
+    }
+
+    #[test]
+    fn test_log_filter_after_id() {
+        let filter = LogFilter { after_id: Some(10), limit: Some(5), ..Default::default() };
+        assert_eq!(
+            clause(" WHERE id > 10", &[]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(" ORDER BY id ASC LIMIT 5", filter.order_clause(&["a", "b"]));
+
+        let filter = LogFilter {
+            after_id: Some(10),
+            order: SortOrder::Descending,
+            max_level: Some(Level::Warn),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE level <= 2 AND id < 10", &[]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(" ORDER BY id DESC", filter.order_clause(&["a", "b"]));
+
+        let row = |id| LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence: 0,
+            hostname: "the-host".into(),
+            level: Level::Error,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id,
+        };
+        assert!(filter.matches(&row(Some(9))));
+        assert!(!filter.matches(&row(Some(10))));
+        assert!(!filter.matches(&row(None)));
+    }
+
+    #[test]
+    fn test_log_filter_after_ulid() {
+        let filter = LogFilter {
+            after_ulid: Some(("".to_owned(), 0)),
+            limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE (ulid, sequence) > (?, 0)", &[""]),
+            filter.where_clause(Dialect::Sqlite, &default_encoding)
+        );
+        assert_eq!(" ORDER BY ulid ASC, sequence ASC LIMIT 5", filter.order_clause(&["a", "b"]));
+        assert!(filter.check_deletable().is_err());
+
+        let filter = LogFilter {
+            after_ulid: Some(("01ARYZ6S41'".to_owned(), 7)),
+            after_id: Some(10),
+            order: SortOrder::Descending,
+            ..Default::default()
+        };
+        assert_eq!(
+            clause(" WHERE id < 10 AND (ulid, sequence) < ($1, 7)", &["01ARYZ6S41'"]),
+            filter.where_clause(Dialect::Postgres, &default_encoding)
+        );
+        assert_eq!(" ORDER BY ulid DESC, sequence DESC", filter.order_clause(&["a", "b"]));
+
+        let row = |ulid: Option<&str>, sequence| LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(0).unwrap(),
+            sequence,
+            hostname: "the-host".into(),
+            level: Level::Error,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: ulid.map(str::to_owned),
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: Some(9),
+        };
+        let filter = LogFilter {
+            after_ulid: Some(("01ARYZ6S41".to_owned(), 5)),
+            order: SortOrder::Descending,
+            ..Default::default()
+        };
+        assert!(filter.matches(&row(Some("01ARYZ6S40ZZ"), 9)));
+        assert!(filter.matches(&row(Some("01ARYZ6S41"), 4)));
+        assert!(!filter.matches(&row(Some("01ARYZ6S41"), 5)));
+        assert!(!filter.matches(&row(Some("01ARYZ6S42"), 0)));
+        assert!(!filter.matches(&row(None, 0)));
+
+        let mut rows =
+            vec![row(Some("B"), 1), row(Some("C"), 0), row(Some("A"), 3), row(Some("B"), 2)];
+        filter.sort_and_limit(&mut rows);
+        assert_eq!(
+            vec![(Some("C"), 0), (Some("B"), 2), (Some("B"), 1), (Some("A"), 3)],
+            rows.iter()
+                .map(|row| (row.ulid.as_deref(), row.sequence))
+                .collect::<Vec<(Option<&str>, i64)>>()
+        );
+    }
+
+    #[test]
+    fn test_log_filter_order_clause() {
+        assert_eq!(" ORDER BY a ASC, b ASC", LogFilter::default().order_clause(&["a", "b"]));
+
+        let filter =
+            LogFilter { order: SortOrder::Descending, limit: Some(10), ..Default::default() };
+        assert_eq!(" ORDER BY a DESC, b DESC LIMIT 10", filter.order_clause(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_log_filter_sort_and_limit() {
+        let row = |secs, sequence| LogEntryRow {
+            timestamp: OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+            sequence,
+            hostname: "the-host".into(),
+            level: Level::Info,
+            module: None,
+            filename: None,
+            line: None,
+            message: "The message".to_owned(),
+            uptime_nanos: None,
+            utc_offset: None,
+            trace_id: None,
+            span_id: None,
+            ulid: None,
+            correlation_id: None,
+            payload: None,
+            fields: None,
+            id: None,
+        };
+        let keys = |rows: &[LogEntryRow]| {
+            rows.iter()
+                .map(|row| (row.timestamp.unix_timestamp(), row.sequence))
+                .collect::<Vec<_>>()
+        };
+
+        let mut rows = vec![row(2, 0), row(1, 1), row(1, 0)];
+        LogFilter::default().sort_and_limit(&mut rows);
+        assert_eq!(vec![(1, 0), (1, 1), (2, 0)], keys(&rows));
+
+        let filter =
+            LogFilter { order: SortOrder::Descending, limit: Some(2), ..Default::default() };
+        filter.sort_and_limit(&mut rows);
+        assert_eq!(vec![(2, 0), (1, 1)], keys(&rows));
+    }
+}
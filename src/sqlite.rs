@@ -16,27 +16,218 @@
 //! Implementation of the database abstraction using SQLite.
 
 use crate::logger::{
-    LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH, LOG_ENTRY_MAX_HOSTNAME_LENGTH,
-    LOG_ENTRY_MAX_MESSAGE_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
+    stderr_error_handler, InternalErrorHandler, LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH,
+    LOG_ENTRY_MAX_HOSTNAME_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
 };
-use crate::{truncate_option_str, Connection, Db, Result};
+use crate::query::{
+    crate_name_of, level_count_from_ints, spawn_stream, storage_stats_from_ints,
+    utc_offset_from_secs, Dialect,
+};
+use crate::{
+    apply_oversize_policy, check_columns, check_inserted_rows, connect_with_retries,
+    describe_batch, into_chunks, missing_columns, rows_per_statement, truncate_message,
+    truncate_option_str, truncate_str, wrap_sequence, Connection, Db, DbError, FieldCodec, Fields,
+    JsonFieldCodec, LevelEncoding, LogEntryRow, LogFilter, OversizePolicy, Result, StorageStats,
+};
+use futures::stream::BoxStream;
 use futures::TryStreamExt;
-use sqlx::sqlite::SqlitePool;
+use log::Level;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions, SqliteRow,
+};
 use sqlx::Row;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use time::OffsetDateTime;
-use tokio::sync::Semaphore;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 /// Schema to use to initialize the test database.
 const SCHEMA: &str = include_str!("../schemas/sqlite.sql");
 
+/// Maximum number of bind parameters that SQLite accepts in a single statement.
+///
+/// This is the default value of `SQLITE_MAX_VARIABLE_NUMBER` since SQLite 3.32.0.
+const MAX_PARAMS_PER_STATEMENT: usize = 32766;
+
+/// Number of bind parameters needed to insert a single log entry.
+const PARAMS_PER_ROW: usize = 20;
+
+/// Columns of the log tables that this code relies on, along with their declared types.
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("timestamp_secs", "INTEGER"),
+    ("timestamp_nsecs", "INTEGER"),
+    ("sequence", "INTEGER"),
+    ("hostname", "TEXT"),
+    ("level", "INTEGER"),
+    ("level_name", "TEXT"),
+    ("module", "TEXT"),
+    ("filename", "TEXT"),
+    ("line", "INTEGER"),
+    ("message", "TEXT"),
+    ("uptime_nanos", "INTEGER"),
+    ("utc_offset_secs", "INTEGER"),
+    ("trace_id", "TEXT"),
+    ("span_id", "TEXT"),
+    ("ulid", "TEXT"),
+    ("crate_name", "TEXT"),
+    ("correlation_id", "TEXT"),
+    ("payload", "BLOB"),
+    ("fields", "BLOB"),
+    ("part", "INTEGER"),
+    ("archived", "INTEGER"),
+    ("id", "INTEGER"),
+];
+
+/// Columns of the log tables that were added after their initial version, along with their
+/// definitions, which `migrate_schema` adds to tables that lack them.
+const MIGRATABLE_COLUMNS: &[(&str, &str)] = &[
+    ("level_name", "level_name TEXT"),
+    ("uptime_nanos", "uptime_nanos INTEGER"),
+    ("utc_offset_secs", "utc_offset_secs INTEGER"),
+    ("trace_id", "trace_id TEXT"),
+    ("span_id", "span_id TEXT"),
+    ("ulid", "ulid TEXT"),
+    ("crate_name", "crate_name TEXT"),
+    ("correlation_id", "correlation_id TEXT"),
+    ("payload", "payload BLOB"),
+    ("fields", "fields BLOB"),
+    ("part", "part INTEGER NOT NULL DEFAULT 0"),
+    ("archived", "archived INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// Query to list the tables that hold the log entries when partitioning by day.
+const PARTITIONS_QUERY: &str = "SELECT name FROM sqlite_master
+    WHERE type = 'table' AND name GLOB 'logs_[0-9][0-9][0-9][0-9]_[0-9][0-9]_[0-9][0-9]'
+    ORDER BY name";
+
+/// Number of operations that the logger issues against the database at the same time.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Concurrency {
+    /// Operations run one at a time, which avoids busy errors altogether.
+    #[default]
+    Serialized,
+
+    /// Up to the given number of operations run at the same time.  Zero is treated as one.
+    Limited(usize),
+
+    /// Operations run as soon as they are issued, subject only to the size of the pool.
+    Unlimited,
+}
+
 /// Options to establish a connection to an SQLite database.
 #[derive(Default)]
 pub struct ConnectionOptions {
     /// URI of the database to connect to.
     pub uri: String,
+
+    /// Whether to store log entries in separate tables for each day instead of a single table.
+    ///
+    /// When enabled, entries are written to tables named `logs_YYYY_MM_DD` based on the UTC date
+    /// of their timestamp, which are created on demand with the same schema as the `logs` table.
+    /// This allows pruning old entries by dropping whole tables instead of deleting rows one by
+    /// one.  Reads union all of these tables, in which case the entries lack an `id` and cannot be
+    /// filtered by it.  There is no need to call `create_schema` in this mode.
+    pub partition_by_day: bool,
+
+    /// Whether to silently skip log entries that already exist in the database.
+    ///
+    /// Entries are identified by their timestamp, hostname and ULID, so only entries recorded with
+    /// `LoggerOptions::record_ulid` enabled can be deduplicated.  When enabled, inserts use
+    /// `ON CONFLICT DO NOTHING` so that writing the same entries again, as happens when replaying
+    /// entries after a partially-failed write, does not create duplicates nor fails the whole
+    /// batch.  When disabled, such writes fail with a `DbError::Constraint`.
+    ///
+    /// This requires `LoggerOptions::record_ulid`: initializing the logger without it fails.
+    pub ignore_duplicates: bool,
+
+    /// Maximum time to wait for exclusive access to the database and for a connection from the
+    /// pool before failing the operation, or `None` to use the pool's default for connections and
+    /// to wait indefinitely for exclusive access.
+    ///
+    /// Writes that fail due to this timeout are retried according to `max_write_retries` in
+    /// `LoggerOptions` instead of stalling the logger while another operation holds the database.
+    pub acquire_timeout: Option<Duration>,
+
+    /// Number of operations to issue against the database at the same time.  Defaults to
+    /// `Concurrency::Serialized`.
+    ///
+    /// SQLite only supports one writer at a time, so concurrent writes wait for each other via
+    /// SQLite's own locking and fail with "database is locked" errors if they wait for longer than
+    /// `busy_timeout`.  Only raise this if the database uses write-ahead logging, which lets
+    /// readers proceed while a write is in progress.  Clearing the log and running `maintenance`
+    /// always wait for all other operations to complete.
+    pub concurrency: Concurrency,
+
+    /// Maximum time that an operation waits for the locks held by other connections before
+    /// failing with a busy error, or `None` to use the default of the underlying driver (5
+    /// seconds).
+    pub busy_timeout: Option<Duration>,
+
+    /// Maximum length, in bytes, of the messages to store, or `None` to store them in full.
+    ///
+    /// Longer messages are handled according to `oversize_policy`.  SQLite has no practical limit
+    /// on the size of text columns so this only serves to bound the size of the database.
+    pub max_message_length: Option<usize>,
+
+    /// What to do with the messages longer than `max_message_length`.  Defaults to truncating
+    /// them.
+    pub oversize_policy: OversizePolicy,
+
+    /// Integers to store in the `level` column for each level.  Defaults to 1 for errors up to 5
+    /// for traces.
+    ///
+    /// Changing this on an existing database makes the entries already in it unreadable.
+    pub level_encoding: LevelEncoding,
+
+    /// Codec to serialize the structured fields of the entries into the `fields` column, or `None`
+    /// to store them as JSON via `JsonFieldCodec`.
+    ///
+    /// Changing this on an existing database makes the fields already in it unreadable.
+    pub field_codec: Option<Arc<dyn FieldCodec>>,
+
+    /// Tables in which to store the entries of specific levels instead of the `logs` table.
+    ///
+    /// Each element maps a level to the name of the table that holds its entries, and levels that
+    /// are not listed are stored in `logs`.  This allows different retention policies per level,
+    /// such as keeping errors in a small table for a long time while pruning a large table of
+    /// debug entries aggressively.  `create_schema` creates all of these tables and reads union
+    /// them, in which case the entries lack an `id` and cannot be filtered by it.  Table names may
+    /// only contain ASCII alphanumeric characters and underscores, and this cannot be combined
+    /// with `partition_by_day`.
+    pub level_tables: Vec<(Level, String)>,
+
+    /// Whether to serve reads from a separate pool of read-only connections that do not wait for
+    /// the writes issued by the logger.
+    ///
+    /// By default, reads go through the same concurrency limit as writes, so a long query, such as
+    /// a large export, stalls the logger until it completes.  Only enable this if the database is
+    /// stored in a file and uses write-ahead logging, which lets readers proceed while a write is
+    /// in progress: otherwise, reads and writes still contend for SQLite's own locks and may fail
+    /// with busy errors.
+    pub separate_reads: bool,
+
+    /// Number of times to retry opening the database if it cannot be opened, such as when it lives
+    /// on a volume that is not mounted yet.  Defaults to zero.  This also applies to the read-only
+    /// pool opened by `separate_reads`.
+    ///
+    /// `connect` fails with the error of the last attempt once all attempts fail.  The attempts
+    /// that are retried are reported to `internal_error_handler`.
+    pub connect_retries: usize,
+
+    /// Time to wait before the first retry to open the database, which doubles after every failed
+    /// attempt up to 30 seconds, or `None` to wait 500 milliseconds.
+    pub connect_retry_delay: Option<Duration>,
+
+    /// Function to handle the failed attempts to open the database that `connect_retries` retries.
+    /// Defaults to `None`, which prints them to stderr.
+    ///
+    /// These happen before the logger exists, so set this to the same function as the
+    /// `internal_error_handler` of `LoggerOptions` to handle all problems in one place.
+    pub internal_error_handler: Option<InternalErrorHandler>,
 }
 
 /// Factory to connect to a SQLite database.
@@ -44,6 +235,16 @@ pub async fn connect(opts: ConnectionOptions) -> Result<Connection> {
     SqliteDb::connect(opts).await.map(|db| Connection(Arc::from(db)))
 }
 
+/// Factory to use an existing `pool` to access a SQLite database.
+///
+/// This is useful to share the pool that the application already uses for its own data instead of
+/// opening a second pool to the same database.  The logger still serializes its own accesses to
+/// the database, but it cannot coordinate with the accesses that the application makes via the
+/// same pool.
+pub fn from_pool(pool: SqlitePool) -> Connection {
+    Connection(Arc::from(SqliteDb::from_pool(pool, false, false)))
+}
+
 /// Converts a timestamp into the seconds and nanoseconds pair needed by the database.
 ///
 /// Nanoseconds are rounded to the next microsecond to emulate the behavior of the `postgres`
@@ -57,138 +258,1190 @@ fn unpack_timestamp(ts: OffsetDateTime) -> Result<(i64, i64)> {
         nanos += 1000;
     }
 
-    let sec = i64::try_from(nanos / 1_000_000_000).map_err(|_| "timestamp too large".to_owned())?;
+    let sec = i64::try_from(nanos / 1_000_000_000)
+        .map_err(|_| DbError::Serialization("timestamp too large".to_owned(), None))?;
     let nsec = i64::try_from(nanos % 1_000_000_000).expect("nanos must fit in i64");
     Ok((sec, nsec))
 }
 
+/// Converts the seconds and nanoseconds pair stored in the database back into a timestamp.
+fn pack_timestamp(secs: i64, nsecs: i64) -> Result<OffsetDateTime> {
+    let nanos = i128::from(secs) * 1_000_000_000 + i128::from(nsecs);
+    OffsetDateTime::from_unix_timestamp_nanos(nanos)
+        .map_err(|e| DbError::Serialization(e.to_string(), None))
+}
+
+/// Returns the name of the table that holds the entries recorded at `ts` when partitioning by day.
+fn partition_name(ts: OffsetDateTime) -> Result<String> {
+    // Use the timestamp as it will be stored in the database so that the entry and its partition
+    // are always consistent, even if rounding moves the entry to the next day.
+    let (secs, _nsecs) = unpack_timestamp(ts)?;
+    let date = OffsetDateTime::from_unix_timestamp(secs)
+        .map_err(|e| DbError::Serialization(e.to_string(), None))?
+        .date();
+    Ok(format!("logs_{:04}_{:02}_{:02}", date.year(), u8::from(date.month()), date.day()))
+}
+
+/// Returns the statement of the schema that creates the logs table, renamed to `table`.
+fn table_statement(table: &str) -> String {
+    let start = SCHEMA.find("CREATE TABLE logs").expect("Schema must create the logs table");
+    let end = start + SCHEMA[start..].find("\n);").expect("Schema must end the logs table") + 3;
+    SCHEMA[start..end].replacen(" logs", &format!(" {}", table), 1)
+}
+
+/// Returns the comma-separated names of the columns of the log tables other than `id`.
+fn columns_without_id() -> String {
+    EXPECTED_COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| *name != "id")
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Recreates `table` via `conn` with the current schema, copying its entries over.
+///
+/// SQLite cannot add a primary key to an existing table, so this is the only way to add the `id`
+/// column to tables created before it existed.  Their entries keep their `rowid`, which is what
+/// those versions exposed as the identifier, as their `id`.  Indexes are dropped along with the
+/// old table and must be recreated afterwards.
+async fn rebuild_with_id(conn: &mut SqliteConnection, table: &str) -> Result<()> {
+    let new_table = format!("{}_rebuild", table);
+    let columns = columns_without_id();
+    for query_str in [
+        table_statement(&new_table),
+        format!(
+            "INSERT INTO {} (id, {}) SELECT rowid, {} FROM {}",
+            new_table, columns, columns, table
+        ),
+        format!("DROP TABLE {}", table),
+        format!("ALTER TABLE {} RENAME TO {}", new_table, table),
+    ] {
+        sqlx::query(&query_str).execute(&mut *conn).await.map_err(DbError::from)?;
+    }
+    Ok(())
+}
+
+/// Returns the statements of the schema that create the indexes of `table`, adjusted so that they
+/// do nothing if the indexes already exist.
+fn index_statements(table: &str) -> Vec<String> {
+    SCHEMA
+        .lines()
+        .filter(|line| {
+            line.starts_with("CREATE INDEX ") || line.starts_with("CREATE UNIQUE INDEX ")
+        })
+        .map(|line| {
+            line.replace(" logs", &format!(" {}", table)).replacen(
+                "INDEX ",
+                "INDEX IF NOT EXISTS ",
+                1,
+            )
+        })
+        .collect()
+}
+
+/// Values of the columns of a log entry, truncated to match the limits of the PostgreSQL backend.
+struct RowValues {
+    timestamp_secs: i64,
+    timestamp_nsecs: i64,
+    sequence: i64,
+    hostname: String,
+    level: i64,
+    level_name: &'static str,
+    module: Option<String>,
+    filename: Option<String>,
+    line: Option<u32>,
+    message: String,
+    uptime_nanos: Option<i64>,
+    utc_offset_secs: Option<i32>,
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    ulid: Option<String>,
+    crate_name: Option<String>,
+    correlation_id: Option<String>,
+    payload: Option<Vec<u8>>,
+    fields: Option<Vec<u8>>,
+    part: u32,
+}
+
+/// Computes the values of the columns for `entry`, which was assigned `sequence` and is the
+/// `part`-th part of a split entry, storing its level as computed by `encode_level` and its
+/// structured fields as serialized by `encode_fields`.
+fn row_values(
+    sequence: u64,
+    part: u32,
+    mut entry: LogEntry,
+    max_message_length: Option<usize>,
+    encode_level: &(dyn Fn(Level) -> i64 + Sync),
+    encode_fields: &(dyn Fn(&Fields) -> Result<Vec<u8>> + Sync),
+) -> Result<RowValues> {
+    // This is not necessary but truncate the contents to match the PostgreSQL implementation.
+    //
+    // TODO(jmmv): This does not make sense now that we expose SQLite as a backend that callers
+    // can choose to use.
+    let module = truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH);
+    let crate_name = module.as_deref().map(|module| crate_name_of(module).to_owned());
+    let filename = truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH);
+    truncate_message(&mut entry.message, max_message_length);
+
+    let (timestamp_secs, timestamp_nsecs) = unpack_timestamp(entry.timestamp)?;
+    let fields = entry.fields.as_ref().map(encode_fields).transpose()?;
+
+    Ok(RowValues {
+        timestamp_secs,
+        timestamp_nsecs,
+        sequence: wrap_sequence(sequence),
+        hostname: truncate_str(&entry.hostname, LOG_ENTRY_MAX_HOSTNAME_LENGTH),
+        level: encode_level(entry.level),
+        level_name: entry.level.as_str(),
+        module,
+        filename,
+        line: entry.line,
+        message: entry.message,
+        uptime_nanos: entry.uptime_nanos,
+        utc_offset_secs: entry.utc_offset.map(|offset| offset.whole_seconds()),
+        trace_id: entry.trace_id,
+        span_id: entry.span_id,
+        ulid: entry.ulid,
+        crate_name,
+        correlation_id: entry.correlation_id,
+        payload: entry.payload,
+        fields,
+        part,
+    })
+}
+
+/// Inserts all `rows` into `table` via `conn` in a single statement and returns the number of
+/// inserted rows.
+///
+/// The caller is responsible for keeping the number of `rows` small enough to not exceed the
+/// maximum number of parameters per statement.
+async fn insert_rows(
+    conn: &mut SqliteConnection,
+    table: &str,
+    rows: Vec<RowValues>,
+    ignore_duplicates: bool,
+) -> Result<u64> {
+    let mut query_str = format!(
+        "
+        INSERT INTO {}
+            (timestamp_secs, timestamp_nsecs, sequence, hostname,
+                level, level_name, module, filename, line, message, uptime_nanos,
+                utc_offset_secs, trace_id, span_id, ulid, crate_name, correlation_id, payload,
+                fields, part)
+        VALUES ",
+        table
+    );
+    let params = format!(", ({})", vec!["?"; PARAMS_PER_ROW].join(", "));
+
+    query_str.push_str(&params[2..]);
+    for _ in 1..rows.len() {
+        query_str.push_str(&params);
+    }
+    if ignore_duplicates {
+        query_str.push_str(" ON CONFLICT DO NOTHING");
+    }
+
+    let mut query = sqlx::query(&query_str);
+    for row in rows.into_iter() {
+        query = query
+            .bind(row.timestamp_secs)
+            .bind(row.timestamp_nsecs)
+            .bind(row.sequence)
+            .bind(row.hostname)
+            .bind(row.level)
+            .bind(row.level_name)
+            .bind(row.module)
+            .bind(row.filename)
+            .bind(row.line)
+            .bind(row.message)
+            .bind(row.uptime_nanos)
+            .bind(row.utc_offset_secs)
+            .bind(row.trace_id)
+            .bind(row.span_id)
+            .bind(row.ulid)
+            .bind(row.crate_name)
+            .bind(row.correlation_id)
+            .bind(row.payload)
+            .bind(row.fields)
+            .bind(row.part);
+    }
+
+    let done = query.execute(&mut *conn).await.map_err(DbError::from)?;
+    Ok(done.rows_affected())
+}
+
+/// Inserts all `rows` into the `logs` table via `conn` with statements that are checked against
+/// the schema at compile time, and returns the number of inserted rows.
+///
+/// The `query!` macro cannot expand a variable number of rows, so this issues one statement per
+/// row within the transaction of the caller.
+#[cfg(feature = "checked-queries")]
+async fn insert_rows_checked(
+    conn: &mut SqliteConnection,
+    rows: Vec<RowValues>,
+    ignore_duplicates: bool,
+) -> Result<u64> {
+    let mut inserted = 0;
+    for row in rows.into_iter() {
+        let done = if ignore_duplicates {
+            sqlx::query!(
+                "INSERT INTO logs
+                    (timestamp_secs, timestamp_nsecs, sequence, hostname,
+                        level, level_name, module, filename, line, message, uptime_nanos,
+                        utc_offset_secs, trace_id, span_id, ulid, crate_name, correlation_id,
+                        payload, fields, part)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT DO NOTHING",
+                row.timestamp_secs,
+                row.timestamp_nsecs,
+                row.sequence,
+                row.hostname,
+                row.level,
+                row.level_name,
+                row.module,
+                row.filename,
+                row.line,
+                row.message,
+                row.uptime_nanos,
+                row.utc_offset_secs,
+                row.trace_id,
+                row.span_id,
+                row.ulid,
+                row.crate_name,
+                row.correlation_id,
+                row.payload,
+                row.fields,
+                row.part,
+            )
+            .execute(&mut *conn)
+            .await
+        } else {
+            sqlx::query!(
+                "INSERT INTO logs
+                    (timestamp_secs, timestamp_nsecs, sequence, hostname,
+                        level, level_name, module, filename, line, message, uptime_nanos,
+                        utc_offset_secs, trace_id, span_id, ulid, crate_name, correlation_id,
+                        payload, fields, part)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                row.timestamp_secs,
+                row.timestamp_nsecs,
+                row.sequence,
+                row.hostname,
+                row.level,
+                row.level_name,
+                row.module,
+                row.filename,
+                row.line,
+                row.message,
+                row.uptime_nanos,
+                row.utc_offset_secs,
+                row.trace_id,
+                row.span_id,
+                row.ulid,
+                row.crate_name,
+                row.correlation_id,
+                row.payload,
+                row.fields,
+                row.part,
+            )
+            .execute(&mut *conn)
+            .await
+        };
+        inserted += done.map_err(DbError::from)?.rows_affected();
+    }
+    Ok(inserted)
+}
+
+/// Inserts all `entries`, tagged with their sequence numbers and part positions, into `table` via
+/// `conn`, storing their levels as computed by `encode_level` and their structured fields as
+/// serialized by `encode_fields`.
+///
+/// The entries are inserted in a single statement, except for the `logs` table when the
+/// `checked-queries` feature is enabled.  The caller is responsible for keeping the number of
+/// `entries` small enough to not exceed the maximum number of parameters per statement.
+async fn insert_batch(
+    conn: &mut SqliteConnection,
+    table: &str,
+    entries: Vec<(u64, u32, LogEntry)>,
+    ignore_duplicates: bool,
+    max_message_length: Option<usize>,
+    encode_level: &(dyn Fn(Level) -> i64 + Sync),
+    encode_fields: &(dyn Fn(&Fields) -> Result<Vec<u8>> + Sync),
+) -> Result<()> {
+    let nentries = u64::try_from(entries.len()).map_err(|e| {
+        DbError::Other(format!("Cannot insert {} log entries at once: {}", entries.len(), e), None)
+    })?;
+    if nentries == 0 {
+        return Ok(());
+    }
+
+    let batch = describe_batch(&entries);
+    let rows = entries
+        .into_iter()
+        .map(|(sequence, part, entry)| {
+            row_values(sequence, part, entry, max_message_length, encode_level, encode_fields)
+        })
+        .collect::<Result<Vec<RowValues>>>()?;
+
+    #[cfg(feature = "checked-queries")]
+    let inserted = if table == "logs" {
+        insert_rows_checked(conn, rows, ignore_duplicates).await?
+    } else {
+        insert_rows(conn, table, rows, ignore_duplicates).await?
+    };
+    #[cfg(not(feature = "checked-queries"))]
+    let inserted = insert_rows(conn, table, rows, ignore_duplicates).await?;
+
+    check_inserted_rows(table, &batch, nentries, inserted, ignore_duplicates)
+}
+
+/// Converts a `row` from the logs table into a `LogEntryRow`, interpreting its level and its
+/// structured fields via `db`.
+fn row_to_entry(db: &dyn Db, row: &SqliteRow) -> Result<LogEntryRow> {
+    let timestamp_secs: i64 = row.try_get("timestamp_secs").map_err(DbError::from)?;
+    let timestamp_nsecs: i64 = row.try_get("timestamp_nsecs").map_err(DbError::from)?;
+    let sequence: i64 = row.try_get("sequence").map_err(DbError::from)?;
+    let hostname: String = row.try_get("hostname").map_err(DbError::from)?;
+    let level: i64 = row.try_get("level").map_err(DbError::from)?;
+    let module: Option<String> = row.try_get("module").map_err(DbError::from)?;
+    let filename: Option<String> = row.try_get("filename").map_err(DbError::from)?;
+    let line: Option<i64> = row.try_get("line").map_err(DbError::from)?;
+    let message: String = row.try_get("message").map_err(DbError::from)?;
+    let uptime_nanos: Option<i64> = row.try_get("uptime_nanos").map_err(DbError::from)?;
+    let utc_offset_secs: Option<i32> = row.try_get("utc_offset_secs").map_err(DbError::from)?;
+    let trace_id: Option<String> = row.try_get("trace_id").map_err(DbError::from)?;
+    let span_id: Option<String> = row.try_get("span_id").map_err(DbError::from)?;
+    let ulid: Option<String> = row.try_get("ulid").map_err(DbError::from)?;
+    let correlation_id: Option<String> = row.try_get("correlation_id").map_err(DbError::from)?;
+    let payload: Option<Vec<u8>> = row.try_get("payload").map_err(DbError::from)?;
+    let fields: Option<Vec<u8>> = row.try_get("fields").map_err(DbError::from)?;
+    let id: Option<i64> = row.try_get("id").map_err(DbError::from)?;
+
+    Ok(LogEntryRow {
+        timestamp: pack_timestamp(timestamp_secs, timestamp_nsecs)?,
+        sequence,
+        hostname,
+        level: db.decode_level(level)?,
+        module,
+        filename,
+        line: match line {
+            Some(line) => Some(
+                u32::try_from(line)
+                    .map_err(|_| DbError::Serialization("line out of range".to_owned(), None))?,
+            ),
+            None => None,
+        },
+        message,
+        uptime_nanos,
+        utc_offset: utc_offset_from_secs(utc_offset_secs)?,
+        trace_id,
+        span_id,
+        ulid,
+        correlation_id,
+        payload,
+        fields: fields.map(|fields| db.decode_fields(&fields)).transpose()?,
+        id,
+    })
+}
+
+/// Columns of a log entry that `Db::get_log_entries` renders, with the types of the schema.
+#[cfg(test)]
+struct EntryLine {
+    timestamp_secs: i64,
+    timestamp_nsecs: i64,
+    hostname: Option<String>,
+    level: i64,
+    module: Option<String>,
+    filename: Option<String>,
+    line: Option<i64>,
+    message: String,
+}
+
+#[cfg(test)]
+impl EntryLine {
+    /// Formats the entry in the layout that `Db::get_log_entries` returns.
+    fn format(&self) -> String {
+        format!(
+            "{}.{} {} {} {} {}:{} {}",
+            self.timestamp_secs,
+            self.timestamp_nsecs,
+            self.hostname.as_deref().unwrap_or(""),
+            self.level,
+            self.module.as_deref().unwrap_or("NO-MODULE"),
+            self.filename.as_deref().unwrap_or("NO-FILENAME"),
+            self.line.unwrap_or(-1),
+            self.message
+        )
+    }
+}
+
 /// A database instance backed by an SQLite database.
 #[derive(Clone)]
 struct SqliteDb {
     pool: SqlitePool,
     sem: Arc<Semaphore>,
+    permits: u32,
     log_sequence: Arc<AtomicU64>,
+    partition_by_day: bool,
+    known_partitions: Arc<Mutex<HashSet<String>>>,
+    ignore_duplicates: bool,
+    acquire_timeout: Option<Duration>,
+    max_message_length: Option<usize>,
+    oversize_policy: OversizePolicy,
+    level_encoding: LevelEncoding,
+    field_codec: Arc<dyn FieldCodec>,
+    rejected: Arc<AtomicU64>,
+    level_tables: Vec<(Level, String)>,
+    read_pool: Option<SqlitePool>,
 }
 
 impl SqliteDb {
     /// Creates a new connection based on environment variables and initializes its schema.
     async fn connect(opts: ConnectionOptions) -> Result<Self> {
-        let pool = SqlitePool::connect(&opts.uri).await.map_err(|e| e.to_string())?;
+        if !opts.level_tables.is_empty() {
+            if opts.partition_by_day {
+                return Err(DbError::Other(
+                    "Cannot route levels to tables when partitioning by day".to_owned(),
+                    None,
+                ));
+            }
+            for (_level, table) in &opts.level_tables {
+                let valid = !table.is_empty()
+                    && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid {
+                    return Err(DbError::Other(format!("Invalid table name '{}'", table), None));
+                }
+            }
+        }
+
+        let mut pool_opts = SqlitePoolOptions::new();
+        if let Some(timeout) = opts.acquire_timeout {
+            pool_opts = pool_opts.acquire_timeout(timeout);
+        }
+        let mut conn_opts = SqliteConnectOptions::from_str(&opts.uri).map_err(DbError::from)?;
+        if let Some(timeout) = opts.busy_timeout {
+            conn_opts = conn_opts.busy_timeout(timeout);
+        }
+        let on_error = opts.internal_error_handler.clone().unwrap_or_else(stderr_error_handler);
+        let pool =
+            connect_with_retries(opts.connect_retries, opts.connect_retry_delay, &on_error, || {
+                let pool_opts = pool_opts.clone();
+                let conn_opts = conn_opts.clone();
+                async move { pool_opts.connect_with(conn_opts).await.map_err(DbError::from) }
+            })
+            .await?;
+        let read_pool = if opts.separate_reads {
+            let read_opts = conn_opts.read_only(true);
+            let read_pool = connect_with_retries(
+                opts.connect_retries,
+                opts.connect_retry_delay,
+                &on_error,
+                || {
+                    let pool_opts = pool_opts.clone();
+                    let read_opts = read_opts.clone();
+                    async move { pool_opts.connect_with(read_opts).await.map_err(DbError::from) }
+                },
+            )
+            .await?;
+            Some(read_pool)
+        } else {
+            None
+        };
+
+        // Exclusive operations acquire all permits at once, which tokio limits to a `u32`.
+        let max_permits = u32::try_from(Semaphore::MAX_PERMITS).unwrap_or(u32::MAX);
+        let permits = match opts.concurrency {
+            Concurrency::Serialized => 1,
+            Concurrency::Limited(n) => u32::try_from(n).unwrap_or(u32::MAX).clamp(1, max_permits),
+            Concurrency::Unlimited => max_permits,
+        };
+        Ok(Self {
+            sem: Arc::from(Semaphore::new(permits as usize)),
+            permits,
+            acquire_timeout: opts.acquire_timeout,
+            max_message_length: opts.max_message_length,
+            oversize_policy: opts.oversize_policy,
+            level_encoding: opts.level_encoding,
+            field_codec: opts.field_codec.unwrap_or_else(|| Arc::from(JsonFieldCodec)),
+            level_tables: opts.level_tables,
+            read_pool,
+            ..Self::from_pool(pool, opts.partition_by_day, opts.ignore_duplicates)
+        })
+    }
 
+    /// Creates a new instance backed by an already-established `pool`.
+    fn from_pool(pool: SqlitePool, partition_by_day: bool, ignore_duplicates: bool) -> Self {
         // Serialize all transactions onto the SQLite database to avoid busy errors that we cannot
         // easily deal with during tests.
         let sem = Arc::from(Semaphore::new(1));
 
         let log_sequence = Arc::from(AtomicU64::new(0));
 
-        Ok(Self { pool, sem, log_sequence })
+        Self {
+            pool,
+            sem,
+            permits: 1,
+            log_sequence,
+            partition_by_day,
+            known_partitions: Arc::from(Mutex::from(HashSet::default())),
+            ignore_duplicates,
+            acquire_timeout: None,
+            max_message_length: None,
+            oversize_policy: OversizePolicy::default(),
+            level_encoding: LevelEncoding::default(),
+            field_codec: Arc::from(JsonFieldCodec),
+            rejected: Arc::from(AtomicU64::new(0)),
+            level_tables: vec![],
+            read_pool: None,
+        }
+    }
+
+    /// Returns the pool on which to run read-only operations.
+    fn reader(&self) -> &SqlitePool {
+        self.read_pool.as_ref().unwrap_or(&self.pool)
+    }
+
+    /// Waits for access to the database to run a read-only operation, which is only necessary if
+    /// reads are not served by a separate pool.
+    async fn acquire_read_permit(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match self.read_pool {
+            Some(_) => Ok(None),
+            None => self.acquire_permit().await.map(Some),
+        }
+    }
+
+    /// Returns the name of the table that holds the entries at `level` when not partitioning.
+    fn level_table(&self, level: Level) -> &str {
+        self.level_tables
+            .iter()
+            .find(|(table_level, _table)| *table_level == level)
+            .map(|(_level, table)| table.as_str())
+            .unwrap_or("logs")
+    }
+
+    /// Returns the names of all tables that hold log entries when not partitioning, starting with
+    /// `logs`.
+    fn static_tables(&self) -> Vec<String> {
+        let mut tables = vec!["logs".to_owned()];
+        for (_level, table) in &self.level_tables {
+            if !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+        tables
+    }
+
+    /// Returns the names of all tables that hold log entries, which, when partitioning by day, are
+    /// those that exist at the time of the call.
+    async fn tables(&self) -> Result<Vec<String>> {
+        if self.partition_by_day {
+            sqlx::query_scalar(PARTITIONS_QUERY)
+                .fetch_all(self.reader())
+                .await
+                .map_err(DbError::from)
+        } else {
+            Ok(self.static_tables())
+        }
+    }
+
+    /// Waits for access to the database, as limited by the configured concurrency, for up to the
+    /// configured `acquire_timeout`.
+    async fn acquire_permit(&self) -> Result<OwnedSemaphorePermit> {
+        self.acquire_permits(1).await
+    }
+
+    /// Waits for exclusive access to the database, which excludes all other operations regardless
+    /// of the configured concurrency, for up to the configured `acquire_timeout`.
+    async fn acquire_exclusive(&self) -> Result<OwnedSemaphorePermit> {
+        self.acquire_permits(self.permits).await
+    }
+
+    /// Waits for `n` permits of the semaphore for up to the configured `acquire_timeout`.
+    async fn acquire_permits(&self, n: u32) -> Result<OwnedSemaphorePermit> {
+        let acquire = self.sem.clone().acquire_many_owned(n);
+        let permit = match self.acquire_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire).await.map_err(|_| {
+                DbError::Connection(
+                    format!("Timed out after {:?} waiting for access to the database", timeout),
+                    None,
+                )
+            })?,
+            None => acquire.await,
+        };
+        Ok(permit.expect("Semaphore prematurely closed"))
+    }
+
+    /// Returns true if the entries are spread across several tables, whose identifiers are not
+    /// unique across them.
+    fn has_many_tables(&self) -> bool {
+        self.partition_by_day || !self.level_tables.is_empty()
+    }
+
+    /// Checks that `filter` can be served, as paginating by identifier requires a single table.
+    fn check_filter(&self, filter: &LogFilter) -> Result<()> {
+        if self.has_many_tables() && filter.after_id.is_some() {
+            return Err(DbError::Other(
+                "Cannot filter by id when partitioning by day or routing levels to tables; \
+                 use after_ulid instead"
+                    .to_owned(),
+                None,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns a subquery that selects the log entries from the table that holds them or, when
+    /// partitioning by day or routing levels to tables, that unions all tables.
+    ///
+    /// The subquery exposes the `id` of the entries only when there is a single table, as
+    /// identifiers are not unique across tables.  Returns `None` if there are no partitions yet.
+    async fn logs_source(&self) -> Result<Option<String>> {
+        if !self.has_many_tables() {
+            return Ok(Some("logs".to_owned()));
+        }
+
+        let tables = self.tables().await?;
+        if tables.is_empty() {
+            return Ok(None);
+        }
+        let columns = columns_without_id();
+        let selects = tables
+            .iter()
+            .map(|table| format!("SELECT NULL AS id, {} FROM {}", columns, table))
+            .collect::<Vec<String>>();
+        Ok(Some(format!("({})", selects.join(" UNION ALL "))))
+    }
+
+    /// Creates the `table` partition via `conn` unless it already exists.
+    async fn ensure_partition(&self, conn: &mut SqliteConnection, table: &str) -> Result<()> {
+        if self.known_partitions.lock().unwrap().contains(table) {
+            return Ok(());
+        }
+
+        let exists: Option<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?")
+                .bind(table)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(DbError::from)?;
+        if exists.is_none() {
+            create_table(conn, table).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates `table`, along with its indexes, with the schema of the `logs` table via `conn`.
+async fn create_table(conn: &mut SqliteConnection, table: &str) -> Result<()> {
+    let schema = SCHEMA.replace(" logs", &format!(" {}", table));
+    let mut results = sqlx::raw_sql(&schema).execute_many(conn);
+    while results.try_next().await.map_err(DbError::from)?.is_some() {
+        // Nothing to do.
     }
+    Ok(())
 }
 
 #[async_trait::async_trait]
 impl Db for SqliteDb {
     async fn create_schema(&self) -> Result<()> {
-        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
-        {
-            let mut results = sqlx::raw_sql(SCHEMA).execute_many(&mut *tx);
-            while results.try_next().await.map_err(|e| e.to_string())?.is_some() {
-                // Nothing to do.
+        if self.partition_by_day {
+            // Partitions are created on demand when writing entries.
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        for table in self.static_tables() {
+            create_table(&mut tx, &table).await?;
+        }
+        tx.commit().await.map_err(DbError::from)
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        let _permit = self.acquire_read_permit().await?;
+
+        // Partitions are created on demand with the right schema but they may predate changes to
+        // it, so check all of them.
+        for table in self.tables().await? {
+            let columns: Vec<(String, String)> =
+                sqlx::query_as("SELECT name, type FROM pragma_table_info(?)")
+                    .bind(&table)
+                    .fetch_all(self.reader())
+                    .await
+                    .map_err(DbError::from)?;
+            check_columns(&table, EXPECTED_COLUMNS, &columns)?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        let _permit = self.acquire_permit().await?;
+
+        let tables = self.tables().await?;
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        for table in tables {
+            let columns: Vec<(String, String)> =
+                sqlx::query_as("SELECT name, type FROM pragma_table_info(?)")
+                    .bind(&table)
+                    .fetch_all(&mut *tx)
+                    .await
+                    .map_err(DbError::from)?;
+            if columns.is_empty() {
+                // Tables routed to by `level_tables` may have been configured after the database
+                // was created, so create them now.  Partitions never get here because they are
+                // listed from the existing tables.
+                create_table(&mut tx, &table).await?;
+                continue;
+            }
+            // The id column cannot be added with ALTER TABLE, so it is handled separately below.
+            let expected = EXPECTED_COLUMNS
+                .iter()
+                .filter(|(name, _)| *name != "id")
+                .copied()
+                .collect::<Vec<(&str, &str)>>();
+            for definition in missing_columns(&table, &expected, MIGRATABLE_COLUMNS, &columns)? {
+                let query_str = format!("ALTER TABLE {} ADD COLUMN {}", table, definition);
+                sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
+            }
+            if !columns.iter().any(|(name, _)| name == "id") {
+                rebuild_with_id(&mut tx, &table).await?;
+            }
+            for query_str in index_statements(&table) {
+                sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
             }
         }
-        tx.commit().await.map_err(|e| e.to_string())
+        tx.commit().await.map_err(DbError::from)
     }
 
+    #[cfg(test)]
     async fn get_log_entries(&self) -> Result<Vec<String>> {
-        let _permit = self.sem.clone().acquire_owned().await.expect("Semaphore prematurely closed");
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+
+        #[cfg(feature = "checked-queries")]
+        if source == "logs" {
+            let lines = sqlx::query_as!(
+                EntryLine,
+                "SELECT timestamp_secs, timestamp_nsecs, hostname, level, module, filename, line,
+                    message
+                FROM logs ORDER BY timestamp_secs, timestamp_nsecs, sequence"
+            )
+            .fetch_all(self.reader())
+            .await
+            .map_err(DbError::from)?;
+            return Ok(lines.iter().map(EntryLine::format).collect());
+        }
+
+        let query_str =
+            format!("SELECT * FROM {} ORDER BY timestamp_secs, timestamp_nsecs, sequence", source);
+        let mut rows = sqlx::query(&query_str).fetch(self.reader());
+        let mut entries = vec![];
+        while let Some(row) = rows.try_next().await.map_err(DbError::from)? {
+            let line = EntryLine {
+                timestamp_secs: row.try_get("timestamp_secs").map_err(DbError::from)?,
+                timestamp_nsecs: row.try_get("timestamp_nsecs").map_err(DbError::from)?,
+                hostname: row.try_get("hostname").map_err(DbError::from)?,
+                level: row.try_get("level").map_err(DbError::from)?,
+                module: row.try_get("module").map_err(DbError::from)?,
+                filename: row.try_get("filename").map_err(DbError::from)?,
+                line: row.try_get("line").map_err(DbError::from)?,
+                message: row.try_get("message").map_err(DbError::from)?,
+            };
+            entries.push(line.format());
+        }
+        Ok(entries)
+    }
 
-        let query_str = "SELECT * FROM logs ORDER BY timestamp_secs, timestamp_nsecs, sequence";
-        let mut rows = sqlx::query(query_str).fetch(&self.pool);
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        self.check_filter(filter)?;
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+        let where_clause =
+            filter.archivable_where_clause(Dialect::Sqlite, &|level| self.encode_level(level));
+        let query_str = format!(
+            "SELECT * FROM {}{}{}",
+            source,
+            where_clause.sql,
+            filter.order_clause(&["timestamp_secs", "timestamp_nsecs", "sequence"])
+        );
+        let mut query = sqlx::query(&query_str);
+        for param in where_clause.params {
+            query = query.bind(param);
+        }
+        let mut rows = query.fetch(self.reader());
         let mut entries = vec![];
-        while let Some(row) = rows.try_next().await.map_err(|e| e.to_string())? {
-            let timestamp_secs: i64 = row.try_get("timestamp_secs").map_err(|e| e.to_string())?;
-            let timestamp_nsecs: i64 = row.try_get("timestamp_nsecs").map_err(|e| e.to_string())?;
-            let hostname: String = row.try_get("hostname").map_err(|e| e.to_string())?;
-            let level: i8 = row.try_get("level").map_err(|e| e.to_string())?;
-            let module: Option<String> = row.try_get("module").map_err(|e| e.to_string())?;
-            let filename: Option<String> = row.try_get("filename").map_err(|e| e.to_string())?;
-            let line: Option<i16> = row.try_get("line").map_err(|e| e.to_string())?;
-            let message: String = row.try_get("message").map_err(|e| e.to_string())?;
-
-            entries.push(format!(
-                "{}.{} {} {} {} {}:{} {}",
-                timestamp_secs,
-                timestamp_nsecs,
-                hostname,
-                level,
-                module.as_deref().unwrap_or("NO-MODULE"),
-                filename.as_deref().unwrap_or("NO-FILENAME"),
-                line.unwrap_or(-1),
-                message
-            ))
+        while let Some(row) = rows.try_next().await.map_err(DbError::from)? {
+            entries.push(row_to_entry(self, &row)?);
         }
         Ok(entries)
     }
 
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        let db = self.clone();
+        let filter = filter.clone();
+        spawn_stream(move |tx| async move {
+            if let Err(e) = db.check_filter(&filter) {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+
+            // Hold the permit until the stream is exhausted or dropped, which means that the
+            // logger cannot write any entries while the stream is alive unless reads are served by
+            // a separate pool.
+            let _permit = match db.acquire_read_permit().await {
+                Ok(permit) => permit,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+
+            let source = match db.logs_source().await {
+                Ok(Some(source)) => source,
+                Ok(None) => return,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let where_clause =
+                filter.archivable_where_clause(Dialect::Sqlite, &|level| db.encode_level(level));
+            let query_str = format!(
+                "SELECT * FROM {}{}{}",
+                source,
+                where_clause.sql,
+                filter.order_clause(&["timestamp_secs", "timestamp_nsecs", "sequence"])
+            );
+            let mut query = sqlx::query(&query_str);
+            for param in where_clause.params {
+                query = query.bind(param);
+            }
+            let mut rows = query.fetch(db.reader());
+            loop {
+                let result = match rows.try_next().await {
+                    Ok(Some(row)) => row_to_entry(&db, &row),
+                    Ok(None) => break,
+                    Err(e) => Err(DbError::from(e)),
+                };
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        })
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        self.check_filter(filter)?;
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+        let where_clause =
+            filter.archivable_where_clause(Dialect::Sqlite, &|level| self.encode_level(level));
+        let query_str = format!(
+            "SELECT level, COUNT(*) FROM {}{} GROUP BY level ORDER BY level",
+            source, where_clause.sql
+        );
+        let mut query = sqlx::query_as(&query_str);
+        for param in where_clause.params {
+            query = query.bind(param);
+        }
+        let rows: Vec<(i64, i64)> = query.fetch_all(self.reader()).await.map_err(DbError::from)?;
+        let mut counts = rows
+            .into_iter()
+            .map(|(level, count)| {
+                level_count_from_ints(level, count, &|level| self.decode_level(level))
+            })
+            .collect::<Result<Vec<(Level, u64)>>>()?;
+        // The encoding of the levels may not sort them from most to least severe.
+        counts.sort_by_key(|(level, _count)| *level);
+        Ok(counts)
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+        let query_str = format!(
+            "SELECT DISTINCT hostname FROM {} WHERE hostname IS NOT NULL ORDER BY hostname",
+            source
+        );
+        sqlx::query_scalar(&query_str).fetch_all(self.reader()).await.map_err(DbError::from)
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+        let query_str = format!(
+            "SELECT DISTINCT module FROM {} WHERE module IS NOT NULL ORDER BY module",
+            source
+        );
+        sqlx::query_scalar(&query_str).fetch_all(self.reader()).await.map_err(DbError::from)
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        let _permit = self.acquire_read_permit().await?;
+
+        let source = match self.logs_source().await? {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+
+        // Timestamps are split in two columns so MIN and MAX cannot compute them.  Instead, fetch
+        // the first row in each direction, which the timestamp index serves without a scan.
+        let mut bounds = Vec::with_capacity(2);
+        for direction in ["ASC", "DESC"] {
+            let query_str = format!(
+                "SELECT timestamp_secs, timestamp_nsecs FROM {}
+                    ORDER BY timestamp_secs {}, timestamp_nsecs {} LIMIT 1",
+                source, direction, direction
+            );
+            let row: Option<(i64, i64)> = sqlx::query_as(&query_str)
+                .fetch_optional(self.reader())
+                .await
+                .map_err(DbError::from)?;
+            match row {
+                Some((secs, nsecs)) => bounds.push(pack_timestamp(secs, nsecs)?),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((bounds[0], bounds[1])))
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        let _permit = self.acquire_read_permit().await?;
+
+        let rows = match self.logs_source().await? {
+            Some(source) => {
+                let query_str = format!("SELECT COUNT(*) FROM {}", source);
+                sqlx::query_scalar(&query_str)
+                    .fetch_one(self.reader())
+                    .await
+                    .map_err(DbError::from)?
+            }
+            None => 0,
+        };
+
+        // Partitions and their indexes all live in the same file, and computing the size of each
+        // table would require the optional dbstat extension, so report the pages in use by the
+        // whole database instead.
+        let bytes: i64 = sqlx::query_scalar(
+            "SELECT (page_count - freelist_count) * page_size
+                FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        )
+        .fetch_one(self.reader())
+        .await
+        .map_err(DbError::from)?;
+
+        storage_stats_from_ints(rows, bytes)
+    }
+
     async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
-        let nentries = u64::try_from(entries.len())
-            .map_err(|e| format!("Cannot insert {} log entries at once: {}", entries.len(), e))?;
+        self.put_prepared_log_entries(self.prepare_log_entries(entries)).await
+    }
+
+    fn prepare_log_entries(&self, entries: Vec<LogEntry>) -> Vec<(u32, LogEntry)> {
+        apply_oversize_policy(
+            entries,
+            self.max_message_length,
+            self.oversize_policy,
+            &self.rejected,
+        )
+    }
+
+    async fn put_prepared_log_entries(&self, entries: Vec<(u32, LogEntry)>) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).map_err(|e| {
+            DbError::Other(
+                format!("Cannot insert {} log entries at once: {}", entries.len(), e),
+                None,
+            )
+        })?;
         if nentries == 0 {
             return Ok(());
         }
 
         let mut sequence = self.log_sequence.fetch_add(nentries, Ordering::SeqCst);
 
-        let mut query_str = "
-            INSERT INTO logs
-                (timestamp_secs, timestamp_nsecs, sequence, hostname,
-                    level, module, filename, line, message)
-            VALUES "
-            .to_owned();
-        let params = ", (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        // Group the entries by the table they belong to, assigning their sequence numbers in the
+        // order in which they were received.
+        let mut batches: BTreeMap<String, Vec<(u64, u32, LogEntry)>> = BTreeMap::default();
+        for (part, entry) in entries.into_iter() {
+            let table = if self.partition_by_day {
+                partition_name(entry.timestamp)?
+            } else {
+                self.level_table(entry.level).to_owned()
+            };
+            batches.entry(table).or_default().push((sequence, part, entry));
+            sequence = sequence.wrapping_add(1);
+        }
+
+        let _permit = self.acquire_permit().await?;
+
+        // Split large batches into multiple statements to respect the limit on the number of
+        // parameters per statement, but write all of them atomically.
+        let rows = rows_per_statement(self.max_params_per_statement(), PARAMS_PER_ROW);
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        let mut tables = Vec::with_capacity(batches.len());
+        for (table, batch) in batches.into_iter() {
+            if self.partition_by_day {
+                self.ensure_partition(&mut tx, &table).await?;
+            }
+            for chunk in into_chunks(batch, rows) {
+                let encode_level = |level| self.encode_level(level);
+                let encode_fields = |fields: &Fields| self.encode_fields(fields);
+                insert_batch(
+                    &mut tx,
+                    &table,
+                    chunk,
+                    self.ignore_duplicates,
+                    self.max_message_length,
+                    &encode_level,
+                    &encode_fields,
+                )
+                .await?;
+            }
+            tables.push(table);
+        }
+        tx.commit().await.map_err(DbError::from)?;
 
-        query_str.push_str(&params[2..]);
-        for _ in 1..nentries {
-            query_str.push_str(params);
+        // Only remember the partitions once the transaction that may have created them commits.
+        if self.partition_by_day {
+            self.known_partitions.lock().unwrap().extend(tables);
         }
+        Ok(())
+    }
 
-        let _permit = self.sem.clone().acquire_owned().await.expect("Semaphore prematurely closed");
+    async fn archive_log_entries_before(&self, ts: OffsetDateTime) -> Result<u64> {
+        let _permit = self.acquire_permit().await?;
 
-        let mut query = sqlx::query(&query_str);
-        for mut entry in entries.into_iter() {
-            // This is not necessary but truncate the contents to match the PostgreSQL
-            // implementation.
-            //
-            // TODO(jmmv): This does not make sense now that we expose SQLite as a backend that
-            // callers can choose to use.
-            let module = truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH);
-            let filename = truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH);
-            entry.hostname.truncate(LOG_ENTRY_MAX_HOSTNAME_LENGTH);
-            entry.message.truncate(LOG_ENTRY_MAX_MESSAGE_LENGTH);
-
-            let (timestamp_secs, timestamp_nsecs) = unpack_timestamp(entry.timestamp)?;
-
-            query = query
-                .bind(timestamp_secs)
-                .bind(timestamp_nsecs)
-                .bind(i64::try_from(sequence).map_err(|_| "sequence out of range".to_owned())?)
-                .bind(entry.hostname)
-                .bind(u8::try_from(entry.level as usize).expect("Levels must fit in u8"))
-                .bind(module)
-                .bind(filename)
-                .bind(entry.line)
-                .bind(entry.message);
-
-            sequence += 1;
-        }
-
-        let done = query.execute(&self.pool).await.map_err(|e| e.to_string())?;
-        if done.rows_affected() != nentries {
-            return Err(format!(
-                "Log entries insertion created {} rows but expected {}",
-                done.rows_affected(),
-                nentries
-            ));
+        let tables = self.tables().await?;
+
+        // Compare against the timestamp as it would be stored in the database so that rounding
+        // does not affect which entries are selected.
+        let (secs, nsecs) = unpack_timestamp(ts)?;
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        let mut archived = 0;
+        for table in tables {
+            let query_str = format!(
+                "UPDATE {} SET archived = 1
+                WHERE archived = 0
+                    AND (timestamp_secs < ? OR (timestamp_secs = ? AND timestamp_nsecs < ?))",
+                table
+            );
+            let done = sqlx::query(&query_str)
+                .bind(secs)
+                .bind(secs)
+                .bind(nsecs)
+                .execute(&mut *tx)
+                .await
+                .map_err(DbError::from)?;
+            archived += done.rows_affected();
+        }
+        tx.commit().await.map_err(DbError::from)?;
+        Ok(archived)
+    }
+
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        self.check_filter(filter)?;
+        let _permit = self.acquire_permit().await?;
+
+        let tables = self.tables().await?;
+
+        let where_clause =
+            filter.archivable_where_clause(Dialect::Sqlite, &|level| self.encode_level(level));
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        let mut deleted = 0;
+        for table in tables {
+            let query_str = format!("DELETE FROM {}{}", table, where_clause.sql);
+            let mut query = sqlx::query(&query_str);
+            for param in &where_clause.params {
+                query = query.bind(param);
+            }
+            let done = query.execute(&mut *tx).await.map_err(DbError::from)?;
+            deleted += done.rows_affected();
+        }
+        tx.commit().await.map_err(DbError::from)?;
+        Ok(deleted)
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        // Hold all permits while resetting the sequence so that no concurrent write observes the
+        // old counter after its entries have been deleted.
+        let _permit = self.acquire_exclusive().await?;
+
+        let tables = self.tables().await?;
+
+        let mut tx = self.pool.begin().await.map_err(DbError::from)?;
+        for table in tables {
+            let query_str = format!("DELETE FROM {}", table);
+            sqlx::query(&query_str).execute(&mut *tx).await.map_err(DbError::from)?;
         }
+        tx.commit().await.map_err(DbError::from)?;
+
+        self.log_sequence.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        MAX_PARAMS_PER_STATEMENT
+    }
+
+    fn encode_level(&self, level: Level) -> i64 {
+        self.level_encoding.encode(level)
+    }
+
+    fn decode_level(&self, level: i64) -> Result<Level> {
+        self.level_encoding.decode(level)
+    }
+
+    fn encode_fields(&self, fields: &Fields) -> Result<Vec<u8>> {
+        self.field_codec.encode(fields)
+    }
+
+    fn decode_fields(&self, data: &[u8]) -> Result<Fields> {
+        self.field_codec.decode(data)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn rejected_entries(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    fn ignores_duplicates(&self) -> bool {
+        self.ignore_duplicates
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        // VACUUM needs exclusive access to the database so hold all permits to keep our own
+        // writers out while it runs.
+        let _permit = self.acquire_exclusive().await?;
+
+        sqlx::query("VACUUM").execute(&self.pool).await.map_err(DbError::from)?;
+        sqlx::query("PRAGMA optimize").execute(&self.pool).await.map_err(DbError::from)?;
         Ok(())
     }
 }
@@ -196,11 +1449,16 @@ impl Db for SqliteDb {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::testutils;
+    use crate::testutils::{self, new_entry};
 
     /// Test context to allow automatic cleanup of the test database.
     struct SqliteTestContext {
         db: SqliteDb,
+
+        /// Runtime on which the database was prepared, which must outlive the setup because the
+        /// pool returns connections via background tasks and closing the last connection to an
+        /// in-memory database discards its contents.
+        _runtime: tokio::runtime::Runtime,
     }
 
     #[async_trait::async_trait]
@@ -210,18 +1468,51 @@ mod tests {
         }
     }
 
+    /// Initializes the test database, optionally with `partition_by_day` enabled.
+    fn setup_with_partitioning(partition_by_day: bool) -> Box<dyn testutils::TestContext> {
+        let _can_fail = env_logger::builder().is_test(true).try_init();
+
+        async fn prepare(partition_by_day: bool) -> SqliteDb {
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                partition_by_day,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            db.create_schema().await.unwrap();
+            db
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let db = runtime.block_on(prepare(partition_by_day));
+        Box::from(SqliteTestContext { db, _runtime: runtime })
+    }
+
     /// Initializes the test database.
     fn setup() -> Box<dyn testutils::TestContext> {
-        let _can_fail = env_logger::builder().is_test(true).try_init();
+        setup_with_partitioning(false)
+    }
 
-        #[tokio::main]
+    /// Initializes the test database with errors and debug entries routed to their own tables.
+    fn setup_with_level_tables() -> Box<dyn testutils::TestContext> {
         async fn prepare() -> SqliteDb {
-            let db =
-                SqliteDb::connect(ConnectionOptions { uri: ":memory:".to_owned() }).await.unwrap();
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                level_tables: vec![
+                    (Level::Error, "errors".to_owned()),
+                    (Level::Debug, "debug".to_owned()),
+                    (Level::Trace, "debug".to_owned()),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
             db.create_schema().await.unwrap();
             db
         }
-        Box::from(SqliteTestContext { db: prepare() })
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let db = runtime.block_on(prepare());
+        Box::from(SqliteTestContext { db, _runtime: runtime })
     }
 
     #[test]
@@ -241,6 +1532,1086 @@ mod tests {
 
     #[test]
     fn test_sqlitedb_log_entries_long_strings() {
-        testutils::test_log_entries_long_strings(setup());
+        testutils::test_log_entries_long_strings(setup(), None);
+    }
+
+    #[test]
+    fn test_sqlitedb_query_log_entries_sequence() {
+        testutils::test_query_log_entries_sequence(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_sequence_wraparound() {
+        #[tokio::main]
+        async fn run() {
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            db.create_schema().await.unwrap();
+            db.log_sequence.store(i64::MAX as u64 - 1, Ordering::SeqCst);
+
+            let mut entries = vec![];
+            for i in 0..3 {
+                entries.push(new_entry(i, &format!("Entry {}", i)));
+            }
+            db.put_log_entries(entries).await.unwrap();
+
+            let sequences = db
+                .query_log_entries(&LogFilter::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| row.sequence)
+                .collect::<Vec<i64>>();
+            assert_eq!(vec![i64::MAX - 1, i64::MAX, 0], sequences);
+        }
+        run();
+    }
+
+    #[test]
+    fn test_sqlitedb_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_query_log_entries_message_contains() {
+        testutils::test_query_log_entries_message_contains(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_stream_log_entries() {
+        testutils::test_stream_log_entries(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_distinct_hostnames_and_modules() {
+        testutils::test_distinct_hostnames_and_modules(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_time_bounds() {
+        testutils::test_time_bounds(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_storage_stats() {
+        testutils::test_storage_stats(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_empty_message() {
+        testutils::test_empty_message(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_delete_log_entries() {
+        testutils::test_delete_log_entries(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_delete_log_entries_by_module() {
+        testutils::test_delete_log_entries_by_module(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_clear_log_entries() {
+        testutils::test_clear_log_entries(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_large_line_number() {
+        testutils::test_large_line_number(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_query_log_entries_after_id() {
+        testutils::test_query_log_entries_after_id(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_uptime_nanos() {
+        testutils::test_uptime_nanos(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_trace_ids() {
+        testutils::test_trace_ids(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_crate_names() {
+        testutils::test_crate_names(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_module_filter() {
+        testutils::test_module_filter(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_correlation_ids() {
+        testutils::test_correlation_ids(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_payloads() {
+        testutils::test_payloads(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_fields() {
+        testutils::test_fields(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_ulids() {
+        testutils::test_ulids(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_verify_schema() {
+        testutils::test_verify_schema(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_migrate_schema() {
+        testutils::test_migrate_schema(setup());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_acquire_timeout() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            acquire_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        {
+            let _permit = db.sem.clone().acquire_owned().await.unwrap();
+            match db.put_log_entries(vec![new_entry(1, "Entry 0")]).await {
+                Err(DbError::Connection(e, _)) => assert!(e.contains("Timed out")),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+        assert_eq!(1, db.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_connect_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let errors = Arc::from(Mutex::new(vec![]));
+        let result = SqliteDb::connect(ConnectionOptions {
+            uri: format!("sqlite://{}", dir.path().join("missing/logs.db").display()),
+            connect_retries: 2,
+            connect_retry_delay: Some(Duration::ZERO),
+            internal_error_handler: Some({
+                let errors = errors.clone();
+                Arc::new(move |error| errors.lock().unwrap().push(error))
+            }),
+            ..Default::default()
+        })
+        .await;
+        let errors = errors.lock().unwrap();
+        assert_eq!(2, errors.len());
+        for error in errors.iter() {
+            assert_eq!(crate::InternalErrorKind::Connection, error.kind);
+            assert!(error.message.starts_with("Failed to connect to the database (attempt "));
+        }
+        match result {
+            Err(e) => {
+                let message = e.to_string();
+                assert!(message.starts_with("Failed to connect to the database after 3 attempts: "))
+            }
+            Ok(_) => panic!("Opening the database should have failed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_concurrency_limited() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            acquire_timeout: Some(Duration::from_millis(10)),
+            concurrency: Concurrency::Limited(2),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let _permit1 = db.acquire_permit().await.unwrap();
+        {
+            let _permit2 = db.acquire_permit().await.unwrap();
+            match db.acquire_permit().await {
+                Err(DbError::Connection(e, _)) => assert!(e.contains("Timed out")),
+                r => panic!("Unexpected result {:?}", r.map(|_| ())),
+            }
+        }
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+        assert_eq!(1, db.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_concurrency_limited_exclusive() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            acquire_timeout: Some(Duration::from_millis(10)),
+            concurrency: Concurrency::Limited(2),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+
+        {
+            // A single writer in progress must keep out the operations that need exclusivity.
+            let _permit = db.acquire_permit().await.unwrap();
+            for result in [db.clear_log_entries().await, db.maintenance().await] {
+                match result {
+                    Err(DbError::Connection(e, _)) => assert!(e.contains("Timed out")),
+                    r => panic!("Unexpected result {:?}", r),
+                }
+            }
+        }
+
+        {
+            // And an exclusive operation in progress must keep out all writers.
+            let _permit = db.acquire_exclusive().await.unwrap();
+            match db.put_log_entries(vec![new_entry(2, "Entry 1")]).await {
+                Err(DbError::Connection(e, _)) => assert!(e.contains("Timed out")),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        db.clear_log_entries().await.unwrap();
+        assert!(db.get_log_entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_concurrency_unlimited() {
+        // Use an on-disk database because in-memory databases share their cache among
+        // connections, and shared-cache locking does not honor the busy timeout.
+        let dir = tempfile::tempdir().unwrap();
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: format!("sqlite://{}?mode=rwc", dir.path().join("logs.db").display()),
+            concurrency: Concurrency::Unlimited,
+            busy_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let writes =
+            (0..4).map(|i| db.put_log_entries(vec![new_entry(i, &format!("Entry {}", i))]));
+        for result in futures::future::join_all(writes).await {
+            result.unwrap();
+        }
+        assert_eq!(4, db.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_separate_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: format!("sqlite://{}?mode=rwc", dir.path().join("logs.db").display()),
+            acquire_timeout: Some(Duration::from_millis(10)),
+            separate_reads: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        sqlx::query("PRAGMA journal_mode = WAL").execute(&db.pool).await.unwrap();
+        db.create_schema().await.unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+
+        // Reads must not wait for the permit that writes need.
+        let _permit = db.acquire_permit().await.unwrap();
+        assert_eq!(1, db.get_log_entries().await.unwrap().len());
+        assert_eq!(1, db.query_log_entries(&LogFilter::default()).await.unwrap().len());
+        match db.put_log_entries(vec![new_entry(2, "Entry 1")]).await {
+            Err(DbError::Connection(e, _)) => assert!(e.contains("Timed out")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_ensure_schema() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let conn = Connection(Arc::from(db.clone()));
+
+        conn.ensure_schema().await.unwrap();
+        conn.ensure_schema().await.unwrap();
+        conn.verify_schema().await.unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+        assert_eq!(1, db.get_log_entries().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_ensure_schema_mismatch() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        sqlx::query("CREATE TABLE logs (timestamp_secs TEXT, message TEXT)")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        match Connection(Arc::from(db)).ensure_schema().await {
+            Err(DbError::Other(e, _)) => {
+                assert!(e.contains("column timestamp_secs has type TEXT but INTEGER was expected"))
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_ensure_schema_migrates_old_schema() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        for query_str in [
+            "CREATE TABLE logs (
+                timestamp_secs INTEGER NOT NULL,
+                timestamp_nsecs INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                hostname TEXT,
+                level INTEGER NOT NULL,
+                module TEXT,
+                filename TEXT,
+                line INTEGER,
+                message TEXT NOT NULL,
+                PRIMARY KEY (timestamp_secs, timestamp_nsecs, sequence, hostname)
+            )",
+            "CREATE INDEX logs_by_timestamp ON logs (timestamp_secs, timestamp_nsecs, sequence)",
+            "INSERT INTO logs VALUES (1, 0, 0, 'old-host', 3, NULL, NULL, NULL, 'Old entry')",
+        ] {
+            sqlx::query(query_str).execute(&db.pool).await.unwrap();
+        }
+        let conn = Connection(Arc::from(db.clone()));
+        assert!(conn.verify_schema().await.is_err());
+
+        conn.ensure_schema().await.unwrap();
+        conn.verify_schema().await.unwrap();
+        let indexes: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'index' ORDER BY name")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert!(indexes.contains(&"logs_by_correlation_id".to_owned()));
+        assert!(indexes.contains(&"logs_by_dedup_key".to_owned()));
+
+        let entry = LogEntry { uptime_nanos: Some(100), ..new_entry(2, "New entry") };
+        db.put_log_entries(vec![entry]).await.unwrap();
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["Old entry", "New entry"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+        assert_eq!(None, rows[0].uptime_nanos);
+        assert_eq!(Some(100), rows[1].uptime_nanos);
+        assert_eq!(vec![Some(1), Some(2)], rows.iter().map(|row| row.id).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_ids_survive_maintenance() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        let entries = (0..5).map(|i| new_entry(i, &format!("Entry {}", i))).collect();
+        db.put_log_entries(entries).await.unwrap();
+        for message in ["Entry 0", "Entry 3"] {
+            let filter =
+                LogFilter { message_contains: Some(message.to_owned()), ..Default::default() };
+            assert_eq!(1, db.delete_log_entries(&filter).await.unwrap());
+        }
+
+        db.maintenance().await.unwrap();
+        db.put_log_entries(vec![new_entry(10, "Entry 10")]).await.unwrap();
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![
+                (Some(2), "Entry 1"),
+                (Some(3), "Entry 2"),
+                (Some(5), "Entry 4"),
+                (Some(6), "Entry 10")
+            ],
+            rows.iter().map(|row| (row.id, row.message.as_str())).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_after_id_unsupported_with_many_tables() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            partition_by_day: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+
+        let filter = LogFilter { after_id: Some(0), ..Default::default() };
+        match db.query_log_entries(&filter).await {
+            Err(DbError::Other(e, _)) => assert!(e.contains("Cannot filter by id")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match db.delete_log_entries(&filter).await {
+            Err(DbError::Other(e, _)) => assert!(e.contains("Cannot filter by id")),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(
+            vec![None],
+            db.query_log_entries(&LogFilter::default())
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_verify_schema_mismatches() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        match db.verify_schema().await {
+            Err(DbError::Other(e, _)) => assert_eq!("Table logs does not exist", e),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        sqlx::query("CREATE TABLE logs (timestamp_secs TEXT, message TEXT)")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        match db.verify_schema().await {
+            Err(DbError::Other(e, _)) => {
+                assert!(e.contains("column timestamp_secs has type TEXT but INTEGER was expected"));
+                assert!(e.contains("missing column level INTEGER"));
+                assert!(!e.contains("message"));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_level_name() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let mut entries = vec![new_entry(1, "Entry 0"), new_entry(2, "Entry 1")];
+        entries[1].level = Level::Error;
+        db.put_log_entries(entries).await.unwrap();
+
+        let names: Vec<String> =
+            sqlx::query_scalar("SELECT level_name FROM logs ORDER BY timestamp_secs")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(vec!["INFO", "ERROR"], names);
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_max_message_length() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            max_message_length: Some(5),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        db.put_log_entries(vec![new_entry(1, "Short"), new_entry(2, "Too long")]).await.unwrap();
+
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["Short", "Too l"],
+            rows.into_iter().map(|row| row.message).collect::<Vec<String>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_oversize_policy() {
+        for (oversize_policy, exp_messages, exp_rejected) in [
+            (OversizePolicy::Truncate, vec!["Short", "Too long m"], 0),
+            (OversizePolicy::Split, vec!["Short", "Too  [...]", "long [...]", " message"], 0),
+            (OversizePolicy::Reject, vec!["Short"], 1),
+        ] {
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                max_message_length: Some(10),
+                oversize_policy,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            db.create_schema().await.unwrap();
+
+            db.put_log_entries(vec![new_entry(1, "Short"), new_entry(2, "Too long message")])
+                .await
+                .unwrap();
+
+            let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+            assert_eq!(
+                exp_messages,
+                rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+            );
+            assert_eq!(exp_rejected, db.rejected_entries());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_level_encoding() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            level_encoding: LevelEncoding::new([3, 4, 6, 7, 8]).unwrap(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let mut entries = vec![];
+        for (i, level) in [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace]
+            .into_iter()
+            .enumerate()
+        {
+            let mut entry = new_entry(i as i64, level.as_str());
+            entry.level = level;
+            entries.push(entry);
+        }
+        db.put_log_entries(entries).await.unwrap();
+
+        let levels: Vec<i64> =
+            sqlx::query_scalar("SELECT level FROM logs ORDER BY timestamp_secs, timestamp_nsecs")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(vec![3, 4, 6, 7, 8], levels);
+
+        let filter = LogFilter {
+            min_level: Some(Level::Warn),
+            max_level: Some(Level::Debug),
+            ..Default::default()
+        };
+        let rows = db.query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["WARN", "INFO", "DEBUG"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+        assert_eq!(
+            vec![(Level::Warn, 1), (Level::Info, 1), (Level::Debug, 1)],
+            db.level_counts(&filter).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_field_codec() {
+        /// Codec that stores each field as a `key=value` line.
+        struct LinesCodec;
+
+        impl FieldCodec for LinesCodec {
+            fn encode(&self, fields: &Fields) -> Result<Vec<u8>> {
+                let lines = fields.iter().map(|(key, value)| format!("{}={}\n", key, value));
+                Ok(lines.collect::<String>().into_bytes())
+            }
+
+            fn decode(&self, data: &[u8]) -> Result<Fields> {
+                let data = String::from_utf8(data.to_vec()).unwrap();
+                Ok(data
+                    .lines()
+                    .map(|line| line.split_once('=').unwrap())
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect())
+            }
+        }
+
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            field_codec: Some(Arc::from(LinesCodec)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let fields =
+            Fields::from([("a".to_owned(), "1".to_owned()), ("b".to_owned(), "x".to_owned())]);
+        db.put_log_entries(vec![LogEntry {
+            fields: Some(fields.clone()),
+            ..new_entry(0, "Entry")
+        }])
+        .await
+        .unwrap();
+
+        let stored: Vec<u8> =
+            sqlx::query_scalar("SELECT fields FROM logs").fetch_one(&db.pool).await.unwrap();
+        assert_eq!(b"a=1\nb=x\n".to_vec(), stored);
+
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(vec![Some(fields)], rows.into_iter().map(|row| row.fields).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_level_tables() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            level_tables: vec![
+                (Level::Error, "errors".to_owned()),
+                (Level::Debug, "debug".to_owned()),
+                (Level::Trace, "debug".to_owned()),
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+        db.verify_schema().await.unwrap();
+
+        let mut entries = vec![];
+        for (i, level) in [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace]
+            .into_iter()
+            .enumerate()
+        {
+            let mut entry = new_entry(i as i64, level.as_str());
+            entry.level = level;
+            entries.push(entry);
+        }
+        db.put_log_entries(entries).await.unwrap();
+
+        for (table, expected) in [("logs", 2), ("errors", 1), ("debug", 2)] {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            assert_eq!(expected, count, "Unexpected number of entries in {}", table);
+        }
+
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["ERROR", "WARN", "INFO", "DEBUG", "TRACE"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+        assert!(rows.iter().all(|row| row.id.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_level_tables_added_to_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = format!("sqlite://{}?mode=rwc", dir.path().join("logs.db").display());
+        let db = SqliteDb::connect(ConnectionOptions { uri: uri.clone(), ..Default::default() })
+            .await
+            .unwrap();
+        db.create_schema().await.unwrap();
+        db.put_log_entries(vec![new_entry(1, "Entry 0")]).await.unwrap();
+        drop(db);
+
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri,
+            level_tables: vec![(Level::Error, "errors".to_owned())],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        Connection(Arc::from(db.clone())).ensure_schema().await.unwrap();
+
+        let mut entry = new_entry(2, "Entry 1");
+        entry.level = Level::Error;
+        db.put_log_entries(vec![entry]).await.unwrap();
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 1"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_level_tables_invalid() {
+        for (partition_by_day, table) in [(true, "errors"), (false, ""), (false, "bad name")] {
+            let result = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                partition_by_day,
+                level_tables: vec![(Level::Error, table.to_owned())],
+                ..Default::default()
+            })
+            .await;
+            assert!(result.is_err(), "Expected failure for table '{}'", table);
+        }
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_log_entries_combined() {
+        testutils::test_log_entries_combined(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_level_counts() {
+        testutils::test_level_counts(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_delete_log_entries() {
+        testutils::test_delete_log_entries(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_delete_log_entries_by_module() {
+        testutils::test_delete_log_entries_by_module(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_tables_clear_log_entries() {
+        testutils::test_clear_log_entries(setup_with_level_tables());
+    }
+
+    #[test]
+    fn test_sqlitedb_level_counts() {
+        testutils::test_level_counts(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_put_log_entries_exceeds_max_params() {
+        testutils::test_put_log_entries_exceeds_max_params(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_utc_offset() {
+        testutils::test_utc_offset(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_archive_log_entries() {
+        testutils::test_archive_log_entries(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_maintenance() {
+        testutils::test_maintenance(setup());
+    }
+
+    #[test]
+    fn test_sqlitedb_from_pool_shares_pool() {
+        #[tokio::main]
+        async fn run() {
+            let pool = SqlitePool::connect(":memory:").await.unwrap();
+            let db = SqliteDb::from_pool(pool.clone(), false, false);
+            db.create_schema().await.unwrap();
+
+            db.put_log_entries(vec![new_entry(1, "Entry 0"), new_entry(2, "Entry 1")])
+                .await
+                .unwrap();
+
+            // The entries must be visible to the application via the pool it supplied.
+            let count: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM logs").fetch_one(&pool).await.unwrap();
+            assert_eq!(2, count);
+        }
+        run();
+    }
+
+    /// Writes the same entries twice to a database configured with `ignore_duplicates` and
+    /// returns the result of the second write and the number of entries in the database.
+    fn write_twice(ignore_duplicates: bool) -> (Result<()>, usize) {
+        #[tokio::main]
+        async fn run(ignore_duplicates: bool) -> (Result<()>, usize) {
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                ignore_duplicates,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            db.create_schema().await.unwrap();
+
+            let entry = |secs, message, ulid: &str| LogEntry {
+                ulid: Some(ulid.to_owned()),
+                ..new_entry(secs, message)
+            };
+
+            db.put_log_entries(vec![entry(1, "Entry 0", "ulid-0"), entry(2, "Entry 1", "ulid-1")])
+                .await
+                .unwrap();
+
+            // The replayed entries get new sequence numbers, so they must be recognized by the
+            // rest of their fields.
+            let result = db
+                .put_log_entries(vec![
+                    entry(1, "Entry 0", "ulid-0"),
+                    entry(2, "Entry 1", "ulid-1"),
+                    entry(3, "Entry 2", "ulid-2"),
+                ])
+                .await;
+
+            (result, db.get_log_entries().await.unwrap().len())
+        }
+        run(ignore_duplicates)
+    }
+
+    #[test]
+    fn test_sqlitedb_ignore_duplicates() {
+        let (result, count) = write_twice(true);
+        result.unwrap();
+        assert_eq!(3, count);
+    }
+
+    #[test]
+    fn test_sqlitedb_reject_duplicates() {
+        let (result, count) = write_twice(false);
+        match result {
+            Err(DbError::Constraint(..)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(2, count);
+    }
+
+    #[tokio::test]
+    async fn test_sqlitedb_ignore_duplicates_split() {
+        let db = SqliteDb::connect(ConnectionOptions {
+            uri: ":memory:".to_owned(),
+            ignore_duplicates: true,
+            max_message_length: Some(10),
+            oversize_policy: OversizePolicy::Split,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.create_schema().await.unwrap();
+
+        let entry =
+            LogEntry { ulid: Some("ulid-0".to_owned()), ..new_entry(1, "Too long message") };
+        db.put_log_entries(vec![entry.clone()]).await.unwrap();
+        db.put_log_entries(vec![entry]).await.unwrap();
+
+        // All parts share the ULID of the original entry but none of them is a duplicate of the
+        // others, whereas all of them are duplicates when replayed.
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["Too  [...]", "long [...]", " message"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_log_entries_none() {
+        testutils::test_log_entries_none(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_log_entries_individual() {
+        testutils::test_log_entries_individual(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_log_entries_combined() {
+        testutils::test_log_entries_combined(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_query_log_entries_sequence() {
+        testutils::test_query_log_entries_sequence(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_query_log_entries_message_contains() {
+        testutils::test_query_log_entries_message_contains(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_stream_log_entries() {
+        testutils::test_stream_log_entries(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_distinct_hostnames_and_modules() {
+        testutils::test_distinct_hostnames_and_modules(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_time_bounds() {
+        testutils::test_time_bounds(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_storage_stats() {
+        testutils::test_storage_stats(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_empty_message() {
+        testutils::test_empty_message(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_delete_log_entries() {
+        testutils::test_delete_log_entries(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_delete_log_entries_by_module() {
+        testutils::test_delete_log_entries_by_module(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_clear_log_entries() {
+        testutils::test_clear_log_entries(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_large_line_number() {
+        testutils::test_large_line_number(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_uptime_nanos() {
+        testutils::test_uptime_nanos(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_trace_ids() {
+        testutils::test_trace_ids(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_crate_names() {
+        testutils::test_crate_names(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_module_filter() {
+        testutils::test_module_filter(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_correlation_ids() {
+        testutils::test_correlation_ids(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_payloads() {
+        testutils::test_payloads(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_ulids() {
+        testutils::test_ulids(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_verify_schema() {
+        testutils::test_verify_schema(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_migrate_schema() {
+        testutils::test_migrate_schema(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_level_counts() {
+        testutils::test_level_counts(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_archive_log_entries() {
+        testutils::test_archive_log_entries(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_maintenance() {
+        testutils::test_maintenance(setup_with_partitioning(true));
+    }
+
+    #[test]
+    fn test_sqlitedb_partitioned_multiple_days() {
+        #[tokio::main]
+        async fn run() {
+            let db = SqliteDb::connect(ConnectionOptions {
+                uri: ":memory:".to_owned(),
+                partition_by_day: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            db.create_schema().await.unwrap();
+
+            const DAY: i64 = 24 * 60 * 60;
+            const JUNE_1ST: i64 = 1_717_200_000;
+            db.put_log_entries(vec![
+                new_entry(JUNE_1ST + DAY, "Entry 0"),
+                new_entry(JUNE_1ST + 10, "Entry 1"),
+                new_entry(JUNE_1ST + DAY - 1, "Entry 2"),
+                new_entry(JUNE_1ST + DAY + 5, "Entry 3"),
+            ])
+            .await
+            .unwrap();
+            db.put_log_entries(vec![new_entry(JUNE_1ST + 20, "Entry 4")]).await.unwrap();
+
+            let tables: Vec<String> =
+                sqlx::query_scalar(PARTITIONS_QUERY).fetch_all(&db.pool).await.unwrap();
+            assert_eq!(vec!["logs_2024_06_01".to_owned(), "logs_2024_06_02".to_owned()], tables);
+
+            let messages = db
+                .query_log_entries(&LogFilter::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| row.message)
+                .collect::<Vec<String>>();
+            assert_eq!(vec!["Entry 1", "Entry 4", "Entry 2", "Entry 0", "Entry 3"], messages);
+        }
+        run();
     }
 }
@@ -0,0 +1,416 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Implementation of the database abstraction that prints log entries to stderr.
+//!
+//! This backend does not persist anything: it is meant for development environments where a real
+//! database is not available.  The most recent entries are kept in memory so that they can still
+//! be queried via the `Handle`.
+
+use crate::export::{self, TimestampFormat};
+use crate::logger::{
+    LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH, LOG_ENTRY_MAX_HOSTNAME_LENGTH,
+    LOG_ENTRY_MAX_MESSAGE_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
+};
+use crate::{
+    round_timestamp, truncate_option_str, truncate_str, wrap_sequence, Connection, Db, DbError,
+    LogEntryRow, LogFilter, Result, StorageStats,
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::Level;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+
+/// Maximum number of recent log entries kept in memory for queries.
+const RECENT_ENTRIES_CAPACITY: usize = 1000;
+
+/// Factory to print log entries to stderr.
+pub fn connect() -> Connection {
+    Connection(Arc::from(StderrDb::new(RECENT_ENTRIES_CAPACITY)))
+}
+
+/// A database instance that prints log entries to stderr and remembers the most recent ones.
+struct StderrDb {
+    capacity: usize,
+    recent: Mutex<VecDeque<LogEntryRow>>,
+    log_sequence: AtomicU64,
+}
+
+impl StderrDb {
+    /// Creates a new instance that remembers up to `capacity` entries.
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: Mutex::from(VecDeque::with_capacity(capacity)),
+            log_sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the remembered entries that match `filter`, sorted by timestamp and sequence.
+    fn read_all(&self, filter: &LogFilter) -> Vec<LogEntryRow> {
+        let recent = self.recent.lock().unwrap();
+        let mut rows: Vec<LogEntryRow> =
+            recent.iter().filter(|row| filter.matches(row)).cloned().collect();
+        filter.sort_and_limit(&mut rows);
+        rows
+    }
+}
+
+#[async_trait::async_trait]
+impl Db for StderrDb {
+    async fn create_schema(&self) -> Result<()> {
+        // There is nothing to initialize.
+        Ok(())
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        // There is no schema to check.
+        Ok(())
+    }
+
+    async fn migrate_schema(&self) -> Result<()> {
+        // There is no schema to migrate.
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn get_log_entries(&self) -> Result<Vec<String>> {
+        self.read_all(&LogFilter::default())
+            .iter()
+            .map(|row| export::format_text(row, TimestampFormat::Raw))
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(|e| DbError::Serialization(e.to_string(), None))
+    }
+
+    async fn query_log_entries(&self, filter: &LogFilter) -> Result<Vec<LogEntryRow>> {
+        Ok(self.read_all(filter))
+    }
+
+    fn stream_log_entries(&self, filter: &LogFilter) -> BoxStream<'static, Result<LogEntryRow>> {
+        stream::iter(self.read_all(filter).into_iter().map(Ok)).boxed()
+    }
+
+    async fn level_counts(&self, filter: &LogFilter) -> Result<Vec<(Level, u64)>> {
+        let filter = LogFilter { limit: None, ..filter.clone() };
+        let mut counts: BTreeMap<Level, u64> = BTreeMap::default();
+        for row in self.read_all(&filter) {
+            *counts.entry(row.level).or_default() += 1;
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    async fn distinct_hostnames(&self) -> Result<Vec<String>> {
+        let rows = self.read_all(&LogFilter::default());
+        let hostnames = rows.into_iter().map(|row| row.hostname).collect::<BTreeSet<String>>();
+        Ok(hostnames.into_iter().collect())
+    }
+
+    async fn distinct_modules(&self) -> Result<Vec<String>> {
+        let rows = self.read_all(&LogFilter::default());
+        let modules = rows.into_iter().filter_map(|row| row.module).collect::<BTreeSet<String>>();
+        Ok(modules.into_iter().collect())
+    }
+
+    async fn time_bounds(&self) -> Result<Option<(OffsetDateTime, OffsetDateTime)>> {
+        let rows = self.read_all(&LogFilter::default());
+        match (rows.first(), rows.last()) {
+            (Some(oldest), Some(newest)) => Ok(Some((oldest.timestamp, newest.timestamp))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats> {
+        // Entries are only kept in memory so they do not take any storage.
+        let rows = u64::try_from(self.recent.lock().unwrap().len())
+            .expect("Number of entries must fit in u64");
+        Ok(StorageStats { rows, bytes: 0 })
+    }
+
+    async fn put_log_entries(&self, entries: Vec<LogEntry>) -> Result<()> {
+        let nentries = u64::try_from(entries.len()).map_err(|e| {
+            DbError::Other(
+                format!("Cannot insert {} log entries at once: {}", entries.len(), e),
+                None,
+            )
+        })?;
+        if nentries == 0 {
+            return Ok(());
+        }
+        let mut sequence = self.log_sequence.fetch_add(nentries, Ordering::SeqCst);
+
+        let mut rows = Vec::with_capacity(entries.len());
+        let mut batch = String::new();
+        for entry in entries.into_iter() {
+            // Truncate the contents to match the database-backed implementations.
+            let hostname = truncate_str(&entry.hostname, LOG_ENTRY_MAX_HOSTNAME_LENGTH);
+            let mut message = entry.message;
+            message.truncate(LOG_ENTRY_MAX_MESSAGE_LENGTH);
+            let row = LogEntryRow {
+                timestamp: round_timestamp(entry.timestamp)?,
+                sequence: wrap_sequence(sequence),
+                hostname,
+                level: entry.level,
+                module: truncate_option_str(entry.module, LOG_ENTRY_MAX_MODULE_LENGTH),
+                filename: truncate_option_str(entry.filename, LOG_ENTRY_MAX_FILENAME_LENGTH),
+                line: entry.line,
+                message,
+                uptime_nanos: entry.uptime_nanos,
+                utc_offset: entry.utc_offset,
+                trace_id: entry.trace_id,
+                span_id: entry.span_id,
+                ulid: entry.ulid,
+                correlation_id: entry.correlation_id,
+                payload: entry.payload,
+                fields: entry.fields,
+                id: None,
+            };
+
+            let line = export::format_text(&row, TimestampFormat::Rfc3339)
+                .map_err(|e| DbError::Serialization(e.to_string(), None))?;
+            batch.push_str(&line);
+            batch.push('\n');
+            rows.push(row);
+            sequence = sequence.wrapping_add(1);
+        }
+
+        io::stderr().lock().write_all(batch.as_bytes()).map_err(|e| {
+            DbError::Other(format!("Failed to write to stderr: {}", e), Some(Box::new(e)))
+        })?;
+
+        let mut recent = self.recent.lock().unwrap();
+        for row in rows {
+            if recent.len() == self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(row);
+        }
+        Ok(())
+    }
+
+    async fn archive_log_entries_before(&self, _ts: OffsetDateTime) -> Result<u64> {
+        Err(DbError::Other("Archiving log entries is not supported by stderr".to_owned(), None))
+    }
+
+    async fn delete_log_entries(&self, filter: &LogFilter) -> Result<u64> {
+        // Only the entries kept in memory can be deleted: those already printed stay printed.
+        let mut recent = self.recent.lock().unwrap();
+        let before = recent.len();
+        recent.retain(|row| !filter.matches(row));
+        Ok(u64::try_from(before - recent.len()).expect("Entry count must fit in u64"))
+    }
+
+    async fn clear_log_entries(&self) -> Result<()> {
+        // Only the entries kept in memory can be cleared: those already printed stay printed.
+        self.recent.lock().unwrap().clear();
+        self.log_sequence.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn max_params_per_statement(&self) -> usize {
+        // Entries are not written via SQL statements so there is no limit.
+        usize::MAX
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "stderr"
+    }
+
+    async fn maintenance(&self) -> Result<()> {
+        // Old entries are discarded as new ones arrive so there is nothing to reclaim.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{self, new_entry};
+
+    /// Test context to hold the in-memory database.
+    struct StderrTestContext {
+        db: StderrDb,
+    }
+
+    impl testutils::TestContext for StderrTestContext {
+        fn db(&self) -> &(dyn Db + Send + Sync) {
+            &self.db
+        }
+    }
+
+    /// Initializes the test database.
+    fn setup() -> Box<dyn testutils::TestContext> {
+        Box::from(StderrTestContext { db: StderrDb::new(RECENT_ENTRIES_CAPACITY) })
+    }
+
+    #[test]
+    fn test_stderrdb_log_entries_none() {
+        testutils::test_log_entries_none(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_log_entries_individual() {
+        testutils::test_log_entries_individual(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_log_entries_combined() {
+        testutils::test_log_entries_combined(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_log_entries_long_strings() {
+        testutils::test_log_entries_long_strings(setup(), Some(LOG_ENTRY_MAX_MESSAGE_LENGTH));
+    }
+
+    #[test]
+    fn test_stderrdb_query_log_entries_sequence() {
+        testutils::test_query_log_entries_sequence(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_query_log_entries_level_range() {
+        testutils::test_query_log_entries_level_range(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_query_log_entries_message_contains() {
+        testutils::test_query_log_entries_message_contains(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_query_log_entries_order() {
+        testutils::test_query_log_entries_order(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_stream_log_entries() {
+        testutils::test_stream_log_entries(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_maintenance() {
+        testutils::test_maintenance(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_distinct_hostnames_and_modules() {
+        testutils::test_distinct_hostnames_and_modules(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_time_bounds() {
+        testutils::test_time_bounds(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_storage_stats() {
+        testutils::test_storage_stats(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_empty_message() {
+        testutils::test_empty_message(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_delete_log_entries() {
+        testutils::test_delete_log_entries(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_delete_log_entries_by_module() {
+        testutils::test_delete_log_entries_by_module(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_clear_log_entries() {
+        testutils::test_clear_log_entries(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_large_line_number() {
+        testutils::test_large_line_number(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_level_counts() {
+        testutils::test_level_counts(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_utc_offset() {
+        testutils::test_utc_offset(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_uptime_nanos() {
+        testutils::test_uptime_nanos(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_trace_ids() {
+        testutils::test_trace_ids(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_crate_names() {
+        testutils::test_crate_names(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_module_filter() {
+        testutils::test_module_filter(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_correlation_ids() {
+        testutils::test_correlation_ids(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_payloads() {
+        testutils::test_payloads(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_fields() {
+        testutils::test_fields(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_ulids() {
+        testutils::test_ulids(setup());
+    }
+
+    #[test]
+    fn test_stderrdb_verify_schema() {
+        testutils::test_verify_schema(setup());
+    }
+
+    #[tokio::test]
+    async fn test_stderrdb_recent_entries_capacity() {
+        let db = StderrDb::new(2);
+        db.put_log_entries(vec![new_entry(1, "first"), new_entry(2, "second")]).await.unwrap();
+        db.put_log_entries(vec![new_entry(3, "third")]).await.unwrap();
+
+        let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+        let messages = rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>();
+        assert_eq!(vec!["second", "third"], messages);
+        assert_eq!(vec![1, 2], rows.iter().map(|row| row.sequence).collect::<Vec<i64>>());
+    }
+}
@@ -0,0 +1,125 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Helpers to test the logging behavior of programs that use this crate.
+//!
+//! The logger is a global resource that can only be installed once per process and whose
+//! background task must outlive the runtime of any individual test.  The helpers in this module
+//! take care of these details by installing a single logger backed by an in-memory SQLite
+//! database the first time they are used and by reusing it afterwards.
+//!
+//! These helpers cannot be combined with other calls to `init` or `init_with_options` in the same
+//! process.
+
+use crate::{init_with_options, sqlite, Handle, LogEntryRow, LogFilter, LoggerOptions};
+use log::Level;
+use std::future::Future;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use tokio::sync::Mutex;
+
+/// Handle to the process-wide logger installed by `memory_logger`.
+static HANDLE: OnceLock<Handle> = OnceLock::new();
+
+/// Lock to prevent concurrent calls to `with_memory_logger` from seeing each other's entries.
+static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+/// Installs the process-wide logger backed by an in-memory SQLite database if not yet done and
+/// returns its handle.
+///
+/// The logger's background task runs on a dedicated runtime so that it keeps working once the
+/// runtime of the test that installed it goes away.
+fn memory_logger() -> &'static Handle {
+    HANDLE.get_or_init(|| {
+        let (handle_tx, handle_rx) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to create runtime for the test logger");
+            runtime.block_on(async move {
+                let db = sqlite::connect(sqlite::ConnectionOptions {
+                    uri: ":memory:".to_owned(),
+                    ..Default::default()
+                })
+                .await
+                .expect("Failed to connect to the in-memory database");
+                db.create_schema().await.expect("Failed to initialize the in-memory database");
+
+                let opts = LoggerOptions {
+                    default_level: Level::Trace,
+                    ignore_env: true,
+                    ..Default::default()
+                };
+                handle_tx
+                    .send(
+                        init_with_options(db, opts)
+                            .await
+                            .expect("Failed to initialize the test logger"),
+                    )
+                    .unwrap();
+
+                // Keep the runtime alive forever so that the logger's background task survives.
+                std::future::pending::<()>().await;
+            });
+        });
+        handle_rx.recv().expect("Failed to install the test logger")
+    })
+}
+
+/// Runs `f` with a logger that captures all log entries and returns the entries recorded while
+/// `f` was running.
+///
+/// The logger is installed the first time this is called and records entries of all levels.
+/// `f` receives the logger's `Handle` in case it needs to interact with it.  Once `f` completes,
+/// all pending entries are flushed and returned sorted by timestamp and sequence number.
+///
+/// Calls to this function are serialized so that tests running in parallel do not see each other's
+/// entries.  However, entries emitted concurrently by code that does not use this function will be
+/// captured too.
+///
+/// As this is only for testing, any errors result in a panic.
+pub async fn with_memory_logger<F, Fut>(f: F) -> Vec<LogEntryRow>
+where
+    F: FnOnce(&'static Handle) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let _guard = LOCK.get_or_init(|| Mutex::new(())).lock().await;
+
+    let handle = memory_logger();
+    handle.set_level(Level::Trace);
+
+    // Sequence numbers are never negative so this captures everything on the first call.
+    handle.flush().expect("Failed to flush log entries");
+    let last_sequence = handle
+        .query_log_entries(&LogFilter::default())
+        .await
+        .expect("Failed to query log entries")
+        .iter()
+        .map(|row| row.sequence)
+        .max()
+        .unwrap_or(-1);
+
+    f(handle).await;
+
+    handle.flush().expect("Failed to flush log entries");
+    handle
+        .query_log_entries(&LogFilter::default())
+        .await
+        .expect("Failed to query log entries")
+        .into_iter()
+        .filter(|row| row.sequence > last_sequence)
+        .collect()
+}
@@ -17,11 +17,12 @@
 
 use crate::logger::{
     LogEntry, LOG_ENTRY_MAX_FILENAME_LENGTH, LOG_ENTRY_MAX_HOSTNAME_LENGTH,
-    LOG_ENTRY_MAX_MESSAGE_LENGTH, LOG_ENTRY_MAX_MODULE_LENGTH,
+    LOG_ENTRY_MAX_MODULE_LENGTH,
 };
-use crate::Db;
+use crate::{Db, Fields, LogEntryRow, LogFilter, SortOrder};
+use futures::TryStreamExt;
 use std::convert::TryFrom;
-use time::OffsetDateTime;
+use time::{OffsetDateTime, UtcOffset};
 
 /// Context to parameterize the tests depending on the backing database.
 ///
@@ -31,6 +32,16 @@ pub(crate) trait TestContext {
     fn db(&self) -> &(dyn Db + Send + Sync);
 }
 
+/// Creates a new log entry for tests with a `message` recorded at `secs`.
+pub(crate) fn new_entry(secs: i64, message: &str) -> LogEntry {
+    LogEntry::new(
+        OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+        "fake-host",
+        log::Level::Info,
+        message.to_owned(),
+    )
+}
+
 pub(crate) fn test_log_entries_none(mut context: Box<dyn TestContext>) {
     #[tokio::main]
     async fn run(context: &mut dyn TestContext) {
@@ -43,25 +54,24 @@ pub(crate) fn test_log_entries_none(mut context: Box<dyn TestContext>) {
 pub(crate) fn test_log_entries_individual(mut context: Box<dyn TestContext>) {
     #[tokio::main]
     async fn run(context: &mut dyn TestContext) {
-        let entry1 = LogEntry {
-            timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_001_001),
-            hostname: "fake-host1".to_owned(),
-            level: log::Level::Error,
-            module: None,
-            filename: None,
-            line: None,
-            message: "Entry without optional fields".to_owned(),
-        };
+        let entry1 = LogEntry::new(
+            OffsetDateTime::from_unix_timestamp_nanos(1_000_001_001).unwrap(),
+            "fake-host1",
+            log::Level::Error,
+            "Entry without optional fields",
+        );
         context.db().put_log_entries(vec![entry1]).await.unwrap();
 
         let entry2 = LogEntry {
-            timestamp: OffsetDateTime::from_unix_timestamp_nanos(12_345_000_006_000),
-            hostname: "fake-host2".to_owned(),
-            level: log::Level::Info,
-            module: Some("the-module"),
-            filename: Some("the-file"),
+            module: Some("the-module".into()),
+            filename: Some("the-file".into()),
             line: Some(42),
-            message: "Entry with optional fields".to_owned(),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp_nanos(12_345_000_006_000).unwrap(),
+                "fake-host2",
+                log::Level::Info,
+                "Entry with optional fields",
+            )
         };
         context.db().put_log_entries(vec![entry2]).await.unwrap();
 
@@ -77,24 +87,23 @@ pub(crate) fn test_log_entries_individual(mut context: Box<dyn TestContext>) {
 pub(crate) fn test_log_entries_combined(mut context: Box<dyn TestContext>) {
     #[tokio::main]
     async fn run(context: &mut dyn TestContext) {
-        let entry1 = LogEntry {
-            timestamp: OffsetDateTime::from_unix_timestamp_nanos(1_000_001_500),
-            hostname: "fake-host1".to_owned(),
-            level: log::Level::Error,
-            module: None,
-            filename: None,
-            line: None,
-            message: "Entry without optional fields".to_owned(),
-        };
+        let entry1 = LogEntry::new(
+            OffsetDateTime::from_unix_timestamp_nanos(1_000_001_500).unwrap(),
+            "fake-host1",
+            log::Level::Error,
+            "Entry without optional fields",
+        );
 
         let entry2 = LogEntry {
-            timestamp: OffsetDateTime::from_unix_timestamp_nanos(12_345_000_006_999),
-            hostname: "fake-host2".to_owned(),
-            level: log::Level::Info,
-            module: Some("the-module"),
-            filename: Some("the-file"),
+            module: Some("the-module".into()),
+            filename: Some("the-file".into()),
             line: Some(42),
-            message: "Entry with optional fields".to_owned(),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp_nanos(12_345_000_006_999).unwrap(),
+                "fake-host2",
+                log::Level::Info,
+                "Entry with optional fields",
+            )
         };
 
         context.db().put_log_entries(vec![entry1, entry2]).await.unwrap();
@@ -107,29 +116,39 @@ pub(crate) fn test_log_entries_combined(mut context: Box<dyn TestContext>) {
     }
     run(context.as_mut());
 }
-pub(crate) fn test_log_entries_long_strings(mut context: Box<dyn TestContext>) {
+
+/// Checks that long strings are truncated, with messages only truncated if `max_message_length`
+/// is set.
+pub(crate) fn test_log_entries_long_strings(
+    mut context: Box<dyn TestContext>,
+    max_message_length: Option<usize>,
+) {
     #[tokio::main]
-    async fn run(context: &mut dyn TestContext) {
+    async fn run(context: &mut dyn TestContext, max_message_length: Option<usize>) {
         let mut long_string = String::with_capacity(5000);
         for i in 0..long_string.capacity() {
             long_string.push(char::from(b'0' + u8::try_from(i % 10).unwrap()));
         }
 
         let entry = LogEntry {
-            timestamp: OffsetDateTime::from_unix_timestamp(0),
-            hostname: long_string.to_owned(),
-            level: log::Level::Trace,
-            module: Some(&long_string),
-            filename: Some(&long_string),
-            line: None,
-            message: long_string.to_owned(),
+            module: Some(long_string.clone().into()),
+            filename: Some(long_string.clone().into()),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(0).unwrap(),
+                long_string.as_str(),
+                log::Level::Trace,
+                long_string.to_owned(),
+            )
         };
         context.db().put_log_entries(vec![entry]).await.unwrap();
 
         let truncated_hostname = &long_string[0..LOG_ENTRY_MAX_HOSTNAME_LENGTH];
         let truncated_module = &long_string[0..LOG_ENTRY_MAX_MODULE_LENGTH];
         let truncated_filename = &long_string[0..LOG_ENTRY_MAX_FILENAME_LENGTH];
-        let truncated_message = &long_string[0..LOG_ENTRY_MAX_MESSAGE_LENGTH];
+        let truncated_message = match max_message_length {
+            Some(max) => &long_string[0..max],
+            None => long_string.as_str(),
+        };
 
         let exp_entries = vec![format!(
             "0.0 {} 5 {} {}:-1 {}",
@@ -137,5 +156,968 @@ pub(crate) fn test_log_entries_long_strings(mut context: Box<dyn TestContext>) {
         )];
         assert_eq!(exp_entries, context.db().get_log_entries().await.unwrap());
     }
+    run(context.as_mut(), max_message_length);
+}
+
+pub(crate) fn test_query_log_entries_sequence(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let timestamp = OffsetDateTime::from_unix_timestamp_nanos(1_000_002_000).unwrap();
+        let mut entries = vec![];
+        for message in &["first", "second", "third"] {
+            entries.push(LogEntry::new(
+                timestamp,
+                "fake-host",
+                log::Level::Info,
+                (*message).to_owned(),
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let exp_rows = vec![
+            LogEntryRow {
+                timestamp,
+                sequence: 0,
+                hostname: "fake-host".into(),
+                level: log::Level::Info,
+                module: None,
+                filename: None,
+                line: None,
+                message: "first".to_owned(),
+                uptime_nanos: None,
+                utc_offset: None,
+                trace_id: None,
+                span_id: None,
+                ulid: None,
+                correlation_id: None,
+                payload: None,
+                fields: None,
+                id: None,
+            },
+            LogEntryRow {
+                timestamp,
+                sequence: 1,
+                hostname: "fake-host".into(),
+                level: log::Level::Info,
+                module: None,
+                filename: None,
+                line: None,
+                message: "second".to_owned(),
+                uptime_nanos: None,
+                utc_offset: None,
+                trace_id: None,
+                span_id: None,
+                ulid: None,
+                correlation_id: None,
+                payload: None,
+                fields: None,
+                id: None,
+            },
+            LogEntryRow {
+                timestamp,
+                sequence: 2,
+                hostname: "fake-host".into(),
+                level: log::Level::Info,
+                module: None,
+                filename: None,
+                line: None,
+                message: "third".to_owned(),
+                uptime_nanos: None,
+                utc_offset: None,
+                trace_id: None,
+                span_id: None,
+                ulid: None,
+                correlation_id: None,
+                payload: None,
+                fields: None,
+                id: None,
+            },
+        ];
+        let mut rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        for row in &mut rows {
+            // Identifiers depend on the backend and are checked separately.
+            row.id = None;
+        }
+        assert_eq!(exp_rows, rows);
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_query_log_entries_level_range(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let levels = [
+            log::Level::Error,
+            log::Level::Warn,
+            log::Level::Info,
+            log::Level::Debug,
+            log::Level::Trace,
+        ];
+        let mut entries = vec![];
+        for (i, level) in levels.iter().enumerate() {
+            entries.push(LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(i as i64).unwrap(),
+                "fake-host",
+                *level,
+                format!("Entry {}", i),
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_levels(db: &(dyn Db + Send + Sync), filter: LogFilter) -> Vec<log::Level> {
+            db.query_log_entries(&filter).await.unwrap().into_iter().map(|row| row.level).collect()
+        }
+
+        // Errors and warnings only.
+        let filter = LogFilter { max_level: Some(log::Level::Warn), ..Default::default() };
+        assert_eq!(
+            vec![log::Level::Error, log::Level::Warn],
+            query_levels(context.db(), filter).await
+        );
+
+        let filter = LogFilter { min_level: Some(log::Level::Info), ..Default::default() };
+        assert_eq!(
+            vec![log::Level::Info, log::Level::Debug, log::Level::Trace],
+            query_levels(context.db(), filter).await
+        );
+
+        let filter = LogFilter {
+            min_level: Some(log::Level::Warn),
+            max_level: Some(log::Level::Info),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![log::Level::Warn, log::Level::Info],
+            query_levels(context.db(), filter).await
+        );
+
+        assert_eq!(levels.to_vec(), query_levels(context.db(), LogFilter::default()).await);
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_query_log_entries_order(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, secs) in [1, 1, 2, 2, 3].iter().enumerate() {
+            entries.push(new_entry(*secs, &format!("Entry {}", i)));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_messages(db: &(dyn Db + Send + Sync), filter: LogFilter) -> Vec<String> {
+            db.query_log_entries(&filter)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| row.message)
+                .collect()
+        }
+
+        let filter = LogFilter { limit: Some(3), ..Default::default() };
+        assert_eq!(
+            vec!["Entry 0", "Entry 1", "Entry 2"],
+            query_messages(context.db(), filter).await
+        );
+
+        let filter = LogFilter { order: SortOrder::Descending, ..Default::default() };
+        assert_eq!(
+            vec!["Entry 4", "Entry 3", "Entry 2", "Entry 1", "Entry 0"],
+            query_messages(context.db(), filter).await
+        );
+
+        let filter =
+            LogFilter { order: SortOrder::Descending, limit: Some(2), ..Default::default() };
+        assert_eq!(vec!["Entry 4", "Entry 3"], query_messages(context.db(), filter).await);
+
+        let filter = LogFilter { limit: Some(0), ..Default::default() };
+        assert!(query_messages(context.db(), filter).await.is_empty());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_stream_log_entries(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let rows: Vec<LogEntryRow> =
+            context.db().stream_log_entries(&LogFilter::default()).try_collect().await.unwrap();
+        assert!(rows.is_empty());
+
+        let mut entries = vec![];
+        for i in 0..200 {
+            entries.push(LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(i).unwrap(),
+                "fake-host",
+                if i % 2 == 0 { log::Level::Info } else { log::Level::Debug },
+                format!("Entry {}", i),
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let filter = LogFilter::default();
+        let rows: Vec<LogEntryRow> =
+            context.db().stream_log_entries(&filter).try_collect().await.unwrap();
+        assert_eq!(context.db().query_log_entries(&filter).await.unwrap(), rows);
+        assert_eq!(200, rows.len());
+
+        let filter = LogFilter {
+            max_level: Some(log::Level::Info),
+            order: SortOrder::Descending,
+            limit: Some(3),
+            ..Default::default()
+        };
+        let rows: Vec<LogEntryRow> =
+            context.db().stream_log_entries(&filter).try_collect().await.unwrap();
+        assert_eq!(
+            vec!["Entry 198", "Entry 196", "Entry 194"],
+            rows.into_iter().map(|row| row.message).collect::<Vec<String>>()
+        );
+
+        // Dropping a stream before consuming it fully must not prevent further accesses.
+        let mut stream = context.db().stream_log_entries(&LogFilter::default());
+        assert_eq!("Entry 0", stream.try_next().await.unwrap().unwrap().message);
+        drop(stream);
+        context.db().put_log_entries(vec![new_entry(200, "Entry 200")]).await.unwrap();
+        assert_eq!(201, context.db().query_log_entries(&LogFilter::default()).await.unwrap().len());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_maintenance(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for i in 0..10 {
+            entries.push(new_entry(i, &format!("Entry {}", i)));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        context.db().maintenance().await.unwrap();
+        assert_eq!(10, context.db().get_log_entries().await.unwrap().len());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_distinct_hostnames_and_modules(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        assert!(context.db().distinct_hostnames().await.unwrap().is_empty());
+        assert!(context.db().distinct_modules().await.unwrap().is_empty());
+
+        let mut entries = vec![];
+        for (i, (hostname, module)) in [
+            ("host-b", Some("module-2")),
+            ("host-a", None),
+            ("host-c", Some("module-1")),
+            ("host-a", Some("module-2")),
+            ("host-b", None),
+        ]
+        .iter()
+        .enumerate()
+        {
+            entries.push(LogEntry {
+                module: module.map(|module| module.to_owned().into()),
+                ..LogEntry::new(
+                    OffsetDateTime::from_unix_timestamp(i64::try_from(i).unwrap()).unwrap(),
+                    *hostname,
+                    log::Level::Info,
+                    "A message",
+                )
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        assert_eq!(
+            vec!["host-a".to_owned(), "host-b".to_owned(), "host-c".to_owned()],
+            context.db().distinct_hostnames().await.unwrap()
+        );
+        assert_eq!(
+            vec!["module-1".to_owned(), "module-2".to_owned()],
+            context.db().distinct_modules().await.unwrap()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_uptime_nanos(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, uptime_nanos) in [Some(1_500_000_000), None, Some(i64::MAX)].iter().enumerate() {
+            entries.push(LogEntry {
+                uptime_nanos: *uptime_nanos,
+                ..new_entry(i64::try_from(i).unwrap(), "A message")
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some(1_500_000_000), None, Some(i64::MAX)],
+            rows.iter().map(|row| row.uptime_nanos).collect::<Vec<Option<i64>>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_trace_ids(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, trace_id, span_id) in [
+            (0, Some("4bf92f3577b34da6a3ce929d0e0e4736"), Some("00f067aa0ba902b7")),
+            (1, None, None),
+            (2, Some("4bf92f3577b34da6a3ce929d0e0e4736"), Some("b7ad6b7169203331")),
+        ] {
+            entries.push(LogEntry {
+                trace_id: trace_id.map(str::to_owned),
+                span_id: span_id.map(str::to_owned),
+                ..new_entry(i, &format!("Entry {}", i))
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some("00f067aa0ba902b7"), None, Some("b7ad6b7169203331")],
+            rows.iter().map(|row| row.span_id.as_deref()).collect::<Vec<Option<&str>>>()
+        );
+
+        let filter = LogFilter {
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_owned()),
+            ..Default::default()
+        };
+        let rows = context.db().query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 2"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_correlation_ids(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, correlation_id) in
+            [(0, Some("req-1")), (1, None), (2, Some("req-2")), (3, Some("req-1"))]
+        {
+            entries.push(LogEntry {
+                correlation_id: correlation_id.map(str::to_owned),
+                ..new_entry(i, &format!("Entry {}", i))
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some("req-1"), None, Some("req-2"), Some("req-1")],
+            rows.iter().map(|row| row.correlation_id.as_deref()).collect::<Vec<Option<&str>>>()
+        );
+
+        let filter = LogFilter { correlation_id: Some("req-1".to_owned()), ..Default::default() };
+        let rows = context.db().query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 3"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_payloads(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, payload) in [(0, Some(vec![0, 1, 2, 255])), (1, None), (2, Some(vec![]))] {
+            entries.push(LogEntry { payload, ..new_entry(i, &format!("Entry {}", i)) });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some(vec![0, 1, 2, 255]), None, Some(vec![])],
+            rows.into_iter().map(|row| row.payload).collect::<Vec<Option<Vec<u8>>>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_fields(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let fields = Fields::from([
+            ("count".to_owned(), "3".to_owned()),
+            ("path".to_owned(), "/a \"b\", c".to_owned()),
+        ]);
+        let mut entries = vec![];
+        for (i, fields) in [(0, Some(fields.clone())), (1, None), (2, Some(Fields::new()))] {
+            entries.push(LogEntry { fields, ..new_entry(i, &format!("Entry {}", i)) });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some(fields), None, Some(Fields::new())],
+            rows.into_iter().map(|row| row.fields).collect::<Vec<Option<Fields>>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_crate_names(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, module) in
+            [(0, Some("foo")), (1, Some("foobar::baz")), (2, None), (3, Some("foo::bar::baz"))]
+        {
+            entries.push(LogEntry {
+                module: module.map(Into::into),
+                ..new_entry(i, &format!("Entry {}", i))
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![Some("foo"), Some("foobar"), None, Some("foo")],
+            rows.iter().map(LogEntryRow::crate_name).collect::<Vec<Option<&str>>>()
+        );
+
+        let filter = LogFilter { crate_name: Some("foo".to_owned()), ..Default::default() };
+        let rows = context.db().query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 3"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+/// Writes entries from a variety of modules and levels for the tests of the module filter.
+async fn put_module_entries(context: &mut dyn TestContext) {
+    let mut entries = vec![];
+    for (i, module, level) in [
+        (0, Some("noisy"), log::Level::Trace),
+        (1, Some("noisy::inner"), log::Level::Trace),
+        (2, Some("noisy::inner"), log::Level::Info),
+        (3, Some("noisybar"), log::Level::Trace),
+        (4, Some("Noisy"), log::Level::Trace),
+        (5, None, log::Level::Trace),
+    ] {
+        entries.push(LogEntry {
+            module: module.map(Into::into),
+            ..LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(i).unwrap(),
+                "fake-host",
+                level,
+                format!("Entry {}", i),
+            )
+        });
+    }
+    context.db().put_log_entries(entries).await.unwrap();
+}
+
+pub(crate) fn test_module_filter(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        put_module_entries(context).await;
+
+        let filter = LogFilter { module: Some("noisy".to_owned()), ..Default::default() };
+        let rows = context.db().query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 0", "Entry 1", "Entry 2"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+
+        let filter = LogFilter { module: Some("noisy::inner".to_owned()), ..Default::default() };
+        let rows = context.db().query_log_entries(&filter).await.unwrap();
+        assert_eq!(
+            vec!["Entry 1", "Entry 2"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_delete_log_entries_by_module(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        put_module_entries(context).await;
+
+        let filter = LogFilter {
+            module: Some("noisy".to_owned()),
+            min_level: Some(log::Level::Trace),
+            ..Default::default()
+        };
+        assert_eq!(2, context.db().delete_log_entries(&filter).await.unwrap());
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec!["Entry 2", "Entry 3", "Entry 4", "Entry 5"],
+            rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_ulids(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        // The ULIDs deliberately sort in a different order than the timestamps, as happens when
+        // merging entries from hosts with skewed clocks.  The first and last entries share their
+        // ULID, as happens with the parts of a split entry.
+        let mut entries = vec![];
+        for (i, ulid) in [
+            (0, Some("01ARYZ6S41TSV4RRFFQ69G5FAC")),
+            (1, None),
+            (2, Some("01ARYZ6S41TSV4RRFFQ69G5FAA")),
+            (3, Some("01ARYZ6S41TSV4RRFFQ69G5FAB")),
+            (4, Some("01ARYZ6S41TSV4RRFFQ69G5FAC")),
+        ] {
+            entries.push(LogEntry {
+                ulid: ulid.map(str::to_owned),
+                ..new_entry(i, &format!("Entry {}", i))
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            vec![
+                Some("01ARYZ6S41TSV4RRFFQ69G5FAC"),
+                None,
+                Some("01ARYZ6S41TSV4RRFFQ69G5FAA"),
+                Some("01ARYZ6S41TSV4RRFFQ69G5FAB"),
+                Some("01ARYZ6S41TSV4RRFFQ69G5FAC"),
+            ],
+            rows.iter().map(|row| row.ulid.as_deref()).collect::<Vec<Option<&str>>>()
+        );
+        let sequences = rows.iter().map(|row| row.sequence).collect::<Vec<i64>>();
+
+        async fn query_page(
+            db: &(dyn Db + Send + Sync),
+            after_ulid: &str,
+            after_sequence: i64,
+            order: SortOrder,
+        ) -> Vec<String> {
+            let filter = LogFilter {
+                after_ulid: Some((after_ulid.to_owned(), after_sequence)),
+                order,
+                limit: Some(2),
+                ..Default::default()
+            };
+            let rows = db.query_log_entries(&filter).await.unwrap();
+            rows.into_iter().map(|row| row.message).collect()
+        }
+
+        let fab = "01ARYZ6S41TSV4RRFFQ69G5FAB";
+        let fac = "01ARYZ6S41TSV4RRFFQ69G5FAC";
+        assert_eq!(
+            vec!["Entry 2", "Entry 3"],
+            query_page(context.db(), "", 0, SortOrder::Ascending).await
+        );
+        assert_eq!(
+            vec!["Entry 0", "Entry 4"],
+            query_page(context.db(), fab, sequences[3], SortOrder::Ascending).await
+        );
+        assert_eq!(
+            vec!["Entry 4"],
+            query_page(context.db(), fac, sequences[0], SortOrder::Ascending).await
+        );
+        assert_eq!(
+            vec!["Entry 4", "Entry 0"],
+            query_page(context.db(), "Z", 0, SortOrder::Descending).await
+        );
+        assert_eq!(
+            vec!["Entry 0", "Entry 3"],
+            query_page(context.db(), fac, sequences[4], SortOrder::Descending).await
+        );
+        assert_eq!(
+            vec!["Entry 2"],
+            query_page(context.db(), fab, sequences[3], SortOrder::Descending).await
+        );
+    }
+    run(context.as_mut());
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) fn test_archive_log_entries(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for i in 0..5 {
+            entries.push(new_entry(i * 86400, &format!("Entry {}", i)));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_messages(db: &(dyn Db + Send + Sync), filter: LogFilter) -> Vec<String> {
+            db.query_log_entries(&filter).await.unwrap().into_iter().map(|r| r.message).collect()
+        }
+
+        let ts = OffsetDateTime::from_unix_timestamp(2 * 86400).unwrap();
+        assert_eq!(2, context.db().archive_log_entries_before(ts).await.unwrap());
+        assert_eq!(0, context.db().archive_log_entries_before(ts).await.unwrap());
+
+        assert_eq!(
+            vec!["Entry 2", "Entry 3", "Entry 4"],
+            query_messages(context.db(), LogFilter::default()).await
+        );
+        assert_eq!(
+            vec!["Entry 0", "Entry 1", "Entry 2", "Entry 3", "Entry 4"],
+            query_messages(
+                context.db(),
+                LogFilter { include_archived: true, ..Default::default() }
+            )
+            .await
+        );
+
+        let rows = context
+            .db()
+            .stream_log_entries(&LogFilter::default())
+            .try_collect::<Vec<LogEntryRow>>()
+            .await
+            .unwrap();
+        assert_eq!(3, rows.len());
+
+        // Archiving does not delete anything.
+        assert_eq!(5, context.db().get_log_entries().await.unwrap().len());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_level_counts(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        assert!(context.db().level_counts(&LogFilter::default()).await.unwrap().is_empty());
+
+        let mut entries = vec![];
+        for (i, level) in [
+            log::Level::Info,
+            log::Level::Error,
+            log::Level::Info,
+            log::Level::Trace,
+            log::Level::Error,
+            log::Level::Info,
+        ]
+        .iter()
+        .enumerate()
+        {
+            entries.push(LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(i64::try_from(i).unwrap()).unwrap(),
+                "fake-host",
+                *level,
+                "A message",
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        assert_eq!(
+            vec![(log::Level::Error, 2), (log::Level::Info, 3), (log::Level::Trace, 1)],
+            context.db().level_counts(&LogFilter::default()).await.unwrap()
+        );
+
+        let filter =
+            LogFilter { min_level: Some(log::Level::Warn), limit: Some(1), ..Default::default() };
+        assert_eq!(
+            vec![(log::Level::Info, 3), (log::Level::Trace, 1)],
+            context.db().level_counts(&filter).await.unwrap()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_utc_offset(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let offsets = [Some(UtcOffset::from_hms(-7, 0, 0).unwrap()), None, Some(UtcOffset::UTC)];
+        let mut entries = vec![];
+        for (i, utc_offset) in offsets.iter().enumerate() {
+            entries.push(LogEntry {
+                utc_offset: *utc_offset,
+                ..new_entry(i64::try_from(i).unwrap(), "A message")
+            });
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(
+            offsets.to_vec(),
+            rows.iter().map(|row| row.utc_offset).collect::<Vec<Option<UtcOffset>>>()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_put_log_entries_exceeds_max_params(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        // Every entry needs many more than 5 parameters so this does not fit in one statement.
+        let nentries = (context.db().max_params_per_statement() / 5).min(10_000);
+        let mut entries = vec![];
+        for i in 0..nentries {
+            entries.push(new_entry(i64::try_from(i).unwrap(), &format!("Entry {}", i)));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(nentries, rows.len());
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(i64::try_from(i).unwrap(), row.sequence);
+            assert_eq!(format!("Entry {}", i), row.message);
+        }
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_verify_schema(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        context.db().verify_schema().await.unwrap();
+
+        // Writing entries may create new tables, such as partitions, so check them too.
+        let entry = new_entry(1000, "The message");
+        context.db().put_log_entries(vec![entry]).await.unwrap();
+        context.db().verify_schema().await.unwrap();
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_migrate_schema(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let entry = new_entry(1000, "The message");
+        context.db().put_log_entries(vec![entry]).await.unwrap();
+
+        // Migrating a schema that is already up to date must not change it.
+        context.db().migrate_schema().await.unwrap();
+        context.db().migrate_schema().await.unwrap();
+        context.db().verify_schema().await.unwrap();
+        assert_eq!(1, context.db().get_log_entries().await.unwrap().len());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_query_log_entries_message_contains(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let messages = [
+            "Connection refused by peer",
+            "Upload 100% done",
+            "Upload 1000 done",
+            "Wrote file_name",
+            "Wrote filename",
+            "It's a trap",
+        ];
+        let mut entries = vec![];
+        for (i, message) in messages.iter().enumerate() {
+            entries.push(new_entry(i as i64, message));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_messages(db: &(dyn Db + Send + Sync), text: &str) -> Vec<String> {
+            let filter =
+                LogFilter { message_contains: Some(text.to_owned()), ..Default::default() };
+            db.query_log_entries(&filter)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| row.message)
+                .collect()
+        }
+
+        assert_eq!(
+            vec!["Connection refused by peer"],
+            query_messages(context.db(), "refused").await
+        );
+        assert_eq!(vec!["Upload 100% done"], query_messages(context.db(), "100%").await);
+        assert_eq!(vec!["Wrote file_name"], query_messages(context.db(), "file_").await);
+        assert_eq!(vec!["It's a trap"], query_messages(context.db(), "It's").await);
+        assert!(query_messages(context.db(), "not found").await.is_empty());
+        assert!(query_messages(context.db(), "connection").await.is_empty());
+        assert_eq!(2, query_messages(context.db(), "Upload").await.len());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_time_bounds(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        assert_eq!(None, context.db().time_bounds().await.unwrap());
+
+        let mut entries = vec![];
+        for secs in [5000, 1000, 86400 * 3, 3000] {
+            entries.push(new_entry(secs, &format!("Entry at {}", secs)));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        assert_eq!(
+            Some((
+                OffsetDateTime::from_unix_timestamp(1000).unwrap(),
+                OffsetDateTime::from_unix_timestamp(86400 * 3).unwrap()
+            )),
+            context.db().time_bounds().await.unwrap()
+        );
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_storage_stats(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let before = context.db().storage_stats().await.unwrap();
+        assert_eq!(0, before.rows);
+
+        let mut entries = vec![];
+        for i in 0..50 {
+            entries.push(new_entry(
+                i,
+                &format!("Entry {} with some padding to take up space: {}", i, "x".repeat(200)),
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        let after = context.db().storage_stats().await.unwrap();
+        assert_eq!(50, after.rows);
+        assert!(after.bytes >= before.bytes, "Size shrank from {:?} to {:?}", before, after);
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_empty_message(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let entry = LogEntry { module: Some("the-module".into()), ..new_entry(1000, "") };
+        context.db().put_log_entries(vec![entry]).await.unwrap();
+
+        assert_eq!(1, context.db().get_log_entries().await.unwrap().len());
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!("", rows[0].message);
+        assert_eq!(Some("the-module"), rows[0].module.as_deref());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_delete_log_entries(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let mut entries = vec![];
+        for (i, level) in [log::Level::Error, log::Level::Info, log::Level::Debug, log::Level::Info]
+            .into_iter()
+            .enumerate()
+        {
+            entries.push(LogEntry::new(
+                OffsetDateTime::from_unix_timestamp(i as i64 * 86400).unwrap(),
+                "fake-host",
+                level,
+                format!("Entry {}", i),
+            ));
+        }
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_messages(db: &(dyn Db + Send + Sync)) -> Vec<String> {
+            let rows = db.query_log_entries(&LogFilter::default()).await.unwrap();
+            rows.into_iter().map(|r| r.message).collect()
+        }
+
+        let filter = LogFilter { min_level: Some(log::Level::Info), ..Default::default() };
+        assert_eq!(3, context.db().delete_log_entries(&filter).await.unwrap());
+        assert_eq!(0, context.db().delete_log_entries(&filter).await.unwrap());
+        assert_eq!(vec!["Entry 0"], query_messages(context.db()).await);
+
+        let filter = LogFilter { message_contains: Some("Entry".to_owned()), ..Default::default() };
+        assert_eq!(1, context.db().delete_log_entries(&filter).await.unwrap());
+        assert!(query_messages(context.db()).await.is_empty());
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_clear_log_entries(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        context.db().clear_log_entries().await.unwrap();
+
+        context
+            .db()
+            .put_log_entries(vec![new_entry(0, "First"), new_entry(86400, "Second")])
+            .await
+            .unwrap();
+        assert_eq!(2, context.db().get_log_entries().await.unwrap().len());
+
+        context.db().clear_log_entries().await.unwrap();
+        assert!(context.db().get_log_entries().await.unwrap().is_empty());
+        assert_eq!(None, context.db().time_bounds().await.unwrap());
+
+        context.db().put_log_entries(vec![new_entry(172800, "Third")]).await.unwrap();
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!("Third", rows[0].message);
+        assert_eq!(0, rows[0].sequence);
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_query_log_entries_after_id(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let entries = (0..5).map(|i| new_entry(i, &format!("Entry {}", i))).collect();
+        context.db().put_log_entries(entries).await.unwrap();
+
+        async fn query_page(
+            db: &(dyn Db + Send + Sync),
+            after_id: i64,
+            order: SortOrder,
+        ) -> (Vec<String>, Option<i64>) {
+            let filter =
+                LogFilter { after_id: Some(after_id), order, limit: Some(2), ..Default::default() };
+            let rows = db.query_log_entries(&filter).await.unwrap();
+            let last_id = rows.last().map(|row| row.id.unwrap());
+            (rows.into_iter().map(|row| row.message).collect(), last_id)
+        }
+
+        let (messages, last_id) = query_page(context.db(), 0, SortOrder::Ascending).await;
+        assert_eq!(vec!["Entry 0", "Entry 1"], messages);
+        let (messages, last_id) =
+            query_page(context.db(), last_id.unwrap(), SortOrder::Ascending).await;
+        assert_eq!(vec!["Entry 2", "Entry 3"], messages);
+        let (messages, last_id) =
+            query_page(context.db(), last_id.unwrap(), SortOrder::Ascending).await;
+        assert_eq!(vec!["Entry 4"], messages);
+
+        // Entries written between pages show up in later pages even if they are older.
+        context.db().put_log_entries(vec![new_entry(-1, "Late")]).await.unwrap();
+        let (messages, last_id) =
+            query_page(context.db(), last_id.unwrap(), SortOrder::Ascending).await;
+        assert_eq!(vec!["Late"], messages);
+        let (messages, _) = query_page(context.db(), last_id.unwrap(), SortOrder::Ascending).await;
+        assert!(messages.is_empty());
+
+        let (messages, last_id) = query_page(context.db(), i64::MAX, SortOrder::Descending).await;
+        assert_eq!(vec!["Late", "Entry 4"], messages);
+        let (messages, _) = query_page(context.db(), last_id.unwrap(), SortOrder::Descending).await;
+        assert_eq!(vec!["Entry 3", "Entry 2"], messages);
+    }
+    run(context.as_mut());
+}
+
+pub(crate) fn test_large_line_number(mut context: Box<dyn TestContext>) {
+    #[tokio::main]
+    async fn run(context: &mut dyn TestContext) {
+        let entry = LogEntry {
+            filename: Some("generated.rs".into()),
+            line: Some(40_000),
+            ..new_entry(0, "A message")
+        };
+        context.db().put_log_entries(vec![entry]).await.unwrap();
+
+        assert_eq!(
+            vec!["0.0 fake-host 3 NO-MODULE generated.rs:40000 A message"],
+            context.db().get_log_entries().await.unwrap()
+        );
+        let rows = context.db().query_log_entries(&LogFilter::default()).await.unwrap();
+        assert_eq!(Some(40_000), rows[0].line);
+    }
     run(context.as_mut());
 }
@@ -0,0 +1,292 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Integration with the `tracing` crate.
+//!
+//! The `DbLayer` captures `tracing` events and feeds them into the same pipeline that persists the
+//! records emitted via the `log` facade.  Events do not go through the lossy `log` bridge: the
+//! fields of the event and of all of its enclosing spans are preserved in the stored message.
+
+use crate::logger::{
+    is_recorder_module, report, Action, ActionSender, EntryBuilder, InternalErrorKind, SharedLevel,
+};
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Converts a `tracing` level into its `log` equivalent.
+fn to_log_level(level: &tracing::Level) -> log::Level {
+    if *level == tracing::Level::ERROR {
+        log::Level::Error
+    } else if *level == tracing::Level::WARN {
+        log::Level::Warn
+    } else if *level == tracing::Level::INFO {
+        log::Level::Info
+    } else if *level == tracing::Level::DEBUG {
+        log::Level::Debug
+    } else {
+        log::Level::Trace
+    }
+}
+
+/// Visitor to collect the fields of an event or span.
+#[derive(Default)]
+struct FieldsVisitor {
+    /// Contents of the special `message` field, if any.
+    message: Option<String>,
+
+    /// All other fields formatted as space-separated `key=value` pairs.
+    fields: String,
+}
+
+impl Visit for FieldsVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_owned());
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            write!(self.fields, "{}={:?}", field.name(), value)
+                .expect("Writing to a String cannot fail");
+        }
+    }
+}
+
+/// Formatted fields of a span, stored in the span's extensions.
+struct SpanFields(String);
+
+/// A `tracing_subscriber::Layer` that persists `tracing` events to the database.
+///
+/// Obtain an instance via `Handle::tracing_layer` and register it with a subscriber registry.
+/// Events are stored as log entries whose message is composed of the names and fields of the
+/// enclosing spans, from the outermost to the innermost, followed by the event's message and its
+/// fields.
+#[derive(Clone)]
+pub struct DbLayer {
+    action_tx: ActionSender,
+    builder: EntryBuilder,
+    max_level: SharedLevel,
+}
+
+impl DbLayer {
+    /// Creates a new layer that sends entries to the recorder via `action_tx`, that turns events
+    /// into entries with `builder`, and that discards events above `max_level`.
+    pub(crate) fn new(
+        action_tx: ActionSender,
+        builder: EntryBuilder,
+        max_level: SharedLevel,
+    ) -> Self {
+        Self { action_tx, builder, max_level }
+    }
+}
+
+impl<S> Layer<S> for DbLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span must exist in on_new_span");
+        let mut visitor = FieldsVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span must exist in on_record");
+        let mut visitor = FieldsVisitor::default();
+        values.record(&mut visitor);
+        if visitor.fields.is_empty() {
+            return;
+        }
+
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<SpanFields>() {
+            Some(fields) if !fields.0.is_empty() => {
+                fields.0.push(' ');
+                fields.0.push_str(&visitor.fields);
+            }
+            Some(fields) => fields.0 = visitor.fields,
+            None => extensions.insert(SpanFields(visitor.fields)),
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = to_log_level(metadata.level());
+        if !self.max_level.enabled(level) {
+            return;
+        }
+
+        // Skip events emitted by the database-persistence code as they would cause us to recurse
+        // and never finish logging.  sqlx emits events with targets that differ from its modules
+        // so check both.
+        if is_recorder_module(Some(metadata.target()), level)
+            || is_recorder_module(metadata.module_path(), level)
+        {
+            return;
+        }
+
+        let mut message = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                message.push_str(span.name());
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    if !fields.0.is_empty() {
+                        write!(message, "{{{}}}", fields.0)
+                            .expect("Writing to a String cannot fail");
+                    }
+                }
+                message.push_str(": ");
+            }
+        }
+
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+        if let Some(event_message) = visitor.message {
+            message.push_str(&event_message);
+        }
+        if !visitor.fields.is_empty() {
+            if !message.is_empty() && !message.ends_with(' ') {
+                message.push(' ');
+            }
+            message.push_str(&visitor.fields);
+        }
+        let entry = self.builder.build(
+            level,
+            metadata.module_path().map(Cow::Borrowed),
+            metadata.file().map(Cow::Borrowed),
+            metadata.line(),
+            message,
+        );
+        if let Err(e) = self.action_tx.send(Action::Record(Box::new(entry))) {
+            report(
+                &self.builder.internal_error_handler,
+                InternalErrorKind::Channel,
+                format!("Failed to record tracing event: {}", e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clocks::MonotonicClock;
+    use crate::logger::{action_channel, ChannelKind, FilenameTransform, LogEntry};
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Runs `f` with a subscriber that uses a `DbLayer` that records events up to `max_level` and
+    /// returns the recorded entries.
+    fn capture_with_level<F: FnOnce()>(max_level: log::Level, f: F) -> Vec<LogEntry> {
+        let (action_tx, mut action_rx) = action_channel(ChannelKind::Bounded(16));
+        let builder = EntryBuilder::new(
+            "fake-hostname".to_owned(),
+            Arc::from(MonotonicClock::new(1000)),
+            FilenameTransform::default(),
+        );
+        let layer = DbLayer::new(action_tx, builder, SharedLevel::new(max_level.to_level_filter()));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, f);
+
+        let mut entries = vec![];
+        while let Ok(action) = action_rx.try_recv() {
+            match action {
+                Action::Record(entry) => entries.push(*entry),
+                action => panic!("Unexpected action {:?}", action),
+            }
+        }
+        entries
+    }
+
+    /// Runs `f` with a subscriber that uses a `DbLayer` and returns the recorded entries.
+    fn capture<F: FnOnce()>(f: F) -> Vec<LogEntry> {
+        capture_with_level(log::Level::Trace, f)
+    }
+
+    #[test]
+    fn test_event_levels_and_metadata() {
+        let entries = capture(|| {
+            tracing::error!("An error message");
+            tracing::trace!("A trace message");
+        });
+        assert_eq!(2, entries.len());
+        assert_eq!(log::Level::Error, entries[0].level);
+        assert_eq!("An error message", entries[0].message);
+        assert_eq!("fake-hostname", &*entries[0].hostname);
+        assert_eq!(Some("db_logger::tracing::tests"), entries[0].module.as_deref());
+        assert_eq!(Some(file!()), entries[0].filename.as_deref());
+        assert_eq!(log::Level::Trace, entries[1].level);
+    }
+
+    #[test]
+    fn test_event_fields() {
+        let entries = capture(|| {
+            tracing::info!(user = "jmmv", attempt = 3, "Logged in");
+            tracing::info!(only = true);
+        });
+        assert_eq!(2, entries.len());
+        assert_eq!("Logged in user=\"jmmv\" attempt=3", entries[0].message);
+        assert_eq!("only=true", entries[1].message);
+    }
+
+    #[test]
+    fn test_span_fields() {
+        let entries = capture(|| {
+            let outer = tracing::info_span!("request", id = 42);
+            let _outer = outer.enter();
+            let inner = tracing::debug_span!("query", table = tracing::field::Empty);
+            inner.record("table", "logs");
+            let _inner = inner.enter();
+            tracing::warn!(rows = 0, "Nothing found");
+        });
+        assert_eq!(1, entries.len());
+        assert_eq!(
+            "request{id=42}: query{table=\"logs\"}: Nothing found rows=0",
+            entries[0].message
+        );
+    }
+
+    #[test]
+    fn test_max_level() {
+        let entries = capture_with_level(log::Level::Warn, || {
+            tracing::warn!("Recorded");
+            tracing::info!("Not recorded");
+        });
+        assert_eq!(1, entries.len());
+        assert_eq!("Recorded", entries[0].message);
+    }
+
+    #[test]
+    fn test_recorder_events_are_skipped() {
+        let entries = capture(|| {
+            tracing::info!(target: "sqlx::query", "Not recorded");
+        });
+        assert!(entries.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Generation of ULIDs to identify log entries across hosts.
+//!
+//! A ULID is a 128-bit identifier made of a 48-bit timestamp in milliseconds followed by 80 random
+//! bits, encoded as 26 characters of Crockford's base32 so that its textual representation sorts
+//! in the same order as its value.  See <https://github.com/ulid/spec> for details.
+
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+/// Alphabet of Crockford's base32, which omits the letters I, L, O and U.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Number of characters in the textual representation of a ULID.
+const ENCODED_LEN: usize = 26;
+
+/// Number of bits in the random component of a ULID.
+const RANDOM_BITS: u32 = 80;
+
+/// Mask to extract the random component of a ULID.
+const RANDOM_MASK: u128 = (1 << RANDOM_BITS) - 1;
+
+/// Mask to extract the timestamp component of a ULID once shifted.
+const TIMESTAMP_MASK: u64 = (1 << 48) - 1;
+
+/// Encodes the 128-bit `value` of a ULID in its textual representation.
+fn encode(mut value: u128) -> String {
+    let mut encoded = [0u8; ENCODED_LEN];
+    for ch in encoded.iter_mut().rev() {
+        *ch = ALPHABET[(value & 0x1f) as usize];
+        value >>= 5;
+    }
+    String::from_utf8(encoded.to_vec()).expect("The alphabet is ASCII")
+}
+
+/// Generator of ULIDs that are monotonically increasing within a process.
+///
+/// ULIDs generated within the same millisecond, or while the clock goes backwards, reuse the
+/// timestamp of the previous ULID and increment its random component, so their order matches the
+/// order in which they were generated.
+pub(crate) struct UlidGenerator {
+    /// Timestamp in milliseconds and random component of the last generated ULID.
+    last: Mutex<(u64, u128)>,
+}
+
+impl UlidGenerator {
+    /// Creates a new generator.
+    pub(crate) fn new() -> Self {
+        Self { last: Mutex::new((0, 0)) }
+    }
+
+    /// Generates a new ULID for an entry recorded at `timestamp`.
+    pub(crate) fn generate(&self, timestamp: OffsetDateTime) -> String {
+        let millis = u64::try_from(timestamp.unix_timestamp_nanos() / 1_000_000).unwrap_or(0)
+            & TIMESTAMP_MASK;
+
+        let mut last = self.last.lock().unwrap();
+        let (millis, random) = if millis > last.0 {
+            (millis, rand::random::<u128>() & RANDOM_MASK)
+        } else if last.1 < RANDOM_MASK {
+            (last.0, last.1 + 1)
+        } else {
+            // The random component overflowed so borrow the next millisecond.
+            ((last.0 + 1) & TIMESTAMP_MASK, rand::random::<u128>() & RANDOM_MASK)
+        };
+        *last = (millis, random);
+
+        encode((u128::from(millis) << RANDOM_BITS) | random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!("00000000000000000000000000", encode(0));
+        assert_eq!("0000000000000000000000000Z", encode(31));
+        assert_eq!("7ZZZZZZZZZZZZZZZZZZZZZZZZZ", encode(u128::MAX));
+        assert_eq!("01ARYZ6S410000000000000000", encode(1_469_918_176_385 << RANDOM_BITS));
+    }
+
+    #[test]
+    fn test_generate_encodes_timestamp() {
+        let generator = UlidGenerator::new();
+        let timestamp =
+            OffsetDateTime::from_unix_timestamp_nanos(1_469_918_176_385_123_456).unwrap();
+        let ulid = generator.generate(timestamp);
+        assert_eq!(ENCODED_LEN, ulid.len());
+        assert!(ulid.starts_with("01ARYZ6S41"), "Unexpected ULID {}", ulid);
+    }
+
+    #[test]
+    fn test_generate_is_monotonic() {
+        let generator = UlidGenerator::new();
+        let later = OffsetDateTime::from_unix_timestamp(2000).unwrap();
+        let earlier = OffsetDateTime::from_unix_timestamp(1000).unwrap();
+
+        let mut ulids = vec![];
+        for timestamp in [later, later, later, earlier, later] {
+            ulids.push(generator.generate(timestamp));
+        }
+        let mut sorted = ulids.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(ulids, sorted);
+    }
+
+    #[test]
+    fn test_generate_random_overflow() {
+        let generator = UlidGenerator::new();
+        *generator.last.lock().unwrap() = (2_000_000, RANDOM_MASK);
+
+        let ulid = generator.generate(OffsetDateTime::from_unix_timestamp(2000).unwrap());
+        assert!(
+            ulid > encode((2_000_000 << RANDOM_BITS) | RANDOM_MASK),
+            "Unexpected ULID {}",
+            ulid
+        );
+        assert!(
+            ulid.starts_with(&encode(2_000_001 << RANDOM_BITS)[..10]),
+            "Unexpected ULID {}",
+            ulid
+        );
+    }
+}
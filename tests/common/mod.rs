@@ -29,7 +29,7 @@ use log::*;
 use std::env;
 use std::time::Duration;
 
-/// Generates a log line to match the test format returned by `Db::get_log_entries` for a log
+/// Generates a log line to match the test format returned by `Handle::get_log_entries` for a log
 /// entry emitted from this module and processed by `make_deterministic`.
 ///
 /// `level` is the numerical level of the entry; `line` is the line of code where the message was
@@ -44,10 +44,12 @@ fn make_log_line(test_name: &str, level: u8, line: u32, message: &str) -> String
     )
 }
 
-/// Takes the log lines returned by `PostgresDb::get_log_entries` and strips out non-determinism.
+/// Takes the log lines returned by `Handle::get_log_entries` and strips out non-determinism.
 fn make_deterministic(lines: Vec<String>) -> Vec<String> {
     let mut new_lines = Vec::with_capacity(lines.len());
-    let timestamp_re = regex::Regex::new("^[0-9]+.[0-9]+").unwrap();
+    let timestamp_re =
+        regex::Regex::new("^[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}:[0-9]{2}(\\.[0-9]+)?Z")
+            .unwrap();
     for line in lines {
         let line = timestamp_re.replace(&line, "SSSS.uuuu");
         new_lines.push(line.to_string());
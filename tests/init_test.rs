@@ -0,0 +1,82 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Integration tests for the initialization of the logger.
+//!
+//! The logger is a global resource that can only be installed once per process, so all checks
+//! live in a single test.
+
+#![cfg(feature = "sqlite")]
+
+use db_logger::{init_with_options, sqlite, LogFilter, LoggerOptions};
+use log::{info, Level};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_with_options_errors() {
+    let db = sqlite::connect(sqlite::ConnectionOptions {
+        uri: ":memory:".to_owned(),
+        ignore_duplicates: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let opts = LoggerOptions { ensure_schema: true, ..Default::default() };
+    match init_with_options(db, opts).await {
+        Ok(_) => panic!("Initialization should have failed without ULIDs"),
+        Err(e) => assert!(e.to_string().contains("record_ulid"), "Unexpected error {}", e),
+    }
+
+    let db = sqlite::connect(sqlite::ConnectionOptions {
+        uri: ":memory:".to_owned(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let opts = LoggerOptions { verify_schema: true, ..Default::default() };
+    match init_with_options(db.clone(), opts).await {
+        Ok(_) => panic!("Initialization should have failed on a missing schema"),
+        Err(e) => assert!(e.to_string().contains("does not exist"), "Unexpected error {}", e),
+    }
+
+    // The failed initialization must not have installed the logger.
+    let opts = LoggerOptions {
+        ensure_schema: true,
+        default_level: Level::Info,
+        ignore_env: true,
+        ..Default::default()
+    };
+    let handle = init_with_options(db, opts).await.unwrap();
+    info!("Recorded after a failed initialization");
+    handle.flush().unwrap();
+    let rows = handle.query_log_entries(&LogFilter::default()).await.unwrap();
+    assert_eq!(
+        vec!["Recorded after a failed initialization"],
+        rows.iter().map(|row| row.message.as_str()).collect::<Vec<&str>>()
+    );
+
+    // A second initialization must fail instead of panicking.
+    let db = sqlite::connect(sqlite::ConnectionOptions {
+        uri: ":memory:".to_owned(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let opts = LoggerOptions { ensure_schema: true, ..Default::default() };
+    match init_with_options(db, opts).await {
+        Ok(_) => panic!("Initialization should have failed with a logger already installed"),
+        Err(e) => assert!(e.to_string().contains("Cannot install"), "Unexpected error {}", e),
+    }
+}
@@ -32,6 +32,7 @@ fn test_everything() {
     async fn prepare(path: &Path) -> Connection {
         let db = sqlite::connect(sqlite::ConnectionOptions {
             uri: format!("file:{}?mode=rwc", path.display()),
+            ..Default::default()
         })
         .await
         .unwrap();
@@ -0,0 +1,46 @@
+// db_logger
+// Copyright 2022 Julio Merino
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License.  You may obtain a copy
+// of the License at:
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.  See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Integration tests for the helpers in the `testing` module.
+
+#![cfg(feature = "testing")]
+
+use db_logger::testing::with_memory_logger;
+use log::{debug, info, Level};
+
+#[tokio::test]
+async fn test_with_memory_logger_captures_entries() {
+    let rows = with_memory_logger(|_handle| async {
+        info!("First message");
+        debug!("Second message");
+    })
+    .await;
+    assert_eq!(2, rows.len());
+    assert_eq!((Level::Info, "First message"), (rows[0].level, rows[0].message.as_str()));
+    assert_eq!((Level::Debug, "Second message"), (rows[1].level, rows[1].message.as_str()));
+    assert_eq!(Some("testing_test"), rows[0].module.as_deref());
+}
+
+#[tokio::test]
+async fn test_with_memory_logger_isolates_calls() {
+    for i in 0..3 {
+        let rows = with_memory_logger(|_handle| async move {
+            info!("Message in call {}", i);
+        })
+        .await;
+        let messages = rows.into_iter().map(|row| row.message).collect::<Vec<String>>();
+        assert_eq!(vec![format!("Message in call {}", i)], messages);
+    }
+}